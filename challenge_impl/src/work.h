@@ -65,6 +65,11 @@ char *work_factors_factors_to_str2(Work_Factors work_factors,
 // Must be cleaned up with work_cleanup_factors2().
 Work_Factors work_generate_target_factors2(uint64_t quads);
 void work_cleanup_factors2(Work_Factors *wf2);
+// Same as work_generate_target_factors2(), but every prime factor is within
+// [min_prime, max_prime]. The smallest prime in the range must be less than
+// 64. On failure, "value2" is NULL.
+Work_Factors work_generate_target_factors3(uint64_t quads, uint16_t min_prime,
+                                           uint16_t max_prime);
 
 // Returns value as contiguous base64 string where first byte is least significant.
 // Must be free'd after use.
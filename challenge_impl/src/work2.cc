@@ -143,18 +143,47 @@ void mult_b64_scalar(std::vector<char> &a, unsigned int scalar) {
     }
     a[idx] = base64_value_to_base64(static_cast<unsigned char>(prod));
   }
-  if (carry != 0) {
-    a.push_back(base64_value_to_base64(static_cast<unsigned char>(carry)));
+  while (carry != 0) {
+    a.push_back(base64_value_to_base64(static_cast<unsigned char>(carry % 64)));
+    carry /= 64;
   }
 }
 
 Work_Factors work_generate_target_factors2(uint64_t quads) {
+  return work_generate_target_factors3(quads, 2, 59);
+}
+
+Work_Factors work_generate_target_factors3(uint64_t quads, uint16_t min_prime,
+                                           uint16_t max_prime) {
+  Work_Factors wf;
+  wf.value = nullptr;
+  wf.value2 = nullptr;
+  wf.factors = nullptr;
+
+  std::vector<uint16_t> primes;
+  for (uint32_t candidate = min_prime < 2 ? 2 : min_prime;
+       candidate <= max_prime; ++candidate) {
+    bool is_prime = true;
+    for (uint32_t div = 2; div * div <= candidate; ++div) {
+      if (candidate % div == 0) {
+        is_prime = false;
+        break;
+      }
+    }
+    if (is_prime) {
+      primes.push_back(static_cast<uint16_t>(candidate));
+    }
+  }
+  // Padding multiplies by the smallest prime, which must grow the value by at
+  // most one base64 char at a time to land on a multiple of 4 chars.
+  if (primes.empty() || primes.front() >= 64) {
+    return wf;
+  }
+
   {
     srand(std::random_device()());
   }
 
-  Work_Factors wf;
-  wf.value = nullptr;
   wf.value2 = std::malloc(sizeof(std::vector<char>));
   std::vector<char> *b64 = new (wf.value2) std::vector<char>();
   b64->push_back('B');
@@ -165,59 +194,7 @@ Work_Factors work_generate_target_factors2(uint64_t quads) {
     if (r < 0) {
       r = -r;
     }
-    switch (r % 17) {
-      case 0:
-        r = 2;
-        break;
-      case 1:
-        r = 3;
-        break;
-      case 2:
-        r = 5;
-        break;
-      case 3:
-        r = 7;
-        break;
-      case 4:
-        r = 11;
-        break;
-      case 5:
-        r = 13;
-        break;
-      case 6:
-        r = 17;
-        break;
-      case 7:
-        r = 19;
-        break;
-      case 8:
-        r = 23;
-        break;
-      case 9:
-        r = 29;
-        break;
-      case 10:
-        r = 31;
-        break;
-      case 11:
-        r = 37;
-        break;
-      case 12:
-        r = 41;
-        break;
-      case 13:
-        r = 43;
-        break;
-      case 14:
-        r = 47;
-        break;
-      case 15:
-        r = 53;
-        break;
-      case 16:
-        r = 59;
-        break;
-    }
+    r = primes[static_cast<size_t>(r) % primes.size()];
     uint16_t *ptr = static_cast<uint16_t *>(std::malloc(2));
     *ptr = static_cast<uint16_t>(r);
     simple_archiver_priority_heap_insert(wf.factors, r, ptr, nullptr);
@@ -226,9 +203,10 @@ Work_Factors work_generate_target_factors2(uint64_t quads) {
 
   while (b64->size() % 4 != 0) {
     uint16_t *ptr = static_cast<uint16_t *>(std::malloc(2));
-    *ptr = 2;
-    simple_archiver_priority_heap_insert(wf.factors, 2, ptr, nullptr);
-    mult_b64_scalar(*b64, 2);
+    *ptr = primes.front();
+    simple_archiver_priority_heap_insert(wf.factors, primes.front(), ptr,
+                                         nullptr);
+    mult_b64_scalar(*b64, primes.front());
   }
 
   return wf;
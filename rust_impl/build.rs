@@ -24,6 +24,23 @@ fn main() {
         .include(format!("{cargo_manifest_dir}/../challenge_impl/third_party/SimpleArchiver/src"))
         .compile("c_work");
 
+    // Reported by "--version", from the "PoorMansAnubis_VERSION" the C
+    // challenge_impl's CMakeLists.txt sets.
+    let cmake_lists = format!("{cargo_manifest_dir}/../challenge_impl/CMakeLists.txt");
+    println!("cargo:rerun-if-changed={cmake_lists}");
+    let challenge_impl_version = std::fs::read_to_string(&cmake_lists)
+        .ok()
+        .and_then(|contents| {
+            contents.lines().find_map(|line| {
+                line.trim()
+                    .strip_prefix("set(PoorMansAnubis_VERSION ")
+                    .and_then(|version| version.strip_suffix(')'))
+                    .map(|version| version.trim().to_owned())
+            })
+        })
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=CHALLENGE_IMPL_VERSION={challenge_impl_version}");
+
     let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
 
     let bindings = bindgen::Builder::default()
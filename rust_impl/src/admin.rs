@@ -0,0 +1,318 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use salvo::Depot;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::json_types::{
+    AdminRequest, AdminResponse, AdminStats, AllowedEntry, ChallengeEntry, MintedToken,
+};
+use crate::state::{CachedAllow, DbFailOpens, FactorsSalt, MonitorOnlyChallenges, UpstreamGauge};
+use crate::{error::Error, helpers, reputation, signal, token::ExemptionTokens};
+
+/// Binds the "--admin-unix-socket", replacing a stale socket file, and makes
+/// it usable only by the owning user.
+pub fn bind(path: &Path) -> Result<UnixListener, Error> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        use std::os::unix::fs::FileTypeExt;
+        if !metadata.file_type().is_socket() {
+            return Err(Error::Generic(format!(
+                "{:?} exists and is not a socket",
+                path
+            )));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+    Ok(listener)
+}
+
+/// Serves admin connections on "listener" against the args, db pools, and
+/// state in "depot" until the server shuts down.
+pub fn spawn(listener: UnixListener, depot: Arc<Depot>) {
+    let started = Instant::now();
+    tokio::spawn(async move {
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = tokio::time::sleep(Duration::from_millis(333)) => {
+                    if signal::SIGNAL_HANDLED.load(std::sync::atomic::Ordering::Relaxed) {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            match accepted {
+                Ok((stream, _)) => {
+                    let depot = depot.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, &depot, started).await {
+                            eprintln!("WARNING: Admin connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("WARNING: Failed to accept admin connection: {}", e),
+            }
+        }
+    });
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    depot: &Depot,
+    started: Instant,
+) -> Result<(), Error> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<AdminRequest>(&line) {
+            Ok(request) => handle_request(depot, &request, started).await,
+            Err(e) => AdminResponse {
+                id: serde_json::Value::Null,
+                result: None,
+                error: Some(format!("Invalid request: {}", e)),
+            },
+        };
+        let mut out = serde_json::to_string(&response)
+            .map_err(|e| Error::Generic(format!("Failed to serialize admin response: {}", e)))?;
+        out.push('\n');
+        writer.write_all(out.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+async fn handle_request(depot: &Depot, request: &AdminRequest, started: Instant) -> AdminResponse {
+    let ret = match request.method.as_str() {
+        "list" => list(depot).await.and_then(to_value),
+        "challenges" => challenges(depot).await.and_then(to_value),
+        "revoke" => revoke(depot, request).await.map(|()| true.into()),
+        "reset" => reset(depot).await.map(|()| true.into()),
+        "reload" => reload(depot).map(|()| true.into()),
+        "rotate-salt" => rotate_salt(depot).map(|()| true.into()),
+        "stats" => stats(depot, started).await.and_then(to_value),
+        "mint-token" => mint_token(depot, request).and_then(to_value),
+        method => Err(Error::Generic(format!("Unknown method \"{}\"", method))),
+    };
+
+    match ret {
+        Ok(result) => AdminResponse {
+            id: request.id.clone(),
+            result: Some(result),
+            error: None,
+        },
+        Err(e) => AdminResponse {
+            id: request.id.clone(),
+            result: None,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+fn to_value(value: impl serde::Serialize) -> Result<serde_json::Value, Error> {
+    serde_json::to_value(value)
+        .map_err(|e| Error::Generic(format!("Failed to serialize admin result: {}", e)))
+}
+
+fn allowed_timeout(depot: &Depot) -> Result<Duration, Error> {
+    let args = crate::state::depot_args(depot)?;
+    Ok(Duration::from_secs(args.allowed_timeout_mins * 60))
+}
+
+async fn list(depot: &Depot) -> Result<Vec<AllowedEntry>, Error> {
+    Ok(crate::db::recently_allowed(depot, allowed_timeout(depot)?)
+        .await?
+        .into_iter()
+        .map(|(ip, port, age)| AllowedEntry {
+            ip,
+            port,
+            age_secs: age.as_secs(),
+        })
+        .collect())
+}
+
+async fn challenges(depot: &Depot) -> Result<Vec<ChallengeEntry>, Error> {
+    Ok(crate::db::pending_challenges(depot)
+        .await?
+        .into_iter()
+        .map(|(ip, port, user_agent)| ChallengeEntry {
+            ip,
+            port,
+            user_agent,
+        })
+        .collect())
+}
+
+async fn revoke(depot: &Depot, request: &AdminRequest) -> Result<(), Error> {
+    let ip = request
+        .params
+        .ip
+        .as_deref()
+        .ok_or(Error::Generic("\"revoke\" requires params.ip".into()))?;
+    let ip = helpers::canonical_ip(ip)?;
+
+    crate::db::revoke_allowed(depot, Some(&ip), request.params.port).await?;
+    if let Ok(cached_allow) = depot.get_typed::<CachedAllow>() {
+        cached_allow.revoke(Some(&ip), request.params.port)?;
+    }
+
+    Ok(())
+}
+
+async fn reset(depot: &Depot) -> Result<(), Error> {
+    crate::db::revoke_allowed(depot, None, None).await?;
+    if let Ok(cached_allow) = depot.get_typed::<CachedAllow>() {
+        cached_allow.revoke(None, None)?;
+    }
+
+    Ok(())
+}
+
+fn reload(depot: &Depot) -> Result<(), Error> {
+    depot
+        .get_typed::<reputation::ReputationList>()
+        .map_err(|_| Error::Generic("Nothing to reload without --reputation-file".into()))?
+        .reload()
+}
+
+fn rotate_salt(depot: &Depot) -> Result<(), Error> {
+    depot
+        .get_typed::<FactorsSalt>()
+        .map_err(|_| Error::Generic("FactorsSalt not in depot".into()))?
+        .rotate()
+}
+
+fn mint_token(depot: &Depot, request: &AdminRequest) -> Result<MintedToken, Error> {
+    let tokens = depot
+        .get_typed::<ExemptionTokens>()
+        .map_err(|_| Error::Generic("Can't mint tokens without --exemption-token-key".into()))?;
+    let ttl_secs = request
+        .params
+        .ttl_secs
+        .unwrap_or(crate::constants::DEFAULT_EXEMPTION_TOKEN_TTL_SECS);
+    let expires = crate::handlers::unix_now().saturating_add_unsigned(ttl_secs);
+    let ip = match &request.params.ip {
+        Some(ip) => Some(helpers::canonical_ip(ip)?),
+        None => None,
+    };
+
+    Ok(MintedToken {
+        token: tokens.mint(expires, ip.as_deref()),
+        expires,
+    })
+}
+
+async fn stats(depot: &Depot, started: Instant) -> Result<AdminStats, Error> {
+    let allowed = crate::db::recently_allowed(depot, allowed_timeout(depot)?)
+        .await?
+        .len();
+    let cached = match depot.get_typed::<CachedAllow>() {
+        Ok(cached_allow) => cached_allow.len()?,
+        Err(_) => 0,
+    };
+    let upstream_in_flight = match depot.get_typed::<UpstreamGauge>() {
+        Ok(upstream_gauge) => upstream_gauge.in_flight(),
+        Err(_) => 0,
+    };
+    let db_fail_opens = match depot.get_typed::<DbFailOpens>() {
+        Ok(db_fail_opens) => db_fail_opens.count(),
+        Err(_) => 0,
+    };
+    let monitor_only_challenges = match depot.get_typed::<MonitorOnlyChallenges>() {
+        Ok(monitor_only_challenges) => monitor_only_challenges.count(),
+        Err(_) => 0,
+    };
+
+    Ok(AdminStats {
+        allowed,
+        cached,
+        upstream_in_flight,
+        db_fail_opens,
+        monitor_only_challenges,
+        uptime_secs: started.elapsed().as_secs(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Args;
+
+    #[tokio::test]
+    async fn test_admin_list() {
+        let sqlite_db_file =
+            std::env::temp_dir().join(format!("pma_admin_test_{}", std::process::id()));
+        let socket_path =
+            std::env::temp_dir().join(format!("pma_admin_test_{}.sock", std::process::id()));
+        let args = Args {
+            sqlite_db_file: sqlite_db_file.clone(),
+            allowed_timeout_mins: crate::constants::ALLOWED_IP_TIMEOUT_MINUTES,
+            mysql_has_priority: false,
+            ..Default::default()
+        };
+        crate::db::init_sqlite_db(&args).await.unwrap();
+        crate::db::add_allowed_sqlite(&args, "203.0.113.5", 8180)
+            .await
+            .unwrap();
+        let mut depot = Depot::new();
+        depot.insert_typed(Arc::new(args));
+
+        let listener = bind(&socket_path).unwrap();
+        let mode = std::fs::metadata(&socket_path)
+            .unwrap()
+            .permissions()
+            .mode();
+        spawn(listener, Arc::new(depot));
+
+        let stream = UnixStream::connect(&socket_path).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer
+            .write_all(b"{\"id\": 1, \"method\": \"list\"}\n{\"id\": 2, \"method\": \"nope\"}\n")
+            .await
+            .unwrap();
+        let listed: AdminResponse =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        let unknown: AdminResponse =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        std::fs::remove_file(&sqlite_db_file).ok();
+        std::fs::remove_file(&socket_path).ok();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(listed.id, 1);
+        assert_eq!(listed.error, None);
+        let entries: Vec<AllowedEntry> = serde_json::from_value(listed.result.unwrap()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].ip, "203.0.113.5");
+        assert_eq!(entries[0].port, 8180);
+        assert_eq!(unknown.id, 2);
+        assert!(unknown.result.is_none());
+        assert!(unknown.error.unwrap().contains("nope"));
+    }
+}
@@ -15,108 +15,1236 @@
 // PERFORMANCE OF THIS SOFTWARE.
 
 use crate::error::Error;
+use ipnet::IpNet;
+use serde::Deserialize;
 use std::{
-    collections::{HashMap, LinkedList},
+    collections::{HashMap, HashSet, LinkedList},
     env::args as args_fn,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderOverflowPolicy {
+    /// Drop the headers past the limit and forward the rest.
+    #[default]
+    Truncate,
+    /// Reply with 502 instead of forwarding the response.
+    Error,
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GenerationFailurePolicy {
+    /// Reply with 503 and keep the client challenged.
+    #[default]
+    FailClosed,
+    /// Allow the client through without a challenge.
+    FailOpen,
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Plain,
+    /// One json object per line.
+    Json,
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChallengeType {
+    /// Factor a large number into primes.
+    #[default]
+    Factors,
+    /// Find a nonce whose SHA-256 starts with "--hashcash-bits" zero bits.
+    Hashcash,
+}
+
 #[derive(Default, Clone, Debug)]
 pub struct Args {
     pub factors: Option<u64>,
-    pub dest_url: String,
+    /// "--factors-distribution" as (quads, weight) pairs, empty if not set.
+    pub factors_distribution: Vec<(u64, u64)>,
+    pub factors_max: Option<u64>,
+    pub datacenter_cidrs: Vec<IpNet>,
+    pub min_prime_factor: Option<u16>,
+    pub max_prime_factor: Option<u16>,
+    pub dest_url: Option<String>,
+    pub auth_only: bool,
     pub addr_port_strs: Vec<String>,
-    pub port_to_dest_urls: HashMap<u16, String>,
+    pub allow_excess_listeners: bool,
+    pub max_listeners: Option<usize>,
+    /// A port's dest urls, forwarded to in turn when there's more than one.
+    pub port_to_dest_urls: HashMap<u16, Vec<String>>,
+    pub host_to_dest_urls: HashMap<String, String>,
     pub mysql_config_file: PathBuf,
     pub sqlite_db_file: PathBuf,
+    pub postgres_config_file: Option<PathBuf>,
+    pub memory_db: bool,
+    pub memory_seq_file: Option<PathBuf>,
     pub enable_x_real_ip_header: bool,
+    pub real_ip_headers: Vec<String>,
+    pub trusted_proxy_cidrs: Vec<IpNet>,
     pub api_url: String,
     pub js_factors_url: String,
+    pub heartbeat_url: String,
+    pub port_to_api_urls: HashMap<u16, String>,
+    pub port_to_js_factors_urls: HashMap<u16, String>,
     pub challenge_timeout_mins: u64,
+    /// "--challenge-heartbeat-max", enables heartbeats when set.
+    pub challenge_heartbeat_max_mins: Option<u64>,
     pub allowed_timeout_mins: u64,
     pub enable_override_dest_url: bool,
     pub mysql_has_priority: bool,
+    pub bad_gateway_page: Option<String>,
+    pub gateway_timeout_page: Option<String>,
+    pub internal_error_page: Option<String>,
+    pub maintenance_page: Option<String>,
+    pub maintenance_status: Option<u16>,
+    pub challenge_html: Option<String>,
+    pub challenge_js: Option<String>,
+    pub benchmark_clients: Option<u64>,
+    pub benchmark_iterations: u64,
+    pub auth_request_url: Option<String>,
+    pub solved_webhook: Option<String>,
+    pub upstream_client_cert: Option<Vec<u8>>,
+    pub upstream_client_key: Option<Vec<u8>>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    pub tls_detect: bool,
+    pub client_cert_cidrs: Vec<IpNet>,
+    pub exemption_token_key: Option<PathBuf>,
+    pub reputation_file: Option<PathBuf>,
+    pub policy_script: Option<PathBuf>,
+    pub record_traffic: Option<PathBuf>,
+    pub replay_traffic: Option<PathBuf>,
+    pub purge_expired: bool,
+    pub hash_salt: String,
+    pub admin_unix_socket: Option<PathBuf>,
+    pub bypass_cidrs: Vec<IpNet>,
+    pub bypass_paths: Vec<String>,
+    pub trust_loopback: bool,
+    pub allow_cache_evict_interval_secs: u64,
+    pub cleanup_interval_secs: u64,
+    pub cache_refresh_interval_secs: Option<u64>,
+    pub shutdown_timeout_secs: u64,
+    pub force_close: bool,
+    pub backend_timeout_secs: u64,
+    pub worker_jitter_ms: u64,
+    pub log_sample_rate: f64,
+    pub log_format: LogFormat,
+    pub max_response_header_bytes: Option<usize>,
+    pub max_proxy_body: usize,
+    pub backend_retries: u32,
+    pub stream_content_types: Vec<String>,
+    pub response_header_overflow: HeaderOverflowPolicy,
+    pub trust_forwarded_for: bool,
+    pub db_fail_open: bool,
+    pub monitor_only: bool,
+    pub on_generation_failure: GenerationFailurePolicy,
+    pub challenge_type: ChallengeType,
+    pub hashcash_bits: u8,
+    pub store_user_agent: bool,
+    pub challenge_preload: bool,
+    pub no_js_message: Option<String>,
+    pub no_js_link: Option<String>,
+    pub json_challenge: bool,
+    pub challenge_status: Option<u16>,
+    pub enable_audit_log: bool,
+    pub audit_log_retention_mins: Option<u64>,
+    pub max_concurrent_validations: Option<usize>,
+    pub max_concurrent: Option<usize>,
+    pub max_concurrent_challenges: Option<usize>,
+    pub max_json_size: usize,
+    /// "--challenge-rate=<count>:<secs>", as count and secs.
+    pub challenge_rate: Option<(u32, u64)>,
+    /// The "--config=<toml_file>" the args were read with, re-read on SIGHUP.
+    pub config_file: Option<PathBuf>,
+}
+
+impl Args {
+    /// Takes the fields of a fresh parse that can change while running.
+    /// Listeners, routes, the db and anything set up once at startup keep
+    /// their current values until a restart.
+    pub fn reload_from(&mut self, fresh: Args) {
+        self.factors = fresh
+            .factors
+            .or(Some(crate::constants::DEFAULT_FACTORS_QUADS));
+        self.factors_distribution = fresh.factors_distribution;
+        self.factors_max = fresh.factors_max;
+        self.datacenter_cidrs = fresh.datacenter_cidrs;
+        self.min_prime_factor = fresh.min_prime_factor;
+        self.max_prime_factor = fresh.max_prime_factor;
+        self.dest_url = fresh.dest_url;
+        self.port_to_dest_urls = fresh.port_to_dest_urls;
+        self.host_to_dest_urls = fresh.host_to_dest_urls;
+        self.enable_x_real_ip_header = fresh.enable_x_real_ip_header;
+        self.real_ip_headers = fresh.real_ip_headers;
+        self.trusted_proxy_cidrs = fresh.trusted_proxy_cidrs;
+        self.challenge_timeout_mins = fresh.challenge_timeout_mins;
+        self.allowed_timeout_mins = fresh.allowed_timeout_mins;
+        self.audit_log_retention_mins = fresh.audit_log_retention_mins;
+        self.bad_gateway_page = fresh.bad_gateway_page;
+        self.gateway_timeout_page = fresh.gateway_timeout_page;
+        self.internal_error_page = fresh.internal_error_page;
+        self.maintenance_page = fresh.maintenance_page;
+        self.maintenance_status = fresh.maintenance_status;
+        self.challenge_html = fresh.challenge_html;
+        self.challenge_js = fresh.challenge_js;
+        self.bypass_cidrs = fresh.bypass_cidrs;
+        self.bypass_paths = fresh.bypass_paths;
+        self.trust_loopback = fresh.trust_loopback;
+        self.worker_jitter_ms = fresh.worker_jitter_ms;
+        self.log_sample_rate = fresh.log_sample_rate;
+        self.log_format = fresh.log_format;
+        self.max_response_header_bytes = fresh.max_response_header_bytes;
+        self.max_proxy_body = fresh.max_proxy_body;
+        self.backend_retries = fresh.backend_retries;
+        self.stream_content_types = fresh.stream_content_types;
+        self.response_header_overflow = fresh.response_header_overflow;
+        self.trust_forwarded_for = fresh.trust_forwarded_for;
+        self.db_fail_open = fresh.db_fail_open;
+        self.monitor_only = fresh.monitor_only;
+        self.on_generation_failure = fresh.on_generation_failure;
+        self.hashcash_bits = fresh.hashcash_bits;
+        self.store_user_agent = fresh.store_user_agent;
+        self.challenge_preload = fresh.challenge_preload;
+        self.no_js_message = fresh.no_js_message;
+        self.no_js_link = fresh.no_js_link;
+        self.json_challenge = fresh.json_challenge;
+        self.challenge_status = fresh.challenge_status;
+        self.max_json_size = fresh.max_json_size;
+    }
+}
+
+/// A "[port_to_dest_url]" entry in a config file, either one url or a list of
+/// them.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum DestUrls {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Values read from a "--config=<toml_file>". Keys are the matching flag names
+/// with "-" replaced by "_"; flags given on the command line take precedence.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub factors: Option<u64>,
+    pub factors_distribution: Option<String>,
+    pub factors_max: Option<u64>,
+    pub datacenter_cidr: Option<Vec<String>>,
+    pub min_prime_factor: Option<u16>,
+    pub max_prime_factor: Option<u16>,
+    pub dest_url: Option<String>,
+    pub auth_only: Option<bool>,
+    pub addr_port: Option<Vec<String>>,
+    pub allow_excess_listeners: Option<bool>,
+    pub max_listeners: Option<usize>,
+    pub port_to_dest_url: Option<HashMap<u16, DestUrls>>,
+    pub host_to_dest_url: Option<HashMap<String, String>>,
+    pub mysql_conf: Option<PathBuf>,
+    pub sqlite_path: Option<PathBuf>,
+    pub postgres_conf: Option<PathBuf>,
+    pub memory_db: Option<bool>,
+    pub memory_seq_file: Option<PathBuf>,
+    pub enable_x_real_ip_header: Option<bool>,
+    pub real_ip_header: Option<Vec<String>>,
+    pub trusted_proxy: Option<Vec<String>>,
+    pub api_url: Option<String>,
+    pub js_factors_url: Option<String>,
+    pub heartbeat_url: Option<String>,
+    pub port_to_api_url: Option<HashMap<u16, String>>,
+    pub port_to_js_factors_url: Option<HashMap<u16, String>>,
+    pub auth_request_url: Option<String>,
+    pub solved_webhook: Option<String>,
+    pub challenge_timeout: Option<u64>,
+    pub challenge_heartbeat_max: Option<u64>,
+    pub allowed_timeout: Option<u64>,
+    pub allow_cache_evict_interval: Option<u64>,
+    pub cleanup_interval: Option<u64>,
+    pub cache_refresh_interval: Option<u64>,
+    pub shutdown_timeout: Option<u64>,
+    pub force_close: Option<bool>,
+    pub backend_timeout: Option<u64>,
+    pub worker_jitter: Option<u64>,
+    pub log_sample_rate: Option<f64>,
+    pub trust_forwarded_for: Option<bool>,
+    pub db_fail_open: Option<bool>,
+    pub monitor_only: Option<bool>,
+    pub store_user_agent: Option<bool>,
+    pub enable_audit_log: Option<bool>,
+    pub audit_log_retention: Option<u64>,
+    pub max_concurrent_validations: Option<usize>,
+    pub max_concurrent: Option<usize>,
+    pub max_concurrent_challenges: Option<usize>,
+    pub max_json_size: Option<usize>,
+    pub challenge_rate: Option<String>,
+    pub challenge_preload: Option<bool>,
+    pub no_js_message: Option<String>,
+    pub no_js_link: Option<String>,
+    pub json_challenge: Option<bool>,
+    pub challenge_status: Option<u16>,
+    pub maintenance_status: Option<u16>,
+    pub max_response_header_bytes: Option<usize>,
+    pub max_proxy_body: Option<usize>,
+    pub backend_retries: Option<u32>,
+    pub stream_content_types: Option<Vec<String>>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    pub tls_detect: Option<bool>,
+    pub require_client_cert_for: Option<Vec<String>>,
+    pub exemption_token_key: Option<PathBuf>,
+    pub reputation_file: Option<PathBuf>,
+    pub policy_script: Option<PathBuf>,
+    pub record_traffic: Option<PathBuf>,
+    pub hash_salt: Option<String>,
+    pub admin_unix_socket: Option<PathBuf>,
+    pub bypass_cidr: Option<Vec<String>>,
+    pub bypass_path: Option<Vec<String>>,
+    pub trust_loopback: Option<bool>,
+}
+
+impl ConfigFile {
+    /// Overwrites the fields of "args" that are set in this config file.
+    pub fn apply(self, args: &mut Args) -> Result<(), Error> {
+        if self.factors.is_some() {
+            args.factors = self.factors;
+        }
+        if let Some(factors_distribution) = self.factors_distribution {
+            args.factors_distribution =
+                parse_factors_distribution("factors_distribution", &factors_distribution)?;
+        }
+        if self.factors_max.is_some() {
+            args.factors_max = self.factors_max;
+        }
+        if let Some(datacenter_cidr) = self.datacenter_cidr {
+            args.datacenter_cidrs = datacenter_cidr
+                .iter()
+                .map(|cidr| crate::helpers::parse_ip_net(cidr))
+                .collect::<Result<Vec<IpNet>, Error>>()?;
+        }
+        if self.min_prime_factor.is_some() {
+            args.min_prime_factor = self.min_prime_factor;
+        }
+        if self.max_prime_factor.is_some() {
+            args.max_prime_factor = self.max_prime_factor;
+        }
+        if self.dest_url.is_some() {
+            args.dest_url = self.dest_url;
+        }
+        if let Some(auth_only) = self.auth_only {
+            args.auth_only = auth_only;
+        }
+        if let Some(addr_port) = self.addr_port {
+            args.addr_port_strs = addr_port;
+        }
+        if let Some(allow_excess_listeners) = self.allow_excess_listeners {
+            args.allow_excess_listeners = allow_excess_listeners;
+        }
+        if let Some(max_listeners) = self.max_listeners {
+            args.max_listeners = Some(max_listeners);
+        }
+        if let Some(port_to_dest_url) = self.port_to_dest_url {
+            args.port_to_dest_urls
+                .extend(port_to_dest_url.into_iter().map(|(port, dest_urls)| {
+                    (
+                        port,
+                        match dest_urls {
+                            DestUrls::One(dest_url) => vec![dest_url],
+                            DestUrls::Many(dest_urls) => dest_urls,
+                        },
+                    )
+                }));
+        }
+        if let Some(host_to_dest_url) = self.host_to_dest_url {
+            args.host_to_dest_urls.extend(host_to_dest_url);
+        }
+        if let Some(mysql_conf) = self.mysql_conf {
+            args.mysql_config_file = mysql_conf;
+            args.mysql_has_priority = true;
+            args.postgres_config_file = None;
+            args.memory_db = false;
+        }
+        if let Some(sqlite_path) = self.sqlite_path {
+            args.sqlite_db_file = sqlite_path;
+            args.mysql_has_priority = false;
+            args.postgres_config_file = None;
+            args.memory_db = false;
+        }
+        if let Some(postgres_conf) = self.postgres_conf {
+            if !cfg!(feature = "postgres") {
+                return Err(Error::Generic(
+                    "postgres_conf requires building with the \"postgres\" feature".into(),
+                ));
+            }
+            args.postgres_config_file = Some(postgres_conf);
+            args.mysql_has_priority = false;
+            args.memory_db = false;
+        }
+        if self.memory_db == Some(true) {
+            if !cfg!(feature = "memory") {
+                return Err(Error::Generic(
+                    "memory_db requires building with the \"memory\" feature".into(),
+                ));
+            }
+            args.memory_db = true;
+            args.mysql_has_priority = false;
+            args.postgres_config_file = None;
+        }
+        if self.memory_seq_file.is_some() {
+            args.memory_seq_file = self.memory_seq_file;
+        }
+        if let Some(enable_x_real_ip_header) = self.enable_x_real_ip_header {
+            args.enable_x_real_ip_header = enable_x_real_ip_header;
+        }
+        if let Some(real_ip_header) = self.real_ip_header {
+            args.real_ip_headers = real_ip_header.iter().map(|h| h.to_lowercase()).collect();
+            args.enable_x_real_ip_header = true;
+        }
+        if let Some(trusted_proxy) = self.trusted_proxy {
+            args.trusted_proxy_cidrs = trusted_proxy
+                .iter()
+                .map(|cidr| crate::helpers::parse_ip_net(cidr))
+                .collect::<Result<Vec<IpNet>, Error>>()?;
+        }
+        if let Some(api_url) = self.api_url {
+            args.api_url = api_url;
+        }
+        if let Some(js_factors_url) = self.js_factors_url {
+            args.js_factors_url = js_factors_url;
+        }
+        if let Some(heartbeat_url) = self.heartbeat_url {
+            args.heartbeat_url = heartbeat_url;
+        }
+        if let Some(port_to_api_url) = self.port_to_api_url {
+            args.port_to_api_urls.extend(port_to_api_url);
+        }
+        if let Some(port_to_js_factors_url) = self.port_to_js_factors_url {
+            args.port_to_js_factors_urls.extend(port_to_js_factors_url);
+        }
+        if self.auth_request_url.is_some() {
+            args.auth_request_url = self.auth_request_url;
+        }
+        if self.solved_webhook.is_some() {
+            args.solved_webhook = self.solved_webhook;
+        }
+        if let Some(challenge_timeout) = self.challenge_timeout {
+            args.challenge_timeout_mins = challenge_timeout;
+        }
+        if self.challenge_heartbeat_max.is_some() {
+            args.challenge_heartbeat_max_mins = self.challenge_heartbeat_max;
+        }
+        if let Some(allowed_timeout) = self.allowed_timeout {
+            args.allowed_timeout_mins = allowed_timeout;
+        }
+        if let Some(allow_cache_evict_interval) = self.allow_cache_evict_interval {
+            if allow_cache_evict_interval == 0 {
+                return Err("allow_cache_evict_interval must be greater than 0".into());
+            }
+            args.allow_cache_evict_interval_secs = allow_cache_evict_interval;
+        }
+        if let Some(cleanup_interval) = self.cleanup_interval {
+            if cleanup_interval == 0 {
+                return Err("cleanup_interval must be greater than 0".into());
+            }
+            args.cleanup_interval_secs = cleanup_interval;
+        }
+        if let Some(cache_refresh_interval) = self.cache_refresh_interval {
+            if cache_refresh_interval == 0 {
+                return Err("cache_refresh_interval must be greater than 0".into());
+            }
+            args.cache_refresh_interval_secs = Some(cache_refresh_interval);
+        }
+        if let Some(shutdown_timeout) = self.shutdown_timeout {
+            args.shutdown_timeout_secs = shutdown_timeout;
+        }
+        if let Some(force_close) = self.force_close {
+            args.force_close = force_close;
+        }
+        if let Some(backend_timeout) = self.backend_timeout {
+            if backend_timeout == 0 {
+                return Err("backend_timeout must be greater than 0".into());
+            }
+            args.backend_timeout_secs = backend_timeout;
+        }
+        if let Some(worker_jitter) = self.worker_jitter {
+            args.worker_jitter_ms = worker_jitter;
+        }
+        if let Some(log_sample_rate) = self.log_sample_rate {
+            if !(0.0..=1.0).contains(&log_sample_rate) {
+                return Err("log_sample_rate must be between 0.0 and 1.0".into());
+            }
+            args.log_sample_rate = log_sample_rate;
+        }
+        if let Some(trust_forwarded_for) = self.trust_forwarded_for {
+            args.trust_forwarded_for = trust_forwarded_for;
+        }
+        if let Some(db_fail_open) = self.db_fail_open {
+            args.db_fail_open = db_fail_open;
+        }
+        if let Some(monitor_only) = self.monitor_only {
+            args.monitor_only = monitor_only;
+        }
+        if let Some(store_user_agent) = self.store_user_agent {
+            args.store_user_agent = store_user_agent;
+        }
+        if let Some(enable_audit_log) = self.enable_audit_log {
+            args.enable_audit_log = enable_audit_log;
+        }
+        if self.audit_log_retention.is_some() {
+            args.audit_log_retention_mins = self.audit_log_retention;
+        }
+        if let Some(max_concurrent_validations) = self.max_concurrent_validations {
+            if max_concurrent_validations == 0 {
+                return Err("max_concurrent_validations must be greater than 0".into());
+            }
+            args.max_concurrent_validations = Some(max_concurrent_validations);
+        }
+        if let Some(max_concurrent) = self.max_concurrent {
+            if max_concurrent == 0 {
+                return Err("max_concurrent must be greater than 0".into());
+            }
+            args.max_concurrent = Some(max_concurrent);
+        }
+        if let Some(max_concurrent_challenges) = self.max_concurrent_challenges {
+            if max_concurrent_challenges == 0 {
+                return Err("max_concurrent_challenges must be greater than 0".into());
+            }
+            args.max_concurrent_challenges = Some(max_concurrent_challenges);
+        }
+        if let Some(max_json_size) = self.max_json_size {
+            if max_json_size == 0 {
+                return Err("max_json_size must be greater than 0".into());
+            }
+            args.max_json_size = max_json_size;
+        }
+        if let Some(challenge_rate) = self.challenge_rate {
+            args.challenge_rate = Some(parse_challenge_rate("challenge_rate", &challenge_rate)?);
+        }
+        if let Some(challenge_preload) = self.challenge_preload {
+            args.challenge_preload = challenge_preload;
+        }
+        if self.no_js_message.is_some() {
+            args.no_js_message = self.no_js_message;
+        }
+        if self.no_js_link.is_some() {
+            args.no_js_link = self.no_js_link;
+        }
+        if let Some(json_challenge) = self.json_challenge {
+            args.json_challenge = json_challenge;
+        }
+        if self.challenge_status.is_some() {
+            args.challenge_status = self.challenge_status;
+        }
+        if self.maintenance_status.is_some() {
+            args.maintenance_status = self.maintenance_status;
+        }
+        if self.max_response_header_bytes.is_some() {
+            args.max_response_header_bytes = self.max_response_header_bytes;
+        }
+        if let Some(max_proxy_body) = self.max_proxy_body {
+            args.max_proxy_body = max_proxy_body;
+        }
+        if let Some(backend_retries) = self.backend_retries {
+            args.backend_retries = backend_retries;
+        }
+        if let Some(stream_content_types) = self.stream_content_types {
+            args.stream_content_types = stream_content_types
+                .iter()
+                .map(|content_type| content_type.trim().to_ascii_lowercase())
+                .collect();
+        }
+        if self.tls_cert.is_some() {
+            args.tls_cert = self.tls_cert;
+        }
+        if self.tls_key.is_some() {
+            args.tls_key = self.tls_key;
+        }
+        if self.tls_client_ca.is_some() {
+            args.tls_client_ca = self.tls_client_ca;
+        }
+        if let Some(tls_detect) = self.tls_detect {
+            args.tls_detect = tls_detect;
+        }
+        if let Some(require_client_cert_for) = self.require_client_cert_for {
+            args.client_cert_cidrs.clear();
+            for range in require_client_cert_for.iter() {
+                args.client_cert_cidrs
+                    .extend(parse_client_cert_range(range)?);
+            }
+        }
+        if self.exemption_token_key.is_some() {
+            args.exemption_token_key = self.exemption_token_key;
+        }
+        if self.reputation_file.is_some() {
+            args.reputation_file = self.reputation_file;
+        }
+        if self.policy_script.is_some() {
+            if !cfg!(feature = "policy-script") {
+                return Err(Error::Generic(
+                    "policy_script requires building with the \"policy-script\" feature".into(),
+                ));
+            }
+            args.policy_script = self.policy_script;
+        }
+        if self.record_traffic.is_some() {
+            args.record_traffic = self.record_traffic;
+        }
+        if let Some(hash_salt) = self.hash_salt {
+            args.hash_salt = hash_salt;
+        }
+        if self.admin_unix_socket.is_some() {
+            args.admin_unix_socket = self.admin_unix_socket;
+        }
+        if let Some(bypass_cidr) = self.bypass_cidr {
+            args.bypass_cidrs = bypass_cidr
+                .iter()
+                .map(|cidr| crate::helpers::parse_ip_net(cidr))
+                .collect::<Result<Vec<IpNet>, Error>>()?;
+        }
+        if let Some(bypass_path) = self.bypass_path {
+            args.bypass_paths = bypass_path;
+        }
+        if let Some(trust_loopback) = self.trust_loopback {
+            args.trust_loopback = trust_loopback;
+        }
+
+        Ok(())
+    }
+}
+
+/// Errors if the template given to "flag" lacks any of "placeholders", which
+/// would leave the challenge unsolvable.
+fn check_placeholders(flag: &str, template: &str, placeholders: &[&str]) -> Result<(), Error> {
+    match placeholders
+        .iter()
+        .find(|placeholder| !template.contains(*placeholder))
+    {
+        Some(placeholder) => {
+            Err(format!("{} is missing the {} placeholder", flag, placeholder).into())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Parses "<port>:<url>" for the "--port-to-*-url=" args named "flag".
+fn parse_port_to_url(flag: &str, end: &str) -> Result<(u16, String), Error> {
+    let (port, url) = end
+        .split_once(':')
+        .ok_or(format!("{}=<port>:<url> invalid url!", flag))?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| format!("{}=<port>:<url> invalid port!", flag))?;
+
+    Ok((port, url.to_owned()))
+}
+
+/// Parses "<quads>:<weight>,..." for "--factors-distribution=" (or its config
+/// key "flag").
+fn parse_factors_distribution(flag: &str, end: &str) -> Result<Vec<(u64, u64)>, Error> {
+    end.split(',')
+        .map(|entry| {
+            let (quads, weight) = entry
+                .trim()
+                .split_once(':')
+                .ok_or(format!("{}=<quads>:<weight>,... missing \":\"!", flag))?;
+            let quads: u64 = quads
+                .parse()
+                .map_err(|_| format!("{}=<quads>:<weight>,... invalid quads!", flag))?;
+            let weight: u64 = weight
+                .parse()
+                .map_err(|_| format!("{}=<quads>:<weight>,... invalid weight!", flag))?;
+            if quads == 0 || weight == 0 {
+                return Err(
+                    format!("{}=<quads>:<weight>,... must both be greater than 0", flag).into(),
+                );
+            }
+            Ok((quads, weight))
+        })
+        .collect()
+}
+
+/// Parses "<count>:<secs>" for "--challenge-rate=" (or its config key "flag").
+fn parse_challenge_rate(flag: &str, end: &str) -> Result<(u32, u64), Error> {
+    let (count, secs) = end
+        .split_once(':')
+        .ok_or(format!("{}=<count>:<secs> missing \":\"!", flag))?;
+    let count: u32 = count
+        .parse()
+        .map_err(|_| format!("{}=<count>:<secs> invalid count!", flag))?;
+    let secs: u64 = secs
+        .parse()
+        .map_err(|_| format!("{}=<count>:<secs> invalid secs!", flag))?;
+    if count == 0 || secs == 0 {
+        return Err(format!("{}=<count>:<secs> must both be greater than 0", flag).into());
+    }
+
+    Ok((count, secs))
+}
+
+/// Parses a "--require-client-cert-for=..." value, where "any" covers every
+/// IPv4 and IPv6 client.
+fn parse_client_cert_range(s: &str) -> Result<Vec<IpNet>, Error> {
+    if s == "any" {
+        Ok(vec![
+            crate::helpers::parse_ip_net("0.0.0.0/0")?,
+            crate::helpers::parse_ip_net("::/0")?,
+        ])
+    } else {
+        Ok(vec![crate::helpers::parse_ip_net(s)?])
+    }
+}
+
+pub fn parse_config_file(path: &Path) -> Result<ConfigFile, Error> {
+    let contents = std::fs::read_to_string(path)?;
+    toml::from_str(&contents)
+        .map_err(|e| Error::Generic(format!("Invalid config file {:?}: {}", path, e)))
+}
+
+/// The crate version and the challenge_impl version it was built with.
+pub fn version_text() -> String {
+    format!(
+        "{} {}\nchallenge_impl {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        env!("CHALLENGE_IMPL_VERSION")
+    )
 }
 
 pub fn print_args() {
     println!("Args:");
+    println!("  --version : Print the version and the challenge_impl version it was built with");
     println!("  --enable-unknown-arg-error : Exit with error if unknown argument is given");
+    println!(
+        "  --config=<toml_file> : Read args from a TOML file, keys are flag names with \"_\" instead of \"-\""
+    );
+    println!("  NOTICE: Args given on the command line override values from --config=...");
+    println!(
+        "  NOTICE: With --config=..., SIGHUP re-reads the file instead of stopping the server; dest urls, timeouts, pages and limits change, listeners and the db need a restart"
+    );
     println!("  --factors=<quads> : Generate factors challenge with <quads> 24-bit-segments");
     println!(
-        "  --dest-url=<url> : Destination URL for verified clients;\n    example: \"--dest-url=http://127.0.0.1:9999\""
+        "  --factors-distribution=<quads>:<weight>,... : Pick each factors challenge's quads at random with the given weights instead of always using --factors=..., e.g. \"1800:1,2200:2,2600:1\""
+    );
+    println!(
+        "  --factors-max=<quads> : Give clients showing bot signals (no User-Agent, a --datacenter-cidr=... address, or near a \"bad\" --reputation-file=... entry) more quads, up to <quads>"
+    );
+    println!(
+        "  --datacenter-cidr=<cidr> : Treat clients in <cidr> (e.g. a hosting provider's ASN ranges) as a bot signal for --factors-max=..."
+    );
+    println!("  NOTICE: Specify --datacenter-cidr=... multiple times to add more ranges");
+    println!(
+        "  --min-prime-factor=<prime> : Smallest prime used in factors challenges (default 2, at most 61)"
+    );
+    println!(
+        "  --max-prime-factor=<prime> : Largest prime used in factors challenges (default 59)"
+    );
+    println!(
+        "  --dest-url=<url> : Destination URL for verified clients;\n    example: \"--dest-url=http://127.0.0.1:9999\""
+    );
+    println!(
+        "  --addr-port=<addr>:<port> : Listening addr/port;\n    example: \"--addr-port=127.0.0.1:8080\""
+    );
+    println!("  NOTICE: Specify --addr-port=... multiple times to listen on multiple ports");
+    println!(
+        "  --max-listeners=<count> : Limit on distinct ports across --addr-port=... and --port-to-dest-url=... (default no limit)"
+    );
+    println!("  --allow-excess-listeners : Ignore the --max-listeners=... limit");
+    println!(
+        "  WARNING: Each listener and its connections use file descriptors, so with\n    --allow-excess-listeners make sure \"ulimit -n\" is high enough or new\n    connections may fail to be accepted!"
+    );
+    println!(
+        "  --port-to-dest-url=<port>:<url> : Ensure requests from listening on <port> is forwarded to <url>"
+    );
+    println!("  example: \"--port-to-dest-url=9001:https://example.com\"");
+    println!(
+        "  NOTICE: Dest urls must be http or https; trailing slashes are dropped, so \"https://x/\" and \"https://x\" are the same"
+    );
+    println!(
+        "  NOTICE: WebSocket upgrades from allowed clients are passed through to the dest url over ws:// or wss://, other clients get the challenge page"
+    );
+    println!("  NOTICE: Specify --port-to-dest-url=... multiple times to add more mappings");
+    println!(
+        "  NOTICE: Giving the same <port> more than once forwards its requests to each <url> in turn"
+    );
+    println!(
+        "  --host-to-dest-url=<host>:<url> : Forward requests whose Host is <host> to <url>, before any --port-to-dest-url=... mapping"
+    );
+    println!("  example: \"--host-to-dest-url=example.com:https://127.0.0.1:9001\"");
+    println!(
+        "  NOTICE: Hosts are matched without a port, lowercased, and with unicode names in their punycode \"xn--\" form, so \"Example.COM:8080\" matches \"example.com\""
+    );
+    println!("  --mysql-conf=<config_file> : Set path to config file for mysql settings");
+    println!("  --sqlite-path=<filename> : Set sqlite db filename path");
+    println!(
+        "  --postgres-conf=<config_file> : Set path to config file for postgres settings (same format as mysql config)"
+    );
+    println!("  NOTICE: --postgres-conf=... requires building with the \"postgres\" feature");
+    println!(
+        "  --memory-db : Keep challenges and allowed clients in memory only, e.g. for tests or throwaway instances; nothing survives a restart"
+    );
+    println!("  NOTICE: --memory-db requires building with the \"memory\" feature");
+    println!(
+        "  --memory-seq-file=<file> : Save the --memory-db sequence counter to <file> periodically and on shutdown, resuming from it on start"
+    );
+    println!(
+        "  --enable-x-real-ip-header : Enable trusting \"x-real-ip\" header as client ip addr"
+    );
+    println!(
+        "  --real-ip-header=<name> : Trust header <name> as client ip addr instead of \"x-real-ip\";\n    example: \"--real-ip-header=CF-Connecting-IP\""
+    );
+    println!(
+        "  NOTICE: Specify --real-ip-header=... multiple times to check headers in the given order"
+    );
+    println!("  NOTE: --real-ip-header=... implies --enable-x-real-ip-header");
+    println!(
+        "  --trusted-proxy=<cidr> : Only trust the real ip header from connecting peers in <cidr>, others are identified by their own address;\n    example: \"--trusted-proxy=10.0.0.0/8\""
+    );
+    println!(
+        "  NOTICE: Specify --trusted-proxy=... multiple times to add more ranges, without any the header is trusted from every peer"
+    );
+    println!(
+        "  --bypass-cidr=<cidr> : Forward clients in <cidr> without a challenge, e.g. monitoring;\n    example: \"--bypass-cidr=10.0.0.0/8\""
+    );
+    println!(
+        "  NOTICE: Specify --bypass-cidr=... multiple times to add more ranges, the client ip is resolved the same as everywhere else"
+    );
+    println!(
+        "  --bypass-path=<glob> : Forward requests whose path matches <glob> without a challenge, \"*\" matches anything including \"/\";\n    example: \"--bypass-path=/.well-known/acme-challenge/*\""
+    );
+    println!(
+        "  NOTICE: Specify --bypass-path=... multiple times to add more globs, paths with \".\" or \"..\" segments or encoded dots or slashes never match"
+    );
+    println!(
+        "  --trust-loopback : Forward loopback clients (127.0.0.0/8, ::1) on loopback listeners without a challenge, e.g. health checks (default on)"
+    );
+    println!("  --no-trust-loopback : Challenge loopback clients like any other");
+    println!(
+        "  NOTICE: A loopback address from a real ip header is only trusted if the listener and the connecting peer are loopback too"
+    );
+    println!(
+        "  --trust-forwarded-for : Append the client ip to incoming \"x-forwarded-for\" (and keep \"x-forwarded-proto\") instead of replacing them"
+    );
+    println!(
+        "  --db-fail-open : Let clients through (with a warning) instead of replying 503 when the db can't be queried"
+    );
+    println!(
+        "  --monitor-only : Never challenge, forward every client and only log (and count in the admin \"stats\") the ones that would have been challenged"
+    );
+    println!(
+        "  --api-url=<url> : Set endpoint for client to POST to this software;\n    example: \"--api-url=/pma_api\""
+    );
+    println!(
+        "  --js-factors-url=<url> : Set endpoint for client to request factors.js from this software;\n    example: \"--js-factors-url=/pma_factors.js\""
+    );
+    println!(
+        "  --port-to-api-url=<port>:<url> : Use <url> instead of --api-url=... for requests on listening <port>, so it can't collide with a path of that port's dest url;\n    example: \"--port-to-api-url=9001:/pma_api_9001\""
+    );
+    println!(
+        "  --port-to-js-factors-url=<port>:<url> : Use <url> instead of --js-factors-url=... for requests on listening <port>;\n    example: \"--port-to-js-factors-url=9001:/pma_factors_9001.js\""
+    );
+    println!(
+        "  NOTICE: On a port with an override, requests to the global --api-url=... or --js-factors-url=... are forwarded to the dest url like any other path"
+    );
+    println!(
+        "  --auth-request-url=<url> : Set endpoint answering 200 (allowed), 401 (not allowed), 403 (denied by --policy-script) or 503 (db unavailable) for nginx \"auth_request\" or Traefik \"ForwardAuth\";\n    example: \"--auth-request-url=/pma_auth\""
+    );
+    println!(
+        "  --auth-only : Only serve challenges and --auth-request-url=..., never forward to a dest url"
+    );
+    println!(
+        "  NOTICE: --dest-url=... or --port-to-dest-url=... is required unless --auth-only is given"
+    );
+    println!(
+        "  --solved-webhook=<url> : POST a JSON event (ip, port, solved_at, validate_ms) to <url> on each solved challenge;\n    example: \"--solved-webhook=http://127.0.0.1:9000/pma_solved\""
+    );
+    println!(
+        "  --on-generation-failure=<fail-closed|fail-open> : Reply with 503 (default) or let the client through if generating a challenge fails"
+    );
+    println!(
+        "  --challenge-type=<factors|hashcash> : Have clients factor a large number (default) or find a SHA-256 hash with leading zero bits"
+    );
+    println!(
+        "  --hashcash-bits=<bits> : Leading zero bits required with --challenge-type=hashcash (default {}, max {})",
+        crate::constants::DEFAULT_HASHCASH_BITS,
+        crate::constants::MAX_HASHCASH_BITS
+    );
+    println!(
+        "  NOTICE: --challenge-type=hashcash uses the browser's crypto.subtle, which is only available over https or on localhost"
+    );
+    println!(
+        "  --store-user-agent : Record the client's User-Agent (up to {} bytes) with each challenge, listed by the admin \"challenges\" method",
+        crate::constants::MAX_USER_AGENT_LEN
+    );
+    println!(
+        "  --enable-audit-log : Record when each client is issued a challenge and passes or fails it in the AUDIT_LOG table"
+    );
+    println!(
+        "  --audit-log-retention=<minutes> : Delete audit log rows older than this along with the other expired rows"
+    );
+    println!("  NOTICE: Without --audit-log-retention=... audit log rows are never deleted");
+    println!(
+        "  --max-concurrent-validations=<count> : Limit challenge answers checked against the db at once, replying 503 to the rest"
+    );
+    println!(
+        "  --max-concurrent=<count> : Limit requests forwarded to dest urls at once, replying 503 to those that wait past {}ms for a turn",
+        crate::constants::CONCURRENCY_WAIT_MS
+    );
+    println!(
+        "  --max-concurrent-challenges=<count> : Limit challenge pages being issued at once the same way (default {} times --max-concurrent)",
+        crate::constants::CHALLENGE_CONCURRENCY_FACTOR
+    );
+    println!(
+        "  --max-json-size=<bytes> : Reject challenge answers larger than this with 413 (default {})",
+        crate::constants::DEFAULT_JSON_MAX_SIZE
+    );
+    println!(
+        "  --challenge-rate=<count>:<secs> : Issue each ip at most <count> challenges per <secs> seconds, replying 429 with Retry-After past that"
+    );
+    println!(
+        "  --challenge-preload : Send \"Link\" headers with the challenge page so the browser fetches the worker js and connects to the api url early"
+    );
+    println!(
+        "  --no-js-message=<text> : Show <text> in a <noscript> block on the challenge page, for clients with JavaScript disabled"
+    );
+    println!("  --no-js-link=<url> : Link to <url> after the --no-js-message, e.g. a contact page");
+    println!(
+        "  NOTICE: A --challenge-html=... page gets the block where it contains \"{{NOSCRIPT}}\""
+    );
+    println!(
+        "  --json-challenge : Answer worker js requests that accept application/json with the factors challenge as JSON, including how its value is encoded, for native solvers"
+    );
+    println!(
+        "  --challenge-status=<code> : HTTP status of the challenge page, a 2xx, 4xx or 5xx, e.g. 503 so crawlers treat it as temporarily unavailable (default 200)"
+    );
+    println!(
+        "  --challenge-timeout=<minutes> : Set minutes for how long challenge answers are stored in db"
+    );
+    println!(
+        "  --challenge-heartbeat-max=<minutes> : Have the factors worker ping --heartbeat-url=... while it works, each ping extending its challenge's expiry, so a slow solve can take up to <minutes> (at least --challenge-timeout=...)"
+    );
+    println!(
+        "  --heartbeat-url=<url> : Set endpoint the factors worker POSTs heartbeats to with --challenge-heartbeat-max=... (default \"/pma_heartbeat\")"
+    );
+    println!(
+        "  --allowed-timeout=<minutes> : Set how long a client is allowed to access before requiring challenge again"
+    );
+    println!(
+        "  --allow-cache-evict-interval=<seconds> : How often expired entries are evicted from the in-memory allow cache (default 60)"
+    );
+    println!(
+        "  --cleanup-interval=<seconds> : How often expired challenges, ids, and allowances are deleted from the db (default 60)"
+    );
+    println!(
+        "  --cache-refresh-interval=<seconds> : How often recently allowed clients are loaded from the db into the allow cache (default off)"
+    );
+    println!(
+        "  NOTICE: --cache-refresh-interval=... lets instances sharing a db see each other's allowed clients without re-challenging"
+    );
+    println!(
+        "  --shutdown-timeout=<seconds> : How long in-flight requests may take to finish on SIGINT/SIGTERM/SIGHUP (default 5)"
+    );
+    println!(
+        "  --force-close : Close every HTTP/1 client connection after one response instead of keeping it alive"
+    );
+    println!(
+        "  NOTICE: A dest url's \"Connection\" header is never forwarded, client keep-alive is managed separately from it"
+    );
+    println!(
+        "  --backend-timeout=<seconds> : How long connecting to and getting a full response from the dest url may take before a 504 is returned (default {})",
+        crate::constants::DEFAULT_BACKEND_TIMEOUT_SECS
+    );
+    println!(
+        "  --worker-jitter=<millis> : Clients wait a random 0 to <millis> ms before solving, so a crowd arriving at once doesn't answer at once (default {})",
+        crate::constants::DEFAULT_WORKER_JITTER_MS
+    );
+    println!(
+        "  --log-format=<plain|json> : Print request events and the startup banner as plain lines (default) or one json object per line with \"event\", \"ip\", \"port\", \"status\", etc."
+    );
+    println!(
+        "  --log-sample-rate=<0.0-1.0> : Fraction of successfully proxied requests that are logged; denies and errors are always logged (default 1.0)"
+    );
+    println!(
+        "  --enable-override-dest-url : Enable \"override-dest-url\" request header to determine where to forward;\n    example header: \"override-dest-url: http://127.0.0.1:8888\""
+    );
+    println!(
+        "  WARNING: If --enable-override-dest-url is used, you must ensure that\n    PoorMansAnubis always receives this header as set by your server. If you\n    don't then anyone accessing your server may be able to set this header and\n    direct PoorMansAnubis to load any website!\n    If you are going to use this anyway, you must ensure that a proper firewall is configured!"
+    );
+    println!(
+        "  --important-warning-has-been-read : Use this option to enable potentially dangerous options"
+    );
+    println!(
+        "  --bad-gateway-page=<html_file> : Page served with 502 when the dest url could not be connected to"
+    );
+    println!(
+        "  --gateway-timeout-page=<html_file> : Page served with 504 when the dest url timed out"
+    );
+    println!(
+        "  --internal-error-page=<html_file> : Page served with 500 when forwarding failed for any other reason"
+    );
+    println!(
+        "  --maintenance-html=<html_file> : Page served to allowed clients in place of the 502 and 504 pages when the dest url is down, after any --backend-retries, with Retry-After"
+    );
+    println!(
+        "  --maintenance-status=<code> : HTTP status of the maintenance page, a 4xx or 5xx (default 503); on its own, serves a built-in maintenance page"
+    );
+    println!(
+        "  --challenge-html=<html_file> : Serve this challenge page instead of the built-in one; it must contain \"{{JS_FACTORS_URL}}\" where the worker url goes"
     );
     println!(
-        "  --addr-port=<addr>:<port> : Listening addr/port;\n    example: \"--addr-port=127.0.0.1:8080\""
+        "  --challenge-js=<js_file> : Serve this factors worker instead of the built-in one; it must contain \"{{API_URL}}\", \"{{LARGE_NUMBER}}\" and \"{{UUID}}\""
     );
-    println!("  NOTICE: Specify --addr-port=... multiple times to listen on multiple ports");
-    println!("  NOTE: There is no longer a hard limit on the number of ports one can listen to");
     println!(
-        "  --port-to-dest-url=<port>:<url> : Ensure requests from listening on <port> is forwarded to <url>"
+        "  NOTICE: --challenge-js=... is not used with --challenge-type=hashcash, and \"{{JITTER_MS}}\" is optional"
     );
-    println!("  example: \"--port-to-dest-url=9001:https://example.com\"");
-    println!("  NOTICE: Specify --port-to-dest-url=... multiple times to add more mappings");
-    println!("  --mysql-conf=<config_file> : Set path to config file for mysql settings");
-    println!("  --sqlite-path=<filename> : Set sqlite db filename path");
     println!(
-        "  --enable-x-real-ip-header : Enable trusting \"x-real-ip\" header as client ip addr"
+        "  --max-response-header-bytes=<bytes> : Limit total size of response headers forwarded from dest urls"
     );
     println!(
-        "  --api-url=<url> : Set endpoint for client to POST to this software;\n    example: \"--api-url=/pma_api\""
+        "  --max-proxy-body=<bytes> : Reject request bodies larger than this with 413 instead of forwarding them (default {})",
+        crate::constants::DEFAULT_MAX_PROXY_BODY
     );
     println!(
-        "  --js-factors-url=<url> : Set endpoint for client to request factors.js from this software;\n    example: \"--js-factors-url=/pma_factors.js\""
+        "  --backend-retries=<count> : Retry GET and HEAD requests up to <count> times with exponential backoff when the dest url can't be connected to or times out (default 0)"
     );
     println!(
-        "  --challenge-timeout=<minutes> : Set minutes for how long challenge answers are stored in db"
+        "  --response-header-overflow=<truncate|error> : Drop headers past the limit (default), or reply with 502"
     );
     println!(
-        "  --allowed-timeout=<minutes> : Set how long a client is allowed to access before requiring challenge again"
+        "  --stream-content-types=<list> : Comma separated content types (e.g. \"video/*,application/octet-stream\") of dest url responses to stream, others are buffered"
     );
     println!(
-        "  --enable-override-dest-url : Enable \"override-dest-url\" request header to determine where to forward;\n    example header: \"override-dest-url: http://127.0.0.1:8888\""
+        "  NOTICE: Without --stream-content-types=... every response is streamed, with it responses over {} bytes or without a length are streamed anyway",
+        crate::constants::STREAM_FALLBACK_BYTES
     );
     println!(
-        "  WARNING: If --enable-override-dest-url is used, you must ensure that\n    PoorMansAnubis always receives this header as set by your server. If you\n    don't then anyone accessing your server may be able to set this header and\n    direct PoorMansAnubis to load any website!\n    If you are going to use this anyway, you must ensure that a proper firewall is configured!"
+        "  --upstream-client-cert=<pem_file> : Client certificate presented to dest urls requiring mutual TLS"
     );
+    println!("  --upstream-client-key=<pem_file> : PKCS#8 private key for --upstream-client-cert");
     println!(
-        "  --important-warning-has-been-read : Use this option to enable potentially dangerous options"
+        "  NOTICE: --upstream-client-cert=... and --upstream-client-key=... must be given together"
+    );
+    println!(
+        "  --tls-cert=<pem_file> : Serve HTTPS on every --addr-port=... with this certificate chain"
+    );
+    println!("  --tls-key=<pem_file> : Private key for --tls-cert");
+    println!(
+        "  NOTICE: --tls-cert=... and --tls-key=... must be given together, and the same certificate is used on all ports"
+    );
+    println!(
+        "  --tls-client-ca=<pem_file> : Ask HTTPS clients for a certificate, verified against the CAs in <pem_file>"
+    );
+    println!(
+        "  --tls-detect : Also accept plain HTTP on the --tls-cert ports, answering it with a redirect to HTTPS"
+    );
+    println!(
+        "  --require-client-cert-for=<cidr|any> : Clients in <cidr> (or any client) that present a certificate verified by --tls-client-ca skip the challenge"
+    );
+    println!(
+        "  NOTICE: Specify --require-client-cert-for=... multiple times to add more ranges, clients without a certificate are still challenged"
+    );
+    println!(
+        "  --exemption-token-key=<file> : Secret for signing tokens minted over the --admin-unix-socket; a valid \"X-PMA-Token\" header skips the challenge"
+    );
+    println!(
+        "  --reputation-file=<file> : Lines of \"<ip_or_cidr> <good|suspicious|bad>\"; good skips the challenge, bad gets a harder one"
+    );
+    println!(
+        "  NOTICE: With --reputation-file=..., SIGHUP reloads the file instead of stopping the server"
+    );
+    println!(
+        "  --policy-script=<file> : Rhai script run on each request with ip, port, method, path, and headers; its last expression is \"allow\", \"deny\" (403), or \"challenge\" (the usual checks)"
+    );
+    println!(
+        "  NOTICE: --policy-script requires building with the \"policy-script\" feature, and SIGHUP reloads the script instead of stopping the server"
+    );
+    println!(
+        "  --admin-unix-socket=<path> : Accept line-delimited JSON admin commands (list, challenges, revoke, reset, reload, rotate-salt, stats, mint-token) on a Unix socket only the owner can use"
+    );
+    println!(
+        "  --hash-salt=<string> : Domain separator hashed into challenge ids and answer hashes (default \"pma.seodisparate.com\"); use your own so ids can't be correlated with other instances"
+    );
+    println!("  NOTICE: Changing --hash-salt=... invalidates challenges issued before the change");
+    println!(
+        "  --record-traffic=<file> : Append each request's ip, port, method, path, headers (minus credentials and real ip headers), and allow/challenge/deny decision to <file> as JSON lines"
+    );
+    println!(
+        "  --replay-traffic=<file> : Run the requests of a --record-traffic=... file through the checks without issuing challenges, print which were decided differently, and exit"
+    );
+    println!(
+        "  --purge-expired : Delete challenges, ids, allowances and audit log rows older than --challenge-timeout=..., --allowed-timeout=... and --audit-log-retention=... from the db, print how many were deleted, and exit"
+    );
+    println!(
+        "  --benchmark=<clients> : Run <clients> concurrent challenge flows against the db, print throughput/latency, and exit"
+    );
+    println!(
+        "  --benchmark-iterations=<count> : Number of challenge flows each benchmark client runs (default 10)"
     );
 }
 
 pub fn parse_args() -> Result<Args, Error> {
+    parse_args_from(args_fn().skip(1))
+}
+
+pub(crate) fn parse_args_from(p_args: impl IntoIterator<Item = String>) -> Result<Args, Error> {
     let mut args = Args {
         factors: None,
-        dest_url: "https://seodisparate.com".into(),
+        factors_distribution: Vec::new(),
+        factors_max: None,
+        datacenter_cidrs: Vec::new(),
+        min_prime_factor: None,
+        max_prime_factor: None,
+        dest_url: None,
+        auth_only: false,
         addr_port_strs: vec!["127.0.0.1:8180".into()],
+        allow_excess_listeners: false,
+        max_listeners: None,
         port_to_dest_urls: HashMap::new(),
+        host_to_dest_urls: HashMap::new(),
         mysql_config_file: "mysql.conf".into(),
         sqlite_db_file: "sqlitedb".into(),
+        postgres_config_file: None,
+        memory_db: false,
+        memory_seq_file: None,
         enable_x_real_ip_header: false,
+        real_ip_headers: vec!["x-real-ip".into()],
+        trusted_proxy_cidrs: Vec::new(),
         api_url: "/pma_api".into(),
         js_factors_url: "/pma_factors.js".into(),
+        heartbeat_url: "/pma_heartbeat".into(),
+        port_to_api_urls: HashMap::new(),
+        port_to_js_factors_urls: HashMap::new(),
         challenge_timeout_mins: crate::constants::CHALLENGE_FACTORS_TIMEOUT_MINUTES,
+        challenge_heartbeat_max_mins: None,
         allowed_timeout_mins: crate::constants::ALLOWED_IP_TIMEOUT_MINUTES,
         enable_override_dest_url: false,
         mysql_has_priority: true,
+        bad_gateway_page: None,
+        gateway_timeout_page: None,
+        internal_error_page: None,
+        maintenance_page: None,
+        maintenance_status: None,
+        challenge_html: None,
+        challenge_js: None,
+        benchmark_clients: None,
+        benchmark_iterations: 10,
+        auth_request_url: None,
+        solved_webhook: None,
+        upstream_client_cert: None,
+        upstream_client_key: None,
+        tls_cert: None,
+        tls_key: None,
+        tls_client_ca: None,
+        tls_detect: false,
+        client_cert_cidrs: Vec::new(),
+        exemption_token_key: None,
+        reputation_file: None,
+        policy_script: None,
+        record_traffic: None,
+        replay_traffic: None,
+        purge_expired: false,
+        hash_salt: crate::constants::DEFAULT_HASH_SALT.to_owned(),
+        admin_unix_socket: None,
+        bypass_cidrs: Vec::new(),
+        bypass_paths: Vec::new(),
+        trust_loopback: true,
+        allow_cache_evict_interval_secs: 60,
+        cleanup_interval_secs: crate::constants::DEFAULT_CLEANUP_INTERVAL_SECS,
+        cache_refresh_interval_secs: None,
+        shutdown_timeout_secs: 5,
+        force_close: false,
+        backend_timeout_secs: crate::constants::DEFAULT_BACKEND_TIMEOUT_SECS,
+        worker_jitter_ms: crate::constants::DEFAULT_WORKER_JITTER_MS,
+        log_sample_rate: 1.0,
+        log_format: LogFormat::Plain,
+        max_response_header_bytes: None,
+        max_proxy_body: crate::constants::DEFAULT_MAX_PROXY_BODY,
+        backend_retries: 0,
+        stream_content_types: Vec::new(),
+        response_header_overflow: HeaderOverflowPolicy::Truncate,
+        trust_forwarded_for: false,
+        db_fail_open: false,
+        monitor_only: false,
+        on_generation_failure: GenerationFailurePolicy::FailClosed,
+        challenge_type: ChallengeType::Factors,
+        hashcash_bits: crate::constants::DEFAULT_HASHCASH_BITS,
+        store_user_agent: false,
+        challenge_preload: false,
+        no_js_message: None,
+        no_js_link: None,
+        json_challenge: false,
+        challenge_status: None,
+        enable_audit_log: false,
+        audit_log_retention_mins: None,
+        max_concurrent_validations: None,
+        max_concurrent: None,
+        max_concurrent_challenges: None,
+        max_json_size: crate::constants::DEFAULT_JSON_MAX_SIZE,
+        challenge_rate: None,
+        config_file: None,
     };
 
-    let p_args = args_fn();
+    let p_args: Vec<String> = p_args.into_iter().collect();
+    if let Some(config) = p_args
+        .iter()
+        .rev()
+        .find_map(|arg| arg.strip_prefix("--config="))
+    {
+        parse_config_file(Path::new(config))?.apply(&mut args)?;
+        args.config_file = Some(PathBuf::from(config));
+    }
 
     let mut is_default_addr_port_strs = true;
+    let mut is_default_real_ip_headers = true;
+    let mut is_default_bypass_cidrs = true;
+    let mut is_default_bypass_paths = true;
+    let mut is_default_trusted_proxy_cidrs = true;
+    let mut is_default_stream_content_types = true;
+    let mut is_default_datacenter_cidrs = true;
+    let mut is_default_client_cert_cidrs = true;
+    let mut cli_dest_url_ports: HashSet<u16> = HashSet::new();
     let mut override_dest_url_warning_read = false;
     let mut unknown_args: LinkedList<String> = LinkedList::new();
     let mut unknown_arg_error = false;
 
-    for mut arg in p_args.skip(1) {
+    for mut arg in p_args.into_iter() {
         if arg == "-h" || arg == "--help" {
             print_args();
             return Err("Printed help text".into());
+        } else if arg == "--version" {
+            println!("{}", version_text());
+            return Err("Printed version".into());
+        } else if arg.starts_with("--config=") {
+            // Intentionally left blank, already applied before other args.
         } else if arg.starts_with("--factors=") {
             let end = arg.split_off(10);
             args.factors = end.parse().ok();
+        } else if arg.starts_with("--factors-distribution=") {
+            let end = arg.split_off(23);
+            args.factors_distribution = parse_factors_distribution("--factors-distribution", &end)?;
+        } else if arg.starts_with("--factors-max=") {
+            let end = arg.split_off(14);
+            args.factors_max = Some(end.parse()?);
+        } else if arg.starts_with("--datacenter-cidr=") {
+            let end = arg.split_off(18);
+            if is_default_datacenter_cidrs {
+                args.datacenter_cidrs.clear();
+                is_default_datacenter_cidrs = false;
+            }
+            args.datacenter_cidrs
+                .push(crate::helpers::parse_ip_net(&end)?);
+        } else if arg.starts_with("--min-prime-factor=") {
+            let end = arg.split_off(19);
+            args.min_prime_factor = Some(end.parse()?);
+        } else if arg.starts_with("--max-prime-factor=") {
+            let end = arg.split_off(19);
+            args.max_prime_factor = Some(end.parse()?);
         } else if arg.starts_with("--dest-url=") {
             let end = arg.split_off(11);
-            args.dest_url = end;
+            args.dest_url = Some(end);
         } else if arg.starts_with("--addr-port=") {
             let end = arg.split_off(12);
             if is_default_addr_port_strs {
@@ -136,37 +1264,380 @@ pub fn parse_args() -> Result<Args, Error> {
                 .next()
                 .ok_or("--port-to-dest-url=<port>:<url> invalid url!")?
                 .to_owned();
-            args.port_to_dest_urls.insert(port, url);
+            // The first time a port is given replaces its config file urls,
+            // after that each one is another url to round-robin among.
+            if cli_dest_url_ports.insert(port) {
+                args.port_to_dest_urls.insert(port, vec![url]);
+            } else {
+                args.port_to_dest_urls.entry(port).or_default().push(url);
+            }
+        } else if arg.starts_with("--host-to-dest-url=") {
+            let end = arg.split_off(19);
+            let (host, url) = end
+                .split_once(':')
+                .ok_or("--host-to-dest-url=<host>:<url> invalid url!")?;
+            args.host_to_dest_urls
+                .insert(host.to_owned(), url.to_owned());
         } else if arg.starts_with("--mysql-conf=") {
             let end = arg.split_off(13);
             args.mysql_config_file = end.into();
             args.mysql_has_priority = true;
+            args.postgres_config_file = None;
+            args.memory_db = false;
         } else if arg.starts_with("--sqlite-path=") {
             let end = arg.split_off(14);
             args.sqlite_db_file = end.into();
             args.mysql_has_priority = false;
+            args.postgres_config_file = None;
+            args.memory_db = false;
+        } else if arg.starts_with("--postgres-conf=") {
+            if !cfg!(feature = "postgres") {
+                return Err(Error::Generic(
+                    "--postgres-conf requires building with the \"postgres\" feature".into(),
+                ));
+            }
+            let end = arg.split_off(16);
+            args.postgres_config_file = Some(end.into());
+            args.mysql_has_priority = false;
+            args.memory_db = false;
+        } else if arg == "--memory-db" {
+            if !cfg!(feature = "memory") {
+                return Err(Error::Generic(
+                    "--memory-db requires building with the \"memory\" feature".into(),
+                ));
+            }
+            args.memory_db = true;
+            args.mysql_has_priority = false;
+            args.postgres_config_file = None;
+        } else if arg.starts_with("--memory-seq-file=") {
+            let end = arg.split_off(18);
+            args.memory_seq_file = Some(end.into());
         } else if arg == "--enable-x-real-ip-header" {
             args.enable_x_real_ip_header = true;
+        } else if arg.starts_with("--real-ip-header=") {
+            let end = arg.split_off(17).to_lowercase();
+            if is_default_real_ip_headers {
+                args.real_ip_headers = vec![end];
+                is_default_real_ip_headers = false;
+            } else {
+                args.real_ip_headers.push(end);
+            }
+            args.enable_x_real_ip_header = true;
+        } else if arg.starts_with("--trusted-proxy=") {
+            let end = arg.split_off(16);
+            if is_default_trusted_proxy_cidrs {
+                args.trusted_proxy_cidrs.clear();
+                is_default_trusted_proxy_cidrs = false;
+            }
+            args.trusted_proxy_cidrs
+                .push(crate::helpers::parse_ip_net(&end)?);
+        } else if arg.starts_with("--bypass-cidr=") {
+            let end = arg.split_off(14);
+            if is_default_bypass_cidrs {
+                args.bypass_cidrs.clear();
+                is_default_bypass_cidrs = false;
+            }
+            args.bypass_cidrs.push(crate::helpers::parse_ip_net(&end)?);
+        } else if arg.starts_with("--bypass-path=") {
+            let end = arg.split_off(14);
+            if is_default_bypass_paths {
+                args.bypass_paths.clear();
+                is_default_bypass_paths = false;
+            }
+            args.bypass_paths.push(end);
+        } else if arg == "--trust-loopback" {
+            args.trust_loopback = true;
+        } else if arg == "--no-trust-loopback" {
+            args.trust_loopback = false;
         } else if arg.starts_with("--api-url=") {
             let end = arg.split_off(10);
             args.api_url = end;
         } else if arg.starts_with("--js-factors-url=") {
             let end = arg.split_off(17);
             args.js_factors_url = end;
+        } else if arg.starts_with("--heartbeat-url=") {
+            let end = arg.split_off(16);
+            args.heartbeat_url = end;
+        } else if arg.starts_with("--port-to-api-url=") {
+            let end = arg.split_off(18);
+            let (port, url) = parse_port_to_url("--port-to-api-url", &end)?;
+            args.port_to_api_urls.insert(port, url);
+        } else if arg.starts_with("--port-to-js-factors-url=") {
+            let end = arg.split_off(25);
+            let (port, url) = parse_port_to_url("--port-to-js-factors-url", &end)?;
+            args.port_to_js_factors_urls.insert(port, url);
+        } else if arg == "--auth-only" {
+            args.auth_only = true;
+        } else if arg.starts_with("--auth-request-url=") {
+            let end = arg.split_off(19);
+            args.auth_request_url = Some(end);
+        } else if arg.starts_with("--solved-webhook=") {
+            let end = arg.split_off(17);
+            args.solved_webhook = Some(end);
+        } else if arg.starts_with("--on-generation-failure=") {
+            let end = arg.split_off(24);
+            args.on_generation_failure = match end.as_str() {
+                "fail-closed" => GenerationFailurePolicy::FailClosed,
+                "fail-open" => GenerationFailurePolicy::FailOpen,
+                _ => {
+                    return Err(format!("Invalid --on-generation-failure value \"{}\"", end).into());
+                }
+            };
+        } else if arg.starts_with("--challenge-type=") {
+            let end = arg.split_off(17);
+            args.challenge_type = match end.as_str() {
+                "factors" => ChallengeType::Factors,
+                "hashcash" => ChallengeType::Hashcash,
+                _ => {
+                    return Err(format!("Invalid --challenge-type value \"{}\"", end).into());
+                }
+            };
+        } else if arg.starts_with("--hashcash-bits=") {
+            let end = arg.split_off(16);
+            args.hashcash_bits = end.parse()?;
+            if args.hashcash_bits > crate::constants::MAX_HASHCASH_BITS {
+                return Err(format!(
+                    "--hashcash-bits must be at most {}",
+                    crate::constants::MAX_HASHCASH_BITS
+                )
+                .into());
+            }
         } else if arg.starts_with("--challenge-timeout=") {
             let end = arg.split_off(20);
             args.challenge_timeout_mins = end
                 .parse()
                 .expect("challenge timeout should be a valid integer");
+        } else if arg.starts_with("--challenge-heartbeat-max=") {
+            let end = arg.split_off(26);
+            args.challenge_heartbeat_max_mins = Some(end.parse()?);
         } else if arg.starts_with("--allowed-timeout=") {
             let end = arg.split_off(18);
             args.allowed_timeout_mins = end
                 .parse()
                 .expect("allowed timeout should be a valid integer");
+        } else if arg.starts_with("--allow-cache-evict-interval=") {
+            let end = arg.split_off(29);
+            args.allow_cache_evict_interval_secs = end.parse()?;
+            if args.allow_cache_evict_interval_secs == 0 {
+                return Err("--allow-cache-evict-interval must be greater than 0".into());
+            }
+        } else if arg.starts_with("--cleanup-interval=") {
+            let end = arg.split_off(19);
+            args.cleanup_interval_secs = end.parse()?;
+            if args.cleanup_interval_secs == 0 {
+                return Err("--cleanup-interval must be greater than 0".into());
+            }
+        } else if arg.starts_with("--cache-refresh-interval=") {
+            let end = arg.split_off(25);
+            let secs: u64 = end.parse()?;
+            if secs == 0 {
+                return Err("--cache-refresh-interval must be greater than 0".into());
+            }
+            args.cache_refresh_interval_secs = Some(secs);
+        } else if arg.starts_with("--shutdown-timeout=") {
+            let end = arg.split_off(19);
+            args.shutdown_timeout_secs = end.parse()?;
+        } else if arg == "--force-close" {
+            args.force_close = true;
+        } else if arg.starts_with("--backend-timeout=") {
+            let end = arg.split_off(18);
+            args.backend_timeout_secs = end.parse()?;
+            if args.backend_timeout_secs == 0 {
+                return Err("--backend-timeout must be greater than 0".into());
+            }
+        } else if arg.starts_with("--worker-jitter=") {
+            let end = arg.split_off(16);
+            args.worker_jitter_ms = end.parse()?;
+        } else if arg.starts_with("--log-format=") {
+            let end = arg.split_off(13);
+            args.log_format = match end.as_str() {
+                "plain" => LogFormat::Plain,
+                "json" => LogFormat::Json,
+                _ => {
+                    return Err(format!("Invalid --log-format value \"{}\"", end).into());
+                }
+            };
+        } else if arg.starts_with("--log-sample-rate=") {
+            let end = arg.split_off(18);
+            args.log_sample_rate = end.parse()?;
+            if !(0.0..=1.0).contains(&args.log_sample_rate) {
+                return Err("--log-sample-rate must be between 0.0 and 1.0".into());
+            }
+        } else if arg == "--allow-excess-listeners" {
+            args.allow_excess_listeners = true;
+        } else if arg.starts_with("--max-listeners=") {
+            let end = arg.split_off(16);
+            args.max_listeners = Some(end.parse()?);
+        } else if arg == "--store-user-agent" {
+            args.store_user_agent = true;
+        } else if arg == "--enable-audit-log" {
+            args.enable_audit_log = true;
+        } else if arg.starts_with("--audit-log-retention=") {
+            let end = arg.split_off(22);
+            args.audit_log_retention_mins = Some(end.parse()?);
+        } else if arg.starts_with("--max-concurrent-validations=") {
+            let end = arg.split_off(29);
+            let max_concurrent_validations: usize = end.parse()?;
+            if max_concurrent_validations == 0 {
+                return Err("--max-concurrent-validations must be greater than 0".into());
+            }
+            args.max_concurrent_validations = Some(max_concurrent_validations);
+        } else if arg.starts_with("--max-concurrent=") {
+            let end = arg.split_off(17);
+            let max_concurrent: usize = end.parse()?;
+            if max_concurrent == 0 {
+                return Err("--max-concurrent must be greater than 0".into());
+            }
+            args.max_concurrent = Some(max_concurrent);
+        } else if arg.starts_with("--max-concurrent-challenges=") {
+            let end = arg.split_off(28);
+            let max_concurrent_challenges: usize = end.parse()?;
+            if max_concurrent_challenges == 0 {
+                return Err("--max-concurrent-challenges must be greater than 0".into());
+            }
+            args.max_concurrent_challenges = Some(max_concurrent_challenges);
+        } else if arg.starts_with("--max-json-size=") {
+            let end = arg.split_off(16);
+            args.max_json_size = end.parse()?;
+            if args.max_json_size == 0 {
+                return Err("--max-json-size must be greater than 0".into());
+            }
+        } else if arg.starts_with("--challenge-rate=") {
+            let end = arg.split_off(17);
+            args.challenge_rate = Some(parse_challenge_rate("--challenge-rate", &end)?);
+        } else if arg == "--challenge-preload" {
+            args.challenge_preload = true;
+        } else if arg.starts_with("--no-js-message=") {
+            let end = arg.split_off(16);
+            args.no_js_message = Some(end);
+        } else if arg.starts_with("--no-js-link=") {
+            let end = arg.split_off(13);
+            args.no_js_link = Some(end);
+        } else if arg == "--json-challenge" {
+            args.json_challenge = true;
+        } else if arg.starts_with("--challenge-status=") {
+            let end = arg.split_off(19);
+            args.challenge_status = Some(end.parse()?);
+        } else if arg == "--trust-forwarded-for" {
+            args.trust_forwarded_for = true;
+        } else if arg == "--db-fail-open" {
+            args.db_fail_open = true;
+        } else if arg == "--monitor-only" {
+            args.monitor_only = true;
         } else if arg == "--enable-override-dest-url" {
             args.enable_override_dest_url = true;
         } else if arg == "--important-warning-has-been-read" {
             override_dest_url_warning_read = true;
+        } else if arg.starts_with("--bad-gateway-page=") {
+            let end = arg.split_off(19);
+            args.bad_gateway_page = Some(std::fs::read_to_string(end)?);
+        } else if arg.starts_with("--gateway-timeout-page=") {
+            let end = arg.split_off(23);
+            args.gateway_timeout_page = Some(std::fs::read_to_string(end)?);
+        } else if arg.starts_with("--internal-error-page=") {
+            let end = arg.split_off(22);
+            args.internal_error_page = Some(std::fs::read_to_string(end)?);
+        } else if arg.starts_with("--maintenance-html=") {
+            let end = arg.split_off(19);
+            args.maintenance_page = Some(std::fs::read_to_string(end)?);
+        } else if arg.starts_with("--maintenance-status=") {
+            let end = arg.split_off(21);
+            args.maintenance_status = Some(end.parse()?);
+        } else if arg.starts_with("--challenge-html=") {
+            let end = arg.split_off(17);
+            args.challenge_html = Some(std::fs::read_to_string(end)?);
+        } else if arg.starts_with("--challenge-js=") {
+            let end = arg.split_off(15);
+            args.challenge_js = Some(std::fs::read_to_string(end)?);
+        } else if arg.starts_with("--max-response-header-bytes=") {
+            let end = arg.split_off(28);
+            args.max_response_header_bytes = Some(end.parse()?);
+        } else if arg.starts_with("--max-proxy-body=") {
+            let end = arg.split_off(17);
+            args.max_proxy_body = end.parse()?;
+        } else if arg.starts_with("--backend-retries=") {
+            let end = arg.split_off(18);
+            args.backend_retries = end.parse()?;
+        } else if arg.starts_with("--stream-content-types=") {
+            let end = arg.split_off(23);
+            if is_default_stream_content_types {
+                args.stream_content_types.clear();
+                is_default_stream_content_types = false;
+            }
+            args.stream_content_types.extend(
+                end.split(',')
+                    .map(|content_type| content_type.trim().to_ascii_lowercase())
+                    .filter(|content_type| !content_type.is_empty()),
+            );
+        } else if arg.starts_with("--response-header-overflow=") {
+            let end = arg.split_off(27);
+            args.response_header_overflow = match end.as_str() {
+                "truncate" => HeaderOverflowPolicy::Truncate,
+                "error" => HeaderOverflowPolicy::Error,
+                _ => {
+                    return Err(
+                        format!("Invalid --response-header-overflow value \"{}\"", end).into(),
+                    );
+                }
+            };
+        } else if arg.starts_with("--upstream-client-cert=") {
+            let end = arg.split_off(23);
+            args.upstream_client_cert = Some(std::fs::read(end)?);
+        } else if arg.starts_with("--upstream-client-key=") {
+            let end = arg.split_off(22);
+            args.upstream_client_key = Some(std::fs::read(end)?);
+        } else if arg.starts_with("--tls-cert=") {
+            let end = arg.split_off(11);
+            args.tls_cert = Some(end.into());
+        } else if arg.starts_with("--tls-key=") {
+            let end = arg.split_off(10);
+            args.tls_key = Some(end.into());
+        } else if arg.starts_with("--tls-client-ca=") {
+            let end = arg.split_off(16);
+            args.tls_client_ca = Some(end.into());
+        } else if arg == "--tls-detect" {
+            args.tls_detect = true;
+        } else if arg.starts_with("--require-client-cert-for=") {
+            let end = arg.split_off(26);
+            if is_default_client_cert_cidrs {
+                args.client_cert_cidrs.clear();
+                is_default_client_cert_cidrs = false;
+            }
+            args.client_cert_cidrs
+                .extend(parse_client_cert_range(&end)?);
+        } else if arg.starts_with("--exemption-token-key=") {
+            let end = arg.split_off(22);
+            args.exemption_token_key = Some(end.into());
+        } else if arg.starts_with("--reputation-file=") {
+            let end = arg.split_off(18);
+            args.reputation_file = Some(end.into());
+        } else if arg.starts_with("--policy-script=") {
+            if !cfg!(feature = "policy-script") {
+                return Err(Error::Generic(
+                    "--policy-script requires building with the \"policy-script\" feature".into(),
+                ));
+            }
+            let end = arg.split_off(16);
+            args.policy_script = Some(end.into());
+        } else if arg.starts_with("--record-traffic=") {
+            let end = arg.split_off(17);
+            args.record_traffic = Some(end.into());
+        } else if arg.starts_with("--replay-traffic=") {
+            let end = arg.split_off(17);
+            args.replay_traffic = Some(end.into());
+        } else if arg == "--purge-expired" {
+            args.purge_expired = true;
+        } else if arg.starts_with("--hash-salt=") {
+            args.hash_salt = arg.split_off(12);
+        } else if arg.starts_with("--admin-unix-socket=") {
+            let end = arg.split_off(20);
+            args.admin_unix_socket = Some(end.into());
+        } else if arg.starts_with("--benchmark=") {
+            let end = arg.split_off(12);
+            args.benchmark_clients = Some(end.parse()?);
+        } else if arg.starts_with("--benchmark-iterations=") {
+            let end = arg.split_off(23);
+            args.benchmark_iterations = end.parse()?;
         } else if arg == "--enable-unknown-arg-error" {
             unknown_arg_error = true;
         } else {
@@ -180,6 +1651,195 @@ pub fn parse_args() -> Result<Args, Error> {
         );
     }
 
+    if args.auth_only {
+        if args.auth_request_url.is_none() {
+            return Err("--auth-only requires --auth-request-url".into());
+        } else if args.dest_url.is_some()
+            || !args.port_to_dest_urls.is_empty()
+            || !args.host_to_dest_urls.is_empty()
+        {
+            return Err(
+                "--auth-only can't be combined with --dest-url, --port-to-dest-url or --host-to-dest-url".into(),
+            );
+        }
+    } else if args.dest_url.is_none()
+        && args.port_to_dest_urls.is_empty()
+        && args.host_to_dest_urls.is_empty()
+        && args.benchmark_clients.is_none()
+        && args.replay_traffic.is_none()
+        && !args.purge_expired
+    {
+        return Err(
+            "--dest-url or --port-to-dest-url is required unless --auth-only is given".into(),
+        );
+    }
+
+    if let Some(dest_url) = &args.dest_url {
+        args.dest_url = Some(crate::helpers::canonicalize_dest_url(dest_url)?);
+    }
+    for (port, dest_urls) in args.port_to_dest_urls.iter_mut() {
+        if dest_urls.is_empty() {
+            return Err(format!("--port-to-dest-url has no urls for port {}", port).into());
+        }
+        for dest_url in dest_urls.iter_mut() {
+            *dest_url = crate::helpers::canonicalize_dest_url(dest_url)?;
+        }
+    }
+    args.host_to_dest_urls = std::mem::take(&mut args.host_to_dest_urls)
+        .into_iter()
+        .map(|(host, dest_url)| {
+            Ok((
+                crate::helpers::normalize_host(&host).ok_or(Error::Generic(format!(
+                    "--host-to-dest-url has invalid host \"{}\"",
+                    host
+                )))?,
+                crate::helpers::canonicalize_dest_url(&dest_url)?,
+            ))
+        })
+        .collect::<Result<HashMap<String, String>, Error>>()?;
+
+    // Port 0 stands for a listener without a port, like a Unix socket.
+    if args.port_to_dest_urls.contains_key(&0)
+        || args.port_to_api_urls.contains_key(&0)
+        || args.port_to_js_factors_urls.contains_key(&0)
+    {
+        return Err("--port-to-*-url mappings can't use port 0".into());
+    }
+
+    let max_listeners = args.max_listeners.unwrap_or(usize::MAX);
+    if args.addr_port_strs.len() > max_listeners && !args.allow_excess_listeners {
+        return Err(format!(
+            "{} --addr-port listeners given but the limit is {}, the listeners past the limit are: {}; pass --allow-excess-listeners to use them anyway",
+            args.addr_port_strs.len(),
+            max_listeners,
+            args.addr_port_strs[max_listeners..].join(", ")
+        )
+        .into());
+    }
+
+    // Each --port-to-dest-url mapping gets its own client and connection pool,
+    // so the mappings count toward the same budget as the listeners.
+    let mut ports: HashSet<u16> = args.port_to_dest_urls.keys().copied().collect();
+    ports.extend(
+        args.addr_port_strs
+            .iter()
+            .filter_map(|addr_port| addr_port.rsplit_once(':'))
+            .filter_map(|(_, port)| port.parse::<u16>().ok()),
+    );
+    if ports.len() > max_listeners && !args.allow_excess_listeners {
+        return Err(format!(
+            "{} distinct ports given across --addr-port and {} --port-to-dest-url mappings but the limit is {}; pass --max-listeners=... or --allow-excess-listeners to use them anyway",
+            ports.len(),
+            args.port_to_dest_urls.len(),
+            max_listeners
+        )
+        .into());
+    }
+
+    if args.upstream_client_cert.is_some() != args.upstream_client_key.is_some() {
+        return Err(
+            "--upstream-client-cert and --upstream-client-key must be specified together".into(),
+        );
+    }
+
+    if let Some(bypass_path) = args.bypass_paths.iter().find(|path| !path.starts_with('/')) {
+        return Err(format!("--bypass-path={} must start with \"/\"", bypass_path).into());
+    }
+
+    if args.tls_cert.is_some() != args.tls_key.is_some() {
+        return Err("--tls-cert and --tls-key must be specified together".into());
+    }
+
+    // The challenge page needs a body, which 1xx, 204 and 205 can't have, and
+    // a 3xx without a "location" header isn't a page browsers will show.
+    if let Some(challenge_status) = args.challenge_status
+        && (!(200..=599).contains(&challenge_status)
+            || (300..=399).contains(&challenge_status)
+            || [204, 205].contains(&challenge_status))
+    {
+        return Err(format!(
+            "--challenge-status={} can't be used for the challenge page",
+            challenge_status
+        )
+        .into());
+    }
+
+    if let Some(maintenance_status) = args.maintenance_status
+        && !(400..=599).contains(&maintenance_status)
+    {
+        return Err(format!(
+            "--maintenance-status={} must be a 4xx or 5xx",
+            maintenance_status
+        )
+        .into());
+    }
+
+    if let Some(challenge_heartbeat_max_mins) = args.challenge_heartbeat_max_mins
+        && challenge_heartbeat_max_mins < args.challenge_timeout_mins
+    {
+        return Err("--challenge-heartbeat-max must be at least --challenge-timeout".into());
+    }
+
+    if args.memory_db && args.enable_audit_log {
+        return Err("--enable-audit-log is not supported with --memory-db".into());
+    }
+
+    if args.memory_seq_file.is_some() && !args.memory_db {
+        return Err("--memory-seq-file requires --memory-db".into());
+    }
+
+    if let Some(factors_max) = args.factors_max
+        && factors_max
+            < args
+                .factors
+                .unwrap_or(crate::constants::DEFAULT_FACTORS_QUADS)
+    {
+        return Err("--factors-max must be at least --factors".into());
+    }
+
+    if args.no_js_link.is_some() && args.no_js_message.is_none() {
+        return Err("--no-js-link needs a --no-js-message".into());
+    }
+
+    if let Some(challenge_html) = &args.challenge_html {
+        check_placeholders("--challenge-html", challenge_html, &["{JS_FACTORS_URL}"])?;
+    }
+    if let Some(challenge_js) = &args.challenge_js {
+        check_placeholders(
+            "--challenge-js",
+            challenge_js,
+            &["{API_URL}", "{LARGE_NUMBER}", "{UUID}"],
+        )?;
+    }
+
+    if args.tls_client_ca.is_some() && args.tls_cert.is_none() {
+        return Err("--tls-client-ca requires --tls-cert and --tls-key".into());
+    }
+
+    if args.tls_detect && args.tls_cert.is_none() {
+        return Err("--tls-detect requires --tls-cert and --tls-key".into());
+    }
+
+    if !args.client_cert_cidrs.is_empty() && args.tls_client_ca.is_none() {
+        return Err("--require-client-cert-for requires --tls-client-ca".into());
+    }
+
+    let min_prime = args
+        .min_prime_factor
+        .unwrap_or(crate::constants::DEFAULT_MIN_PRIME_FACTOR);
+    let max_prime = args
+        .max_prime_factor
+        .unwrap_or(crate::constants::DEFAULT_MAX_PRIME_FACTOR);
+    if min_prime > 61 {
+        return Err("--min-prime-factor must be at most 61".into());
+    } else if !(min_prime..=max_prime).any(crate::helpers::is_prime) {
+        return Err(format!(
+            "No primes between --min-prime-factor={} and --max-prime-factor={}",
+            min_prime, max_prime
+        )
+        .into());
+    }
+
     if !unknown_args.is_empty() {
         if unknown_arg_error {
             print_args();
@@ -213,3 +1873,412 @@ pub fn parse_args() -> Result<Args, Error> {
 
     Ok(args)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_file() {
+        let config_file =
+            std::env::temp_dir().join(format!("pma_test_config_{}.toml", std::process::id()));
+        std::fs::write(
+            &config_file,
+            r#"
+factors = 100
+dest_url = "http://127.0.0.1:9999"
+addr_port = ["127.0.0.1:8180", "127.0.0.1:8181"]
+sqlite_path = "pma.sqlite"
+trust_forwarded_for = true
+
+[port_to_dest_url]
+8181 = "http://127.0.0.1:9998"
+"#,
+        )
+        .unwrap();
+
+        let args = parse_args_from([
+            format!("--config={}", config_file.display()),
+            "--dest-url=http://127.0.0.1:7777".into(),
+        ]);
+
+        std::fs::write(&config_file, "factors = 100\nnot_a_flag = true\n").unwrap();
+        let unknown_key = parse_config_file(&config_file);
+        std::fs::remove_file(&config_file).ok();
+
+        let args = args.unwrap();
+        assert_eq!(args.factors, Some(100));
+        assert_eq!(args.config_file.as_ref(), Some(&config_file));
+        assert_eq!(args.dest_url.as_deref(), Some("http://127.0.0.1:7777"));
+        assert_eq!(
+            args.addr_port_strs,
+            vec!["127.0.0.1:8180".to_owned(), "127.0.0.1:8181".to_owned()]
+        );
+        assert_eq!(
+            args.port_to_dest_urls.get(&8181),
+            Some(&vec!["http://127.0.0.1:9998".to_owned()])
+        );
+        assert_eq!(args.sqlite_db_file, PathBuf::from("pma.sqlite"));
+        assert!(!args.mysql_has_priority);
+        assert!(args.trust_forwarded_for);
+
+        match unknown_key {
+            Err(Error::Generic(msg)) => assert!(msg.contains("not_a_flag")),
+            _ => panic!("Expected unknown config key to be an error"),
+        }
+    }
+
+    #[test]
+    fn test_auth_only() {
+        let args =
+            parse_args_from(["--auth-only".into(), "--auth-request-url=/pma_auth".into()]).unwrap();
+        assert!(args.auth_only);
+        assert!(args.dest_url.is_none());
+
+        assert!(parse_args_from(Vec::<String>::new()).is_err());
+        assert!(parse_args_from(["--auth-only".into()]).is_err());
+        assert!(
+            parse_args_from([
+                "--auth-only".into(),
+                "--auth-request-url=/pma_auth".into(),
+                "--dest-url=http://127.0.0.1:9999".into(),
+            ])
+            .is_err()
+        );
+        assert!(parse_args_from(["--port-to-dest-url=8180:http://127.0.0.1:9999".into()]).is_ok());
+    }
+
+    #[test]
+    fn test_repeated_port_to_dest_url() {
+        let config_file = std::env::temp_dir().join(format!(
+            "pma_test_config_dest_urls_{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &config_file,
+            r#"
+[port_to_dest_url]
+8180 = ["http://127.0.0.1:9001", "http://127.0.0.1:9002/"]
+8181 = "http://127.0.0.1:9003"
+"#,
+        )
+        .unwrap();
+
+        let config_args = parse_args_from([format!("--config={}", config_file.display())]);
+        let args = parse_args_from([
+            format!("--config={}", config_file.display()),
+            "--port-to-dest-url=8181:http://127.0.0.1:9004".into(),
+            "--port-to-dest-url=8181:http://127.0.0.1:9005".into(),
+        ]);
+        std::fs::remove_file(&config_file).ok();
+
+        let config_args = config_args.unwrap();
+        assert_eq!(
+            config_args.port_to_dest_urls.get(&8180),
+            Some(&vec![
+                "http://127.0.0.1:9001".to_owned(),
+                "http://127.0.0.1:9002".to_owned()
+            ])
+        );
+        assert_eq!(
+            config_args.port_to_dest_urls.get(&8181),
+            Some(&vec!["http://127.0.0.1:9003".to_owned()])
+        );
+
+        let args = args.unwrap();
+        assert_eq!(args.port_to_dest_urls.get(&8180).map(Vec::len), Some(2));
+        assert_eq!(
+            args.port_to_dest_urls.get(&8181),
+            Some(&vec![
+                "http://127.0.0.1:9004".to_owned(),
+                "http://127.0.0.1:9005".to_owned()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_excess_port_to_dest_urls() {
+        let mut p_args: Vec<String> = vec![
+            "--addr-port=127.0.0.1:9000".into(),
+            "--max-listeners=32".into(),
+        ];
+        for port in 0..32 {
+            p_args.push(format!(
+                "--port-to-dest-url={}:http://127.0.0.1:9999",
+                9001 + port
+            ));
+        }
+
+        match parse_args_from(p_args.clone()) {
+            Err(Error::Generic(msg)) => {
+                assert!(msg.contains(&format!(
+                    "{} distinct ports given across --addr-port and {} --port-to-dest-url mappings",
+                    33, 32
+                )));
+            }
+            _ => panic!("Expected too many port_to_dest_urls to be an error"),
+        }
+
+        p_args.pop();
+        assert!(parse_args_from(p_args.clone()).is_ok());
+
+        p_args.push("--port-to-dest-url=9999:http://127.0.0.1:9999".into());
+        p_args.push("--max-listeners=33".into());
+        assert!(parse_args_from(p_args).is_ok());
+    }
+
+    #[test]
+    fn test_excess_listeners() {
+        let mut p_args: Vec<String> = vec!["--dest-url=http://127.0.0.1:9999".into()];
+        for port in 0..34 {
+            p_args.push(format!("--addr-port=127.0.0.1:{}", 9000 + port));
+        }
+
+        // Without "--max-listeners" there is no limit.
+        assert_eq!(
+            parse_args_from(p_args.clone())
+                .unwrap()
+                .addr_port_strs
+                .len(),
+            34
+        );
+
+        p_args.push("--max-listeners=32".into());
+        match parse_args_from(p_args.clone()) {
+            Err(Error::Generic(msg)) => {
+                assert!(msg.contains("127.0.0.1:9032, 127.0.0.1:9033"));
+                assert!(!msg.contains("127.0.0.1:9031"));
+                assert!(msg.contains("--allow-excess-listeners"));
+            }
+            _ => panic!("Expected too many listeners to be an error"),
+        }
+
+        p_args.push("--allow-excess-listeners".into());
+        let args = parse_args_from(p_args).unwrap();
+        assert_eq!(args.addr_port_strs.len(), 34);
+    }
+
+    #[test]
+    fn test_bypass_path() {
+        let dest_url = "--dest-url=http://127.0.0.1:9999".to_owned();
+        let args = parse_args_from(vec![
+            dest_url.clone(),
+            "--bypass-path=/favicon.ico".into(),
+            "--bypass-path=/.well-known/acme-challenge/*".into(),
+        ])
+        .unwrap();
+        assert_eq!(
+            args.bypass_paths,
+            vec![
+                "/favicon.ico".to_owned(),
+                "/.well-known/acme-challenge/*".to_owned()
+            ]
+        );
+
+        assert!(parse_args_from(vec![dest_url, "--bypass-path=favicon.ico".into()]).is_err());
+    }
+
+    #[test]
+    fn test_version() {
+        assert!(parse_args_from(["--version".into()]).is_err());
+        let version = version_text();
+        assert!(version.starts_with(&format!(
+            "{} {}\n",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        )));
+        assert!(!version.ends_with("challenge_impl unknown"));
+    }
+
+    #[test]
+    fn test_challenge_status() {
+        let dest_url = "--dest-url=http://127.0.0.1:9999".to_owned();
+        let args =
+            parse_args_from(vec![dest_url.clone(), "--challenge-status=503".into()]).unwrap();
+        assert_eq!(args.challenge_status, Some(503));
+
+        for status in ["100", "204", "302", "304", "600"] {
+            assert!(
+                parse_args_from(vec![
+                    dest_url.clone(),
+                    format!("--challenge-status={}", status)
+                ])
+                .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_maintenance_status() {
+        let dest_url = "--dest-url=http://127.0.0.1:9999".to_owned();
+        let args =
+            parse_args_from(vec![dest_url.clone(), "--maintenance-status=502".into()]).unwrap();
+        assert_eq!(args.maintenance_status, Some(502));
+
+        for status in ["200", "302", "600"] {
+            assert!(
+                parse_args_from(vec![
+                    dest_url.clone(),
+                    format!("--maintenance-status={}", status)
+                ])
+                .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_factors_distribution() {
+        let dest_url = "--dest-url=http://127.0.0.1:9999".to_owned();
+        let args = parse_args_from(vec![
+            dest_url.clone(),
+            "--factors-distribution=1800:1, 2200:2".into(),
+        ])
+        .unwrap();
+        assert_eq!(args.factors_distribution, vec![(1800, 1), (2200, 2)]);
+
+        for distribution in ["", "1800", "1800:0", "0:1", "1800:1,", "1800:-1"] {
+            assert!(
+                parse_args_from(vec![
+                    dest_url.clone(),
+                    format!("--factors-distribution={}", distribution)
+                ])
+                .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_challenge_rate() {
+        let dest_url = "--dest-url=http://127.0.0.1:9999".to_owned();
+        let args =
+            parse_args_from(vec![dest_url.clone(), "--challenge-rate=10:60".into()]).unwrap();
+        assert_eq!(args.challenge_rate, Some((10, 60)));
+
+        for rate in ["10", "0:60", "10:0", "ten:60", "10:-1"] {
+            assert!(
+                parse_args_from(vec![dest_url.clone(), format!("--challenge-rate={}", rate)])
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_challenge_heartbeat_max() {
+        let dest_url = "--dest-url=http://127.0.0.1:9999".to_owned();
+        let args = parse_args_from(vec![dest_url.clone()]).unwrap();
+        assert_eq!(args.challenge_heartbeat_max_mins, None);
+        assert_eq!(args.heartbeat_url, "/pma_heartbeat");
+
+        let args = parse_args_from(vec![
+            dest_url.clone(),
+            "--challenge-timeout=10".into(),
+            "--challenge-heartbeat-max=30".into(),
+            "--heartbeat-url=/hb".into(),
+        ])
+        .unwrap();
+        assert_eq!(args.challenge_heartbeat_max_mins, Some(30));
+        assert_eq!(args.heartbeat_url, "/hb");
+
+        assert!(
+            parse_args_from(vec![
+                dest_url,
+                "--challenge-timeout=10".into(),
+                "--challenge-heartbeat-max=5".into(),
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_host_to_dest_url() {
+        let args = parse_args_from(vec![
+            "--host-to-dest-url=Example.COM:http://127.0.0.1:9001/".to_owned(),
+            "--host-to-dest-url=bücher.example:http://127.0.0.1:9002".to_owned(),
+        ])
+        .unwrap();
+        assert_eq!(
+            args.host_to_dest_urls
+                .get("example.com")
+                .map(|s| s.as_str()),
+            Some("http://127.0.0.1:9001")
+        );
+        assert_eq!(
+            args.host_to_dest_urls
+                .get("xn--bcher-kva.example")
+                .map(|s| s.as_str()),
+            Some("http://127.0.0.1:9002")
+        );
+
+        assert!(
+            parse_args_from(vec![
+                "--host-to-dest-url=user@example.com:http://127.0.0.1:9001".to_owned()
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_challenge_templates() {
+        let html = std::env::temp_dir().join(format!("pma_challenge_html_{}", std::process::id()));
+        let js = std::env::temp_dir().join(format!("pma_challenge_js_{}", std::process::id()));
+        std::fs::write(&html, "<script src=\"{JS_FACTORS_URL}\"></script>").unwrap();
+        std::fs::write(&js, "post(\"{API_URL}\", \"{UUID}\");").unwrap();
+        let parse = |html: &PathBuf, js: &PathBuf| {
+            parse_args_from(vec![
+                "--dest-url=http://127.0.0.1:9999".to_owned(),
+                format!("--challenge-html={}", html.display()),
+                format!("--challenge-js={}", js.display()),
+            ])
+        };
+
+        let missing = parse(&html, &js);
+        std::fs::write(&js, "post(\"{API_URL}\", \"{UUID}\", \"{LARGE_NUMBER}\");").unwrap();
+        let args = parse(&html, &js);
+        std::fs::remove_file(&html).ok();
+        std::fs::remove_file(&js).ok();
+
+        match missing {
+            Err(Error::Generic(e)) => assert!(e.contains("{LARGE_NUMBER}")),
+            _ => panic!("Expected a template without {{LARGE_NUMBER}} to be an error"),
+        }
+        let args = args.unwrap();
+        assert_eq!(
+            args.challenge_html.as_deref(),
+            Some("<script src=\"{JS_FACTORS_URL}\"></script>")
+        );
+        assert!(args.challenge_js.unwrap().contains("{LARGE_NUMBER}"));
+    }
+
+    #[test]
+    fn test_port_to_api_url() {
+        let args = parse_args_from(vec![
+            "--dest-url=http://127.0.0.1:9999".to_owned(),
+            "--port-to-api-url=8181:/pma_api_8181".to_owned(),
+            "--port-to-js-factors-url=8181:/pma_factors_8181.js".to_owned(),
+        ])
+        .unwrap();
+        assert_eq!(
+            args.port_to_api_urls.get(&8181).map(|s| s.as_str()),
+            Some("/pma_api_8181")
+        );
+        assert_eq!(
+            args.port_to_js_factors_urls.get(&8181).map(|s| s.as_str()),
+            Some("/pma_factors_8181.js")
+        );
+
+        assert!(
+            parse_args_from(vec![
+                "--dest-url=http://127.0.0.1:9999".to_owned(),
+                "--port-to-api-url=/pma_api_8181".to_owned(),
+            ])
+            .is_err()
+        );
+        assert!(
+            parse_args_from(vec![
+                "--dest-url=http://127.0.0.1:9999".to_owned(),
+                "--port-to-api-url=0:/pma_api_0".to_owned(),
+            ])
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,192 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use salvo::Depot;
+
+use crate::{
+    constants,
+    error::Error,
+    ffi, helpers,
+    json_types::{ChallengeResponse, FactorsResponse},
+};
+
+pub struct BenchmarkReport {
+    pub flows: u64,
+    pub failures: u64,
+    pub elapsed: Duration,
+    pub min_latency: Duration,
+    pub avg_latency: Duration,
+    pub max_latency: Duration,
+}
+
+impl BenchmarkReport {
+    /// Successful challenge flows per second.
+    pub fn throughput(&self) -> f64 {
+        self.flows as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl Display for BenchmarkReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Benchmark:")?;
+        writeln!(f, "  Flows: {} ({} failed)", self.flows, self.failures)?;
+        writeln!(f, "  Elapsed: {:?}", self.elapsed)?;
+        writeln!(f, "  Throughput: {:.2} flows/sec", self.throughput())?;
+        write!(
+            f,
+            "  Latency: min {:?}, avg {:?}, max {:?}",
+            self.min_latency, self.avg_latency, self.max_latency
+        )
+    }
+}
+
+/// Runs one client through the same db calls a real client triggers: issuing
+/// the challenge id, fetching the worker js, answering, and being allowed.
+async fn run_flow(depot: &Depot, ip: &str, port: u16) -> Result<(), Error> {
+    let args = crate::state::depot_args(depot)?;
+
+    let id = crate::db::init_id_to_port(depot, ip, port).await?;
+
+    let port = crate::db::challenge_port(depot, &id).await?;
+
+    let (value, factors) = ffi::generate_value_and_factors_strings3(
+        args.factors.unwrap_or(constants::DEFAULT_FACTORS_QUADS),
+        args.min_prime_factor
+            .unwrap_or(constants::DEFAULT_MIN_PRIME_FACTOR),
+        args.max_prime_factor
+            .unwrap_or(constants::DEFAULT_MAX_PRIME_FACTOR),
+    )?;
+    let hash =
+        crate::db::store_factors_challenge(depot, ip, port, value.as_str(), &factors, None).await?;
+
+    let response = ChallengeResponse::Factors(FactorsResponse {
+        r#type: "factors".into(),
+        id: hash,
+        factors,
+    });
+    crate::db::validate_client(depot, &response, &helpers::answer_factors(&response)?, ip).await?;
+
+    if crate::db::check_is_allowed(depot, ip, port).await? {
+        Ok(())
+    } else {
+        Err("Benchmark client was not allowed after answering".into())
+    }
+}
+
+/// Runs `clients` concurrent clients doing `iterations` challenge flows each
+/// against the args and db pool in `depot`. Clients use addresses in the
+/// 198.18.0.0/15 benchmarking range.
+pub async fn run_benchmark(
+    depot: Arc<Depot>,
+    clients: u64,
+    iterations: u64,
+) -> Result<BenchmarkReport, Error> {
+    let args = crate::state::depot_args(&depot)?;
+    let port: u16 = args
+        .addr_port_strs
+        .first()
+        .and_then(|addr_port| addr_port.rsplit_once(':'))
+        .and_then(|(_, port)| port.parse().ok())
+        .unwrap_or(1);
+
+    let start = Instant::now();
+
+    let mut tasks = Vec::new();
+    for client in 0..clients {
+        let depot = depot.clone();
+        tasks.push(tokio::spawn(async move {
+            let ip = format!("198.18.{}.{}", (client >> 8) & 0xFF, client & 0xFF);
+
+            let mut latencies: Vec<Duration> = Vec::new();
+            let mut failures: u64 = 0;
+            for _ in 0..iterations {
+                let flow_start = Instant::now();
+                match run_flow(&depot, &ip, port).await {
+                    Ok(()) => latencies.push(flow_start.elapsed()),
+                    Err(e) => {
+                        eprintln!("WARNING: Benchmark client {} failed: {}", ip, e);
+                        failures += 1;
+                    }
+                }
+            }
+
+            (latencies, failures)
+        }));
+    }
+
+    let mut latencies: Vec<Duration> = Vec::new();
+    let mut failures: u64 = 0;
+    for task in tasks.into_iter() {
+        let (task_latencies, task_failures) = task
+            .await
+            .map_err(|e| Error::Generic(format!("Benchmark client panicked: {}", e)))?;
+        latencies.extend(task_latencies);
+        failures += task_failures;
+    }
+
+    let elapsed = start.elapsed();
+
+    Ok(BenchmarkReport {
+        flows: latencies.len() as u64,
+        failures,
+        elapsed,
+        min_latency: latencies.iter().min().copied().unwrap_or_default(),
+        avg_latency: latencies
+            .iter()
+            .sum::<Duration>()
+            .checked_div(latencies.len() as u32)
+            .unwrap_or_default(),
+        max_latency: latencies.iter().max().copied().unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Args;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_benchmark_sqlite() {
+        let sqlite_db_file =
+            std::env::temp_dir().join(format!("pma_benchmark_test_{}", std::process::id()));
+        let args = Args {
+            factors: Some(4),
+            addr_port_strs: vec!["127.0.0.1:8180".into()],
+            sqlite_db_file: sqlite_db_file.clone(),
+            challenge_timeout_mins: constants::CHALLENGE_FACTORS_TIMEOUT_MINUTES,
+            allowed_timeout_mins: constants::ALLOWED_IP_TIMEOUT_MINUTES,
+            mysql_has_priority: false,
+            ..Default::default()
+        };
+        crate::db::init_sqlite_db(&args).await.unwrap();
+        let mut depot = Depot::new();
+        depot.insert_typed(Arc::new(args));
+
+        let report = run_benchmark(Arc::new(depot), 2, 3).await;
+        std::fs::remove_file(&sqlite_db_file).ok();
+        let report = report.unwrap();
+
+        assert_eq!(report.failures, 0);
+        assert_eq!(report.flows, 6);
+        assert!(report.throughput() > 0.0);
+        assert!(report.min_latency <= report.avg_latency);
+        assert!(report.avg_latency <= report.max_latency);
+    }
+}
@@ -15,9 +15,46 @@
 // PERFORMANCE OF THIS SOFTWARE.
 
 pub const DEFAULT_FACTORS_QUADS: u64 = 2200;
+/// Percent of the configured quads each bot signal adds with "--factors-max".
+pub const BOT_SIGNAL_FACTORS_PERCENT: u64 = 50;
+pub const DEFAULT_MIN_PRIME_FACTOR: u16 = 2;
+pub const DEFAULT_MAX_PRIME_FACTOR: u16 = 59;
 pub const DEFAULT_JSON_MAX_SIZE: usize = 50000;
+pub const HEARTBEAT_JSON_MAX_SIZE: usize = 1024;
 pub const ALLOWED_IP_TIMEOUT_MINUTES: u64 = 60;
+pub const DEFAULT_CLEANUP_INTERVAL_SECS: u64 = 60;
 pub const CHALLENGE_FACTORS_TIMEOUT_MINUTES: u64 = 2;
+pub const DB_POOL_MAX_CONNECTIONS: usize = 16;
+pub const DEFAULT_WORKER_JITTER_MS: u64 = 1000;
+pub const DEFAULT_HASHCASH_BITS: u8 = 20;
+pub const MAX_HASHCASH_BITS: u8 = 32;
+pub const MAX_USER_AGENT_LEN: usize = 256;
+pub const UPSTREAM_RETRY_AFTER_SECS: u64 = 5;
+pub const DEFAULT_BACKEND_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_MAX_PROXY_BODY: usize = 64 * 1024;
+/// The wait before the first "--backend-retries" retry, doubled for each one
+/// after it up to BACKEND_RETRY_MAX_DELAY_MS.
+pub const BACKEND_RETRY_BASE_DELAY_MS: u64 = 100;
+pub const BACKEND_RETRY_MAX_DELAY_MS: u64 = 5000;
+pub const VALIDATION_RETRY_AFTER_SECS: u64 = 1;
+/// How long a request waits for a "--max-concurrent" permit before the 503.
+pub const CONCURRENCY_WAIT_MS: u64 = 250;
+pub const CONCURRENCY_RETRY_AFTER_SECS: u64 = 1;
+/// "--max-concurrent-challenges" defaults to this many times "--max-concurrent".
+pub const CHALLENGE_CONCURRENCY_FACTOR: usize = 4;
+/// Domain separator for the blake3 hashes of challenge ids.
+pub const DEFAULT_HASH_SALT: &str = "pma.seodisparate.com";
+pub const DEFAULT_EXEMPTION_TOKEN_TTL_SECS: u64 = 60 * 60 * 24;
+/// Responses of a type not in "--stream-content-types" are still streamed
+/// past this many bytes, or when the dest url doesn't give a length.
+pub const STREAM_FALLBACK_BYTES: u64 = 1024 * 1024;
+/// Named in the "encoding" of "--json-challenge" responses: the challenge value
+/// is written in base 64 with VALUE_ALPHABET, least significant digit first.
+pub const VALUE_ENCODING: &str = "pma-b64-le";
+pub const VALUE_ALPHABET: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+/// Stored in place of the factors of a hashcash challenge, whose answer isn't
+/// known until the client finds one.
+pub const HASHCASH_FACTORS_TAG: &str = "hashcash";
 
 pub const HTML_BODY_FACTORS: &str = r#"<!DOCTYPE html>
     <html lang="en">
@@ -44,6 +81,7 @@ pub const HTML_BODY_FACTORS: &str = r#"<!DOCTYPE html>
     <body>
         <h2 class="center">Checking Your Browser...</h2>
         <pre id="progress" class="center">Waiting to start verification...</pre>
+        {NOSCRIPT}
         <script>
             "use strict";
 
@@ -115,6 +153,34 @@ pub const HTML_BODY_FACTORS: &str = r#"<!DOCTYPE html>
     </html>
 "#;
 
+/// Shown when forwarding to the dest url fails and no page was configured for
+/// the status with "--bad-gateway-page" and friends.
+pub const HTML_UPSTREAM_ERROR: &str = r#"<!DOCTYPE html>
+    <html lang="en">
+    <head>
+        <meta charset="utf-8">
+        <title>{STATUS}</title>
+        <style>
+            body {
+                color: #FFF;
+                background: #555;
+                font-family: sans-serif;
+            }
+            .center {
+                text-align: center;
+                display: block;
+                margin-left: auto;
+                margin-right: auto;
+            }
+        </style>
+    </head>
+    <body>
+        <h2 class="center">{STATUS}</h2>
+        <p class="center">{MESSAGE}</p>
+    </body>
+    </html>
+"#;
+
 pub const JAVASCRIPT_FACTORS_WORKER: &str = r#""use strict";
 
 function b64_to_val(c) {
@@ -165,6 +231,27 @@ function revb64_long_div_mod(b64_str, val) {
     return [result, rem];
 }
 
+// Pings the heartbeat url while factoring so a slow solve doesn't outlive
+// its challenge. 0 if the server doesn't take heartbeats.
+const heartbeat_ms = {HEARTBEAT_MS};
+let last_heartbeat = Date.now();
+
+function heartbeat() {
+    if (heartbeat_ms === 0 || Date.now() - last_heartbeat < heartbeat_ms) {
+        return;
+    }
+    last_heartbeat = Date.now();
+    try {
+        let xhr = new XMLHttpRequest();
+        // Synchronous, as the factoring loop never yields to send it.
+        xhr.open("POST", "{HEARTBEAT_URL}", false);
+        xhr.setRequestHeader("Content-Type", "application/json");
+        xhr.send(JSON.stringify({"id": "{UUID}"}));
+    } catch (e) {
+        // A missed heartbeat only risks the challenge expiring.
+    }
+}
+
 function getFactors() {
     let ret = [ "{LARGE_NUMBER}", 0 ];
 
@@ -173,6 +260,9 @@ function getFactors() {
     let result = [];
     let ticks = 1000000;
     while (ret[0].length > 1 || ret[0][0] != "B") {
+        if (ticks % 1000 === 0) {
+            heartbeat();
+        }
         let inner_ret = revb64_long_div_mod(ret[0], current);
         if (inner_ret[1] === 0) {
             current_count += 1;
@@ -223,12 +313,100 @@ function getFactors() {
     xhr.send(data);
 }
 
+// Spreads out clients that all load the page at once (e.g. after an outage) so
+// their answers don't hit the api at the same moment.
+const jitter_ms = {JITTER_MS};
+
 addEventListener("message", (message) => {
     if (message.data === "start") {
         postMessage({status: "Starting..."});
-        getFactors();
+        setTimeout(getFactors, Math.floor(Math.random() * (jitter_ms + 1)));
     } else {
         postMessage({status: "Invalid start message."});
     }
 });
 "#;
+
+/// Served in place of the factors worker with "--challenge-type=hashcash". Finds
+/// a nonce such that SHA-256 of "{PREFIX}" followed by the nonce starts with
+/// "{BITS}" zero bits.
+pub const JAVASCRIPT_HASHCASH_WORKER: &str = r#""use strict";
+
+function leading_zero_bits(bytes) {
+    let bits = 0;
+    for (let idx = 0; idx < bytes.length; ++idx) {
+        if (bytes[idx] === 0) {
+            bits += 8;
+            continue;
+        }
+        let byte = bytes[idx];
+        while ((byte & 0x80) === 0) {
+            bits += 1;
+            byte <<= 1;
+        }
+        break;
+    }
+    return bits;
+}
+
+async function getNonce() {
+    if (!self.crypto || !self.crypto.subtle) {
+        postMessage({status: "error_decoding"});
+        return;
+    }
+
+    const encoder = new TextEncoder();
+    const prefix = "{PREFIX}";
+    const bits = {BITS};
+    let nonce = 0;
+    while (true) {
+        const digest = await crypto.subtle.digest(
+            "SHA-256", encoder.encode(prefix + String(nonce)));
+        if (leading_zero_bits(new Uint8Array(digest)) >= bits) {
+            break;
+        }
+        nonce += 1;
+    }
+
+    let xhr = new XMLHttpRequest();
+    let url = "{API_URL}";
+    xhr.open("POST", url, true);
+    xhr.setRequestHeader("Content-Type", "application/json");
+    xhr.onreadystatechange = function () {
+        if (xhr.readyState === 4) {
+            if (xhr.status === 200) {
+                postMessage({status: "done"});
+            } else {
+                postMessage({status: "error_from_api"});
+            }
+        }
+    };
+    let data = JSON.stringify({"type": "hashcash",
+                               "id": "{UUID}",
+                               "nonce": String(nonce)});
+    xhr.send(data);
+}
+
+// Spreads out clients that all load the page at once (e.g. after an outage) so
+// their answers don't hit the api at the same moment.
+const jitter_ms = {JITTER_MS};
+
+addEventListener("message", (message) => {
+    if (message.data === "start") {
+        postMessage({status: "Starting..."});
+        setTimeout(getNonce, Math.floor(Math.random() * (jitter_ms + 1)));
+    } else {
+        postMessage({status: "Invalid start message."});
+    }
+});
+"#;
+
+/// Served in place of the factors worker when challenge generation failed and
+/// "--on-generation-failure=fail-open" is set; the client was already allowed,
+/// so this just tells the page to reload.
+pub const JAVASCRIPT_FAIL_OPEN_WORKER: &str = r#""use strict";
+
+addEventListener("message", (message) => {
+    postMessage({status: "done"});
+});
+"#;
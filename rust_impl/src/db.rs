@@ -0,0 +1,1988 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use std::path::Path;
+
+use rusqlite::Connection;
+use salvo::prelude::*;
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, BufReader},
+};
+
+use msql_ffi::{MSQLParamsWrapper, MSQLValueEnum};
+
+use crate::helpers::GenericCleanup;
+#[cfg(feature = "memory")]
+use crate::memory_db;
+use crate::msql_pool::{MSQLPool, MSQLPoolConn};
+#[cfg(feature = "postgres")]
+use crate::postgres_db;
+use crate::state::{depot_args, hash_factors};
+use crate::{args, constants, error::Error, helpers, json_types, signal};
+
+pub(crate) const GETRANDOM_BUF_SIZE: usize = 64;
+
+#[allow(unused)]
+const MSQL_RUST_SEQ_ID_1_CREATE: &str = r"CREATE TABLE IF NOT EXISTS RUST_SEQ_ID_1 (
+        ID INT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+        SEQ_ID INT8 UNSIGNED NOT NULL
+    )";
+
+#[allow(unused)]
+const MSQL_RUST_CHALLENGE_FACTORS_7_CREATE: &str = r"CREATE TABLE IF NOT EXISTS RUST_CHALLENGE_FACTORS_7 (
+        ID CHAR(64) CHARACTER SET ascii NOT NULL PRIMARY KEY,
+        IP VARCHAR(45) NOT NULL,
+        FACTORS CHAR(64) CHARACTER SET ascii NOT NULL,
+        VALUE MEDIUMTEXT CHARACTER SET ascii NOT NULL,
+        USER_AGENT VARCHAR(256),
+        PORT INT UNSIGNED NOT NULL,
+        GEN_TIME DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        ISSUE_TIME DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        INDEX ON_TIME_INDEX USING BTREE (GEN_TIME)
+    )";
+
+#[allow(unused)]
+const MSQL_RUST_ALLOWED_IPS_CREATE: &str = r"CREATE TABLE IF NOT EXISTS RUST_ALLOWED_IPS (
+        ID INT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+        IP VARCHAR(45) NOT NULL,
+        PORT INT UNSIGNED NOT NULL,
+        ON_TIME DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        INDEX IP_PORT_INDEX USING HASH (IP, PORT),
+        INDEX ON_TIME_INDEX USING BTREE (ON_TIME)
+    )";
+
+#[allow(unused)]
+const MSQL_RUST_ID_TO_PORT_3_CREATE: &str = r"CREATE TABLE IF NOT EXISTS RUST_ID_TO_PORT_3 (
+        ID CHAR(64) CHARACTER SET ascii NOT NULL PRIMARY KEY,
+        PORT INT UNSIGNED NOT NULL,
+        ON_TIME DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        INDEX ON_TIME_INDEX USING BTREE (ON_TIME)
+    )";
+
+#[allow(unused)]
+const MSQL_RUST_AUDIT_LOG_CREATE: &str = r"CREATE TABLE IF NOT EXISTS RUST_AUDIT_LOG (
+        ID INT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+        IP VARCHAR(45) NOT NULL,
+        PORT INT UNSIGNED NOT NULL,
+        EVENT VARCHAR(16) CHARACTER SET ascii NOT NULL,
+        ON_TIME DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        INDEX IP_INDEX USING HASH (IP),
+        INDEX ON_TIME_INDEX USING BTREE (ON_TIME)
+    )";
+
+#[allow(unused)]
+const SQLITE_SEQ_ID_CREATE: &str = r"CREATE TABLE IF NOT EXISTS SEQ_ID
+        (ID INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT)";
+
+#[allow(unused)]
+const SQLITE_ID_TO_PORT_CREATE: &str = r"CREATE TABLE IF NOT EXISTS ID_TO_PORT
+        (ID TEXT NOT NULL PRIMARY KEY,
+         PORT INT UNSIGNED NOT NULL,
+         ON_TIME TEXT NOT NULL DEFAULT ( datetime() ) )";
+
+#[allow(unused)]
+const SQLITE_CHALLENGE_FACTOR_4_CREATE: &str = r"CREATE TABLE IF NOT EXISTS CHALLENGE_FACTOR_4
+        (ID TEXT NOT NULL PRIMARY KEY,
+         FACTORS TEXT NOT NULL,
+         VALUE TEXT NOT NULL,
+         USER_AGENT TEXT,
+         IP TEXT NOT NULL,
+         PORT INT NOT NULL,
+         ON_TIME TEXT DEFAULT ( datetime() ),
+         ISSUE_TIME TEXT NOT NULL DEFAULT ( datetime() ) )";
+
+#[allow(unused)]
+const SQLITE_ALLOWED_IP_CREATE: &str = r"CREATE TABLE IF NOT EXISTS ALLOWED_IP
+        (ID INTEGER PRIMARY KEY AUTOINCREMENT,
+         IP TEXT NOT NULL,
+         PORT INTEGER NOT NULL,
+         ON_TIME TEXT NOT NULL DEFAULT ( datetime() ) )";
+
+#[allow(unused)]
+const SQLITE_AUDIT_LOG_CREATE: &str = r"CREATE TABLE IF NOT EXISTS AUDIT_LOG
+        (ID INTEGER PRIMARY KEY AUTOINCREMENT,
+         IP TEXT NOT NULL,
+         PORT INTEGER NOT NULL,
+         EVENT TEXT NOT NULL,
+         ON_TIME TEXT NOT NULL DEFAULT ( datetime() ) )";
+
+/// What an "--enable-audit-log" row records about a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AuditEvent {
+    Issued,
+    Passed,
+    Failed,
+}
+
+impl AuditEvent {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            AuditEvent::Issued => "issued",
+            AuditEvent::Passed => "passed",
+            AuditEvent::Failed => "failed",
+        }
+    }
+}
+
+/// Number of expired rows deleted from each table by "--purge-expired" or the
+/// "--cleanup-interval" task.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct PurgedRows {
+    pub(crate) challenge_factors: u64,
+    pub(crate) allowed_ips: u64,
+    pub(crate) id_to_port: u64,
+    pub(crate) audit_log: u64,
+}
+
+impl std::fmt::Display for PurgedRows {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Purged:")?;
+        writeln!(f, "  Challenge factors: {}", self.challenge_factors)?;
+        writeln!(f, "  Allowed ips: {}", self.allowed_ips)?;
+        writeln!(f, "  Id to port: {}", self.id_to_port)?;
+        write!(f, "  Audit log: {}", self.audit_log)
+    }
+}
+
+async fn parse_db_conf(config: &Path) -> Result<HashMap<String, String>, Error> {
+    let mut map: HashMap<String, String> = HashMap::new();
+
+    let mut lines = BufReader::new(File::open(config).await?).lines();
+    while let Some(line) = lines.next_line().await? {
+        if let Some(eq_pos) = line.find('=') {
+            let (first, last) = line.split_at(eq_pos);
+            let (_, actual_last) = last.split_at(1);
+            map.insert(first.to_owned(), actual_last.to_owned());
+        } else {
+            eprintln!("WARNING: parse_db_conf(): config had invalid entry!");
+        }
+    }
+
+    Ok(map)
+}
+
+async fn get_mysql_db_pool(args: &args::Args) -> Result<MSQLPool, Error> {
+    if args.mysql_has_priority {
+        let config_map = parse_db_conf(&args.mysql_config_file)
+            .await
+            .expect("Parse config for mysql usage");
+
+        MSQLPool::new(&config_map, constants::DB_POOL_MAX_CONNECTIONS)
+    } else {
+        Err(String::from("Prioritizing sqlite over msql").into())
+    }
+}
+
+fn get_mysql_pool(depot: &Depot) -> Result<&MSQLPool, Error> {
+    depot
+        .get_typed::<MSQLPool>()
+        .map_err(|_| Error::Generic("MSQLPool not in depot".into()))
+}
+
+#[cfg(feature = "postgres")]
+fn get_postgres_pool(depot: &Depot) -> Result<&deadpool_postgres::Pool, Error> {
+    depot
+        .get_typed::<deadpool_postgres::Pool>()
+        .map_err(|_| Error::Generic("Postgres pool not in depot".into()))
+}
+
+#[cfg(feature = "memory")]
+pub(crate) fn get_memory_db(depot: &Depot) -> Result<&memory_db::MemoryDb, Error> {
+    depot
+        .get_typed::<memory_db::MemoryDb>()
+        .map_err(|_| Error::Generic("MemoryDb not in depot".into()))
+}
+
+/// The pool for the db backend the args select. Sqlite opens a connection per
+/// query, so it has none.
+#[derive(Clone)]
+pub(crate) enum DbPool {
+    Sqlite,
+    Mysql(MSQLPool),
+    #[cfg(feature = "postgres")]
+    Postgres(deadpool_postgres::Pool),
+    #[cfg(feature = "memory")]
+    Memory(memory_db::MemoryDb),
+}
+
+impl DbPool {
+    pub(crate) async fn new(args: &args::Args) -> Result<Self, Error> {
+        #[cfg(feature = "memory")]
+        if args.memory_db {
+            return Ok(Self::Memory(memory_db::MemoryDb::new(args)?));
+        }
+
+        #[cfg(feature = "postgres")]
+        if let Some(postgres_config_file) = &args.postgres_config_file {
+            let config_map = parse_db_conf(postgres_config_file).await?;
+            return Ok(Self::Postgres(postgres_db::get_postgres_db_pool(
+                &config_map,
+            )?));
+        }
+
+        if args.mysql_has_priority {
+            Ok(Self::Mysql(get_mysql_db_pool(args).await?))
+        } else {
+            Ok(Self::Sqlite)
+        }
+    }
+
+    pub(crate) fn insert_into(&self, depot: &mut Depot) {
+        match self {
+            Self::Sqlite => (),
+            Self::Mysql(pool) => {
+                depot.insert_typed(pool.clone());
+            }
+            #[cfg(feature = "postgres")]
+            Self::Postgres(pool) => {
+                depot.insert_typed(pool.clone());
+            }
+            #[cfg(feature = "memory")]
+            Self::Memory(memory_db) => {
+                depot.insert_typed(memory_db.clone());
+            }
+        }
+    }
+
+    /// Starts saving whatever the backend only keeps in memory, i.e. the
+    /// "--memory-seq-file" counter.
+    pub(crate) fn spawn_flush(&self) {
+        #[cfg(feature = "memory")]
+        if let Self::Memory(memory_db) = self {
+            memory_db.spawn_seq_flush();
+        }
+    }
+
+    /// Saves whatever the backend only keeps in memory, e.g. on shutdown.
+    pub(crate) fn flush(&self) -> Result<(), Error> {
+        #[cfg(feature = "memory")]
+        if let Self::Memory(memory_db) = self {
+            return memory_db.flush_seq();
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn inject(&self, router: Router) -> Router {
+        match self {
+            Self::Sqlite => router,
+            Self::Mysql(pool) => router.hoop(affix_state::inject(pool.clone())),
+            #[cfg(feature = "postgres")]
+            Self::Postgres(pool) => router.hoop(affix_state::inject(pool.clone())),
+            #[cfg(feature = "memory")]
+            Self::Memory(memory_db) => router.hoop(affix_state::inject(memory_db.clone())),
+        }
+    }
+}
+
+async fn init_mysql_db(pool: &MSQLPool) -> Result<(), Error> {
+    let mut conn = pool.get_conn().await?;
+
+    conn.query_drop(r"DROP TABLE IF EXISTS RUST_SEQ_ID")?;
+
+    conn.query_drop(MSQL_RUST_SEQ_ID_1_CREATE)?;
+
+    conn.query_drop(r"DROP TABLE IF EXISTS RUST_CHALLENGE_FACTORS")?;
+
+    conn.query_drop(r"DROP TABLE IF EXISTS RUST_CHALLENGE_FACTORS_2")?;
+
+    conn.query_drop(r"DROP TABLE IF EXISTS RUST_CHALLENGE_FACTORS_3")?;
+
+    conn.query_drop(r"DROP TABLE IF EXISTS RUST_CHALLENGE_FACTORS_4")?;
+
+    conn.query_drop(r"DROP TABLE IF EXISTS RUST_CHALLENGE_FACTORS_5")?;
+
+    conn.query_drop(r"DROP TABLE IF EXISTS RUST_CHALLENGE_FACTORS_6")?;
+
+    conn.query_drop(MSQL_RUST_CHALLENGE_FACTORS_7_CREATE)?;
+
+    conn.query_drop(MSQL_RUST_ALLOWED_IPS_CREATE)?;
+
+    conn.query_drop(r"DROP TABLE IF EXISTS RUST_ID_TO_PORT")?;
+
+    conn.query_drop(r"DROP TABLE IF EXISTS RUST_ID_TO_PORT_2")?;
+
+    conn.query_drop(MSQL_RUST_ID_TO_PORT_3_CREATE)?;
+
+    conn.query_drop(MSQL_RUST_AUDIT_LOG_CREATE)?;
+
+    Ok(())
+}
+
+pub(crate) async fn init_sqlite_db(args: &args::Args) -> Result<(), Error> {
+    use rusqlite::Connection;
+
+    let conn = Connection::open(&args.sqlite_db_file)?;
+
+    conn.execute(SQLITE_SEQ_ID_CREATE, ())?;
+
+    conn.execute(SQLITE_ID_TO_PORT_CREATE, ())?;
+
+    conn.execute(
+        r#"CREATE INDEX IF NOT EXISTS ID_TO_PORT_TIME ON ID_TO_PORT (ON_TIME)"#,
+        (),
+    )?;
+
+    conn.execute(r"DROP TABLE IF EXISTS CHALLENGE_FACTOR", ())?;
+
+    conn.execute(r"DROP TABLE IF EXISTS CHALLENGE_FACTOR_2", ())?;
+
+    conn.execute(r"DROP TABLE IF EXISTS CHALLENGE_FACTOR_3", ())?;
+
+    conn.execute(SQLITE_CHALLENGE_FACTOR_4_CREATE, ())?;
+
+    conn.execute(
+        r#"CREATE INDEX IF NOT EXISTS CHALLENGE_FACTOR_4_TIME
+        ON CHALLENGE_FACTOR_4 (ON_TIME)"#,
+        (),
+    )?;
+
+    conn.execute(SQLITE_ALLOWED_IP_CREATE, ())?;
+
+    conn.execute(
+        r#"CREATE INDEX IF NOT EXISTS ALLOWED_IP_IP ON ALLOWED_IP (IP)"#,
+        (),
+    )?;
+
+    conn.execute(
+        r#"CREATE INDEX IF NOT EXISTS ALLOWED_IP_TIME ON ALLOWED_IP (ON_TIME)"#,
+        (),
+    )?;
+
+    conn.execute(SQLITE_AUDIT_LOG_CREATE, ())?;
+
+    conn.execute(
+        r#"CREATE INDEX IF NOT EXISTS AUDIT_LOG_IP ON AUDIT_LOG (IP)"#,
+        (),
+    )?;
+
+    Ok(())
+}
+
+fn insert_audit_log_mysql(
+    conn: &mut MSQLPoolConn,
+    addr: &str,
+    port: u16,
+    event: AuditEvent,
+) -> Result<(), Error> {
+    let mut params = MSQLParamsWrapper::new();
+    params.append_str(addr)?;
+    params.append_uint64(port as u64);
+    params.append_str(event.as_str())?;
+
+    conn.query_with_params_drop(
+        "INSERT INTO RUST_AUDIT_LOG (IP, PORT, EVENT) VALUES (?, ?, ?)",
+        &params,
+    )?;
+
+    Ok(())
+}
+
+fn insert_audit_log_sqlite(
+    conn: &Connection,
+    addr: &str,
+    port: u16,
+    event: AuditEvent,
+) -> Result<(), Error> {
+    conn.execute(
+        r"INSERT INTO AUDIT_LOG (IP, PORT, EVENT) VALUES (?1, ?2, ?3)",
+        (addr, port, event.as_str()),
+    )?;
+
+    Ok(())
+}
+
+pub async fn init_db(depot: &Depot) -> Result<(), Error> {
+    let args = &depot_args(depot)?;
+
+    #[cfg(feature = "postgres")]
+    if args.postgres_config_file.is_some() {
+        return postgres_db::init_postgres_db(get_postgres_pool(depot)?).await;
+    }
+
+    // Nothing to create, the tables start out empty.
+    #[cfg(feature = "memory")]
+    if args.memory_db {
+        return Ok(());
+    }
+
+    if args.mysql_has_priority {
+        init_mysql_db(get_mysql_pool(depot)?).await?;
+    } else {
+        init_sqlite_db(args).await?;
+    }
+
+    Ok(())
+}
+
+async fn get_next_seq_mysql(pool: &MSQLPool) -> Result<u64, Error> {
+    let seq: u64;
+    let conn: Arc<Mutex<MSQLPoolConn>> = Arc::new(Mutex::new(pool.get_conn().await?));
+
+    let _unlock_cleanup = GenericCleanup::new(&conn, |conn_ref: &Arc<Mutex<MSQLPoolConn>>| {
+        let mut locked = conn_ref.lock().unwrap();
+        locked
+            .query_drop("UNLOCK TABLES")
+            .expect("Should be able to UNLOCK TABLES in cleanup");
+    });
+
+    let mut locked = conn.lock().unwrap();
+
+    locked.query_drop("LOCK TABLE RUST_SEQ_ID_1 WRITE")?;
+
+    let seq_rows: Option<Vec<Vec<MSQLValueEnum>>> = locked
+        .query_rows("SELECT ID, SEQ_ID FROM RUST_SEQ_ID_1")
+        .map_err(|e| e.to_owned())?;
+
+    if let Some(seq_r) = seq_rows {
+        let id: u64 = match seq_r[0][0] {
+            MSQLValueEnum::Int64(i) => i as u64,
+            MSQLValueEnum::UInt64(u) => u,
+            _ => {
+                return Err(Error::Generic(String::from(
+                    "Failed to get ID from SEQ_ID table!",
+                )));
+            }
+        };
+
+        match seq_r[0][1] {
+            MSQLValueEnum::Int64(i) => seq = i as u64,
+            MSQLValueEnum::UInt64(u) => seq = u,
+            _ => {
+                return Err(Error::Generic(String::from(
+                    "Failed to get SEQ from SEQ_ID table!",
+                )));
+            }
+        }
+
+        if seq + 1 >= 0x7FFFFFFFFFFFFFFF {
+            let mut params = MSQLParamsWrapper::new();
+            params.append_uint64(1);
+            params.append_uint64(id);
+
+            locked
+                .query_with_params_drop("UPDATE RUST_SEQ_ID_1 SET SEQ_ID = ? WHERE ID = ?", &params)
+                .map_err(|e| e.to_owned())?;
+        } else {
+            let mut params = MSQLParamsWrapper::new();
+            params.append_uint64(seq + 1);
+            params.append_uint64(id);
+
+            locked
+                .query_with_params_drop("UPDATE RUST_SEQ_ID_1 SET SEQ_ID = ? WHERE ID = ?", &params)
+                .map_err(|e| e.to_owned())?;
+        }
+    } else {
+        let mut params = MSQLParamsWrapper::new();
+        params.append_uint64(1);
+        locked
+            .query_with_params_drop("INSERT INTO RUST_SEQ_ID_1 (SEQ_ID) VALUES (?)", &params)
+            .map_err(|e| e.to_owned())?;
+        seq = 1;
+    }
+
+    Ok(seq)
+}
+
+async fn get_next_seq_sqlite(args: &args::Args) -> Result<u64, Error> {
+    let seq: i64;
+    let conn = Connection::open(&args.sqlite_db_file)?;
+
+    let query_res = conn.query_one(r#"SELECT ID FROM SEQ_ID"#, (), |r| r.get::<usize, i64>(0));
+    match query_res {
+        Ok(s) => {
+            seq = s;
+            if seq == 0x7FFFFFFFFFFFFFFF {
+                conn.execute(r#"UPDATE SEQ_ID SET ID = ?1"#, (1,))?;
+            } else {
+                conn.execute(r#"UPDATE SEQ_ID SET ID = ?1"#, (s + 1,))?;
+            }
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            seq = 1;
+            conn.execute(r#"INSERT INTO SEQ_ID (ID) VALUES (1)"#, ())?;
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(seq as u64)
+}
+
+async fn has_challenge_factor_id_mysql(pool: &MSQLPool, hash: &str) -> Result<bool, Error> {
+    let mut conn: MSQLPoolConn = pool.get_conn().await?;
+
+    let mut params = MSQLParamsWrapper::new();
+    params.append_str(hash)?;
+    let rows_opt = conn.query_with_params_rows(
+        "SELECT ID FROM RUST_CHALLENGE_FACTORS_7 WHERE ID = ?",
+        &params,
+    )?;
+
+    if let Some(rows) = rows_opt {
+        if let MSQLValueEnum::String(_) = &rows[0][0] {
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    } else {
+        Ok(false)
+    }
+}
+
+async fn has_challenge_factor_id_sqlite(args: &args::Args, hash: &str) -> Result<bool, Error> {
+    let conn = Connection::open(&args.sqlite_db_file)?;
+
+    match conn.query_one(
+        r"SELECT ID FROM CHALLENGE_FACTOR_4 WHERE ID = ?1",
+        (hash,),
+        |r| r.get::<usize, String>(0),
+    ) {
+        Ok(_) => Ok(true),
+        Err(_) => Ok(false),
+    }
+}
+
+async fn set_challenge_factor_mysql(
+    pool: &MSQLPool,
+    ip: &str,
+    hash: &str,
+    port: u16,
+    factors_hash: &str,
+    value: &str,
+    user_agent: Option<&str>,
+) -> Result<(), Error> {
+    let conn: Arc<Mutex<MSQLPoolConn>> = Arc::new(Mutex::new(pool.get_conn().await?));
+
+    let _unlock_cleanup = GenericCleanup::new(&conn, |conn_ref: &Arc<Mutex<MSQLPoolConn>>| {
+        let mut locked = conn_ref.lock().unwrap();
+        locked
+            .query_drop("UNLOCK TABLES")
+            .expect("Should be able to UNLOCK TABLES in cleanup");
+    });
+
+    let mut locked = conn.lock().unwrap();
+
+    locked.query_drop("LOCK TABLE RUST_CHALLENGE_FACTORS_7 WRITE")?;
+
+    let mut params = MSQLParamsWrapper::new();
+    params.append_str(hash)?;
+    params.append_str(ip)?;
+    params.append_uint64(port as u64);
+    params.append_str(factors_hash)?;
+    params.append_str(value)?;
+    match user_agent {
+        Some(user_agent) => params.append_str(user_agent)?,
+        None => params.append_null(),
+    }
+
+    locked
+        .query_with_params_drop(
+            "INSERT INTO RUST_CHALLENGE_FACTORS_7 (ID, IP, PORT, FACTORS, VALUE, USER_AGENT) VALUES (?, ?, ?, ?, ?, ?)",
+            &params,
+        )
+        .ok();
+
+    Ok(())
+}
+
+pub(crate) async fn set_challenge_factor_sqlite(
+    args: &args::Args,
+    ip: &str,
+    hash: &str,
+    port: u16,
+    factors_hash: &str,
+    value: &str,
+    user_agent: Option<&str>,
+) -> Result<(), Error> {
+    let conn = Connection::open(&args.sqlite_db_file)?;
+
+    conn.execute(
+        r"INSERT INTO CHALLENGE_FACTOR_4 (ID, FACTORS, VALUE, USER_AGENT, IP, PORT) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        (hash, factors_hash, value, user_agent, ip, port),
+    )?;
+
+    Ok(())
+}
+
+async fn pending_challenges_mysql(
+    pool: &MSQLPool,
+) -> Result<Vec<(String, u16, Option<String>)>, Error> {
+    let mut conn = pool.get_conn().await?;
+
+    let rows_opt = conn
+        .query_rows("SELECT IP, PORT, USER_AGENT FROM RUST_CHALLENGE_FACTORS_7")
+        .map_err(|e| e.to_owned())?;
+
+    let mut challenges = Vec::new();
+    for row in rows_opt.unwrap_or_default().iter() {
+        let ip = match &row[0] {
+            MSQLValueEnum::String(s) => s.to_owned(),
+            _ => return Err("Failed to get IP from challenge factors".into()),
+        };
+        let port = match row[1] {
+            MSQLValueEnum::Int64(i) => i as u16,
+            MSQLValueEnum::UInt64(u) => u as u16,
+            _ => return Err("Failed to get PORT from challenge factors".into()),
+        };
+        let user_agent = match &row[2] {
+            MSQLValueEnum::String(s) => Some(s.to_owned()),
+            _ => None,
+        };
+        challenges.push((ip, port, user_agent));
+    }
+
+    Ok(challenges)
+}
+
+async fn pending_challenges_sqlite(
+    args: &args::Args,
+) -> Result<Vec<(String, u16, Option<String>)>, Error> {
+    let conn = Connection::open(&args.sqlite_db_file)?;
+
+    let mut stmt = conn.prepare(r"SELECT IP, PORT, USER_AGENT FROM CHALLENGE_FACTOR_4")?;
+    let rows = stmt.query_map((), |r| {
+        Ok((
+            r.get::<usize, String>(0)?,
+            r.get::<usize, u16>(1)?,
+            r.get::<usize, Option<String>>(2)?,
+        ))
+    })?;
+
+    Ok(rows.collect::<Result<Vec<_>, _>>()?)
+}
+
+/// How often the factors worker pings the heartbeat url, 0 if it doesn't.
+/// Twice per "--challenge-timeout" so one lost ping doesn't expire it.
+pub(crate) fn heartbeat_ms(args: &args::Args) -> u64 {
+    if args.challenge_heartbeat_max_mins.is_some() {
+        args.challenge_timeout_mins * 60 * 1000 / 2
+    } else {
+        0
+    }
+}
+
+/// Minutes after ISSUE_TIME a heartbeat can move a challenge's expiry base
+/// to, so it expires at most "--challenge-heartbeat-max" minutes after it was
+/// issued.
+pub(crate) fn heartbeat_extension_mins(args: &args::Args) -> u64 {
+    args.challenge_heartbeat_max_mins
+        .unwrap_or(0)
+        .saturating_sub(args.challenge_timeout_mins)
+}
+
+async fn extend_challenge_mysql(
+    args: &args::Args,
+    pool: &MSQLPool,
+    id: &str,
+    addr: &str,
+) -> Result<bool, Error> {
+    let mut conn = pool.get_conn().await?;
+
+    let mut params = MSQLParamsWrapper::new();
+    params.append_str(id)?;
+    params.append_str(addr)?;
+    params.append_uint64(args.challenge_timeout_mins);
+
+    // ROW_COUNT() of an UPDATE leaves out rows it didn't change, so whether
+    // the challenge is still pending is checked first.
+    let rows_opt = conn
+        .query_with_params_rows(
+            "SELECT ID FROM RUST_CHALLENGE_FACTORS_7 WHERE ID = ? AND IP = ? AND TIMESTAMPDIFF(MINUTE, GEN_TIME, NOW()) < ?",
+            &params,
+        )
+        .map_err(|e| e.to_owned())?;
+    if rows_opt.is_none() {
+        return Ok(false);
+    }
+
+    let mut params = MSQLParamsWrapper::new();
+    params.append_uint64(heartbeat_extension_mins(args));
+    params.append_str(id)?;
+
+    conn.query_with_params_drop(
+        "UPDATE RUST_CHALLENGE_FACTORS_7 SET GEN_TIME = GREATEST(GEN_TIME, LEAST(NOW(), ISSUE_TIME + INTERVAL ? MINUTE)) WHERE ID = ?",
+        &params,
+    )
+    .map_err(|e| e.to_owned())?;
+
+    Ok(true)
+}
+
+pub(crate) async fn extend_challenge_sqlite(
+    args: &args::Args,
+    id: &str,
+    addr: &str,
+) -> Result<bool, Error> {
+    let conn = Connection::open(&args.sqlite_db_file)?;
+
+    let extended = conn.execute(
+        &format!(
+            r#"UPDATE CHALLENGE_FACTOR_4 SET ON_TIME = max(ON_TIME, min(datetime('now'), datetime(ISSUE_TIME, '{} minutes')))
+            WHERE ID = ?1 AND IP = ?2 AND datetime(ON_TIME, '{} minutes') >= datetime('now')"#,
+            heartbeat_extension_mins(args),
+            args.challenge_timeout_mins
+        ),
+        (id, addr),
+    )?;
+
+    Ok(extended != 0)
+}
+
+pub(crate) async fn store_factors_challenge(
+    depot: &Depot,
+    ip: &str,
+    port: u16,
+    value: &str,
+    factors: &str,
+    user_agent: Option<&str>,
+) -> Result<String, Error> {
+    let args = &depot_args(depot)?;
+    let mut hash: String;
+    let user_agent = user_agent.map(helpers::truncate_user_agent);
+
+    let seq: u64 = get_next_seq(depot).await?;
+
+    loop {
+        let mut hasher = helpers::salted_hasher(args);
+        hasher.update(&seq.to_ne_bytes());
+        let mut buf = [0u8; GETRANDOM_BUF_SIZE];
+        getrandom::fill(&mut buf)?;
+        hasher.update(&buf);
+        let hasher = hasher.finalize();
+
+        hash = hasher.to_string();
+
+        if has_challenge_factor_id(depot, &hash).await? {
+            continue;
+        }
+
+        let factors_hash = hash_factors(depot, factors)?;
+
+        set_challenge_factor(depot, ip, &hash, port, &factors_hash, value, user_agent).await?;
+        break;
+    }
+
+    Ok(hash)
+}
+
+async fn challenge_port_mysql(args: &args::Args, pool: &MSQLPool, id: &str) -> Result<u16, Error> {
+    let mut port: Option<u16> = None;
+    let conn: Arc<Mutex<MSQLPoolConn>> = Arc::new(Mutex::new(pool.get_conn().await?));
+
+    let _unlock_cleanup = GenericCleanup::new(&conn, |conn_ref: &Arc<Mutex<MSQLPoolConn>>| {
+        let mut locked = conn_ref.lock().unwrap();
+        locked
+            .query_drop("UNLOCK TABLES")
+            .expect("Should be able to UNLOCK TABLES in cleanup");
+    });
+
+    let mut locked = conn.lock().unwrap();
+
+    locked.query_drop("LOCK TABLE RUST_ID_TO_PORT_3 WRITE")?;
+
+    {
+        let mut params = MSQLParamsWrapper::new();
+        params.append_str(id)?;
+        params.append_uint64(args.challenge_timeout_mins);
+
+        let rows_opt_ret = locked
+            .query_with_params_rows(
+                "SELECT PORT FROM RUST_ID_TO_PORT_3 WHERE ID = ? AND TIMESTAMPDIFF(MINUTE, ON_TIME, NOW()) < ?",
+                &params,
+            )
+            .map_err(|e| e.to_owned())?;
+        if let Some(rows) = rows_opt_ret {
+            match rows[0][0] {
+                MSQLValueEnum::Int64(i) => port = Some(i as u16),
+                MSQLValueEnum::UInt64(u) => port = Some(u as u16),
+                _ => {
+                    return Err(Error::Generic(String::from(
+                        "Failed to get port from id-to-port",
+                    )));
+                }
+            }
+        }
+    }
+
+    if port.is_some() {
+        let mut params = MSQLParamsWrapper::new();
+        params.append_str(id)?;
+
+        locked
+            .query_with_params_drop("DELETE FROM RUST_ID_TO_PORT_3 WHERE ID = ?", &params)
+            .map_err(|e| e.to_owned())?;
+    }
+
+    port.ok_or(Error::Generic(String::from(
+        "gen challenge, failed to get port",
+    )))
+}
+
+async fn challenge_port_sqlite(args: &args::Args, id: &str) -> Result<u16, Error> {
+    let conn = Connection::open(&args.sqlite_db_file)?;
+
+    match conn.query_one(
+        &format!(
+            r#"SELECT PORT FROM ID_TO_PORT WHERE ID = ?1 AND datetime(ON_TIME, '{} minutes') >= datetime('now')"#,
+            args.challenge_timeout_mins
+        ),
+        (id,),
+        |r| r.get::<usize, u16>(0),
+    ) {
+        Ok(p) => {
+            conn.execute(r"DELETE FROM ID_TO_PORT WHERE ID = ?1", (id,))?;
+            Ok(p)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn validate_client_mysql(
+    args: &args::Args,
+    pool: &MSQLPool,
+    challenge_response: &json_types::ChallengeResponse,
+    hashed_factors: &str,
+    addr: &str,
+) -> Result<u16, Error> {
+    let correct;
+    let mut port: u16 = 0;
+    let conn: Arc<Mutex<MSQLPoolConn>> = Arc::new(Mutex::new(pool.get_conn().await?));
+
+    {
+        let _unlock_cleanup = GenericCleanup::new(&conn, |conn_ref: &Arc<Mutex<MSQLPoolConn>>| {
+            let mut locked = conn_ref.lock().unwrap();
+            locked
+                .query_drop("UNLOCK TABLES")
+                .expect("Should be able to UNLOCK TABLES in cleanup");
+        });
+
+        let mut locked = conn.lock().unwrap();
+
+        locked.query_drop("LOCK TABLE RUST_CHALLENGE_FACTORS_7 WRITE")?;
+
+        let mut params = MSQLParamsWrapper::new();
+        params.append_str(challenge_response.id())?;
+        params.append_str(hashed_factors)?;
+        params.append_uint64(args.challenge_timeout_mins);
+
+        let addr_port_rows_opt: Option<Vec<Vec<MSQLValueEnum>>> = locked
+            .query_with_params_rows(
+                "SELECT IP, PORT, VALUE FROM RUST_CHALLENGE_FACTORS_7 WHERE ID = ? AND FACTORS = ? AND TIMESTAMPDIFF(MINUTE, GEN_TIME, NOW()) < ?",
+                &params,
+            )
+            .map_err(|e| e.to_owned())?;
+
+        if let Some(rows) = addr_port_rows_opt {
+            let client_addr: String = match &rows[0][0] {
+                MSQLValueEnum::String(s) => s.to_owned(),
+                _ => {
+                    return Err(Error::Generic(String::from("No IP from ChallengeFactors")));
+                }
+            };
+
+            let value: &str = match &rows[0][2] {
+                MSQLValueEnum::String(s) => s,
+                _ => {
+                    return Err(Error::Generic(String::from(
+                        "No Value from ChallengeFactors",
+                    )));
+                }
+            };
+
+            port = match rows[0][1] {
+                MSQLValueEnum::Int64(i) => i as u16,
+                MSQLValueEnum::UInt64(u) => u as u16,
+                _ => {
+                    return Err(Error::Generic(String::from(
+                        "No Port from ChallengeFactors",
+                    )));
+                }
+            };
+
+            if client_addr == addr && helpers::verify_challenge_answer(value, challenge_response)? {
+                correct = true;
+
+                let mut params = MSQLParamsWrapper::new();
+                params.append_str(challenge_response.id())?;
+
+                locked
+                    .query_with_params_drop(
+                        "DELETE FROM RUST_CHALLENGE_FACTORS_7 WHERE ID = ?",
+                        &params,
+                    )
+                    .map_err(|e| e.to_owned())?;
+            } else {
+                correct = false;
+            }
+        } else {
+            correct = false;
+        }
+    }
+
+    let mut locked = conn.lock().unwrap();
+    if correct && port != 0 {
+        let mut params = MSQLParamsWrapper::new();
+        params.append_str(addr)?;
+        params.append_uint64(port as u64);
+
+        locked.query_with_params_drop(
+            "INSERT INTO RUST_ALLOWED_IPS (IP, PORT) VALUES (?, ?)",
+            &params,
+        )?;
+
+        if args.enable_audit_log {
+            insert_audit_log_mysql(&mut locked, addr, port, AuditEvent::Passed)?;
+        }
+
+        Ok(port)
+    } else {
+        if args.enable_audit_log {
+            insert_audit_log_mysql(&mut locked, addr, port, AuditEvent::Failed)?;
+        }
+
+        Err(String::from("Incorrect").into())
+    }
+}
+
+async fn validate_client_sqlite(
+    args: &args::Args,
+    challenge_response: &json_types::ChallengeResponse,
+    hashed_factors: &str,
+    addr: &str,
+) -> Result<u16, Error> {
+    let conn = Connection::open(&args.sqlite_db_file)?;
+
+    let res = conn.query_one(
+        &format!(
+            r#"SELECT IP, PORT, VALUE FROM CHALLENGE_FACTOR_4 WHERE ID = ?1 AND FACTORS = ?2 AND datetime(ON_TIME, '{} minutes') >= datetime('now')"#,
+            args.challenge_timeout_mins
+        ),
+        (challenge_response.id(), hashed_factors),
+        |r| {
+            Ok((
+                r.get::<usize, String>(0),
+                r.get::<usize, u16>(1),
+                r.get::<usize, String>(2),
+            ))
+        },
+    );
+
+    let (port, result): (u16, Result<u16, Error>) = if let Ok((Ok(ip), Ok(port), Ok(value))) = res {
+        if !helpers::verify_challenge_answer(&value, challenge_response)? {
+            (port, Err(String::from("Incorrect").into()))
+        } else if ip == addr && port != 0 {
+            conn.execute(
+                r"DELETE FROM CHALLENGE_FACTOR_4 WHERE ID = ?1",
+                (challenge_response.id(),),
+            )?;
+            conn.execute(
+                r"INSERT INTO ALLOWED_IP (IP, PORT) VALUES (?1, ?2)",
+                (&ip, &port),
+            )?;
+            (port, Ok(port))
+        } else {
+            (
+                port,
+                Err(String::from("Invalid entries from ChallengeFactor").into()),
+            )
+        }
+    } else {
+        (0, Err(String::from("Incorrect").into()))
+    };
+
+    if args.enable_audit_log {
+        let event = if result.is_ok() {
+            AuditEvent::Passed
+        } else {
+            AuditEvent::Failed
+        };
+        insert_audit_log_sqlite(&conn, addr, port, event)?;
+    }
+
+    result
+}
+
+async fn check_is_allowed_mysql(
+    args: &args::Args,
+    pool: &MSQLPool,
+    addr: &str,
+    port: u16,
+) -> Result<bool, Error> {
+    let conn: Arc<Mutex<MSQLPoolConn>> = Arc::new(Mutex::new(pool.get_conn().await?));
+
+    let _unlock_cleanup = GenericCleanup::new(&conn, |conn_ref: &Arc<Mutex<MSQLPoolConn>>| {
+        let mut locked = conn_ref.lock().unwrap();
+        locked
+            .query_drop("UNLOCK TABLES")
+            .expect("Should be able to UNLOCK TABLES in cleanup");
+    });
+
+    let mut locked = conn.lock().unwrap();
+
+    locked.query_drop("LOCK TABLE RUST_ALLOWED_IPS READ")?;
+
+    let mut params = MSQLParamsWrapper::new();
+    params.append_str(addr)?;
+    params.append_uint64(port as u64);
+    params.append_uint64(args.allowed_timeout_mins);
+
+    let ip_entry_row_opt: Option<Vec<Vec<MSQLValueEnum>>> = locked
+        .query_with_params_rows(
+            "SELECT IP FROM RUST_ALLOWED_IPS WHERE IP = ? AND PORT = ? AND TIMESTAMPDIFF(MINUTE, ON_TIME, NOW()) < ?",
+            &params,
+        )
+        .map_err(|e| e.to_owned())?;
+
+    if ip_entry_row_opt.is_some() {
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+pub(crate) async fn check_is_allowed_sqlite(
+    args: &args::Args,
+    addr: &str,
+    port: u16,
+) -> Result<bool, Error> {
+    let conn = Connection::open(&args.sqlite_db_file)?;
+
+    let mut stmt = conn.prepare(&format!(
+        r#"SELECT PORT FROM ALLOWED_IP WHERE IP = ?1 AND PORT = ?2 AND datetime(ON_TIME, '{} minutes') >= datetime('now')"#,
+        args.allowed_timeout_mins
+    ))?;
+    let rows = stmt.query_map((addr, port), |r| r.get::<usize, u16>(0));
+    let is_allowed: bool = rows?.count() != 0;
+
+    Ok(is_allowed)
+}
+
+async fn add_allowed_mysql(pool: &MSQLPool, addr: &str, port: u16) -> Result<(), Error> {
+    let mut conn = pool.get_conn().await?;
+
+    let mut params = MSQLParamsWrapper::new();
+    params.append_str(addr)?;
+    params.append_uint64(port as u64);
+
+    conn.query_with_params_drop(
+        "INSERT INTO RUST_ALLOWED_IPS (IP, PORT) VALUES (?, ?)",
+        &params,
+    )?;
+
+    Ok(())
+}
+
+pub(crate) async fn add_allowed_sqlite(
+    args: &args::Args,
+    addr: &str,
+    port: u16,
+) -> Result<(), Error> {
+    let conn = Connection::open(&args.sqlite_db_file)?;
+
+    conn.execute(
+        r"INSERT INTO ALLOWED_IP (IP, PORT) VALUES (?1, ?2)",
+        (addr, port),
+    )?;
+
+    Ok(())
+}
+
+async fn recently_allowed_mysql(
+    pool: &MSQLPool,
+    within: Duration,
+) -> Result<Vec<(String, u16, Duration)>, Error> {
+    let mut conn = pool.get_conn().await?;
+
+    let mut params = MSQLParamsWrapper::new();
+    params.append_uint64(within.as_secs());
+
+    let rows_opt = conn
+        .query_with_params_rows(
+            "SELECT IP, PORT, TIMESTAMPDIFF(SECOND, ON_TIME, NOW()) FROM RUST_ALLOWED_IPS WHERE TIMESTAMPDIFF(SECOND, ON_TIME, NOW()) < ?",
+            &params,
+        )
+        .map_err(|e| e.to_owned())?;
+
+    let mut allowed = Vec::new();
+    for row in rows_opt.unwrap_or_default().iter() {
+        let ip = match &row[0] {
+            MSQLValueEnum::String(s) => s.to_owned(),
+            _ => return Err("Failed to get IP from allowed ips".into()),
+        };
+        let port = match row[1] {
+            MSQLValueEnum::Int64(i) => i as u16,
+            MSQLValueEnum::UInt64(u) => u as u16,
+            _ => return Err("Failed to get PORT from allowed ips".into()),
+        };
+        let age = match row[2] {
+            MSQLValueEnum::Int64(i) => i.max(0) as u64,
+            MSQLValueEnum::UInt64(u) => u,
+            _ => return Err("Failed to get age from allowed ips".into()),
+        };
+        allowed.push((ip, port, Duration::from_secs(age)));
+    }
+
+    Ok(allowed)
+}
+
+async fn recently_allowed_sqlite(
+    args: &args::Args,
+    within: Duration,
+) -> Result<Vec<(String, u16, Duration)>, Error> {
+    let conn = Connection::open(&args.sqlite_db_file)?;
+
+    let mut stmt = conn.prepare(
+        r"SELECT IP, PORT, CAST(strftime('%s', 'now') AS INTEGER) - CAST(strftime('%s', ON_TIME) AS INTEGER)
+          FROM ALLOWED_IP WHERE datetime(ON_TIME) > datetime('now', ?1)",
+    )?;
+    let rows = stmt.query_map((format!("-{} seconds", within.as_secs()),), |r| {
+        Ok((
+            r.get::<usize, String>(0)?,
+            r.get::<usize, u16>(1)?,
+            Duration::from_secs(r.get::<usize, i64>(2)?.max(0) as u64),
+        ))
+    })?;
+
+    Ok(rows.collect::<Result<Vec<_>, _>>()?)
+}
+
+async fn revoke_allowed_mysql(
+    pool: &MSQLPool,
+    addr: Option<&str>,
+    port: Option<u16>,
+) -> Result<(), Error> {
+    let mut conn = pool.get_conn().await?;
+
+    let mut params = MSQLParamsWrapper::new();
+    for _ in 0..2 {
+        match addr {
+            Some(addr) => params.append_str(addr)?,
+            None => params.append_null(),
+        }
+    }
+    for _ in 0..2 {
+        match port {
+            Some(port) => params.append_uint64(port as u64),
+            None => params.append_null(),
+        }
+    }
+
+    conn.query_with_params_drop(
+        "DELETE FROM RUST_ALLOWED_IPS WHERE (? IS NULL OR IP = ?) AND (? IS NULL OR PORT = ?)",
+        &params,
+    )?;
+
+    Ok(())
+}
+
+async fn revoke_allowed_sqlite(
+    args: &args::Args,
+    addr: Option<&str>,
+    port: Option<u16>,
+) -> Result<(), Error> {
+    let conn = Connection::open(&args.sqlite_db_file)?;
+
+    conn.execute(
+        r"DELETE FROM ALLOWED_IP WHERE (?1 IS NULL OR IP = ?1) AND (?2 IS NULL OR PORT = ?2)",
+        (addr, port),
+    )?;
+
+    Ok(())
+}
+
+/// Runs a "DELETE ... >= ?" of rows older than "mins" minutes and returns how
+/// many were deleted.
+fn delete_expired_mysql(conn: &mut MSQLPoolConn, stmt: &str, mins: u64) -> Result<u64, Error> {
+    let mut params = MSQLParamsWrapper::new();
+    params.append_uint64(mins);
+
+    conn.query_with_params_drop(stmt, &params)
+        .map_err(|e| e.to_owned())?;
+
+    let rows_opt = conn
+        .query_rows("SELECT ROW_COUNT()")
+        .map_err(|e| e.to_owned())?;
+    match rows_opt.as_ref().and_then(|rows| rows.first()?.first()) {
+        Some(MSQLValueEnum::Int64(i)) => Ok((*i).max(0) as u64),
+        Some(MSQLValueEnum::UInt64(u)) => Ok(*u),
+        _ => Err("Failed to get ROW_COUNT() of purge".into()),
+    }
+}
+
+async fn purge_expired_mysql(args: &args::Args, pool: &MSQLPool) -> Result<PurgedRows, Error> {
+    let mut conn = pool.get_conn().await?;
+
+    Ok(PurgedRows {
+        challenge_factors: delete_expired_mysql(
+            &mut conn,
+            "DELETE FROM RUST_CHALLENGE_FACTORS_7 WHERE TIMESTAMPDIFF(MINUTE, GEN_TIME, NOW()) >= ?",
+            args.challenge_timeout_mins,
+        )?,
+        allowed_ips: delete_expired_mysql(
+            &mut conn,
+            "DELETE FROM RUST_ALLOWED_IPS WHERE TIMESTAMPDIFF(MINUTE, ON_TIME, NOW()) >= ?",
+            args.allowed_timeout_mins,
+        )?,
+        id_to_port: delete_expired_mysql(
+            &mut conn,
+            "DELETE FROM RUST_ID_TO_PORT_3 WHERE TIMESTAMPDIFF(MINUTE, ON_TIME, NOW()) >= ?",
+            args.challenge_timeout_mins,
+        )?,
+        audit_log: match args.audit_log_retention_mins {
+            Some(audit_log_retention_mins) => delete_expired_mysql(
+                &mut conn,
+                "DELETE FROM RUST_AUDIT_LOG WHERE TIMESTAMPDIFF(MINUTE, ON_TIME, NOW()) >= ?",
+                audit_log_retention_mins,
+            )?,
+            None => 0,
+        },
+    })
+}
+
+async fn purge_expired_sqlite(args: &args::Args) -> Result<PurgedRows, Error> {
+    let conn = Connection::open(&args.sqlite_db_file)?;
+
+    let challenge_factors = conn.execute(
+        &format!(
+            r#"DELETE FROM CHALLENGE_FACTOR_4 WHERE datetime(ON_TIME, '{} minutes') < datetime('now')"#,
+            args.challenge_timeout_mins
+        ),
+        (),
+    )?;
+    let allowed_ips = conn.execute(
+        &format!(
+            r#"DELETE FROM ALLOWED_IP WHERE datetime(ON_TIME, '{} minutes') < datetime('now')"#,
+            args.allowed_timeout_mins
+        ),
+        (),
+    )?;
+    let id_to_port = conn.execute(
+        &format!(
+            r#"DELETE FROM ID_TO_PORT WHERE datetime(ON_TIME, '{} minutes') < datetime('now')"#,
+            args.challenge_timeout_mins
+        ),
+        (),
+    )?;
+    let audit_log = match args.audit_log_retention_mins {
+        Some(audit_log_retention_mins) => conn.execute(
+            &format!(
+                r#"DELETE FROM AUDIT_LOG WHERE datetime(ON_TIME, '{} minutes') < datetime('now')"#,
+                audit_log_retention_mins
+            ),
+            (),
+        )?,
+        None => 0,
+    };
+
+    Ok(PurgedRows {
+        challenge_factors: challenge_factors as u64,
+        allowed_ips: allowed_ips as u64,
+        id_to_port: id_to_port as u64,
+        audit_log: audit_log as u64,
+    })
+}
+
+async fn init_id_to_port_mysql(
+    args: &args::Args,
+    pool: &MSQLPool,
+    addr: &str,
+    port: u16,
+) -> Result<String, Error> {
+    let mut hash: String;
+    let conn: Arc<Mutex<MSQLPoolConn>> = Arc::new(Mutex::new(pool.get_conn().await?));
+
+    let _unlock_cleanup = GenericCleanup::new(&conn, |conn_ref: &Arc<Mutex<MSQLPoolConn>>| {
+        let mut locked = conn_ref.lock().unwrap();
+        locked
+            .query_drop("UNLOCK TABLES")
+            .expect("Should be able to UNLOCK TABLES in cleanup");
+    });
+
+    let mut locked = conn.lock().unwrap();
+
+    locked.query_drop(if args.enable_audit_log {
+        "LOCK TABLES RUST_ID_TO_PORT_3 WRITE, RUST_AUDIT_LOG WRITE"
+    } else {
+        "LOCK TABLE RUST_ID_TO_PORT_3 WRITE"
+    })?;
+
+    let mut hasher = helpers::salted_hasher(args);
+    let mut buf = [0u8; GETRANDOM_BUF_SIZE];
+    getrandom::fill(&mut buf).map_err(Into::<Error>::into)?;
+    hasher.update(&buf);
+    hash = hasher.finalize().to_string();
+
+    let mut params = MSQLParamsWrapper::new();
+    params.append_str(&hash)?;
+
+    loop {
+        let rows_opt: Option<Vec<Vec<MSQLValueEnum>>> = locked
+            .query_with_params_rows("SELECT ID FROM RUST_ID_TO_PORT_3 WHERE ID = ?", &params)
+            .map_err(|e| e.to_owned())?;
+
+        if let Some(rows) = rows_opt {
+            let id: String = match &rows[0][0] {
+                MSQLValueEnum::String(s) => s.to_owned(),
+                _ => {
+                    return Err(Error::Generic(String::from(
+                        "Failed to fetch ID from id-to-port",
+                    )));
+                }
+            };
+
+            if id == hash {
+                hasher = helpers::salted_hasher(args);
+                getrandom::fill(&mut buf).map_err(Into::<Error>::into)?;
+                hasher.update(&buf);
+                hash = hasher.finalize().to_string();
+                continue;
+            }
+        }
+        break;
+    }
+
+    let mut params = MSQLParamsWrapper::new();
+    params.append_str(&hash)?;
+    params.append_uint64(port as u64);
+
+    locked
+        .query_with_params_drop(
+            "INSERT INTO RUST_ID_TO_PORT_3 (ID, PORT) VALUES (?, ?)",
+            &params,
+        )
+        .map_err(|e| e.to_owned())?;
+
+    if args.enable_audit_log {
+        insert_audit_log_mysql(&mut locked, addr, port, AuditEvent::Issued)?;
+    }
+
+    Ok(hash)
+}
+
+pub(crate) async fn init_id_to_port_sqlite(
+    args: &args::Args,
+    addr: &str,
+    port: u16,
+) -> Result<String, Error> {
+    let mut hash: String;
+
+    let conn = Connection::open(&args.sqlite_db_file)?;
+
+    let mut hasher = helpers::salted_hasher(args);
+    let mut buf = [0u8; GETRANDOM_BUF_SIZE];
+    getrandom::fill(&mut buf)?;
+    hasher.update(&buf);
+    hash = hasher.finalize().to_string();
+
+    while conn
+        .query_one(
+            r"SELECT PORT FROM ID_TO_PORT WHERE ID = ?1",
+            (&hash,),
+            |r| r.get::<usize, u16>(0),
+        )
+        .is_ok()
+    {
+        hasher = helpers::salted_hasher(args);
+        getrandom::fill(&mut buf)?;
+        hasher.update(&buf);
+        hash = hasher.finalize().to_string();
+    }
+
+    conn.execute(
+        r"INSERT INTO ID_TO_PORT (ID, PORT) VALUES (?1, ?2)",
+        (&hash, port),
+    )?;
+
+    if args.enable_audit_log {
+        insert_audit_log_sqlite(&conn, addr, port, AuditEvent::Issued)?;
+    }
+
+    Ok(hash)
+}
+
+async fn get_next_seq(depot: &Depot) -> Result<u64, Error> {
+    let args = &depot_args(depot)?;
+
+    #[cfg(feature = "postgres")]
+    if args.postgres_config_file.is_some() {
+        return postgres_db::get_next_seq_postgres(get_postgres_pool(depot)?).await;
+    }
+
+    #[cfg(feature = "memory")]
+    if args.memory_db {
+        return memory_db::get_next_seq_memory(get_memory_db(depot)?).await;
+    }
+
+    if args.mysql_has_priority {
+        get_next_seq_mysql(get_mysql_pool(depot)?).await
+    } else {
+        get_next_seq_sqlite(args).await
+    }
+}
+
+async fn has_challenge_factor_id(depot: &Depot, hash: &str) -> Result<bool, Error> {
+    let args = &depot_args(depot)?;
+
+    #[cfg(feature = "postgres")]
+    if args.postgres_config_file.is_some() {
+        return postgres_db::has_challenge_factor_id_postgres(get_postgres_pool(depot)?, hash)
+            .await;
+    }
+
+    #[cfg(feature = "memory")]
+    if args.memory_db {
+        return memory_db::has_challenge_factor_id_memory(get_memory_db(depot)?, hash).await;
+    }
+
+    if args.mysql_has_priority {
+        has_challenge_factor_id_mysql(get_mysql_pool(depot)?, hash).await
+    } else {
+        has_challenge_factor_id_sqlite(args, hash).await
+    }
+}
+
+async fn set_challenge_factor(
+    depot: &Depot,
+    ip: &str,
+    hash: &str,
+    port: u16,
+    factors_hash: &str,
+    value: &str,
+    user_agent: Option<&str>,
+) -> Result<(), Error> {
+    let args = &depot_args(depot)?;
+
+    #[cfg(feature = "postgres")]
+    if args.postgres_config_file.is_some() {
+        return postgres_db::set_challenge_factor_postgres(
+            get_postgres_pool(depot)?,
+            ip,
+            hash,
+            port,
+            factors_hash,
+            value,
+            user_agent,
+        )
+        .await;
+    }
+
+    #[cfg(feature = "memory")]
+    if args.memory_db {
+        return memory_db::set_challenge_factor_memory(
+            get_memory_db(depot)?,
+            ip,
+            hash,
+            port,
+            factors_hash,
+            value,
+            user_agent,
+        )
+        .await;
+    }
+
+    if args.mysql_has_priority {
+        set_challenge_factor_mysql(
+            get_mysql_pool(depot)?,
+            ip,
+            hash,
+            port,
+            factors_hash,
+            value,
+            user_agent,
+        )
+        .await
+    } else {
+        set_challenge_factor_sqlite(args, ip, hash, port, factors_hash, value, user_agent).await
+    }
+}
+
+/// Lists the ip, port, and User-Agent (if "--store-user-agent" was set) of
+/// every stored challenge, including expired ones not cleaned up yet.
+pub(crate) async fn pending_challenges(
+    depot: &Depot,
+) -> Result<Vec<(String, u16, Option<String>)>, Error> {
+    let args = &depot_args(depot)?;
+
+    #[cfg(feature = "postgres")]
+    if args.postgres_config_file.is_some() {
+        return postgres_db::pending_challenges_postgres(get_postgres_pool(depot)?).await;
+    }
+
+    #[cfg(feature = "memory")]
+    if args.memory_db {
+        return memory_db::pending_challenges_memory(get_memory_db(depot)?).await;
+    }
+
+    if args.mysql_has_priority {
+        pending_challenges_mysql(get_mysql_pool(depot)?).await
+    } else {
+        pending_challenges_sqlite(args).await
+    }
+}
+
+pub(crate) async fn challenge_port(depot: &Depot, id: &str) -> Result<u16, Error> {
+    let args = &depot_args(depot)?;
+
+    #[cfg(feature = "postgres")]
+    if args.postgres_config_file.is_some() {
+        return postgres_db::challenge_port_postgres(args, get_postgres_pool(depot)?, id).await;
+    }
+
+    #[cfg(feature = "memory")]
+    if args.memory_db {
+        return memory_db::challenge_port_memory(args, get_memory_db(depot)?, id).await;
+    }
+
+    if args.mysql_has_priority {
+        challenge_port_mysql(args, get_mysql_pool(depot)?, id).await
+    } else {
+        challenge_port_sqlite(args, id).await
+    }
+}
+
+/// Moves the expiry of challenge "id" issued to "addr" forward, no further
+/// than "--challenge-heartbeat-max" allows. Returns false if it is unknown or
+/// already expired.
+pub(crate) async fn extend_challenge(depot: &Depot, id: &str, addr: &str) -> Result<bool, Error> {
+    let args = &depot_args(depot)?;
+
+    #[cfg(feature = "postgres")]
+    if args.postgres_config_file.is_some() {
+        return postgres_db::extend_challenge_postgres(args, get_postgres_pool(depot)?, id, addr)
+            .await;
+    }
+
+    #[cfg(feature = "memory")]
+    if args.memory_db {
+        return memory_db::extend_challenge_memory(args, get_memory_db(depot)?, id, addr).await;
+    }
+
+    if args.mysql_has_priority {
+        extend_challenge_mysql(args, get_mysql_pool(depot)?, id, addr).await
+    } else {
+        extend_challenge_sqlite(args, id, addr).await
+    }
+}
+
+/// Checks "challenge_response" against its stored challenge. "factors" is
+/// what "helpers::answer_factors" returned for it.
+pub(crate) async fn validate_client(
+    depot: &Depot,
+    challenge_response: &json_types::ChallengeResponse,
+    factors: &str,
+    addr: &str,
+) -> Result<u16, Error> {
+    let args = &depot_args(depot)?;
+    let hashed_factors = hash_factors(depot, factors)?;
+
+    #[cfg(feature = "postgres")]
+    if args.postgres_config_file.is_some() {
+        return postgres_db::validate_client_postgres(
+            args,
+            get_postgres_pool(depot)?,
+            challenge_response,
+            &hashed_factors,
+            addr,
+        )
+        .await;
+    }
+
+    #[cfg(feature = "memory")]
+    if args.memory_db {
+        return memory_db::validate_client_memory(
+            args,
+            get_memory_db(depot)?,
+            challenge_response,
+            &hashed_factors,
+            addr,
+        )
+        .await;
+    }
+
+    if args.mysql_has_priority {
+        validate_client_mysql(
+            args,
+            get_mysql_pool(depot)?,
+            challenge_response,
+            &hashed_factors,
+            addr,
+        )
+        .await
+    } else {
+        validate_client_sqlite(args, challenge_response, &hashed_factors, addr).await
+    }
+}
+
+pub(crate) async fn check_is_allowed(depot: &Depot, addr: &str, port: u16) -> Result<bool, Error> {
+    let args = &depot_args(depot)?;
+
+    #[cfg(feature = "postgres")]
+    if args.postgres_config_file.is_some() {
+        return postgres_db::check_is_allowed_postgres(args, get_postgres_pool(depot)?, addr, port)
+            .await;
+    }
+
+    #[cfg(feature = "memory")]
+    if args.memory_db {
+        return memory_db::check_is_allowed_memory(args, get_memory_db(depot)?, addr, port).await;
+    }
+
+    if args.mysql_has_priority {
+        check_is_allowed_mysql(args, get_mysql_pool(depot)?, addr, port).await
+    } else {
+        check_is_allowed_sqlite(args, addr, port).await
+    }
+}
+
+pub(crate) async fn add_allowed(depot: &Depot, addr: &str, port: u16) -> Result<(), Error> {
+    let args = &depot_args(depot)?;
+
+    #[cfg(feature = "postgres")]
+    if args.postgres_config_file.is_some() {
+        return postgres_db::add_allowed_postgres(get_postgres_pool(depot)?, addr, port).await;
+    }
+
+    #[cfg(feature = "memory")]
+    if args.memory_db {
+        return memory_db::add_allowed_memory(get_memory_db(depot)?, addr, port).await;
+    }
+
+    if args.mysql_has_priority {
+        add_allowed_mysql(get_mysql_pool(depot)?, addr, port).await
+    } else {
+        add_allowed_sqlite(args, addr, port).await
+    }
+}
+
+/// Returns the (ip, port, age) of every allowance granted within "within".
+pub(crate) async fn recently_allowed(
+    depot: &Depot,
+    within: Duration,
+) -> Result<Vec<(String, u16, Duration)>, Error> {
+    let args = &depot_args(depot)?;
+
+    #[cfg(feature = "postgres")]
+    if args.postgres_config_file.is_some() {
+        return postgres_db::recently_allowed_postgres(get_postgres_pool(depot)?, within).await;
+    }
+
+    #[cfg(feature = "memory")]
+    if args.memory_db {
+        return memory_db::recently_allowed_memory(get_memory_db(depot)?, within).await;
+    }
+
+    if args.mysql_has_priority {
+        recently_allowed_mysql(get_mysql_pool(depot)?, within).await
+    } else {
+        recently_allowed_sqlite(args, within).await
+    }
+}
+
+/// Deletes allowances matching "addr" and "port" from the db. None matches
+/// any, so passing neither revokes everything.
+pub(crate) async fn revoke_allowed(
+    depot: &Depot,
+    addr: Option<&str>,
+    port: Option<u16>,
+) -> Result<(), Error> {
+    let args = &depot_args(depot)?;
+
+    #[cfg(feature = "postgres")]
+    if args.postgres_config_file.is_some() {
+        return postgres_db::revoke_allowed_postgres(get_postgres_pool(depot)?, addr, port).await;
+    }
+
+    #[cfg(feature = "memory")]
+    if args.memory_db {
+        return memory_db::revoke_allowed_memory(get_memory_db(depot)?, addr, port).await;
+    }
+
+    if args.mysql_has_priority {
+        revoke_allowed_mysql(get_mysql_pool(depot)?, addr, port).await
+    } else {
+        revoke_allowed_sqlite(args, addr, port).await
+    }
+}
+
+/// Deletes challenges, ids, and allowances older than the timeouts currently
+/// in "depot", which follow a reload. The queries made per request skip
+/// expired rows instead of deleting them, so this runs every
+/// "--cleanup-interval" to keep the tables small.
+pub(crate) async fn purge_expired(depot: &Depot) -> Result<PurgedRows, Error> {
+    let args = &depot_args(depot)?;
+
+    #[cfg(feature = "postgres")]
+    if args.postgres_config_file.is_some() {
+        return postgres_db::purge_expired_postgres(args, get_postgres_pool(depot)?).await;
+    }
+
+    #[cfg(feature = "memory")]
+    if args.memory_db {
+        return memory_db::purge_expired_memory(args, get_memory_db(depot)?).await;
+    }
+
+    if args.mysql_has_priority {
+        purge_expired_mysql(args, get_mysql_pool(depot)?).await
+    } else {
+        purge_expired_sqlite(args).await
+    }
+}
+
+/// Runs "purge_expired" every "interval" on the pools in "depot".
+pub(crate) fn spawn_cleanup(depot: Arc<Depot>, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if signal::SIGNAL_HANDLED.load(std::sync::atomic::Ordering::Relaxed) {
+                break;
+            }
+            if let Err(e) = purge_expired(&depot).await {
+                eprintln!("WARNING: Failed to delete expired rows from db: {}", e);
+            }
+        }
+    });
+}
+
+pub(crate) async fn init_id_to_port(depot: &Depot, addr: &str, port: u16) -> Result<String, Error> {
+    let args = &depot_args(depot)?;
+
+    #[cfg(feature = "postgres")]
+    if args.postgres_config_file.is_some() {
+        return postgres_db::init_id_to_port_postgres(args, get_postgres_pool(depot)?, addr, port)
+            .await;
+    }
+
+    #[cfg(feature = "memory")]
+    if args.memory_db {
+        return memory_db::init_id_to_port_memory(args, get_memory_db(depot)?, port).await;
+    }
+
+    if args.mysql_has_priority {
+        init_id_to_port_mysql(args, get_mysql_pool(depot)?, addr, port).await
+    } else {
+        init_id_to_port_sqlite(args, addr, port).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::LiveArgs;
+    use crate::test_util::test_sqlite_args;
+
+    #[tokio::test]
+    async fn test_has_challenge_factor_id_sqlite() {
+        let args = test_sqlite_args("has_challenge_factor_id");
+        init_sqlite_db(&args).await.unwrap();
+        let hash = blake3::hash(b"known").to_string();
+        set_challenge_factor_sqlite(&args, "203.0.113.9", &hash, 8180, "factors_hash", "M", None)
+            .await
+            .unwrap();
+
+        let known = has_challenge_factor_id_sqlite(&args, &hash).await.unwrap();
+        let unused = has_challenge_factor_id_sqlite(&args, &blake3::hash(b"unused").to_string())
+            .await
+            .unwrap();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+        assert!(known);
+        assert!(!unused);
+    }
+
+    #[tokio::test]
+    async fn test_purge_expired_sqlite() {
+        let args = args::Args {
+            enable_audit_log: true,
+            audit_log_retention_mins: Some(60),
+            ..test_sqlite_args("purge_expired")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        set_challenge_factor_sqlite(&args, "203.0.113.9", "old", 8180, "factors_hash", "M", None)
+            .await
+            .unwrap();
+        set_challenge_factor_sqlite(&args, "203.0.113.9", "new", 8180, "factors_hash", "M", None)
+            .await
+            .unwrap();
+        add_allowed_sqlite(&args, "203.0.113.9", 8180)
+            .await
+            .unwrap();
+        add_allowed_sqlite(&args, "203.0.113.10", 8180)
+            .await
+            .unwrap();
+        init_id_to_port_sqlite(&args, "203.0.113.9", 8180)
+            .await
+            .unwrap();
+        {
+            let conn = Connection::open(&args.sqlite_db_file).unwrap();
+            conn.execute(
+                r"UPDATE CHALLENGE_FACTOR_4 SET ON_TIME = datetime('now', '-1 day') WHERE ID = 'old'",
+                (),
+            )
+            .unwrap();
+            conn.execute(
+                r"UPDATE ALLOWED_IP SET ON_TIME = datetime('now', '-1 day') WHERE IP = '203.0.113.9'",
+                (),
+            )
+            .unwrap();
+            conn.execute(
+                r"UPDATE ID_TO_PORT SET ON_TIME = datetime('now', '-1 day')",
+                (),
+            )
+            .unwrap();
+            conn.execute(
+                r"UPDATE AUDIT_LOG SET ON_TIME = datetime('now', '-1 day') WHERE IP = '203.0.113.9'",
+                (),
+            )
+            .unwrap();
+            insert_audit_log_sqlite(&conn, "203.0.113.10", 8180, AuditEvent::Passed).unwrap();
+        }
+
+        let purged = purge_expired_sqlite(&args).await.unwrap();
+        let again = purge_expired_sqlite(&args).await.unwrap();
+        let old_allowed = check_is_allowed_sqlite(&args, "203.0.113.9", 8180)
+            .await
+            .unwrap();
+        let new_allowed = check_is_allowed_sqlite(&args, "203.0.113.10", 8180)
+            .await
+            .unwrap();
+        let new_challenge = has_challenge_factor_id_sqlite(&args, "new").await.unwrap();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+        assert_eq!(
+            purged,
+            PurgedRows {
+                challenge_factors: 1,
+                allowed_ips: 1,
+                id_to_port: 1,
+                audit_log: 1,
+            }
+        );
+        assert_eq!(again, PurgedRows::default());
+        assert!(!old_allowed);
+        assert!(new_allowed);
+        assert!(new_challenge);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_task() {
+        let args = test_sqlite_args("cleanup_task");
+        init_sqlite_db(&args).await.unwrap();
+        add_allowed_sqlite(&args, "203.0.113.42", 8180)
+            .await
+            .unwrap();
+        let conn = Connection::open(&args.sqlite_db_file).unwrap();
+        conn.execute(
+            r"UPDATE ALLOWED_IP SET ON_TIME = datetime('now', '-1 day')",
+            (),
+        )
+        .unwrap();
+        let count = || {
+            conn.query_one(r"SELECT COUNT(*) FROM ALLOWED_IP", (), |r| {
+                r.get::<usize, i64>(0)
+            })
+            .unwrap()
+        };
+
+        // Expired, but only deleted once the task runs.
+        let allowed = check_is_allowed_sqlite(&args, "203.0.113.42", 8180)
+            .await
+            .unwrap();
+        let before_cleanup = count();
+        let mut depot = Depot::new();
+        depot.insert_typed(LiveArgs::new(args.clone()));
+        spawn_cleanup(Arc::new(depot), Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let after_cleanup = count();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert!(!allowed);
+        assert_eq!(before_cleanup, 1);
+        assert_eq!(after_cleanup, 0);
+    }
+
+    #[tokio::test]
+    async fn test_validate_client_checks_product() {
+        let args = test_sqlite_args("validate_client_checks_product");
+        init_sqlite_db(&args).await.unwrap();
+        let mut depot = Depot::new();
+        depot.insert_typed(Arc::new(args.clone()));
+
+        // The stored hash matches the answer, but "N" (13) is not 2*2*3.
+        let wrong_id = store_factors_challenge(&depot, "203.0.113.7", 8180, "N", "2x2 3x1", None)
+            .await
+            .unwrap();
+        let wrong = validate_client(
+            &depot,
+            &json_types::ChallengeResponse::Factors(json_types::FactorsResponse {
+                r#type: "factors".into(),
+                id: wrong_id,
+                factors: "2x2 3x1".into(),
+            }),
+            "2x2 3x1",
+            "203.0.113.7",
+        )
+        .await;
+
+        let right_id = store_factors_challenge(&depot, "203.0.113.7", 8180, "M", "2x2 3x1", None)
+            .await
+            .unwrap();
+        let right = validate_client(
+            &depot,
+            &json_types::ChallengeResponse::Factors(json_types::FactorsResponse {
+                r#type: "factors".into(),
+                id: right_id,
+                factors: "2x2 3x1".into(),
+            }),
+            "2x2 3x1",
+            "203.0.113.7",
+        )
+        .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert!(wrong.is_err());
+        assert_eq!(right.unwrap(), 8180);
+    }
+
+    #[tokio::test]
+    async fn test_validate_client_canonicalizes_factors() {
+        let args = test_sqlite_args("validate_client_canonicalizes_factors");
+        init_sqlite_db(&args).await.unwrap();
+        let mut depot = Depot::new();
+        depot.insert_typed(Arc::new(args.clone()));
+
+        let mut results = Vec::new();
+        for factors in ["3x1 2x2", " 2x2\t 3x1 ", "2x1 3x1 2x1"] {
+            let id = store_factors_challenge(&depot, "203.0.113.9", 8180, "M", "2x2 3x1", None)
+                .await
+                .unwrap();
+            let response = json_types::ChallengeResponse::Factors(json_types::FactorsResponse {
+                r#type: "factors".into(),
+                id,
+                factors: factors.into(),
+            });
+            let factors = helpers::answer_factors(&response).unwrap();
+            results.push(validate_client(&depot, &response, &factors, "203.0.113.9").await);
+        }
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        for result in results {
+            assert_eq!(result.unwrap(), 8180);
+        }
+    }
+}
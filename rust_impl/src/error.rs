@@ -30,7 +30,13 @@ pub enum Error {
     ToStrE(reqwest::header::ToStrError),
     ReqParse(salvo::http::ParseError),
     IntParse(std::num::ParseIntError),
+    FloatParse(std::num::ParseFloatError),
     GetRand(getrandom::Error),
+    WebSocket(tokio_tungstenite::tungstenite::Error),
+    #[cfg(feature = "postgres")]
+    Postgres(tokio_postgres::Error),
+    #[cfg(feature = "postgres")]
+    PostgresPool(deadpool_postgres::PoolError),
 }
 
 impl error::Error for Error {
@@ -48,7 +54,13 @@ impl error::Error for Error {
             Error::ToStrE(error) => error.source(),
             Error::ReqParse(error) => error.source(),
             Error::IntParse(error) => error.source(),
+            Error::FloatParse(error) => error.source(),
             Error::GetRand(error) => error.source(),
+            Error::WebSocket(error) => error.source(),
+            #[cfg(feature = "postgres")]
+            Error::Postgres(error) => error.source(),
+            #[cfg(feature = "postgres")]
+            Error::PostgresPool(error) => error.source(),
         }
     }
 }
@@ -68,7 +80,13 @@ impl Display for Error {
             Error::ToStrE(error) => error.fmt(f),
             Error::ReqParse(error) => error.fmt(f),
             Error::IntParse(error) => error.fmt(f),
+            Error::FloatParse(error) => error.fmt(f),
             Error::GetRand(error) => error.fmt(f),
+            Error::WebSocket(error) => error.fmt(f),
+            #[cfg(feature = "postgres")]
+            Error::Postgres(error) => error.fmt(f),
+            #[cfg(feature = "postgres")]
+            Error::PostgresPool(error) => error.fmt(f),
         }
     }
 }
@@ -151,12 +169,38 @@ impl From<std::num::ParseIntError> for Error {
     }
 }
 
+impl From<std::num::ParseFloatError> for Error {
+    fn from(value: std::num::ParseFloatError) -> Self {
+        Error::FloatParse(value)
+    }
+}
+
 impl From<getrandom::Error> for Error {
     fn from(value: getrandom::Error) -> Self {
         Error::GetRand(value)
     }
 }
 
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(value: tokio_tungstenite::tungstenite::Error) -> Self {
+        Error::WebSocket(value)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<tokio_postgres::Error> for Error {
+    fn from(value: tokio_postgres::Error) -> Self {
+        Error::Postgres(value)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<deadpool_postgres::PoolError> for Error {
+    fn from(value: deadpool_postgres::PoolError) -> Self {
+        Error::PostgresPool(value)
+    }
+}
+
 impl From<Error> for salvo::Error {
     fn from(value: Error) -> Self {
         salvo::Error::other(value)
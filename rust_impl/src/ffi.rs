@@ -19,7 +19,11 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
-use std::ffi::{CStr, c_void};
+use std::ffi::{CStr, c_char, c_void};
+
+use num_bigint::BigUint;
+
+use crate::error::Error;
 
 include!(concat!(env!("OUT_DIR"), "/work_bindings.rs"));
 
@@ -28,37 +32,57 @@ struct WorkFactorsWrapper {
 }
 
 impl WorkFactorsWrapper {
-    pub fn new(quads: u64) -> Self {
-        Self {
-            w_factors: unsafe { work_generate_target_factors2(quads) },
+    pub fn try_new(quads: u64, min_prime: u16, max_prime: u16) -> Result<Self, Error> {
+        if quads == 0 {
+            return Err("Cannot generate factors challenge with 0 quads".into());
         }
+
+        let wf = Self {
+            w_factors: unsafe { work_generate_target_factors3(quads, min_prime, max_prime) },
+        };
+        if wf.w_factors.value2.is_null() {
+            return Err("work_generate_target_factors3 failed".into());
+        }
+
+        Ok(wf)
     }
 
-    pub fn get_value2(&self) -> String {
-        let value;
+    pub fn get_value2(&self) -> Result<String, Error> {
         unsafe {
-            let value_ffi_cstr = work_factors2_value_to_str(self.w_factors, std::ptr::null_mut());
-            value = CStr::from_ptr(value_ffi_cstr).to_str().unwrap().to_owned();
-            libc::free(value_ffi_cstr as *mut c_void);
+            owned_c_str(work_factors2_value_to_str(
+                self.w_factors,
+                std::ptr::null_mut(),
+            ))
         }
-        value
     }
 
-    pub fn get_factors2(&self) -> String {
-        let factors;
+    pub fn get_factors2(&self) -> Result<String, Error> {
         unsafe {
-            let factors_ffi_cstr =
-                work_factors2_factors_to_str(self.w_factors, std::ptr::null_mut());
-            factors = CStr::from_ptr(factors_ffi_cstr)
-                .to_str()
-                .unwrap()
-                .to_owned();
-            libc::free(factors_ffi_cstr as *mut c_void);
+            owned_c_str(work_factors2_factors_to_str(
+                self.w_factors,
+                std::ptr::null_mut(),
+            ))
         }
-        factors
     }
 }
 
+/// Copies and frees a C-string returned by the work functions.
+unsafe fn owned_c_str(c_str: *mut c_char) -> Result<String, Error> {
+    if c_str.is_null() {
+        return Err("Factors challenge string was NULL".into());
+    }
+
+    let ret = unsafe { CStr::from_ptr(c_str) }
+        .to_str()
+        .map(|s| s.to_owned())
+        .map_err(|_| Error::Generic("Factors challenge string was not UTF-8".into()));
+    unsafe {
+        libc::free(c_str as *mut c_void);
+    }
+
+    ret
+}
+
 impl Drop for WorkFactorsWrapper {
     fn drop(&mut self) {
         unsafe {
@@ -67,7 +91,118 @@ impl Drop for WorkFactorsWrapper {
     }
 }
 
-pub fn generate_value_and_factors_strings2(digits: u64) -> (String, String) {
-    let wf = WorkFactorsWrapper::new(digits);
-    (wf.get_value2(), wf.get_factors2())
+/// A factors challenge value as the C side writes it: base 64 with
+/// constants::VALUE_ALPHABET, least significant digit first. The string is
+/// what's stored and sent to clients, to_biguint is the number it encodes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChallengeValue(String);
+
+impl ChallengeValue {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+
+    pub fn to_biguint(&self) -> Result<BigUint, Error> {
+        decode_challenge_value(&self.0)
+    }
+}
+
+/// Reads back the number of an encoded challenge value, e.g. one stored in
+/// the db.
+pub fn decode_challenge_value(value: &str) -> Result<BigUint, Error> {
+    let digits: Vec<u8> = value
+        .bytes()
+        .map(|c| match c {
+            b'A'..=b'Z' => Ok(c - b'A'),
+            b'a'..=b'z' => Ok(c - b'a' + 26),
+            b'0'..=b'9' => Ok(c - b'0' + 52),
+            b'+' => Ok(62),
+            b'/' => Ok(63),
+            _ => Err(Error::Generic("Invalid digit in challenge value".into())),
+        })
+        .collect::<Result<_, _>>()?;
+    BigUint::from_radix_le(&digits, 64)
+        .ok_or(Error::Generic("Failed to parse challenge value".into()))
+}
+
+/// Checks that every prime in a "2x5 3x9 ..." factors string is within
+/// [min_prime, max_prime].
+fn check_prime_factor_bounds(factors: &str, min_prime: u16, max_prime: u16) -> Result<(), Error> {
+    for factor in factors.split_whitespace() {
+        let prime: u64 = factor
+            .split_once('x')
+            .ok_or(Error::Generic(format!("Invalid factor \"{}\"", factor)))?
+            .0
+            .parse()?;
+        if prime < min_prime as u64 || prime > max_prime as u64 {
+            return Err(format!(
+                "Generated prime factor {} is outside of [{}, {}]",
+                prime, min_prime, max_prime
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the challenge value and its "2x5 3x9 ..." prime factors. The value
+/// is base64 with the least significant digit first, which is what the factors
+/// worker's "revb64_long_div_mod" divides and "helpers::factors_multiply_to"
+/// reads back.
+pub fn generate_value_and_factors_strings3(
+    quads: u64,
+    min_prime: u16,
+    max_prime: u16,
+) -> Result<(ChallengeValue, String), Error> {
+    let wf = WorkFactorsWrapper::try_new(quads, min_prime, max_prime)?;
+    let factors = wf.get_factors2()?;
+    check_prime_factor_bounds(&factors, min_prime, max_prime)?;
+    Ok((ChallengeValue(wf.get_value2()?), factors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_prime_factor() {
+        let (_value, factors) = generate_value_and_factors_strings3(2, 2, 7).unwrap();
+        assert!(!factors.is_empty());
+        check_prime_factor_bounds(&factors, 2, 7).unwrap();
+        for factor in factors.split_whitespace() {
+            let prime: u16 = factor.split_once('x').unwrap().0.parse().unwrap();
+            assert!(prime <= 7);
+        }
+
+        assert!(check_prime_factor_bounds("2x3 5x1", 2, 5).is_ok());
+        assert!(check_prime_factor_bounds("2x3 61x1", 2, 59).is_err());
+        assert!(check_prime_factor_bounds("2x3 5x1", 3, 59).is_err());
+    }
+
+    #[test]
+    fn test_value_round_trip() {
+        let (value, factors) = generate_value_and_factors_strings3(4, 2, 59).unwrap();
+        assert!(!value.as_str().is_empty());
+        assert!(crate::helpers::factors_multiply_to(value.as_str(), &factors).unwrap());
+        assert_eq!(
+            crate::helpers::canonicalize_factors(&factors).unwrap(),
+            factors
+        );
+
+        let mut product = BigUint::from(1u32);
+        for term in factors.split_whitespace() {
+            let (prime, count) = term.split_once('x').unwrap();
+            product *= BigUint::from(prime.parse::<u32>().unwrap()).pow(count.parse().unwrap());
+        }
+        assert_eq!(value.to_biguint().unwrap(), product);
+
+        assert_eq!(decode_challenge_value("BA").unwrap(), BigUint::from(1u32));
+        assert_eq!(decode_challenge_value("AB").unwrap(), BigUint::from(64u32));
+        assert!(decode_challenge_value("A-").is_err());
+    }
 }
@@ -0,0 +1,4007 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::time::{Duration, Instant};
+
+use reqwest::Client;
+use salvo::http::Method;
+use salvo::prelude::*;
+
+use crate::db::{
+    add_allowed, challenge_port, check_is_allowed, extend_challenge, heartbeat_ms, init_id_to_port,
+    store_factors_challenge, validate_client,
+};
+use crate::logging::{Logger, RequestEvent};
+#[cfg(feature = "policy-script")]
+use crate::policy;
+use crate::reputation::ReputationTier;
+use crate::state::{
+    CACHED_TIMEOUT, CachedAllow, ChallengeRateLimit, ClientWrapper, ConcurrencyLimit, DbFailOpens,
+    DestRoundRobin, MonitorOnlyChallenges, UpstreamGauge, ValidationLimit, depot_args,
+};
+use crate::websocket;
+use crate::{
+    args, constants, error::Error, ffi, helpers, json_types, reputation, salvo_compat, token,
+    traffic, webhook,
+};
+
+async fn req_to_url(
+    req: &mut Request,
+    url: String,
+    real_ip: Option<&str>,
+    body: Option<Vec<u8>>,
+    method: &str,
+    client: &Client,
+    args: &args::Args,
+) -> Result<reqwest::Response, Error> {
+    let method = reqwest::Method::from_bytes(method.as_bytes())
+        .map_err(|_| Error::Generic(format!("Invalid HTML method {}!", method)))?;
+    // Only idempotent requests are safe to send again.
+    let retries = if method == reqwest::Method::GET || method == reqwest::Method::HEAD {
+        args.backend_retries
+    } else {
+        0
+    };
+    let req_builder = client.request(method, url);
+
+    let req_builder = if let Some(ip) = real_ip {
+        req_builder.header("x-real-ip", ip)
+    } else {
+        req_builder
+    };
+
+    let mut req_builder = if let Some(body) = body {
+        //eprintln!("Body of size {}", body.len());
+        req_builder.body(body)
+    } else {
+        req_builder
+    };
+
+    let client_ip: String = if let Some(ip) = real_ip {
+        ip.to_owned()
+    } else {
+        req.remote_addr()
+            .ip()
+            .ok_or(Error::from("Failed to get connected-client addr!"))?
+            .to_string()
+    };
+    req_builder = req_builder.header(
+        "x-forwarded-for",
+        helpers::forwarded_for(req.headers(), &client_ip, args.trust_forwarded_for)?,
+    );
+    let incoming_proto: Option<&str> = if args.trust_forwarded_for {
+        req.header("x-forwarded-proto")
+    } else {
+        None
+    };
+    req_builder = req_builder.header(
+        "x-forwarded-proto",
+        incoming_proto.unwrap_or(if req.scheme() == &salvo::http::uri::Scheme::HTTPS {
+            "https"
+        } else {
+            "http"
+        }),
+    );
+
+    for (k, v) in req.headers().iter() {
+        if helpers::is_forwarded_request_header(req.headers(), k.as_str()) {
+            //eprintln!("Header {:?} -> {:?}", k, v);
+            req_builder = req_builder.header(k, v);
+        }
+    }
+
+    let mut attempt: u32 = 0;
+    loop {
+        let Some(attempt_builder) = req_builder.try_clone() else {
+            return Ok(req_builder.send().await?);
+        };
+        match attempt_builder.send().await {
+            Err(e) if attempt < retries && (e.is_connect() || e.is_timeout()) => {
+                tokio::time::sleep(helpers::backend_retry_delay(attempt)).await;
+                attempt += 1;
+            }
+            resp => return Ok(resp?),
+        }
+    }
+}
+
+pub struct ClientIPAddrRet {
+    pub addr: String,
+    pub remote_port: Option<u16>,
+    pub local_port: Option<u16>,
+}
+
+/// Returns true if the connecting peer may set the real ip header, which is
+/// any peer unless "--trusted-proxy" ranges are given.
+fn is_trusted_proxy(args: &args::Args, req: &Request) -> bool {
+    args.trusted_proxy_cidrs.is_empty()
+        || req
+            .remote_addr()
+            .clone()
+            .into_std()
+            .is_some_and(|remote_addr| {
+                let ip = remote_addr.ip().to_canonical();
+                args.trusted_proxy_cidrs
+                    .iter()
+                    .any(|range| range.contains(&ip))
+            })
+}
+
+async fn get_client_ip_addr(depot: &Depot, req: &mut Request) -> Result<ClientIPAddrRet, Error> {
+    let args = &depot_args(depot)?;
+    let addr_string: String;
+    let local_port: Option<u16>;
+    let remote_port: Option<u16>;
+
+    let real_ip_header = if args.enable_x_real_ip_header && is_trusted_proxy(args, req) {
+        helpers::real_ip_from_headers(req.headers(), &args.real_ip_headers)?
+    } else {
+        None
+    };
+
+    if let Some(real_ip_h) = real_ip_header {
+        addr_string = helpers::canonical_ip(real_ip_h)?;
+
+        if let Some(ipv4) = req.local_addr().as_ipv4() {
+            local_port = Some(ipv4.port());
+        } else if let Some(ipv6) = req.local_addr().as_ipv6() {
+            local_port = Some(ipv6.port());
+        } else {
+            local_port = None;
+        }
+
+        if let Some(ipv4) = req.remote_addr().as_ipv4() {
+            remote_port = Some(ipv4.port());
+        } else if let Some(ipv6) = req.remote_addr().as_ipv6() {
+            remote_port = Some(ipv6.port());
+        } else {
+            remote_port = None;
+        }
+    } else {
+        //eprintln!("GET from ip {}", req.remote_addr());
+        if let Some(ipv4) = req.local_addr().as_ipv4() {
+            local_port = Some(ipv4.port());
+        } else if let Some(ipv6) = req.local_addr().as_ipv6() {
+            local_port = Some(ipv6.port());
+        } else {
+            local_port = None;
+        }
+
+        if let Some(ipv4) = req.remote_addr().as_ipv4() {
+            //eprintln!(" ipv4: {}", ipv4.ip());
+            addr_string = format!("{}", ipv4.ip());
+            remote_port = Some(ipv4.port());
+        } else if let Some(ipv6) = req.remote_addr().as_ipv6() {
+            //eprintln!(" ipv6: {}", ipv6.ip());
+            // A dual-stack listener sees IPv4 clients as IPv4-mapped IPv6.
+            addr_string = format!("{}", ipv6.ip().to_canonical());
+            remote_port = Some(ipv6.port());
+        } else {
+            return Err("Failed to get client addr".into());
+        }
+    }
+
+    Ok(ClientIPAddrRet {
+        addr: addr_string,
+        remote_port,
+        local_port,
+    })
+}
+
+#[handler]
+pub async fn factors_js_fn(
+    depot: &mut Depot,
+    req: &mut Request,
+    res: &mut Response,
+) -> salvo::Result<()> {
+    let args = &depot_args(depot)?;
+    let client_info_ret = get_client_ip_addr(depot, req).await?;
+    let id: String = req.query("id").ok_or(crate::Error::Generic(
+        "No id passed to factors_js url!".to_owned(),
+    ))?;
+
+    let logger = Logger::new(args);
+    let port: Result<u16, Error> = challenge_port(depot, &id).await;
+    if port.is_err() {
+        logger.log(
+            RequestEvent::ChallengeLookupFailed,
+            &client_info_ret.addr,
+            client_info_ret.remote_port,
+            client_info_ret.local_port.unwrap_or(0),
+            None,
+            format_args!(
+                "Failed to query id-to-port for client {}:{} to {}!",
+                client_info_ret.addr,
+                client_info_ret.remote_port.unwrap_or(0),
+                client_info_ret.local_port.unwrap_or(0)
+            ),
+        );
+    }
+    let port: u16 = port?;
+
+    logger.log(
+        RequestEvent::ChallengeRequested,
+        &client_info_ret.addr,
+        client_info_ret.remote_port,
+        port,
+        None,
+        format_args!(
+            "Requested challenge from {}:{} -> {}",
+            client_info_ret.addr,
+            client_info_ret.remote_port.unwrap_or(0),
+            port
+        ),
+    );
+
+    // Lossy so a User-Agent that isn't valid UTF-8 is still recorded.
+    let user_agent: Option<String> = if args.store_user_agent {
+        req.headers()
+            .get("user-agent")
+            .map(|user_agent| String::from_utf8_lossy(user_agent.as_bytes()).into_owned())
+    } else {
+        None
+    };
+
+    if args.challenge_type == args::ChallengeType::Hashcash {
+        let bits = client_reputation(depot, &client_info_ret.addr)?
+            .and_then(|tier| tier.hashcash_bits(args.hashcash_bits))
+            .unwrap_or(args.hashcash_bits);
+        let mut buf = [0u8; 16];
+        getrandom::fill(&mut buf).map_err(Error::from)?;
+        let prefix: String = buf.iter().map(|b| format!("{:02x}", b)).collect();
+
+        let uuid = store_factors_challenge(
+            depot,
+            &client_info_ret.addr,
+            port,
+            &format!("{}:{}", bits, prefix),
+            constants::HASHCASH_FACTORS_TAG,
+            user_agent.as_deref(),
+        )
+        .await?;
+
+        let js = constants::JAVASCRIPT_HASHCASH_WORKER
+            .replacen("{API_URL}", api_url_for(args, port), 1)
+            .replacen("{PREFIX}", &prefix, 1)
+            .replacen("{BITS}", &bits.to_string(), 1)
+            .replacen("{UUID}", &uuid, 1)
+            .replacen("{JITTER_MS}", &args.worker_jitter_ms.to_string(), 1);
+        res.add_header("content-type", "text/javascript", true)?
+            .write_body(js)?;
+
+        return Ok(());
+    }
+
+    let quads = challenge_quads(depot, req, &client_info_ret.addr)?;
+
+    let (value, factors) = match ffi::generate_value_and_factors_strings3(
+        quads,
+        args.min_prime_factor
+            .unwrap_or(constants::DEFAULT_MIN_PRIME_FACTOR),
+        args.max_prime_factor
+            .unwrap_or(constants::DEFAULT_MAX_PRIME_FACTOR),
+    ) {
+        Ok(generated) => generated,
+        Err(e) => {
+            logger.log(
+                RequestEvent::GenerationFailed,
+                &client_info_ret.addr,
+                client_info_ret.remote_port,
+                port,
+                None,
+                format_args!(
+                    "Failed to generate challenge for {}:{} -> {} ({:?}): {}",
+                    client_info_ret.addr,
+                    client_info_ret.remote_port.unwrap_or(0),
+                    port,
+                    args.on_generation_failure,
+                    e
+                ),
+            );
+            match args.on_generation_failure {
+                args::GenerationFailurePolicy::FailOpen => {
+                    add_allowed(depot, &client_info_ret.addr, port).await?;
+                    res.add_header("content-type", "text/javascript", true)?
+                        .write_body(constants::JAVASCRIPT_FAIL_OPEN_WORKER)?;
+                }
+                args::GenerationFailurePolicy::FailClosed => {
+                    res.body("Challenge unavailable")
+                        .add_header("content-type", "text/plain", true)?
+                        .status_code(StatusCode::SERVICE_UNAVAILABLE);
+                }
+            }
+            return Ok(());
+        }
+    };
+    let uuid = store_factors_challenge(
+        depot,
+        &client_info_ret.addr,
+        port,
+        value.as_str(),
+        &factors,
+        user_agent.as_deref(),
+    )
+    .await?;
+
+    if args.json_challenge && helpers::accepts_json(req.headers()) {
+        res.render(Json(json_types::FactorsChallenge {
+            r#type: "factors".into(),
+            id: uuid,
+            value: value.into_string(),
+            encoding: json_types::ValueEncoding {
+                name: constants::VALUE_ENCODING.into(),
+                radix: 64,
+                alphabet: constants::VALUE_ALPHABET.into(),
+                least_significant_first: true,
+            },
+            api_url: api_url_for(args, port).to_owned(),
+        }));
+        return Ok(());
+    }
+
+    let js = args
+        .challenge_js
+        .as_deref()
+        .unwrap_or(constants::JAVASCRIPT_FACTORS_WORKER);
+    let js = js
+        .replacen("{API_URL}", api_url_for(args, port), 1)
+        .replacen("{LARGE_NUMBER}", value.as_str(), 1)
+        .replace("{UUID}", &uuid)
+        .replacen("{JITTER_MS}", &args.worker_jitter_ms.to_string(), 1)
+        .replacen("{HEARTBEAT_URL}", &args.heartbeat_url, 1)
+        .replacen("{HEARTBEAT_MS}", &heartbeat_ms(args).to_string(), 1);
+    res.add_header("content-type", "text/javascript", true)?
+        .write_body(js)?;
+
+    Ok(())
+}
+
+#[handler]
+pub async fn heartbeat_fn(
+    depot: &Depot,
+    req: &mut Request,
+    res: &mut Response,
+) -> salvo::Result<()> {
+    let client_info_ret = get_client_ip_addr(depot, req).await?;
+    let heartbeat: json_types::ChallengeHeartbeat = req
+        .parse_json_with_max_size(constants::HEARTBEAT_JSON_MAX_SIZE)
+        .await
+        .map_err(Error::from)?;
+
+    if extend_challenge(depot, &heartbeat.id, &client_info_ret.addr).await? {
+        res.status_code(StatusCode::NO_CONTENT);
+    } else {
+        res.body("Expired")
+            .add_header("content-type", "text/plain", true)?
+            .status_code(StatusCode::NOT_FOUND);
+    }
+
+    Ok(())
+}
+
+#[handler]
+pub async fn api_fn(depot: &Depot, req: &mut Request, res: &mut Response) -> salvo::Result<()> {
+    let start = Instant::now();
+    let client_info_ret = get_client_ip_addr(depot, req).await?;
+    //eprintln!("API: {}", &addr_string);
+    let max_json_size = depot_args(depot)?.max_json_size;
+    let challenge_response: json_types::ChallengeResponse =
+        match req.parse_json_with_max_size(max_json_size).await {
+            Ok(challenge_response) => challenge_response,
+            Err(salvo::http::ParseError::PayloadTooLarge) => {
+                res.render(Json(json_types::ApiResult::error(format!(
+                    "Challenge answer is over {} bytes",
+                    max_json_size
+                ))));
+                res.status_code(StatusCode::PAYLOAD_TOO_LARGE);
+                return Ok(());
+            }
+            Err(e) => return Err(Error::from(e).into()),
+        };
+
+    let factors = helpers::answer_factors(&challenge_response)?;
+
+    // Held until the answer is checked.
+    let _validation_permit = match depot.get_typed::<ValidationLimit>() {
+        Ok(validation_limit) => match validation_limit.try_enter() {
+            Some(permit) => Some(permit),
+            None => {
+                Logger::new(depot_args(depot)?.as_ref()).log(
+                    RequestEvent::ValidationThrottled,
+                    &client_info_ret.addr,
+                    client_info_ret.remote_port,
+                    client_info_ret.local_port.unwrap_or(0),
+                    Some(StatusCode::SERVICE_UNAVAILABLE),
+                    format_args!(
+                        "Too many concurrent validations, turned away {}:{} -> {}",
+                        client_info_ret.addr,
+                        client_info_ret.remote_port.unwrap_or(0),
+                        client_info_ret.local_port.unwrap_or(0)
+                    ),
+                );
+                res.render(Json(json_types::ApiResult::error(
+                    "Too many validations, try again",
+                )));
+                res.add_header("retry-after", constants::VALIDATION_RETRY_AFTER_SECS, true)?
+                    .status_code(StatusCode::SERVICE_UNAVAILABLE);
+                return Ok(());
+            }
+        },
+        Err(_) => None,
+    };
+
+    let validate_result: Result<u16, Error> =
+        validate_client(depot, &challenge_response, &factors, &client_info_ret.addr).await;
+
+    let logger = Logger::new(depot_args(depot)?.as_ref());
+    if let Ok(port) = validate_result {
+        logger.log(
+            RequestEvent::ChallengeAccepted,
+            &client_info_ret.addr,
+            client_info_ret.remote_port,
+            port,
+            Some(StatusCode::OK),
+            format_args!(
+                "Challenge response accepted from {}:{} -> {}",
+                client_info_ret.addr,
+                client_info_ret.remote_port.unwrap_or(0),
+                port
+            ),
+        );
+        if let Ok(solved_webhook) = depot.get_typed::<webhook::SolvedWebhook>() {
+            solved_webhook.notify(json_types::SolvedEvent {
+                ip: client_info_ret.addr.clone(),
+                port,
+                solved_at: time::OffsetDateTime::now_utc().unix_timestamp(),
+                validate_ms: start.elapsed().as_millis() as u64,
+            });
+        }
+        res.render(Json(json_types::ApiResult::ok()));
+        res.status_code(StatusCode::OK);
+    } else {
+        logger.log(
+            RequestEvent::ChallengeDenied,
+            &client_info_ret.addr,
+            client_info_ret.remote_port,
+            client_info_ret.local_port.unwrap_or(0),
+            Some(StatusCode::BAD_REQUEST),
+            format_args!(
+                "Challenge response DENIED from {}:{} -> {}",
+                client_info_ret.addr,
+                client_info_ret.remote_port.unwrap_or(0),
+                client_info_ret.local_port.unwrap_or(0)
+            ),
+        );
+        res.render(Json(json_types::ApiResult::error("Incorrect")));
+        res.status_code(StatusCode::BAD_REQUEST);
+    }
+
+    Ok(())
+}
+
+/// Returns the "--reputation-file" tier of "addr", if any.
+fn client_reputation(depot: &Depot, addr: &str) -> Result<Option<ReputationTier>, Error> {
+    match depot.get_typed::<reputation::ReputationList>() {
+        Ok(reputation_list) => reputation_list.tier(addr),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Counts the signs that "addr" is a bot: no User-Agent, an address in a
+/// "--datacenter-cidr", and being near a "bad" "--reputation-file" entry.
+fn bot_signals(depot: &Depot, req: &Request, addr: &str) -> Result<u64, Error> {
+    let args = &depot_args(depot)?;
+    let mut signals = 0;
+
+    if req
+        .headers()
+        .get("user-agent")
+        .is_none_or(|user_agent| user_agent.is_empty())
+    {
+        signals += 1;
+    }
+    if helpers::is_in_ranges(&args.datacenter_cidrs, addr) {
+        signals += 1;
+    }
+    if let Ok(reputation_list) = depot.get_typed::<reputation::ReputationList>()
+        && reputation_list.is_near_bad(addr)?
+    {
+        signals += 1;
+    }
+
+    Ok(signals)
+}
+
+/// The quads of the factors challenge for "addr", picked from the
+/// "--factors-distribution" if given, then raised by its
+/// "--reputation-file" tier and, with "--factors-max", its bot signals.
+fn challenge_quads(depot: &Depot, req: &Request, addr: &str) -> Result<u64, Error> {
+    let args = &depot_args(depot)?;
+    let quads = if args.factors_distribution.is_empty() {
+        args.factors.unwrap_or(constants::DEFAULT_FACTORS_QUADS)
+    } else {
+        helpers::pick_factors_quads(&args.factors_distribution)?
+    };
+    let quads = client_reputation(depot, addr)?
+        .and_then(|tier| tier.factors_quads(quads))
+        .unwrap_or(quads);
+
+    match args.factors_max {
+        Some(factors_max) => Ok(helpers::factors_quads_for_signals(
+            quads,
+            bot_signals(depot, req, addr)?,
+            factors_max,
+        )),
+        None => Ok(quads),
+    }
+}
+
+async fn is_client_allowed(depot: &Depot, addr: &str, port: u16) -> Result<bool, Error> {
+    let args = &depot_args(depot)?;
+    if helpers::is_in_ranges(&args.bypass_cidrs, addr) {
+        return Ok(true);
+    }
+    if client_reputation(depot, addr)? == Some(ReputationTier::Good) {
+        return Ok(true);
+    }
+
+    let cached_allow: &CachedAllow = depot.get_typed::<CachedAllow>().unwrap();
+    cached_allow.check_cleanup()?;
+
+    let mut is_allowed: bool = cached_allow.get_allowed(addr, port, CACHED_TIMEOUT)?;
+    if !is_allowed {
+        is_allowed = check_is_allowed(depot, addr, port).await?;
+        if is_allowed {
+            cached_allow.add_allowed(addr, port)?;
+        }
+    }
+
+    Ok(is_allowed)
+}
+
+/// The "--api-url" clients on listening "port" answer challenges at, which
+/// "--port-to-api-url" can override.
+fn api_url_for(args: &args::Args, port: u16) -> &str {
+    args.port_to_api_urls.get(&port).unwrap_or(&args.api_url)
+}
+
+/// The "--js-factors-url" clients on listening "port" fetch the worker from,
+/// which "--port-to-js-factors-url" can override.
+fn js_factors_url_for(args: &args::Args, port: u16) -> &str {
+    args.port_to_js_factors_urls
+        .get(&port)
+        .unwrap_or(&args.js_factors_url)
+}
+
+/// The "--challenge-status" the challenge page is served with.
+pub(crate) fn challenge_status(args: &args::Args) -> StatusCode {
+    args.challenge_status
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(StatusCode::OK)
+}
+
+/// The dest url to forward "req" to: the "--host-to-dest-url" mapping for its
+/// normalized Host, else the next of the "--port-to-dest-url" mappings for
+/// "port", else "--dest-url".
+fn dest_url_for<'a>(
+    args: &'a args::Args,
+    depot: &Depot,
+    req: &Request,
+    port: u16,
+) -> Option<&'a String> {
+    let host_dest_url = if args.host_to_dest_urls.is_empty() {
+        None
+    } else {
+        req.headers()
+            .get("host")
+            .and_then(|host| host.to_str().ok())
+            .or(req.uri().host())
+            .and_then(helpers::normalize_host)
+            .and_then(|host| args.host_to_dest_urls.get(&host))
+    };
+
+    host_dest_url
+        .or_else(|| {
+            let dest_urls = args.port_to_dest_urls.get(&port)?;
+            match depot.get_typed::<DestRoundRobin>() {
+                Ok(round_robin) => round_robin.pick(port, dest_urls),
+                Err(_) => dest_urls.first(),
+            }
+        })
+        .or(args.dest_url.as_ref())
+}
+
+/// Returns true if "addr" is in a "--require-client-cert-for" range and its
+/// connection presented a certificate that verified against "--tls-client-ca".
+pub(crate) fn has_client_cert(depot: &Depot, req: &Request, addr: &str) -> bool {
+    let Ok(args) = &depot_args(depot) else {
+        return false;
+    };
+    let Ok(peers) = depot.get_typed::<salvo_compat::ClientCertPeers>() else {
+        return false;
+    };
+    helpers::is_in_ranges(&args.client_cert_cidrs, addr)
+        && req
+            .remote_addr()
+            .clone()
+            .into_std()
+            .is_some_and(|remote_addr| peers.contains(&remote_addr))
+}
+
+/// Returns true if "--trust-loopback" is on and "addr", the connecting peer
+/// and the listener are all loopback. A loopback "addr" from a real ip header
+/// alone is not enough, since anyone reaching a public listener can send it.
+fn is_trusted_loopback(args: &args::Args, req: &Request, addr: &str) -> bool {
+    let is_loopback = |socket_addr: &salvo::conn::SocketAddr| {
+        socket_addr
+            .clone()
+            .into_std()
+            .is_some_and(|socket_addr| socket_addr.ip().to_canonical().is_loopback())
+    };
+
+    args.trust_loopback
+        && is_loopback(req.local_addr())
+        && is_loopback(req.remote_addr())
+        && addr
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Returns the current time as seconds since the unix epoch.
+pub fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Returns true if the request has an "x-pma-token" header holding a token
+/// minted with the "--exemption-token-key" that is still valid for "addr".
+fn has_exemption_token(depot: &Depot, req: &Request, addr: &str) -> bool {
+    let Ok(tokens) = depot.get_typed::<token::ExemptionTokens>() else {
+        return false;
+    };
+    req.headers()
+        .get("x-pma-token")
+        .and_then(|token| token.to_str().ok())
+        .is_some_and(|token| tokens.verify(token, addr, unix_now()))
+}
+
+/// Logs a db error hit while checking "addr", and returns whether
+/// "--db-fail-open" lets the client through anyway.
+fn db_error_fails_open(depot: &Depot, addr: &str, port: u16, e: &Error) -> bool {
+    let Ok(args) = &depot_args(depot) else {
+        return false;
+    };
+    Logger::new(args).log(
+        RequestEvent::DbError,
+        addr,
+        None,
+        port,
+        (!args.db_fail_open).then_some(StatusCode::SERVICE_UNAVAILABLE),
+        format_args!(
+            "Db error for {} -> {}, {}: {}",
+            addr,
+            port,
+            if args.db_fail_open {
+                "letting through (--db-fail-open)"
+            } else {
+                "replying 503"
+            },
+            e
+        ),
+    );
+    if args.db_fail_open
+        && let Ok(db_fail_opens) = depot.get_typed::<DbFailOpens>()
+    {
+        db_fail_opens.record();
+    }
+    args.db_fail_open
+}
+
+/// Logs and counts a client that isn't allowed, and returns whether
+/// "--monitor-only" lets it through anyway.
+fn monitor_only_allows(depot: &Depot, addr: &str, remote_port: Option<u16>, port: u16) -> bool {
+    let Ok(args) = &depot_args(depot) else {
+        return false;
+    };
+    if !args.monitor_only {
+        return false;
+    }
+
+    Logger::new(args).log(
+        RequestEvent::WouldChallenge,
+        addr,
+        remote_port,
+        port,
+        None,
+        format_args!(
+            "Would have challenged {}:{} -> {} (--monitor-only)",
+            addr,
+            remote_port.unwrap_or(0),
+            port
+        ),
+    );
+    if let Ok(monitor_only_challenges) = depot.get_typed::<MonitorOnlyChallenges>() {
+        monitor_only_challenges.record();
+    }
+    true
+}
+
+/// Runs the "--policy-script" on the request, if one is loaded. A script that
+/// fails is logged and leaves the client to the usual checks.
+#[cfg(feature = "policy-script")]
+fn policy_decision(
+    depot: &Depot,
+    req: &Request,
+    addr: &str,
+    remote_port: Option<u16>,
+    port: u16,
+) -> policy::PolicyDecision {
+    let Ok(policy_script) = depot.get_typed::<policy::PolicyScript>() else {
+        return policy::PolicyDecision::Challenge;
+    };
+
+    let input = policy::PolicyInput {
+        ip: addr,
+        port,
+        method: req.method().as_str(),
+        path: req.uri().path(),
+        headers: req.headers(),
+    };
+    match policy_script.decide(&input) {
+        Ok(decision) => decision,
+        Err(e) => {
+            if let Ok(args) = depot_args(depot) {
+                Logger::new(&args).log(
+                    RequestEvent::PolicyFailed,
+                    addr,
+                    remote_port,
+                    port,
+                    None,
+                    format_args!(
+                        "Policy script failed for {}:{} -> {}: {}",
+                        addr,
+                        remote_port.unwrap_or(0),
+                        port,
+                        e
+                    ),
+                );
+            }
+            policy::PolicyDecision::Challenge
+        }
+    }
+}
+
+/// Decides what handler_fn does with "addr" before any challenge is issued:
+/// the "--policy-script", then the checks that let a client skip the
+/// challenge, then "--db-fail-open" and "--monitor-only". Doesn't write to the
+/// db, so "--replay-traffic" can run it too.
+pub(crate) async fn client_decision(
+    depot: &Depot,
+    req: &Request,
+    addr: &str,
+    remote_port: Option<u16>,
+    port: u16,
+) -> json_types::ClientDecision {
+    let Ok(args) = &depot_args(depot) else {
+        return json_types::ClientDecision::Unavailable;
+    };
+
+    #[cfg(feature = "policy-script")]
+    let policy_allows = match policy_decision(depot, req, addr, remote_port, port) {
+        policy::PolicyDecision::Allow => true,
+        policy::PolicyDecision::Deny => {
+            Logger::new(args).log(
+                RequestEvent::PolicyDenied,
+                addr,
+                remote_port,
+                port,
+                Some(StatusCode::FORBIDDEN),
+                format_args!(
+                    "Policy script denied {}:{} -> {} {}",
+                    addr,
+                    remote_port.unwrap_or(0),
+                    port,
+                    req.uri().path()
+                ),
+            );
+            return json_types::ClientDecision::Deny;
+        }
+        policy::PolicyDecision::Challenge => false,
+    };
+    #[cfg(not(feature = "policy-script"))]
+    let policy_allows = false;
+
+    let is_allowed = if policy_allows
+        || is_trusted_loopback(args, req, addr)
+        || has_client_cert(depot, req, addr)
+        || has_exemption_token(depot, req, addr)
+    {
+        Ok(true)
+    } else {
+        is_client_allowed(depot, addr, port).await
+    };
+    match is_allowed {
+        Ok(true) => json_types::ClientDecision::Allow,
+        Ok(false) if monitor_only_allows(depot, addr, remote_port, port) => {
+            json_types::ClientDecision::Allow
+        }
+        Ok(false) => json_types::ClientDecision::Challenge,
+        Err(e) if db_error_fails_open(depot, addr, port, &e) => json_types::ClientDecision::Allow,
+        Err(_) => json_types::ClientDecision::Unavailable,
+    }
+}
+
+#[handler]
+pub async fn auth_request_fn(
+    depot: &Depot,
+    req: &mut Request,
+    res: &mut Response,
+) -> salvo::Result<()> {
+    let client_info_ret = get_client_ip_addr(depot, req).await?;
+
+    // Unix socket listeners have no port, and 0 matches no "--port-to-*"
+    // mapping so they get "--dest-url" and the other defaults.
+    let port: u16 = client_info_ret.local_port.unwrap_or(0);
+
+    match client_decision(
+        depot,
+        req,
+        &client_info_ret.addr,
+        client_info_ret.remote_port,
+        port,
+    )
+    .await
+    {
+        json_types::ClientDecision::Allow => {
+            res.status_code(StatusCode::OK);
+        }
+        json_types::ClientDecision::Deny => {
+            res.status_code(StatusCode::FORBIDDEN);
+        }
+        json_types::ClientDecision::Unavailable => {
+            res.status_code(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        json_types::ClientDecision::Challenge => {
+            let original_uri: &str = req
+                .header("x-original-uri")
+                .or(req.header("x-forwarded-uri"))
+                .unwrap_or("");
+            Logger::new(depot_args(depot)?.as_ref()).log(
+                RequestEvent::AuthDenied,
+                &client_info_ret.addr,
+                client_info_ret.remote_port,
+                port,
+                Some(StatusCode::UNAUTHORIZED),
+                format_args!(
+                    "Auth request DENIED for {}:{} -> {} {}",
+                    client_info_ret.addr,
+                    client_info_ret.remote_port.unwrap_or(0),
+                    port,
+                    original_uri
+                ),
+            );
+            res.status_code(StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    Ok(())
+}
+
+/// With "--tls-detect", answers plain HTTP that arrived on a TLS port with a
+/// permanent redirect to the same host and path over HTTPS.
+#[handler]
+pub(crate) async fn plain_http_redirect_fn(
+    depot: &Depot,
+    req: &mut Request,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    let Ok(peers) = depot.get_typed::<salvo_compat::PlainHttpPeers>() else {
+        return;
+    };
+    if !req
+        .remote_addr()
+        .clone()
+        .into_std()
+        .is_some_and(|remote_addr| peers.contains(&remote_addr))
+    {
+        return;
+    }
+
+    ctrl.skip_rest();
+    let Some(host) = req.header::<String>("host") else {
+        res.status_code(StatusCode::BAD_REQUEST);
+        return;
+    };
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or("/");
+    res.render(Redirect::permanent(format!(
+        "https://{}{}",
+        host, path_and_query
+    )));
+}
+
+/// Takes a "--max-concurrent" permit if "proxy", else a
+/// "--max-concurrent-challenges" one. Replies 503 and returns Ok(Err(())) if
+/// none freed up in time.
+async fn concurrency_permit(
+    depot: &Depot,
+    req: &Request,
+    res: &mut Response,
+    client_info_ret: &ClientIPAddrRet,
+    port: u16,
+    proxy: bool,
+) -> salvo::Result<Result<Option<tokio::sync::OwnedSemaphorePermit>, ()>> {
+    let Ok(concurrency_limit) = depot.get_typed::<ConcurrencyLimit>() else {
+        return Ok(Ok(None));
+    };
+    let entered = if proxy {
+        concurrency_limit.enter_proxy().await
+    } else {
+        concurrency_limit.enter_challenge().await
+    };
+    if entered.is_err() {
+        Logger::new(depot_args(depot)?.as_ref()).log(
+            RequestEvent::ConcurrencyLimited,
+            &client_info_ret.addr,
+            client_info_ret.remote_port,
+            port,
+            Some(StatusCode::SERVICE_UNAVAILABLE),
+            format_args!(
+                "Too many concurrent {} requests, turned away {}:{} -> {} {}",
+                if proxy { "proxied" } else { "challenge" },
+                client_info_ret.addr,
+                client_info_ret.remote_port.unwrap_or(0),
+                port,
+                req.uri().path()
+            ),
+        );
+        res.body("Server busy, try again")
+            .add_header("content-type", "text/plain", true)?
+            .add_header("retry-after", constants::CONCURRENCY_RETRY_AFTER_SECS, true)?
+            .status_code(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    Ok(entered)
+}
+
+#[handler]
+pub async fn handler_fn(depot: &Depot, req: &mut Request, res: &mut Response) -> salvo::Result<()> {
+    let args = &depot_args(depot)?;
+    let client_wrapper: &ClientWrapper = depot.get_typed().unwrap();
+
+    let client_info_ret = get_client_ip_addr(depot, req).await?;
+
+    // Unix socket listeners have no port, and 0 matches no "--port-to-*"
+    // mapping so they get "--dest-url" and the other defaults.
+    let port: u16 = client_info_ret.local_port.unwrap_or(0);
+
+    let decision = if helpers::is_bypass_path(&args.bypass_paths, req.uri().path()) {
+        json_types::ClientDecision::Allow
+    } else {
+        client_decision(
+            depot,
+            req,
+            &client_info_ret.addr,
+            client_info_ret.remote_port,
+            port,
+        )
+        .await
+    };
+    if let Ok(traffic_recorder) = depot.get_typed::<traffic::TrafficRecorder>() {
+        traffic_recorder.record(args, req, &client_info_ret.addr, port, decision);
+    }
+    let mut is_allowed: bool = match decision {
+        json_types::ClientDecision::Allow => true,
+        json_types::ClientDecision::Challenge => false,
+        json_types::ClientDecision::Deny => {
+            res.status_code(StatusCode::FORBIDDEN);
+            res.render("Forbidden");
+            return Ok(());
+        }
+        json_types::ClientDecision::Unavailable => {
+            res.render("Service unavailable");
+            res.status_code = Some(StatusCode::SERVICE_UNAVAILABLE);
+            return Ok(());
+        }
+    };
+
+    // HEAD can't carry the challenge page, so don't issue a challenge (and
+    // write to the db) for it.
+    let mut challenge_id: Option<String> = None;
+    if !is_allowed
+        && req.method() != Method::HEAD
+        && let Ok(rate_limit) = depot.get_typed::<ChallengeRateLimit>()
+        && let Some(wait) = rate_limit.try_take(&client_info_ret.addr)?
+    {
+        Logger::new(args).log(
+            RequestEvent::ChallengeRateLimited,
+            &client_info_ret.addr,
+            client_info_ret.remote_port,
+            port,
+            Some(StatusCode::TOO_MANY_REQUESTS),
+            format_args!(
+                "Too many challenges for {}:{} -> {} (--challenge-rate)",
+                client_info_ret.addr,
+                client_info_ret.remote_port.unwrap_or(0),
+                port
+            ),
+        );
+        res.body("Too many challenges, try again later")
+            .add_header("content-type", "text/plain", true)?
+            .add_header(
+                "retry-after",
+                wait.as_secs_f64().ceil().max(1.0) as u64,
+                true,
+            )?
+            .status_code(StatusCode::TOO_MANY_REQUESTS);
+        return Ok(());
+    }
+    // Held until the challenge page is written.
+    let _challenge_permit = if !is_allowed && req.method() != Method::HEAD {
+        match concurrency_permit(depot, req, res, &client_info_ret, port, false).await? {
+            Ok(permit) => permit,
+            Err(()) => return Ok(()),
+        }
+    } else {
+        None
+    };
+    if !is_allowed && req.method() != Method::HEAD {
+        match init_id_to_port(depot, &client_info_ret.addr, port).await {
+            Ok(id) => challenge_id = Some(id),
+            Err(e) => {
+                if !db_error_fails_open(depot, &client_info_ret.addr, port, &e) {
+                    res.render("Service unavailable");
+                    res.status_code = Some(StatusCode::SERVICE_UNAVAILABLE);
+                    return Ok(());
+                }
+                is_allowed = true;
+            }
+        }
+    }
+
+    let logger = Logger::new(args);
+    if is_allowed {
+        // Held until the dest url's response is passed on (or starts
+        // streaming).
+        let _proxy_permit =
+            match concurrency_permit(depot, req, res, &client_info_ret, port, true).await? {
+                Ok(permit) => permit,
+                Err(()) => return Ok(()),
+            };
+        let Some(dest_url) = dest_url_for(args, depot, req, port) else {
+            logger.log(
+                RequestEvent::NoDestUrl,
+                &client_info_ret.addr,
+                client_info_ret.remote_port,
+                port,
+                Some(StatusCode::BAD_GATEWAY),
+                format_args!(
+                    "No dest url to forward {}:{} -> {} to{}",
+                    client_info_ret.addr,
+                    client_info_ret.remote_port.unwrap_or(0),
+                    port,
+                    if args.auth_only { " (--auth-only)" } else { "" }
+                ),
+            );
+            res.status_code(StatusCode::BAD_GATEWAY);
+            if args.auth_only {
+                res.render("Proxying is disabled (--auth-only)");
+            } else {
+                res.render("No dest url configured for this port");
+            }
+            return Ok(());
+        };
+        let client: Client = client_wrapper.get_client(dest_url).await?;
+
+        let path_str = req.uri().path_and_query().unwrap().as_str().to_owned();
+
+        let url = if args.enable_override_dest_url
+            && let Some(override_url) = req.header::<&str>("override-dest-url")
+        {
+            override_url.to_owned()
+        } else {
+            dest_url.to_owned()
+        };
+
+        if websocket::is_upgrade_request(req.headers()) {
+            let url = helpers::join_dest_url(&url, &path_str)?;
+            let ret = websocket::proxy(
+                req,
+                res,
+                &url,
+                &client_info_ret.addr,
+                args.trust_forwarded_for,
+                (args.backend_timeout_secs > 0)
+                    .then(|| Duration::from_secs(args.backend_timeout_secs)),
+            )
+            .await;
+            match ret {
+                Ok(()) => {
+                    logger.log(
+                        RequestEvent::Proxied,
+                        &client_info_ret.addr,
+                        client_info_ret.remote_port,
+                        port,
+                        res.status_code,
+                        format_args!(
+                            "Proxied WebSocket {}:{} -> {} {} ({})",
+                            client_info_ret.addr,
+                            client_info_ret.remote_port.unwrap_or(0),
+                            port,
+                            path_str,
+                            res.status_code.unwrap_or(StatusCode::SWITCHING_PROTOCOLS)
+                        ),
+                    );
+                }
+                Err(e) => {
+                    let status = helpers::upstream_error_status(&e);
+                    logger.log(
+                        RequestEvent::UpstreamFailed,
+                        &client_info_ret.addr,
+                        client_info_ret.remote_port,
+                        port,
+                        Some(status),
+                        format_args!(
+                            "Failed to open WebSocket to dest url for {}:{} -> {} ({}): {}",
+                            client_info_ret.addr,
+                            client_info_ret.remote_port.unwrap_or(0),
+                            port,
+                            status,
+                            e
+                        ),
+                    );
+                    res.render(Text::Html(helpers::upstream_error_page(args, status)));
+                    if let Some(retry_after) = helpers::upstream_retry_after(status) {
+                        res.add_header("retry-after", retry_after, true)?;
+                    }
+                    res.status_code = Some(status);
+                }
+            }
+            return Ok(());
+        }
+
+        // A declared length over the limit is turned away without reading
+        // the body at all.
+        let payload_res = match req.header::<u64>("content-length") {
+            Some(len) if len > args.max_proxy_body as u64 => {
+                Err(salvo::http::ParseError::PayloadTooLarge)
+            }
+            _ => req
+                .payload_with_max_size(args.max_proxy_body)
+                .await
+                .map(|payload| payload.to_vec()),
+        };
+        let payload: Vec<u8> = match payload_res {
+            Ok(payload) => payload,
+            Err(salvo::http::ParseError::PayloadTooLarge) => {
+                logger.log(
+                    RequestEvent::BodyTooLarge,
+                    &client_info_ret.addr,
+                    client_info_ret.remote_port,
+                    port,
+                    Some(StatusCode::PAYLOAD_TOO_LARGE),
+                    format_args!(
+                        "Request body from {}:{} -> {} exceeded {} bytes",
+                        client_info_ret.addr,
+                        client_info_ret.remote_port.unwrap_or(0),
+                        port,
+                        args.max_proxy_body
+                    ),
+                );
+                res.status_code(StatusCode::PAYLOAD_TOO_LARGE);
+                res.render("Payload too large");
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let method_str: String = req.method().as_str().to_owned();
+        let _in_flight = depot
+            .get_typed::<UpstreamGauge>()
+            .ok()
+            .map(|upstream_gauge| upstream_gauge.enter());
+        let url = helpers::join_dest_url(&url, &path_str)?;
+        let res_body_res = if payload.is_empty() {
+            req_to_url(
+                req,
+                url,
+                Some(&client_info_ret.addr),
+                None,
+                &method_str,
+                &client,
+                args,
+            )
+            .await
+        } else {
+            req_to_url(
+                req,
+                url,
+                Some(&client_info_ret.addr),
+                Some(payload),
+                &method_str,
+                &client,
+                args,
+            )
+            .await
+        };
+
+        match res_body_res {
+            Ok(res_body) => {
+                let status = StatusCode::from_u16(res_body.status().as_u16()).unwrap();
+                logger.log(
+                    RequestEvent::Proxied,
+                    &client_info_ret.addr,
+                    client_info_ret.remote_port,
+                    port,
+                    Some(status),
+                    format_args!(
+                        "Proxied {}:{} -> {} {} {} ({})",
+                        client_info_ret.addr,
+                        client_info_ret.remote_port.unwrap_or(0),
+                        port,
+                        method_str,
+                        path_str,
+                        status
+                    ),
+                );
+                let all_copied = helpers::copy_response_headers(
+                    res_body.headers(),
+                    &mut res.headers,
+                    args.max_response_header_bytes,
+                );
+                if !all_copied {
+                    logger.log(
+                        RequestEvent::ResponseHeadersTruncated,
+                        &client_info_ret.addr,
+                        client_info_ret.remote_port,
+                        port,
+                        Some(status),
+                        format_args!(
+                            "Response headers from dest url for {}:{} -> {} exceeded {} bytes",
+                            client_info_ret.addr,
+                            client_info_ret.remote_port.unwrap_or(0),
+                            port,
+                            args.max_response_header_bytes.unwrap_or(0)
+                        ),
+                    );
+                }
+
+                if !all_copied && args.response_header_overflow == args::HeaderOverflowPolicy::Error
+                {
+                    res.headers.clear();
+                    res.render(Text::Html(helpers::upstream_error_page(
+                        args,
+                        StatusCode::BAD_GATEWAY,
+                    )));
+                    res.status_code = Some(StatusCode::BAD_GATEWAY);
+                } else if !helpers::should_stream_response(
+                    &args.stream_content_types,
+                    res_body
+                        .headers()
+                        .get("content-type")
+                        .and_then(|content_type| content_type.to_str().ok()),
+                    res_body.content_length(),
+                ) {
+                    match res_body.bytes().await {
+                        Ok(bytes) => {
+                            res.status_code = Some(status);
+                            res.body(bytes);
+                        }
+                        Err(e) => {
+                            let e = Error::from(e);
+                            let error_status = helpers::upstream_error_status(&e);
+                            logger.log(
+                                RequestEvent::ResponseEndedEarly,
+                                &client_info_ret.addr,
+                                client_info_ret.remote_port,
+                                port,
+                                Some(error_status),
+                                format_args!(
+                                    "Response from dest url for {}:{} -> {} ended early: {}",
+                                    client_info_ret.addr,
+                                    client_info_ret.remote_port.unwrap_or(0),
+                                    port,
+                                    e
+                                ),
+                            );
+                            res.headers.clear();
+                            res.render(Text::Html(helpers::upstream_error_page(
+                                args,
+                                error_status,
+                            )));
+                            res.status_code = Some(error_status);
+                        }
+                    }
+                } else {
+                    res.status_code = Some(status);
+                    // Chunks are passed on as they arrive, so memory use doesn't
+                    // grow with the size of the response. An error partway
+                    // through aborts the response rather than ending it cleanly.
+                    let addr = client_info_ret.addr.clone();
+                    let remote_port = client_info_ret.remote_port;
+                    res.stream(futures::TryStreamExt::inspect_err(
+                        res_body.bytes_stream(),
+                        move |e| {
+                            logger.log(
+                                RequestEvent::ResponseEndedEarly,
+                                &addr,
+                                remote_port,
+                                port,
+                                Some(status),
+                                format_args!(
+                                    "Response from dest url for {}:{} -> {} ended early: {}",
+                                    addr,
+                                    remote_port.unwrap_or(0),
+                                    port,
+                                    e
+                                ),
+                            );
+                        },
+                    ));
+                }
+            }
+            Err(e) => {
+                let status = helpers::upstream_error_status(&e);
+                let (status, page, retry_after) = match helpers::maintenance_response(args, status)
+                {
+                    Some((status, page)) => {
+                        (status, page, Some(constants::UPSTREAM_RETRY_AFTER_SECS))
+                    }
+                    None => (
+                        status,
+                        helpers::upstream_error_page(args, status),
+                        helpers::upstream_retry_after(status),
+                    ),
+                };
+                logger.log(
+                    RequestEvent::UpstreamFailed,
+                    &client_info_ret.addr,
+                    client_info_ret.remote_port,
+                    port,
+                    Some(status),
+                    format_args!(
+                        "Failed to query dest url for {}:{} -> {} ({}): {}",
+                        client_info_ret.addr,
+                        client_info_ret.remote_port.unwrap_or(0),
+                        port,
+                        status,
+                        e
+                    ),
+                );
+                res.render(Text::Html(page));
+                if let Some(retry_after) = retry_after {
+                    res.add_header("retry-after", retry_after, true)?;
+                }
+                res.status_code = Some(status);
+            }
+        }
+    } else if let Some(hash) = challenge_id {
+        let js_factors_url = format!("{}?id={}", js_factors_url_for(args, port), hash);
+        if args.challenge_preload {
+            // The preload must be the exact url the page's Worker requests,
+            // otherwise the browser fetches (and a challenge is issued) twice.
+            res.add_header(
+                "link",
+                format!("<{}>; rel=preload; as=worker", js_factors_url),
+                false,
+            )?;
+            res.add_header(
+                "link",
+                format!("<{}>; rel=preconnect", api_url_for(args, port)),
+                false,
+            )?;
+        }
+        let html = args
+            .challenge_html
+            .as_deref()
+            .unwrap_or(constants::HTML_BODY_FACTORS);
+        let html = html
+            .replacen("{JS_FACTORS_URL}", &js_factors_url, 1)
+            .replacen("{NOSCRIPT}", &helpers::noscript_block(args), 1);
+        res.body(html).status_code(challenge_status(args));
+    } else {
+        // A challenged client's HEAD request.
+        res.add_header("content-type", "text/html; charset=utf-8", true)?
+            .status_code(challenge_status(args));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use rusqlite::Connection;
+    use salvo::test::{ResponseExt, TestClient};
+
+    use super::*;
+    #[cfg(feature = "memory")]
+    use crate::db::recently_allowed;
+    use crate::db::{
+        add_allowed_sqlite, check_is_allowed_sqlite, extend_challenge_sqlite,
+        init_id_to_port_sqlite, init_sqlite_db, pending_challenges, set_challenge_factor_sqlite,
+    };
+    use crate::server::{build_router, new_server, tcp_vector_listener};
+    use crate::state::{FactorsSalt, SharedState};
+    use crate::test_util::{test_request, test_service, test_sqlite_args};
+
+    #[tokio::test]
+    async fn test_client_ip_ipv4_mapped() {
+        let mut depot = Depot::new();
+        depot.insert_typed(Arc::new(args::Args {
+            enable_x_real_ip_header: false,
+            ..test_sqlite_args("client_ip_ipv4_mapped")
+        }));
+
+        // A dual-stack listener and an IPv4 one see the same client.
+        let mut addrs = Vec::new();
+        for remote_addr in ["[::ffff:203.0.113.40]:5000", "203.0.113.40:5001"] {
+            let mut req = TestClient::get("http://127.0.0.1:8180/").build();
+            *req.remote_addr_mut() = remote_addr.parse::<std::net::SocketAddr>().unwrap().into();
+            addrs.push(get_client_ip_addr(&depot, &mut req).await.unwrap().addr);
+        }
+
+        assert_eq!(addrs, vec!["203.0.113.40", "203.0.113.40"]);
+    }
+
+    #[tokio::test]
+    async fn test_client_ip_empty_header() {
+        let mut depot = Depot::new();
+        depot.insert_typed(Arc::new(test_sqlite_args("client_ip_empty_header")));
+
+        for real_ip in ["", "   ", "not-an-ip"] {
+            let mut req = test_request(TestClient::get("http://127.0.0.1:8180/"), real_ip, 8180);
+            *req.remote_addr_mut() = "198.51.100.1:5000"
+                .parse::<std::net::SocketAddr>()
+                .unwrap()
+                .into();
+            assert!(get_client_ip_addr(&depot, &mut req).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_request() {
+        let args = args::Args {
+            auth_request_url: Some("/pma_auth".into()),
+            ..test_sqlite_args("auth_request")
+        };
+        let service = test_service(&args).await;
+
+        let res = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/pma_auth"),
+                "203.0.113.7",
+                8180,
+            ))
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+
+        Connection::open(&args.sqlite_db_file)
+            .unwrap()
+            .execute(
+                r"INSERT INTO ALLOWED_IP (IP, PORT) VALUES (?1, ?2)",
+                ("203.0.113.7", 8180),
+            )
+            .unwrap();
+
+        let res = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/pma_auth"),
+                "203.0.113.7",
+                8180,
+            ))
+            .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+    }
+
+    #[tokio::test]
+    async fn test_auth_only() {
+        let args = args::Args {
+            auth_only: true,
+            auth_request_url: Some("/pma_auth".into()),
+            ..test_sqlite_args("auth_only")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        Connection::open(&args.sqlite_db_file)
+            .unwrap()
+            .execute(
+                r"INSERT INTO ALLOWED_IP (IP, PORT) VALUES (?1, ?2)",
+                ("203.0.113.13", 8180),
+            )
+            .unwrap();
+        let service = test_service(&args).await;
+
+        let auth = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/pma_auth"),
+                "203.0.113.13",
+                8180,
+            ))
+            .await;
+        let mut proxied = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.13",
+                8180,
+            ))
+            .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(auth.status_code, Some(StatusCode::OK));
+        assert_eq!(proxied.status_code, Some(StatusCode::BAD_GATEWAY));
+        assert_eq!(
+            proxied.take_string().await.unwrap(),
+            "Proxying is disabled (--auth-only)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bypass_cidr() {
+        let args = args::Args {
+            bypass_cidrs: vec![helpers::parse_ip_net("203.0.113.0/24").unwrap()],
+            auth_request_url: Some("/pma_auth".into()),
+            ..test_sqlite_args("bypass_cidr")
+        };
+        let service = test_service(&args).await;
+
+        // The x-real-ip address is checked, not the address of the proxy.
+        let bypassed = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/pma_auth"),
+                "203.0.113.7",
+                8180,
+            ))
+            .await;
+        let challenged = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/pma_auth"),
+                "198.51.100.7",
+                8180,
+            ))
+            .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(bypassed.status_code, Some(StatusCode::OK));
+        assert_eq!(challenged.status_code, Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn test_reputation_tiers() {
+        let reputation_file =
+            std::env::temp_dir().join(format!("pma_test_reputation_{}", std::process::id()));
+        std::fs::write(
+            &reputation_file,
+            "203.0.113.0/24 good
+",
+        )
+        .unwrap();
+        let args = args::Args {
+            auth_request_url: Some("/pma_auth".into()),
+            reputation_file: Some(reputation_file.clone()),
+            ..test_sqlite_args("reputation_tiers")
+        };
+        let state = SharedState::new(&args).await.unwrap();
+        let reputation_list = state
+            .depot()
+            .get_typed::<reputation::ReputationList>()
+            .unwrap()
+            .clone();
+        let service = Service::new(build_router(&state));
+
+        let good = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/pma_auth"),
+                "203.0.113.7",
+                8180,
+            ))
+            .await;
+        let unlisted = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/pma_auth"),
+                "198.51.100.7",
+                8180,
+            ))
+            .await;
+
+        std::fs::write(
+            &reputation_file,
+            "203.0.113.0/24 bad
+",
+        )
+        .unwrap();
+        reputation_list.reload().unwrap();
+        let bad = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/pma_auth"),
+                "203.0.113.7",
+                8180,
+            ))
+            .await;
+        std::fs::remove_file(&reputation_file).ok();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(good.status_code, Some(StatusCode::OK));
+        assert_eq!(unlisted.status_code, Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(bad.status_code, Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(
+            reputation_list.tier("203.0.113.7").unwrap(),
+            Some(ReputationTier::Bad)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_response_header_bytes() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let mut request: Vec<u8> = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..read]);
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nx-small: ok\r\nx-big: {}\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello",
+                    "a".repeat(4096)
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        for policy in [
+            args::HeaderOverflowPolicy::Truncate,
+            args::HeaderOverflowPolicy::Error,
+        ] {
+            let args = args::Args {
+                dest_url: Some(format!("http://{}", upstream_addr)),
+                max_response_header_bytes: Some(1024),
+                response_header_overflow: policy,
+                ..test_sqlite_args(&format!("max_response_header_bytes_{:?}", policy))
+            };
+            init_sqlite_db(&args).await.unwrap();
+            Connection::open(&args.sqlite_db_file)
+                .unwrap()
+                .execute(
+                    r"INSERT INTO ALLOWED_IP (IP, PORT) VALUES (?1, ?2)",
+                    ("203.0.113.11", 8180),
+                )
+                .unwrap();
+
+            let service = test_service(&args).await;
+            let mut res = service
+                .handle(test_request(
+                    TestClient::get("http://127.0.0.1:8180/"),
+                    "203.0.113.11",
+                    8180,
+                ))
+                .await;
+            std::fs::remove_file(&args.sqlite_db_file).ok();
+
+            match policy {
+                args::HeaderOverflowPolicy::Truncate => {
+                    assert_eq!(res.status_code, Some(StatusCode::OK));
+                    assert_eq!(res.headers.get("x-small").unwrap(), "ok");
+                    assert!(res.headers.get("x-big").is_none());
+                    assert_eq!(res.take_string().await.unwrap(), "hello");
+                }
+                args::HeaderOverflowPolicy::Error => {
+                    assert_eq!(res.status_code, Some(StatusCode::BAD_GATEWAY));
+                    assert!(res.headers.get("x-small").is_none());
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_proxy_body() {
+        let args = args::Args {
+            dest_url: Some("http://127.0.0.1:9".into()),
+            max_proxy_body: 16,
+            ..test_sqlite_args("max_proxy_body")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        Connection::open(&args.sqlite_db_file)
+            .unwrap()
+            .execute(
+                r"INSERT INTO ALLOWED_IP (IP, PORT) VALUES (?1, ?2)",
+                ("203.0.113.12", 8180),
+            )
+            .unwrap();
+
+        let service = test_service(&args).await;
+        let mut res = service
+            .handle(test_request(
+                TestClient::post("http://127.0.0.1:8180/upload").text("a".repeat(17)),
+                "203.0.113.12",
+                8180,
+            ))
+            .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(res.status_code, Some(StatusCode::PAYLOAD_TOO_LARGE));
+        assert_eq!(res.take_string().await.unwrap(), "Payload too large");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_gauge() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Holds each request open until "release" has a permit for it.
+        let release = Arc::new(tokio::sync::Semaphore::new(0));
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let upstream_release = release.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let release = upstream_release.clone();
+                tokio::spawn(async move {
+                    let mut request: Vec<u8> = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let read = stream.read(&mut buf).await.unwrap();
+                        if read == 0 {
+                            break;
+                        }
+                        request.extend_from_slice(&buf[..read]);
+                    }
+                    release.acquire().await.unwrap().forget();
+                    stream
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello",
+                        )
+                        .await
+                        .unwrap();
+                });
+            }
+        });
+
+        let args = args::Args {
+            dest_url: Some(format!("http://{}", upstream_addr)),
+            ..test_sqlite_args("upstream_gauge")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        Connection::open(&args.sqlite_db_file)
+            .unwrap()
+            .execute(
+                r"INSERT INTO ALLOWED_IP (IP, PORT) VALUES (?1, ?2)",
+                ("203.0.113.15", 8180),
+            )
+            .unwrap();
+        let state = SharedState::new(&args).await.unwrap();
+        let upstream_gauge = state.depot().get_typed::<UpstreamGauge>().unwrap().clone();
+
+        let service = Service::new(build_router(&state));
+        let request = || {
+            service.handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.15",
+                8180,
+            ))
+        };
+        let (first, second, peak) = futures::join!(request(), request(), async {
+            let waited = Instant::now();
+            while upstream_gauge.in_flight() < 2 && waited.elapsed() < Duration::from_secs(10) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            let peak = upstream_gauge.in_flight();
+            release.add_permits(2);
+            peak
+        });
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(first.status_code, Some(StatusCode::OK));
+        assert_eq!(second.status_code, Some(StatusCode::OK));
+        assert_eq!(peak, 2);
+        assert_eq!(upstream_gauge.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_backend_timeout() {
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        // Accepts but never responds.
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = upstream.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        let args = args::Args {
+            dest_url: Some(format!("http://{}", upstream_addr)),
+            backend_timeout_secs: 1,
+            ..test_sqlite_args("backend_timeout")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        Connection::open(&args.sqlite_db_file)
+            .unwrap()
+            .execute(
+                r"INSERT INTO ALLOWED_IP (IP, PORT) VALUES (?1, ?2)",
+                ("203.0.113.22", 8180),
+            )
+            .unwrap();
+
+        let service = test_service(&args).await;
+        let started = Instant::now();
+        let res = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.22",
+                8180,
+            ))
+            .await;
+        let elapsed = started.elapsed();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(res.status_code, Some(StatusCode::GATEWAY_TIMEOUT));
+        assert!(elapsed >= Duration::from_secs(1));
+        assert!(elapsed < Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_db_fail_open() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = upstream.accept().await {
+                let mut request: Vec<u8> = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..read]);
+                }
+                stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello",
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+
+        // The sqlite db can't be opened once its directory is removed.
+        let unreachable_dir =
+            std::env::temp_dir().join(format!("pma_test_no_such_dir_{}", std::process::id()));
+        let mut responses = Vec::new();
+        for db_fail_open in [true, false] {
+            let args = args::Args {
+                dest_url: Some(format!("http://{}", upstream_addr)),
+                db_fail_open,
+                sqlite_db_file: unreachable_dir.join("db.sqlite"),
+                ..test_sqlite_args("db_fail_open")
+            };
+            std::fs::create_dir_all(&unreachable_dir).unwrap();
+            let state = SharedState::new(&args).await.unwrap();
+            std::fs::remove_dir_all(&unreachable_dir).unwrap();
+            let db_fail_opens = state.depot().get_typed::<DbFailOpens>().unwrap().clone();
+
+            let mut res = Service::new(build_router(&state))
+                .handle(test_request(
+                    TestClient::get("http://127.0.0.1:8180/"),
+                    "203.0.113.23",
+                    8180,
+                ))
+                .await;
+            responses.push((
+                res.status_code,
+                res.take_string().await.unwrap(),
+                db_fail_opens.count(),
+            ));
+        }
+
+        assert_eq!(
+            responses[0],
+            (Some(StatusCode::OK), "hello".to_owned(), 1),
+            "--db-fail-open should proxy"
+        );
+        assert_eq!(
+            responses[1],
+            (
+                Some(StatusCode::SERVICE_UNAVAILABLE),
+                "Service unavailable".to_owned(),
+                0
+            ),
+            "fail-closed should reply 503"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bypass_path() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let mut request: Vec<u8> = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..read]);
+                }
+                stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 9\r\nconnection: close\r\n\r\nkey-authz",
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let args = args::Args {
+            dest_url: Some(format!("http://{}", upstream_addr)),
+            bypass_paths: vec!["/.well-known/acme-challenge/*".into()],
+            ..test_sqlite_args("bypass_path")
+        };
+        let service = test_service(&args).await;
+        let get = |path: &str| {
+            test_request(
+                TestClient::get(format!("http://127.0.0.1:8180{}", path)),
+                "203.0.113.42",
+                8180,
+            )
+        };
+
+        let mut acme = service
+            .handle(get("/.well-known/acme-challenge/token"))
+            .await;
+        let mut traversal = service
+            .handle(get("/.well-known/acme-challenge/../../admin"))
+            .await;
+        let mut other = service.handle(get("/admin")).await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(acme.status_code, Some(StatusCode::OK));
+        assert_eq!(acme.take_string().await.unwrap(), "key-authz");
+        for res in [&mut traversal, &mut other] {
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert!(
+                res.take_string()
+                    .await
+                    .unwrap()
+                    .contains(&format!("{}?id=", args.js_factors_url))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_head_unallowed() {
+        let args = test_sqlite_args("head_unallowed");
+        let service = test_service(&args).await;
+        let issued = || -> i64 {
+            Connection::open(&args.sqlite_db_file)
+                .unwrap()
+                .query_row("SELECT COUNT(*) FROM ID_TO_PORT", (), |row| row.get(0))
+                .unwrap()
+        };
+
+        let mut head = service
+            .handle(test_request(
+                TestClient::head("http://127.0.0.1:8180/"),
+                "203.0.113.43",
+                8180,
+            ))
+            .await;
+        let issued_after_head = issued();
+        service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.43",
+                8180,
+            ))
+            .await;
+        let issued_after_get = issued();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(head.status_code, Some(StatusCode::OK));
+        assert_eq!(head.take_string().await.unwrap(), "");
+        assert_eq!(issued_after_head, 0);
+        assert_eq!(issued_after_get, 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_js_message() {
+        let args = test_sqlite_args("no_js_message");
+        let page = |args: &args::Args| {
+            let args = args.clone();
+            async move {
+                test_service(&args)
+                    .await
+                    .handle(test_request(
+                        TestClient::get("http://127.0.0.1:8180/"),
+                        "203.0.113.45",
+                        8180,
+                    ))
+                    .await
+                    .take_string()
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let plain = page(&args).await;
+        let with_message = page(&args::Args {
+            no_js_message: Some("JavaScript is required".into()),
+            no_js_link: Some("mailto:admin@example.com".into()),
+            ..args.clone()
+        })
+        .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert!(!plain.contains("{NOSCRIPT}"));
+        assert!(!plain.contains("<noscript>"));
+        assert!(with_message.contains(
+            r#"<noscript><p class="center">JavaScript is required <a href="mailto:admin@example.com">mailto:admin@example.com</a></p></noscript>"#
+        ));
+        assert!(with_message.contains(&format!("{}?id=", args.js_factors_url)));
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_page() {
+        // Nothing listens on a port that was just released.
+        let upstream_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let args = args::Args {
+            dest_url: Some(format!("http://{}", upstream_addr)),
+            bypass_paths: vec!["/proxied".into()],
+            maintenance_page: Some("down for maintenance".into()),
+            ..test_sqlite_args("maintenance_page")
+        };
+        let service = test_service(&args).await;
+        let get = |path: &str| {
+            test_request(
+                TestClient::get(format!("http://127.0.0.1:8180{}", path)),
+                "203.0.113.48",
+                8180,
+            )
+        };
+
+        let mut allowed = service.handle(get("/proxied")).await;
+        let mut challenged = service.handle(get("/")).await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(allowed.status_code, Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(
+            allowed.headers.get("retry-after").unwrap(),
+            &constants::UPSTREAM_RETRY_AFTER_SECS.to_string()
+        );
+        assert_eq!(allowed.take_string().await.unwrap(), "down for maintenance");
+        assert_eq!(challenged.status_code, Some(StatusCode::OK));
+        assert!(
+            challenged
+                .take_string()
+                .await
+                .unwrap()
+                .contains(&format!("{}?id=", args.js_factors_url))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backend_retries() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Nothing listens on a port that was just released, until the
+        // upstream "restarts" on it.
+        let upstream_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let args = args::Args {
+            dest_url: Some(format!("http://{}", upstream_addr)),
+            bypass_paths: vec!["/*".into()],
+            backend_retries: 4,
+            ..test_sqlite_args("backend_retries")
+        };
+        let service = test_service(&args).await;
+
+        // Not retried, so it fails before the upstream is back.
+        let post = service
+            .handle(test_request(
+                TestClient::post("http://127.0.0.1:8180/").text("form"),
+                "203.0.113.44",
+                8180,
+            ))
+            .await;
+
+        let upstream_task = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(
+                constants::BACKEND_RETRY_BASE_DELAY_MS / 2,
+            ))
+            .await;
+            let upstream = tokio::net::TcpListener::bind(upstream_addr).await.unwrap();
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut request: Vec<u8> = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buf).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 4\r\nconnection: close\r\n\r\nback")
+                .await
+                .unwrap();
+        });
+        let mut get = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.44",
+                8180,
+            ))
+            .await;
+        upstream_task.await.unwrap();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(post.status_code, Some(StatusCode::BAD_GATEWAY));
+        assert_eq!(get.status_code, Some(StatusCode::OK));
+        assert_eq!(get.take_string().await.unwrap(), "back");
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_request() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let upstream_task = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut request: Vec<u8> = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"payload") {
+                let read = stream.read(&mut buf).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&request).into_owned()
+        });
+
+        let args = args::Args {
+            dest_url: Some(format!("http://{}", upstream_addr)),
+            ..test_sqlite_args("forwarded_request")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        Connection::open(&args.sqlite_db_file)
+            .unwrap()
+            .execute(
+                r"INSERT INTO ALLOWED_IP (IP, PORT) VALUES (?1, ?2)",
+                ("203.0.113.24", 8180),
+            )
+            .unwrap();
+
+        let res = test_service(&args)
+            .await
+            .handle(test_request(
+                TestClient::put("http://127.0.0.1:8180/items/1?a=b")
+                    .add_header("x-custom", "custom value", true)
+                    .add_header("cookie", "session=abc", true)
+                    .add_header("authorization", "Bearer token", true)
+                    .add_header("content-type", "text/plain", true)
+                    .add_header("user-agent", "TestBrowser/1.0", true)
+                    .add_header("connection", "x-drop-me", true)
+                    .add_header("x-drop-me", "dropped", true)
+                    .text("payload"),
+                "203.0.113.24",
+                8180,
+            ))
+            .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        let request = tokio::time::timeout(Duration::from_secs(10), upstream_task)
+            .await
+            .expect("Request should be forwarded")
+            .unwrap();
+        assert_eq!(res.status_code, Some(StatusCode::NO_CONTENT));
+        assert!(request.starts_with("PUT /items/1?a=b HTTP/1.1\r\n"));
+        for header in [
+            "x-custom: custom value",
+            "cookie: session=abc",
+            "authorization: Bearer token",
+            "content-type: text/plain",
+            "user-agent: TestBrowser/1.0",
+            "x-real-ip: 203.0.113.24",
+        ] {
+            assert!(
+                request.contains(&format!("\r\n{}\r\n", header)),
+                "Missing {:?} in {:?}",
+                header,
+                request
+            );
+        }
+        assert!(!request.contains("x-drop-me"));
+        assert!(request.ends_with("\r\n\r\npayload"));
+    }
+
+    #[tokio::test]
+    async fn test_repeated_response_headers() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut request: Vec<u8> = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buf).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\n\
+                      set-cookie: a=1\r\n\
+                      set-cookie: b=2\r\n\
+                      content-type: text/html\r\n\
+                      content-type: text/plain\r\n\
+                      content-length: 5\r\n\
+                      connection: close\r\n\r\nhello",
+                )
+                .await
+                .unwrap();
+        });
+
+        let args = args::Args {
+            dest_url: Some(format!("http://{}", upstream_addr)),
+            ..test_sqlite_args("repeated_response_headers")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        Connection::open(&args.sqlite_db_file)
+            .unwrap()
+            .execute(
+                r"INSERT INTO ALLOWED_IP (IP, PORT) VALUES (?1, ?2)",
+                ("203.0.113.12", 8180),
+            )
+            .unwrap();
+
+        let service = test_service(&args).await;
+        let mut res = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.12",
+                8180,
+            ))
+            .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        let cookies: Vec<&str> = res
+            .headers
+            .get_all("set-cookie")
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect();
+        assert_eq!(cookies, vec!["a=1", "b=2"]);
+        let content_types: Vec<&str> = res
+            .headers
+            .get_all("content-type")
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect();
+        assert_eq!(content_types, vec!["text/html"]);
+        assert_eq!(res.take_string().await.unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_hop_by_hop_response_headers() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut request: Vec<u8> = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buf).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\n\
+                      transfer-encoding: chunked\r\n\
+                      keep-alive: timeout=5\r\n\
+                      x-hop: 1\r\n\
+                      x-end-to-end: 1\r\n\
+                      connection: close, x-hop\r\n\r\n\
+                      5\r\nhello\r\n0\r\n\r\n",
+                )
+                .await
+                .unwrap();
+        });
+
+        let args = args::Args {
+            dest_url: Some(format!("http://{}", upstream_addr)),
+            ..test_sqlite_args("hop_by_hop_response_headers")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        Connection::open(&args.sqlite_db_file)
+            .unwrap()
+            .execute(
+                r"INSERT INTO ALLOWED_IP (IP, PORT) VALUES (?1, ?2)",
+                ("203.0.113.25", 8180),
+            )
+            .unwrap();
+
+        let mut res = test_service(&args)
+            .await
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.25",
+                8180,
+            ))
+            .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        for header in ["transfer-encoding", "connection", "keep-alive", "x-hop"] {
+            assert!(
+                res.headers.get(header).is_none(),
+                "{} should not be forwarded",
+                header
+            );
+        }
+        assert_eq!(res.headers.get("x-end-to-end").unwrap(), "1");
+        assert_eq!(res.take_string().await.unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_streamed_response() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        const BODY_LEN: usize = 4 * 1024 * 1024;
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            // The second response claims more than it sends.
+            for sent_len in [BODY_LEN, BODY_LEN / 2] {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let mut request: Vec<u8> = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..read]);
+                }
+                stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                            BODY_LEN
+                        )
+                        .as_bytes(),
+                    )
+                    .await
+                    .unwrap();
+                stream.write_all(&vec![b'a'; sent_len]).await.unwrap();
+            }
+        });
+
+        let args = args::Args {
+            dest_url: Some(format!("http://{}", upstream_addr)),
+            ..test_sqlite_args("streamed_response")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        Connection::open(&args.sqlite_db_file)
+            .unwrap()
+            .execute(
+                r"INSERT INTO ALLOWED_IP (IP, PORT) VALUES (?1, ?2)",
+                ("203.0.113.26", 8180),
+            )
+            .unwrap();
+
+        let service = test_service(&args).await;
+        let mut complete = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.26",
+                8180,
+            ))
+            .await;
+        assert!(complete.body.is_stream());
+        let complete_body = complete.take_bytes(None).await.map(|bytes| bytes.len());
+        let mut truncated = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.26",
+                8180,
+            ))
+            .await;
+        let truncated_body = truncated.take_bytes(None).await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(complete.status_code, Some(StatusCode::OK));
+        assert_eq!(complete_body.unwrap(), BODY_LEN);
+        assert_eq!(truncated.status_code, Some(StatusCode::OK));
+        assert!(truncated_body.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_content_types() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            for content_type in ["video/mp4", "text/html; charset=utf-8"] {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let mut request: Vec<u8> = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..read]);
+                }
+                stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 200 OK\r\ncontent-type: {}\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello",
+                            content_type
+                        )
+                        .as_bytes(),
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let args = args::Args {
+            dest_url: Some(format!("http://{}", upstream_addr)),
+            stream_content_types: vec!["video/*".into(), "application/octet-stream".into()],
+            ..test_sqlite_args("stream_content_types")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        add_allowed_sqlite(&args, "203.0.113.27", 8180)
+            .await
+            .unwrap();
+
+        let service = test_service(&args).await;
+        let mut video = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/video.mp4"),
+                "203.0.113.27",
+                8180,
+            ))
+            .await;
+        assert!(video.body.is_stream());
+        let video_body = video.take_string().await;
+        let mut html = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.27",
+                8180,
+            ))
+            .await;
+        assert!(html.body.is_once());
+        let html_body = html.take_string().await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(video.status_code, Some(StatusCode::OK));
+        assert_eq!(video_body.unwrap(), "hello");
+        assert_eq!(html.status_code, Some(StatusCode::OK));
+        assert_eq!(html_body.unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_websocket_passthrough() {
+        use futures::{SinkExt, StreamExt};
+        use salvo::conn::Acceptor;
+        use tokio_tungstenite::tungstenite::{Message, client::IntoClientRequest};
+
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = upstream.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(message)) = ws.next().await {
+                if message.is_text() {
+                    let echo = format!("echo {}", message.to_text().unwrap());
+                    ws.send(Message::text(echo)).await.unwrap();
+                }
+            }
+        });
+
+        let args = args::Args {
+            addr_port_strs: vec!["127.0.0.1:0".into()],
+            dest_url: Some(format!("http://{}", upstream_addr)),
+            ..test_sqlite_args("websocket_passthrough")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        let acceptor = tcp_vector_listener(&args).bind().await;
+        let port = acceptor.holdings()[0].local_addr.port().unwrap();
+        add_allowed_sqlite(&args, "203.0.113.42", port)
+            .await
+            .unwrap();
+        let router = build_router(&SharedState::new(&args).await.unwrap());
+        let server = new_server(acceptor, &args);
+        let handle = server.handle();
+        tokio::spawn(server.serve(router));
+
+        let ws_request = |ip: &str| {
+            let mut request = format!("ws://127.0.0.1:{}/socket", port)
+                .into_client_request()
+                .unwrap();
+            request
+                .headers_mut()
+                .insert("x-real-ip", ip.parse().unwrap());
+            request
+        };
+        let (mut allowed, _) = tokio_tungstenite::connect_async(ws_request("203.0.113.42"))
+            .await
+            .unwrap();
+        allowed.send(Message::text("hello")).await.unwrap();
+        let echo = allowed.next().await.unwrap().unwrap();
+        allowed.close(None).await.ok();
+        let challenged = tokio_tungstenite::connect_async(ws_request("203.0.113.43")).await;
+        handle.stop_forceful();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(echo.to_text().unwrap(), "echo hello");
+        match challenged {
+            Err(tokio_tungstenite::tungstenite::Error::Http(res)) => {
+                assert_eq!(res.status(), StatusCode::OK);
+                assert!(
+                    String::from_utf8_lossy(res.body().as_deref().unwrap_or_default())
+                        .contains("pma_factors.js")
+                );
+            }
+            _ => panic!("Expected the challenge page instead of an upgrade"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generation_failure() {
+        for policy in [
+            args::GenerationFailurePolicy::FailClosed,
+            args::GenerationFailurePolicy::FailOpen,
+        ] {
+            // 0 quads makes challenge generation fail.
+            let args = args::Args {
+                factors: Some(0),
+                on_generation_failure: policy,
+                ..test_sqlite_args(&format!("generation_failure_{:?}", policy))
+            };
+            init_sqlite_db(&args).await.unwrap();
+            let id = init_id_to_port_sqlite(&args, "203.0.113.13", 8180)
+                .await
+                .unwrap();
+
+            let service = test_service(&args).await;
+            let mut res = service
+                .handle(test_request(
+                    TestClient::get(format!("http://127.0.0.1:8180/pma_factors.js?id={}", id)),
+                    "203.0.113.13",
+                    8180,
+                ))
+                .await;
+            let is_allowed = check_is_allowed_sqlite(&args, "203.0.113.13", 8180)
+                .await
+                .unwrap();
+            std::fs::remove_file(&args.sqlite_db_file).ok();
+
+            match policy {
+                args::GenerationFailurePolicy::FailClosed => {
+                    assert_eq!(res.status_code, Some(StatusCode::SERVICE_UNAVAILABLE));
+                    assert!(!is_allowed);
+                }
+                args::GenerationFailurePolicy::FailOpen => {
+                    assert!(res.status_code.is_none_or(|s| s == StatusCode::OK));
+                    assert_eq!(
+                        res.take_string().await.unwrap(),
+                        constants::JAVASCRIPT_FAIL_OPEN_WORKER
+                    );
+                    assert!(is_allowed);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_worker_jitter() {
+        assert!(constants::JAVASCRIPT_FACTORS_WORKER.contains("const jitter_ms = {JITTER_MS};"));
+        assert!(
+            constants::JAVASCRIPT_FACTORS_WORKER
+                .contains("setTimeout(getFactors, Math.floor(Math.random() * (jitter_ms + 1)));")
+        );
+
+        let args = args::Args {
+            factors: Some(4),
+            worker_jitter_ms: 2500,
+            ..test_sqlite_args("worker_jitter")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        let id = init_id_to_port_sqlite(&args, "203.0.113.14", 8180)
+            .await
+            .unwrap();
+
+        let service = test_service(&args).await;
+        let mut res = service
+            .handle(test_request(
+                TestClient::get(format!("http://127.0.0.1:8180/pma_factors.js?id={}", id)),
+                "203.0.113.14",
+                8180,
+            ))
+            .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        let js = res.take_string().await.unwrap();
+        assert!(js.contains("const jitter_ms = 2500;"));
+        assert!(!js.contains("{JITTER_MS}"));
+    }
+
+    #[tokio::test]
+    async fn test_cached_allow_real_ip() {
+        let args = test_sqlite_args("cached_allow_real_ip");
+        init_sqlite_db(&args).await.unwrap();
+        add_allowed_sqlite(&args, "203.0.113.47", 8180)
+            .await
+            .unwrap();
+        let state = SharedState::new(&args).await.unwrap();
+        let cached_allow = state.depot().get_typed::<CachedAllow>().unwrap().clone();
+        let service = Service::new(build_router(&state));
+        let via_proxy = |real_ip: &str, proxy: [u8; 4]| {
+            let mut req = test_request(TestClient::get("http://127.0.0.1:8180/"), real_ip, 8180);
+            *req.remote_addr_mut() = std::net::SocketAddr::from((proxy, 40000)).into();
+            req
+        };
+
+        // No dest url is configured, so an allowed client gets a 502.
+        let first = service
+            .handle(via_proxy("203.0.113.47", [192, 0, 2, 1]))
+            .await;
+        assert_eq!(first.status_code, Some(StatusCode::BAD_GATEWAY));
+        assert!(
+            cached_allow
+                .get_allowed("203.0.113.47", 8180, CACHED_TIMEOUT)
+                .unwrap()
+        );
+        assert!(
+            !cached_allow
+                .get_allowed("192.0.2.1", 8180, CACHED_TIMEOUT)
+                .unwrap()
+        );
+
+        // Without the db only the cache can allow the client, whichever proxy
+        // and spelling of its ip the header comes through.
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+        let cached = service
+            .handle(via_proxy("::ffff:203.0.113.47", [192, 0, 2, 2]))
+            .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+        assert_eq!(cached.status_code, Some(StatusCode::BAD_GATEWAY));
+        assert_eq!(cached_allow.len().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_challenge_heartbeat() {
+        let args = args::Args {
+            challenge_timeout_mins: 10,
+            challenge_heartbeat_max_mins: Some(30),
+            heartbeat_url: "/pma_heartbeat".into(),
+            ..test_sqlite_args("challenge_heartbeat")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        for id in ["pending", "capped", "expired"] {
+            set_challenge_factor_sqlite(&args, "203.0.113.40", id, 8180, "factors_hash", "M", None)
+                .await
+                .unwrap();
+        }
+        let conn = Connection::open(&args.sqlite_db_file).unwrap();
+        // "pending" is close to expiring, "capped" is past the point a heartbeat
+        // can extend it to, and "expired" is gone.
+        for (id, issued, on_time) in [
+            ("pending", "-9 minutes", "-9 minutes"),
+            ("capped", "-25 minutes", "-9 minutes"),
+            ("expired", "-11 minutes", "-11 minutes"),
+        ] {
+            conn.execute(
+                r"UPDATE CHALLENGE_FACTOR_4 SET ISSUE_TIME = datetime('now', ?2), ON_TIME = datetime('now', ?3) WHERE ID = ?1",
+                (id, issued, on_time),
+            )
+            .unwrap();
+        }
+
+        let service = test_service(&args).await;
+        let heartbeat = |id: &str| {
+            test_request(
+                TestClient::post("http://127.0.0.1:8180/pma_heartbeat")
+                    .json(&json_types::ChallengeHeartbeat { id: id.into() }),
+                "203.0.113.40",
+                8180,
+            )
+        };
+        let pending = service.handle(heartbeat("pending")).await;
+        let capped = service.handle(heartbeat("capped")).await;
+        let expired = service.handle(heartbeat("expired")).await;
+        let other_ip = extend_challenge_sqlite(&args, "pending", "203.0.113.41")
+            .await
+            .unwrap();
+        let pending_extended: bool = conn
+            .query_one(
+                r"SELECT ON_TIME >= datetime('now', '-5 seconds') FROM CHALLENGE_FACTOR_4 WHERE ID = 'pending'",
+                (),
+                |r| r.get(0),
+            )
+            .unwrap();
+        let capped_at_max: bool = conn
+            .query_one(
+                r"SELECT ON_TIME = datetime(ISSUE_TIME, '20 minutes') FROM CHALLENGE_FACTOR_4 WHERE ID = 'capped'",
+                (),
+                |r| r.get(0),
+            )
+            .unwrap();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(pending.status_code, Some(StatusCode::NO_CONTENT));
+        assert!(pending_extended);
+        assert_eq!(capped.status_code, Some(StatusCode::NO_CONTENT));
+        assert!(capped_at_max);
+        assert_eq!(expired.status_code, Some(StatusCode::NOT_FOUND));
+        assert!(!other_ip);
+    }
+
+    #[tokio::test]
+    async fn test_factors_salt_restart() {
+        let args = test_sqlite_args("factors_salt_restart");
+        init_sqlite_db(&args).await.unwrap();
+        let answer = |id: String| {
+            json_types::ChallengeResponse::Factors(json_types::FactorsResponse {
+                r#type: "factors".into(),
+                id,
+                factors: "2x2 3x1".into(),
+            })
+        };
+
+        let mut depot = Depot::new();
+        depot.insert_typed(Arc::new(args.clone()));
+        depot.insert_typed(FactorsSalt::default());
+        let issued_id = store_factors_challenge(&depot, "203.0.113.7", 8180, "M", "2x2 3x1", None)
+            .await
+            .unwrap();
+        let rotated_id = store_factors_challenge(&depot, "203.0.113.7", 8180, "M", "2x2 3x1", None)
+            .await
+            .unwrap();
+
+        // A restart starts over with a new FactorsSalt on the same db.
+        let mut restarted = Depot::new();
+        restarted.insert_typed(Arc::new(args.clone()));
+        restarted.insert_typed(FactorsSalt::default());
+        let after_restart =
+            validate_client(&restarted, &answer(issued_id), "2x2 3x1", "203.0.113.7").await;
+
+        restarted
+            .get_typed::<FactorsSalt>()
+            .unwrap()
+            .rotate()
+            .unwrap();
+        let after_rotate =
+            validate_client(&restarted, &answer(rotated_id), "2x2 3x1", "203.0.113.7").await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(after_restart.unwrap(), 8180);
+        assert!(after_rotate.is_err());
+    }
+
+    #[test]
+    fn test_host_to_dest_url() {
+        let args = args::Args {
+            dest_url: Some("http://127.0.0.1:9000".into()),
+            host_to_dest_urls: HashMap::from([
+                ("example.com".to_owned(), "http://127.0.0.1:9001".to_owned()),
+                (
+                    "xn--bcher-kva.example".to_owned(),
+                    "http://127.0.0.1:9002".to_owned(),
+                ),
+            ]),
+            ..Default::default()
+        };
+        let dest_url = |host: &str| {
+            let req = TestClient::get("http://127.0.0.1:8180/")
+                .add_header("host", host, true)
+                .build();
+            dest_url_for(&args, &Depot::new(), &req, 8180).cloned()
+        };
+
+        for host in ["example.com", "Example.COM", "example.com:8080"] {
+            assert_eq!(dest_url(host).as_deref(), Some("http://127.0.0.1:9001"));
+        }
+        assert_eq!(
+            dest_url("XN--BCHER-KVA.example:443").as_deref(),
+            Some("http://127.0.0.1:9002")
+        );
+        assert_eq!(
+            dest_url("other.example").as_deref(),
+            Some("http://127.0.0.1:9000")
+        );
+    }
+
+    #[test]
+    fn test_port_to_dest_urls_round_robin() {
+        let args = args::Args {
+            port_to_dest_urls: HashMap::from([
+                (
+                    8180,
+                    vec![
+                        "http://127.0.0.1:9001".to_owned(),
+                        "http://127.0.0.1:9002".to_owned(),
+                    ],
+                ),
+                (8181, vec!["http://127.0.0.1:9003".to_owned()]),
+            ]),
+            ..Default::default()
+        };
+        let mut depot = Depot::new();
+        depot.insert_typed(DestRoundRobin::default());
+        let req = TestClient::get("http://127.0.0.1:8180/").build();
+
+        let picked: Vec<Option<&String>> = (0..4)
+            .map(|_| dest_url_for(&args, &depot, &req, 8180))
+            .collect();
+        assert_eq!(
+            picked,
+            vec![
+                Some(&args.port_to_dest_urls[&8180][0]),
+                Some(&args.port_to_dest_urls[&8180][1]),
+                Some(&args.port_to_dest_urls[&8180][0]),
+                Some(&args.port_to_dest_urls[&8180][1]),
+            ]
+        );
+        for _ in 0..2 {
+            assert_eq!(
+                dest_url_for(&args, &depot, &req, 8181).map(|s| s.as_str()),
+                Some("http://127.0.0.1:9003")
+            );
+        }
+        assert_eq!(dest_url_for(&args, &depot, &req, 8182), None);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let args = args::Args {
+            enable_audit_log: true,
+            ..test_sqlite_args("audit_log")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        let mut depot = Depot::new();
+        depot.insert_typed(Arc::new(args.clone()));
+
+        init_id_to_port(&depot, "203.0.113.28", 8180).await.unwrap();
+        for value in ["N", "M"] {
+            let id = store_factors_challenge(&depot, "203.0.113.28", 8180, value, "2x2 3x1", None)
+                .await
+                .unwrap();
+            validate_client(
+                &depot,
+                &json_types::ChallengeResponse::Factors(json_types::FactorsResponse {
+                    r#type: "factors".into(),
+                    id,
+                    factors: "2x2 3x1".into(),
+                }),
+                "2x2 3x1",
+                "203.0.113.28",
+            )
+            .await
+            .ok();
+        }
+
+        let conn = Connection::open(&args.sqlite_db_file).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT IP, PORT, EVENT, ON_TIME FROM AUDIT_LOG ORDER BY ID")
+            .unwrap();
+        let rows: Vec<(String, u16, String, String)> = stmt
+            .query_map((), |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        drop(stmt);
+        drop(conn);
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        let events: Vec<(&str, u16, &str)> = rows
+            .iter()
+            .map(|(ip, port, event, _)| (ip.as_str(), *port, event.as_str()))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ("203.0.113.28", 8180, "issued"),
+                ("203.0.113.28", 8180, "failed"),
+                ("203.0.113.28", 8180, "passed"),
+            ]
+        );
+        assert!(rows.iter().all(|(_, _, _, on_time)| !on_time.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_validations() {
+        let args = args::Args {
+            max_concurrent_validations: Some(1),
+            ..test_sqlite_args("max_concurrent_validations")
+        };
+        let state = SharedState::new(&args).await.unwrap();
+        let validation_limit = state
+            .depot()
+            .get_typed::<ValidationLimit>()
+            .unwrap()
+            .clone();
+        let service = Service::new(build_router(&state));
+        let answer = || {
+            test_request(
+                TestClient::post("http://127.0.0.1:8180/pma_api").json(
+                    &json_types::FactorsResponse {
+                        r#type: "factors".into(),
+                        id: "unknown".into(),
+                        factors: "2x1".into(),
+                    },
+                ),
+                "203.0.113.29",
+                8180,
+            )
+        };
+
+        // Another validation holds the only permit.
+        let permit = validation_limit.try_enter().unwrap();
+        let throttled = service.handle(answer()).await;
+        let challenge = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.29",
+                8180,
+            ))
+            .await;
+        drop(permit);
+        let validated = service.handle(answer()).await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(throttled.status_code, Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(
+            throttled.headers.get("retry-after").unwrap(),
+            &constants::VALIDATION_RETRY_AFTER_SECS.to_string()
+        );
+        assert_eq!(challenge.status_code, Some(StatusCode::OK));
+        assert_eq!(validated.status_code, Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent() {
+        let args = args::Args {
+            bypass_paths: vec!["/proxied".into()],
+            max_concurrent: Some(1),
+            max_concurrent_challenges: Some(2),
+            ..test_sqlite_args("max_concurrent")
+        };
+        let state = SharedState::new(&args).await.unwrap();
+        let concurrency_limit = state
+            .depot()
+            .get_typed::<ConcurrencyLimit>()
+            .unwrap()
+            .clone();
+        let service = Service::new(build_router(&state));
+        let get = |path: &str| {
+            test_request(
+                TestClient::get(format!("http://127.0.0.1:8180{}", path)),
+                "203.0.113.46",
+                8180,
+            )
+        };
+
+        // Another request holds the only proxy permit.
+        let proxy_permit = concurrency_limit.enter_proxy().await.unwrap();
+        let throttled = service.handle(get("/proxied")).await;
+        let challenge = service.handle(get("/")).await;
+        drop(proxy_permit);
+        // No dest url is configured, so getting past the limit means a 502.
+        let proxied = service.handle(get("/proxied")).await;
+
+        let challenge_permits = [
+            concurrency_limit.enter_challenge().await.unwrap(),
+            concurrency_limit.enter_challenge().await.unwrap(),
+        ];
+        let throttled_challenge = service.handle(get("/")).await;
+        let proxied_during_challenges = service.handle(get("/proxied")).await;
+        drop(challenge_permits);
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(throttled.status_code, Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(
+            throttled.headers.get("retry-after").unwrap(),
+            &constants::CONCURRENCY_RETRY_AFTER_SECS.to_string()
+        );
+        assert_eq!(challenge.status_code, Some(StatusCode::OK));
+        assert_eq!(proxied.status_code, Some(StatusCode::BAD_GATEWAY));
+        assert_eq!(
+            throttled_challenge.status_code,
+            Some(StatusCode::SERVICE_UNAVAILABLE)
+        );
+        assert_eq!(
+            proxied_during_challenges.status_code,
+            Some(StatusCode::BAD_GATEWAY)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_json_size() {
+        let args = args::Args {
+            max_json_size: 128,
+            ..test_sqlite_args("max_json_size")
+        };
+        let service = test_service(&args).await;
+        let answer = |factors: String| {
+            test_request(
+                TestClient::post("http://127.0.0.1:8180/pma_api").json(
+                    &json_types::FactorsResponse {
+                        r#type: "factors".into(),
+                        id: "unknown".into(),
+                        factors,
+                    },
+                ),
+                "203.0.113.30",
+                8180,
+            )
+        };
+
+        let mut oversized = service.handle(answer("2x1 ".repeat(64))).await;
+        let mut small = service.handle(answer("2x1".into())).await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(oversized.status_code, Some(StatusCode::PAYLOAD_TOO_LARGE));
+        assert_eq!(
+            oversized
+                .take_json::<json_types::ApiResult>()
+                .await
+                .unwrap(),
+            json_types::ApiResult::error("Challenge answer is over 128 bytes")
+        );
+        assert_eq!(small.status_code, Some(StatusCode::BAD_REQUEST));
+        assert!(
+            small
+                .headers
+                .get("content-type")
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .starts_with("application/json")
+        );
+        assert_eq!(
+            small.take_string().await.unwrap(),
+            r#"{"status":"error","reason":"Incorrect"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_user_agent() {
+        let args = args::Args {
+            store_user_agent: true,
+            factors: Some(4),
+            ..test_sqlite_args("store_user_agent")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        let mut depot = Depot::new();
+        depot.insert_typed(Arc::new(args.clone()));
+
+        let service = test_service(&args).await;
+        for (ip, user_agent) in [
+            ("203.0.113.19", "TestBrowser/1.0".to_owned()),
+            ("203.0.113.20", "é".repeat(constants::MAX_USER_AGENT_LEN)),
+        ] {
+            let id = init_id_to_port_sqlite(&args, ip, 8180).await.unwrap();
+            service
+                .handle(test_request(
+                    TestClient::get(format!("http://127.0.0.1:8180/pma_factors.js?id={}", id))
+                        .add_header("user-agent", user_agent, true),
+                    ip,
+                    8180,
+                ))
+                .await;
+        }
+        let mut challenges = pending_challenges(&depot).await.unwrap();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        challenges.sort();
+        assert_eq!(challenges.len(), 2);
+        assert_eq!(challenges[0].2.as_deref(), Some("TestBrowser/1.0"));
+        // Each "é" is 2 bytes, so exactly half fit.
+        assert_eq!(
+            challenges[1].2,
+            Some("é".repeat(constants::MAX_USER_AGENT_LEN / 2))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_challenge_preload() {
+        let args = args::Args {
+            challenge_preload: true,
+            api_url: "/custom_api".into(),
+            js_factors_url: "/custom_factors.js".into(),
+            ..test_sqlite_args("challenge_preload")
+        };
+        let service = test_service(&args).await;
+
+        let mut res = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.21",
+                8180,
+            ))
+            .await;
+        let no_preload = test_service(&args::Args {
+            challenge_preload: false,
+            ..args.clone()
+        })
+        .await
+        .handle(test_request(
+            TestClient::get("http://127.0.0.1:8180/"),
+            "203.0.113.21",
+            8180,
+        ))
+        .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        let links: Vec<String> = res
+            .headers
+            .get_all("link")
+            .iter()
+            .map(|link| link.to_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(links.len(), 2);
+        let body = res.take_string().await.unwrap();
+        let worker_url = body
+            .split('"')
+            .find(|s| s.starts_with("/custom_factors.js?id="))
+            .unwrap();
+        assert_eq!(
+            links[0],
+            format!("<{}>; rel=preload; as=worker", worker_url)
+        );
+        assert_eq!(links[1], "</custom_api>; rel=preconnect");
+
+        assert_eq!(no_preload.status_code, Some(StatusCode::OK));
+        assert!(no_preload.headers.get("link").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_challenge_status() {
+        let args = args::Args {
+            challenge_status: Some(503),
+            ..test_sqlite_args("challenge_status")
+        };
+        let service = test_service(&args).await;
+
+        let mut res = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.27",
+                8180,
+            ))
+            .await;
+        let head = service
+            .handle(test_request(
+                TestClient::head("http://127.0.0.1:8180/"),
+                "203.0.113.27",
+                8180,
+            ))
+            .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(res.status_code, Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(
+            res.take_string()
+                .await
+                .unwrap()
+                .contains(&format!("{}?id=", args.js_factors_url))
+        );
+        assert_eq!(head.status_code, Some(StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    #[tokio::test]
+    async fn test_exemption_token() {
+        let key_file =
+            std::env::temp_dir().join(format!("pma_test_exemption_key_{}", std::process::id()));
+        std::fs::write(&key_file, b"exemption_token").unwrap();
+        let args = args::Args {
+            exemption_token_key: Some(key_file.clone()),
+            ..test_sqlite_args("exemption_token")
+        };
+        let state = SharedState::new(&args).await.unwrap();
+        std::fs::remove_file(&key_file).ok();
+        let tokens = state
+            .depot()
+            .get_typed::<token::ExemptionTokens>()
+            .unwrap()
+            .clone();
+        let service = Service::new(build_router(&state));
+
+        let mut statuses = Vec::new();
+        for token in [
+            Some(tokens.mint(unix_now() + 60, Some("203.0.113.30"))),
+            Some(tokens.mint(unix_now() + 60, Some("203.0.113.31"))),
+            Some(tokens.mint(unix_now() - 1, None)),
+            Some(token::ExemptionTokens::new(b"other").mint(unix_now() + 60, None)),
+            None,
+        ] {
+            let mut req = TestClient::get("http://127.0.0.1:8180/");
+            if let Some(token) = token {
+                req = req.add_header("x-pma-token", token, true);
+            }
+            let res = service
+                .handle(test_request(req, "203.0.113.30", 8180))
+                .await;
+            statuses.push(res.status_code);
+        }
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        // No dest url is set, so an allowed client gets a 502 instead of the
+        // challenge page.
+        assert_eq!(
+            statuses,
+            vec![
+                Some(StatusCode::BAD_GATEWAY),
+                Some(StatusCode::OK),
+                Some(StatusCode::OK),
+                Some(StatusCode::OK),
+                Some(StatusCode::OK),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hashcash_challenge() {
+        let args = args::Args {
+            challenge_type: args::ChallengeType::Hashcash,
+            hashcash_bits: 8,
+            ..test_sqlite_args("hashcash_challenge")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        let id = init_id_to_port_sqlite(&args, "203.0.113.18", 8180)
+            .await
+            .unwrap();
+
+        let service = test_service(&args).await;
+        let js = service
+            .handle(test_request(
+                TestClient::get(format!("http://127.0.0.1:8180/pma_factors.js?id={}", id)),
+                "203.0.113.18",
+                8180,
+            ))
+            .await
+            .take_string()
+            .await
+            .unwrap();
+        assert!(js.contains("const bits = 8;"));
+        let prefix = js
+            .split_once("const prefix = \"")
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(prefix, _)| prefix.to_owned())
+            .unwrap();
+        let uuid = js
+            .split_once("\"id\": \"")
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(uuid, _)| uuid.to_owned())
+            .unwrap();
+        let value = format!("8:{}", prefix);
+        let nonce = (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| helpers::hashcash_solves(&value, nonce).unwrap())
+            .unwrap();
+        let wrong_nonce = (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| !helpers::hashcash_solves(&value, nonce).unwrap())
+            .unwrap();
+
+        let mut statuses = Vec::new();
+        for nonce in [wrong_nonce, nonce] {
+            let res = service
+                .handle(test_request(
+                    TestClient::post("http://127.0.0.1:8180/pma_api").json(
+                        &json_types::HashcashResponse {
+                            r#type: "hashcash".into(),
+                            id: uuid.clone(),
+                            nonce,
+                        },
+                    ),
+                    "203.0.113.18",
+                    8180,
+                ))
+                .await;
+            statuses.push(res.status_code);
+        }
+        let is_allowed = check_is_allowed_sqlite(&args, "203.0.113.18", 8180)
+            .await
+            .unwrap();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(
+            statuses,
+            vec![Some(StatusCode::BAD_REQUEST), Some(StatusCode::OK)]
+        );
+        assert!(is_allowed);
+    }
+
+    #[tokio::test]
+    async fn test_monitor_only() {
+        let args = args::Args {
+            monitor_only: true,
+            auth_request_url: Some("/pma_auth".into()),
+            ..test_sqlite_args("monitor_only")
+        };
+        let state = SharedState::new(&args).await.unwrap();
+        let monitor_only_challenges = state
+            .depot()
+            .get_typed::<MonitorOnlyChallenges>()
+            .unwrap()
+            .clone();
+        let service = Service::new(build_router(&state));
+
+        // No dest url is set, so a forwarded client gets a 502 instead of the
+        // challenge page.
+        let proxied = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.41",
+                8180,
+            ))
+            .await;
+        let auth = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/pma_auth"),
+                "203.0.113.41",
+                8180,
+            ))
+            .await;
+        let issued: i64 = Connection::open(&args.sqlite_db_file)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM ID_TO_PORT", (), |row| row.get(0))
+            .unwrap();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(proxied.status_code, Some(StatusCode::BAD_GATEWAY));
+        assert_eq!(auth.status_code, Some(StatusCode::OK));
+        assert_eq!(monitor_only_challenges.count(), 2);
+        assert_eq!(issued, 0);
+    }
+
+    #[tokio::test]
+    async fn test_challenge_rate() {
+        let args = args::Args {
+            challenge_rate: Some((2, 60)),
+            ..test_sqlite_args("challenge_rate")
+        };
+        let service = test_service(&args).await;
+
+        let mut responses = Vec::new();
+        for ip in [
+            "203.0.113.71",
+            "203.0.113.71",
+            "203.0.113.71",
+            "203.0.113.72",
+        ] {
+            responses.push(
+                service
+                    .handle(test_request(
+                        TestClient::get("http://127.0.0.1:8180/"),
+                        ip,
+                        8180,
+                    ))
+                    .await,
+            );
+        }
+        let issued: i64 = Connection::open(&args.sqlite_db_file)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM ID_TO_PORT", (), |row| row.get(0))
+            .unwrap();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(responses[0].status_code, Some(StatusCode::OK));
+        assert_eq!(responses[1].status_code, Some(StatusCode::OK));
+        assert_eq!(
+            responses[2].status_code,
+            Some(StatusCode::TOO_MANY_REQUESTS)
+        );
+        assert_eq!(responses[2].headers().get("retry-after").unwrap(), "30");
+        assert_eq!(responses[3].status_code, Some(StatusCode::OK));
+        assert_eq!(issued, 3);
+    }
+
+    #[tokio::test]
+    async fn test_record_replay_traffic() {
+        let traffic_file =
+            std::env::temp_dir().join(format!("pma_test_traffic_{}.jsonl", std::process::id()));
+        std::fs::remove_file(&traffic_file).ok();
+        let args = args::Args {
+            record_traffic: Some(traffic_file.clone()),
+            ..test_sqlite_args("record_replay_traffic")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        add_allowed_sqlite(&args, "203.0.113.61", 8180)
+            .await
+            .unwrap();
+        let state = SharedState::new(&args).await.unwrap();
+        state
+            .depot()
+            .get_typed::<traffic::TrafficRecorder>()
+            .unwrap()
+            .spawn_writer()
+            .unwrap();
+        let service = Service::new(build_router(&state));
+
+        for ip in ["203.0.113.61", "203.0.113.62"] {
+            service
+                .handle(test_request(
+                    TestClient::get("http://127.0.0.1:8180/page?session=secret")
+                        .add_header("cookie", "session=secret", true)
+                        .add_header("user-agent", "Mozilla/5.0", true),
+                    ip,
+                    8180,
+                ))
+                .await;
+        }
+        let mut records: Vec<json_types::TrafficRecord> = Vec::new();
+        for _ in 0..100 {
+            records = std::fs::read_to_string(&traffic_file)
+                .unwrap_or_default()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            if records.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut depot = Depot::new();
+        depot.insert_typed(Arc::new(args.clone()));
+        depot.insert_typed(CachedAllow::new());
+        let report = traffic::replay(&depot, &traffic_file).await.unwrap();
+        std::fs::remove_file(&traffic_file).ok();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].decision, json_types::ClientDecision::Allow);
+        assert_eq!(records[1].decision, json_types::ClientDecision::Challenge);
+        assert_eq!(records[1].path, "/page");
+        assert!(
+            records[1]
+                .headers
+                .contains(&("user-agent".into(), "Mozilla/5.0".into()))
+        );
+        assert!(
+            records[1]
+                .headers
+                .iter()
+                .all(|(name, _)| name != "cookie" && name != "x-real-ip")
+        );
+        assert_eq!(report.requests, 2);
+        assert!(report.mismatches.is_empty());
+    }
+
+    #[cfg(feature = "policy-script")]
+    #[tokio::test]
+    async fn test_policy_script() {
+        let script_file = std::env::temp_dir().join(format!(
+            "pma_test_policy_script_{}.rhai",
+            std::process::id()
+        ));
+        std::fs::write(
+            &script_file,
+            r#"if path.starts_with("/private") { "deny" } else { "allow" }"#,
+        )
+        .unwrap();
+        let args = args::Args {
+            policy_script: Some(script_file.clone()),
+            ..test_sqlite_args("policy_script")
+        };
+        let service = test_service(&args).await;
+        std::fs::remove_file(&script_file).ok();
+
+        let denied = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/private/notes"),
+                "203.0.113.51",
+                8180,
+            ))
+            .await;
+        // No dest url is set, so an allowed client gets a 502 instead of the
+        // challenge page.
+        let allowed = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/public"),
+                "203.0.113.51",
+                8180,
+            ))
+            .await;
+        let issued: i64 = Connection::open(&args.sqlite_db_file)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM ID_TO_PORT", (), |row| row.get(0))
+            .unwrap();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(denied.status_code, Some(StatusCode::FORBIDDEN));
+        assert_eq!(allowed.status_code, Some(StatusCode::BAD_GATEWAY));
+        assert_eq!(issued, 0);
+    }
+
+    #[cfg(feature = "policy-script")]
+    #[tokio::test]
+    async fn test_policy_script_auth_request() {
+        let args = args::Args {
+            auth_request_url: Some("/pma_auth".into()),
+            ..test_sqlite_args("policy_script_auth_request")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        add_allowed_sqlite(&args, "203.0.113.52", 8180)
+            .await
+            .unwrap();
+        let script_file = std::env::temp_dir().join(format!(
+            "pma_test_policy_script_auth_request_{}.rhai",
+            std::process::id()
+        ));
+        std::fs::write(&script_file, r#""deny""#).unwrap();
+        let service = test_service(&args::Args {
+            policy_script: Some(script_file.clone()),
+            ..args.clone()
+        })
+        .await;
+        std::fs::remove_file(&script_file).ok();
+
+        // The allow row doesn't matter once the script denies the client.
+        let res = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/pma_auth"),
+                "203.0.113.52",
+                8180,
+            ))
+            .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+        assert_eq!(res.status_code, Some(StatusCode::FORBIDDEN));
+    }
+
+    #[tokio::test]
+    async fn test_trusted_proxy() {
+        let args = args::Args {
+            trusted_proxy_cidrs: vec![helpers::parse_ip_net("10.0.0.0/8").unwrap()],
+            auth_request_url: Some("/pma_auth".into()),
+            ..test_sqlite_args("trusted_proxy")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        add_allowed_sqlite(&args, "203.0.113.8", 8180)
+            .await
+            .unwrap();
+        add_allowed_sqlite(&args, "198.51.100.8", 8180)
+            .await
+            .unwrap();
+        let service = test_service(&args).await;
+
+        let mut statuses = Vec::new();
+        for (ip, remote_addr) in [
+            ("203.0.113.8", [10, 0, 0, 5]),
+            ("203.0.113.9", [10, 0, 0, 5]),
+            ("203.0.113.8", [198, 51, 100, 9]),
+            ("203.0.113.9", [198, 51, 100, 8]),
+        ] {
+            let mut req = test_request(TestClient::get("http://127.0.0.1:8180/pma_auth"), ip, 8180);
+            *req.remote_addr_mut() = std::net::SocketAddr::from((remote_addr, 4321)).into();
+            statuses.push(service.handle(req).await.status_code);
+        }
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        // A trusted peer is identified by the header, any other by its own
+        // address whatever the header says.
+        assert_eq!(
+            statuses,
+            [
+                Some(StatusCode::OK),
+                Some(StatusCode::UNAUTHORIZED),
+                Some(StatusCode::UNAUTHORIZED),
+                Some(StatusCode::OK),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trust_loopback() {
+        let args = args::Args {
+            trust_loopback: true,
+            ..test_sqlite_args("trust_loopback")
+        };
+        let request = |ip: &str, local_addr: [u8; 4], remote_addr: [u8; 4]| {
+            let mut req = test_request(TestClient::get("http://127.0.0.1:8180/"), ip, 8180);
+            *req.local_addr_mut() = std::net::SocketAddr::from((local_addr, 8180)).into();
+            *req.remote_addr_mut() = std::net::SocketAddr::from((remote_addr, 4321)).into();
+            req
+        };
+
+        // No dest url is set, so a trusted client gets a 502 instead of the
+        // challenge page.
+        let service = test_service(&args).await;
+        let trusted = service
+            .handle(request("::1", [127, 0, 0, 1], [127, 0, 0, 1]))
+            .await;
+        let spoofed = service
+            .handle(request("127.0.0.1", [192, 0, 2, 1], [198, 51, 100, 9]))
+            .await;
+        let proxied = service
+            .handle(request("198.51.100.9", [127, 0, 0, 1], [127, 0, 0, 1]))
+            .await;
+        let disabled = test_service(&args::Args {
+            trust_loopback: false,
+            ..args.clone()
+        })
+        .await
+        .handle(request("127.0.0.1", [127, 0, 0, 1], [127, 0, 0, 1]))
+        .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(trusted.status_code, Some(StatusCode::BAD_GATEWAY));
+        assert_eq!(spoofed.status_code, Some(StatusCode::OK));
+        assert_eq!(proxied.status_code, Some(StatusCode::OK));
+        assert_eq!(disabled.status_code, Some(StatusCode::OK));
+    }
+
+    #[tokio::test]
+    async fn test_canonical_client_ip() {
+        let args = test_sqlite_args("canonical_client_ip");
+        let service = test_service(&args).await;
+
+        add_allowed_sqlite(&args, "2001:db8::39", 8180)
+            .await
+            .unwrap();
+        // No dest url is set, so an allowed client gets a 502 instead of the
+        // challenge page.
+        let mut statuses = Vec::new();
+        for ip in [
+            "2001:db8::39",
+            "2001:0DB8:0:0:0:0:0:0039",
+            "[2001:db8::39]:4321",
+            "2001:db8::40",
+            "not-an-ip",
+        ] {
+            let res = service
+                .handle(test_request(
+                    TestClient::get("http://127.0.0.1:8180/"),
+                    ip,
+                    8180,
+                ))
+                .await;
+            statuses.push(res.status_code);
+        }
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(statuses[..3], [Some(StatusCode::BAD_GATEWAY); 3]);
+        assert_eq!(statuses[3], Some(StatusCode::OK));
+        assert_ne!(statuses[4], Some(StatusCode::OK));
+        assert_ne!(statuses[4], Some(StatusCode::BAD_GATEWAY));
+    }
+
+    #[test]
+    fn test_bot_signals() {
+        let args = args::Args {
+            factors: Some(4),
+            factors_max: Some(12),
+            datacenter_cidrs: vec![helpers::parse_ip_net("203.0.113.37").unwrap()],
+            ..Default::default()
+        };
+        let mut depot = Depot::new();
+        depot.insert_typed(Arc::new(args));
+
+        let clean = TestClient::get("http://127.0.0.1:8180/pma_factors.js")
+            .add_header("user-agent", "Mozilla/5.0", true)
+            .build();
+        let suspicious = TestClient::get("http://127.0.0.1:8180/pma_factors.js")
+            .add_header("user-agent", "", true)
+            .build();
+
+        assert_eq!(challenge_quads(&depot, &clean, "203.0.113.38").unwrap(), 4);
+        assert_eq!(challenge_quads(&depot, &clean, "203.0.113.37").unwrap(), 6);
+        assert_eq!(
+            challenge_quads(&depot, &suspicious, "203.0.113.37").unwrap(),
+            8
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_challenge() {
+        let args = args::Args {
+            factors: Some(1),
+            json_challenge: true,
+            ..test_sqlite_args("json_challenge")
+        };
+        let service = test_service(&args).await;
+
+        // Each id is only good for one worker js request.
+        let mut js_urls = Vec::new();
+        for _ in 0..2 {
+            let id = init_id_to_port_sqlite(&args, "203.0.113.81", 8180)
+                .await
+                .unwrap();
+            js_urls.push(format!("http://127.0.0.1:8180/pma_factors.js?id={}", id));
+        }
+        let js = service
+            .handle(test_request(
+                TestClient::get(&js_urls[0]),
+                "203.0.113.81",
+                8180,
+            ))
+            .await
+            .take_string()
+            .await
+            .unwrap();
+        let challenge: json_types::FactorsChallenge = service
+            .handle(test_request(
+                TestClient::get(&js_urls[1]).add_header(
+                    "accept",
+                    "application/json, text/plain;q=0.5",
+                    true,
+                ),
+                "203.0.113.81",
+                8180,
+            ))
+            .await
+            .take_json()
+            .await
+            .unwrap();
+
+        assert!(js.contains("revb64_long_div_mod"));
+        assert_eq!(challenge.r#type, "factors");
+        assert_eq!(challenge.api_url, "/pma_api");
+        assert_eq!(
+            challenge.encoding,
+            json_types::ValueEncoding {
+                name: "pma-b64-le".into(),
+                radix: 64,
+                alphabet: "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/".into(),
+                least_significant_first: true,
+            }
+        );
+
+        // Solve it from the metadata alone, as a native client would.
+        let digits: Vec<u8> = challenge
+            .value
+            .chars()
+            .map(|c| challenge.encoding.alphabet.find(c).unwrap() as u8)
+            .collect();
+        let mut value =
+            num_bigint::BigUint::from_radix_le(&digits, challenge.encoding.radix).unwrap();
+        let mut terms = Vec::new();
+        for prime in (2u32..=59).filter(|n| helpers::is_prime(*n as u16)) {
+            let mut count = 0;
+            while (&value % prime) == num_bigint::BigUint::from(0u32) {
+                value /= prime;
+                count += 1;
+            }
+            if count > 0 {
+                terms.push(format!("{}x{}", prime, count));
+            }
+        }
+        assert_eq!(value, num_bigint::BigUint::from(1u32));
+        let answer = service
+            .handle(test_request(
+                TestClient::post(format!("http://127.0.0.1:8180{}", challenge.api_url)).json(
+                    &json_types::FactorsResponse {
+                        r#type: challenge.r#type,
+                        id: challenge.id,
+                        factors: terms.join(" "),
+                    },
+                ),
+                "203.0.113.81",
+                8180,
+            ))
+            .await;
+        let is_allowed = check_is_allowed_sqlite(&args, "203.0.113.81", 8180)
+            .await
+            .unwrap();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(answer.status_code, Some(StatusCode::OK));
+        assert!(is_allowed);
+    }
+
+    #[cfg(feature = "memory")]
+    #[tokio::test]
+    async fn test_memory_db() {
+        let args = args::Args {
+            memory_db: true,
+            challenge_type: args::ChallengeType::Hashcash,
+            hashcash_bits: 8,
+            ..test_sqlite_args("memory_db")
+        };
+        let state = SharedState::new(&args).await.unwrap();
+        let depot = state.depot();
+        let service = Service::new(build_router(&state));
+
+        let page = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.34",
+                8180,
+            ))
+            .await
+            .take_string()
+            .await
+            .unwrap();
+        let id = page
+            .split_once("/pma_factors.js?id=")
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(id, _)| id.to_owned())
+            .unwrap();
+        let js = service
+            .handle(test_request(
+                TestClient::get(format!("http://127.0.0.1:8180/pma_factors.js?id={}", id)),
+                "203.0.113.34",
+                8180,
+            ))
+            .await
+            .take_string()
+            .await
+            .unwrap();
+        let prefix = js
+            .split_once("const prefix = \"")
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(prefix, _)| prefix.to_owned())
+            .unwrap();
+        let uuid = js
+            .split_once("\"id\": \"")
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(uuid, _)| uuid.to_owned())
+            .unwrap();
+        let value = format!("8:{}", prefix);
+        let nonce = (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| helpers::hashcash_solves(&value, nonce).unwrap())
+            .unwrap();
+        let answered = service
+            .handle(test_request(
+                TestClient::post("http://127.0.0.1:8180/pma_api").json(
+                    &json_types::HashcashResponse {
+                        r#type: "hashcash".into(),
+                        id: uuid,
+                        nonce,
+                    },
+                ),
+                "203.0.113.34",
+                8180,
+            ))
+            .await;
+        // No dest url is set, so the now allowed client gets a 502.
+        let allowed = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.34",
+                8180,
+            ))
+            .await;
+
+        assert_eq!(answered.status_code, Some(StatusCode::OK));
+        assert_eq!(allowed.status_code, Some(StatusCode::BAD_GATEWAY));
+        assert_eq!(
+            recently_allowed(&depot, Duration::from_secs(60))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(!args.sqlite_db_file.exists());
+    }
+
+    #[tokio::test]
+    async fn test_solved_webhook() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mock = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mock_addr = mock.local_addr().unwrap();
+        let mock_task = tokio::spawn(async move {
+            let (mut stream, _) = mock.accept().await.unwrap();
+            let mut request: Vec<u8> = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !String::from_utf8_lossy(&request).contains("\"validate_ms\"") {
+                let read = stream.read(&mut buf).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&request).into_owned()
+        });
+
+        let args = args::Args {
+            solved_webhook: Some(format!("http://{}/solved", mock_addr)),
+            ..test_sqlite_args("solved_webhook")
+        };
+        let state = SharedState::new(&args).await.unwrap();
+        let id = store_factors_challenge(&state.depot(), "203.0.113.8", 8180, "M", "2x2 3x1", None)
+            .await
+            .unwrap();
+
+        state
+            .depot()
+            .get_typed::<webhook::SolvedWebhook>()
+            .unwrap()
+            .spawn_sender()
+            .unwrap();
+        let service = Service::new(build_router(&state));
+        let mut res = service
+            .handle(test_request(
+                TestClient::post("http://127.0.0.1:8180/pma_api").json(
+                    &json_types::FactorsResponse {
+                        r#type: "factors".into(),
+                        id,
+                        factors: "2x2 3x1".into(),
+                    },
+                ),
+                "203.0.113.8",
+                8180,
+            ))
+            .await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        assert_eq!(res.take_string().await.unwrap(), r#"{"status":"ok"}"#);
+
+        let request = tokio::time::timeout(Duration::from_secs(10), mock_task)
+            .await
+            .expect("Webhook should be called")
+            .unwrap();
+        assert!(request.starts_with("POST /solved "));
+        assert!(request.contains("\"ip\":\"203.0.113.8\""));
+        assert!(request.contains("\"port\":8180"));
+    }
+}
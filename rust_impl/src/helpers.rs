@@ -14,9 +14,19 @@
 // OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
 // PERFORMANCE OF THIS SOFTWARE.
 
-use crate::error::Error;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
 
-pub fn validate_client_response(resp: &str) -> Result<(), Error> {
+use ipnet::IpNet;
+use num_bigint::BigUint;
+use salvo::http::{HeaderMap, StatusCode};
+use sha2::{Digest, Sha256};
+
+use crate::{args::Args, constants, error::Error, json_types::ChallengeResponse};
+
+/// Parses a "2x5 3x9 ..." client response into its (base, count) pairs, in
+/// the order given.
+pub fn validate_client_response(resp: &str) -> Result<Vec<(u64, u64)>, Error> {
     #[derive(PartialEq, Debug)]
     enum State {
         Num,
@@ -24,26 +34,26 @@ pub fn validate_client_response(resp: &str) -> Result<(), Error> {
         Whitespace,
     }
 
-    let mut state = State::Num;
+    fn push_digit(num: u64, c: char) -> Result<u64, Error> {
+        c.to_digit(10)
+            .and_then(|digit| num.checked_mul(10)?.checked_add(digit as u64))
+            .ok_or(Error::Generic(
+                "Failed to parse digit in client response".into(),
+            ))
+    }
+
+    let mut state = State::Whitespace;
     let mut num: u64 = 0;
-    let mut max_num: u64 = 0;
+    let mut count: Option<u64> = None;
+    let mut pairs: Vec<(u64, u64)> = Vec::new();
 
     for c in resp.chars() {
         match &state {
             State::Num => {
                 if c.is_ascii_digit() {
-                    num = num * 10
-                        + c.to_digit(10).ok_or(Error::Generic(
-                            "Failed to parse digit in client response".into(),
-                        ))? as u64;
+                    num = push_digit(num, c)?;
                 } else if c == 'x' {
-                    if max_num >= num {
-                        return Err(Error::Generic(
-                            "Invalid client response, numbers out of order".into(),
-                        ));
-                    }
-                    max_num = num;
-                    num = 0;
+                    count = None;
                     state = State::Amt;
                 } else {
                     return Err(Error::Generic(
@@ -53,8 +63,11 @@ pub fn validate_client_response(resp: &str) -> Result<(), Error> {
             }
             State::Amt => {
                 if c.is_ascii_digit() {
-                    // Intentionally left blank.
-                } else if c.is_whitespace() {
+                    count = Some(push_digit(count.unwrap_or(0), c)?);
+                } else if let Some(count) = count
+                    && c.is_whitespace()
+                {
+                    pairs.push((num, count));
                     state = State::Whitespace;
                 } else {
                     return Err(Error::Generic(
@@ -67,9 +80,7 @@ pub fn validate_client_response(resp: &str) -> Result<(), Error> {
                     // Intentionally left blank.
                 } else if c.is_ascii_digit() {
                     state = State::Num;
-                    num = c.to_digit(10).ok_or(Error::Generic(
-                        "Failed to parse digit in client response".into(),
-                    ))? as u64;
+                    num = push_digit(0, c)?;
                 } else {
                     return Err(Error::Generic(
                         "Invalid state parsing client response".into(),
@@ -78,15 +89,753 @@ pub fn validate_client_response(resp: &str) -> Result<(), Error> {
             }
         }
     }
-    if state != State::Amt {
+    match (state, count) {
+        (State::Amt, Some(count)) => pairs.push((num, count)),
+        (State::Whitespace, _) if !pairs.is_empty() => (),
+        _ => {
+            return Err(Error::Generic(
+                "Invalid end state parsing client response".into(),
+            ));
+        }
+    }
+
+    Ok(pairs)
+}
+
+/// Rewrites a "2x5 3x9 ..." client response in the form generated factors
+/// are stored in: bases ascending, each base once and terms separated by one
+/// space, so "3x1  2x1 2x2" becomes "2x3 3x1".
+pub fn canonicalize_factors(resp: &str) -> Result<String, Error> {
+    let mut counts: BTreeMap<u64, u64> = BTreeMap::new();
+    for (base, count) in validate_client_response(resp)? {
+        if base < 2 {
+            return Err(Error::Generic("Invalid base in client response".into()));
+        }
+        let total = counts.entry(base).or_default();
+        *total = total
+            .checked_add(count)
+            .ok_or(Error::Generic("Invalid count in client response".into()))?;
+    }
+
+    let terms: Vec<String> = counts
+        .into_iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(base, count)| format!("{}x{}", base, count))
+        .collect();
+    if terms.is_empty() {
+        return Err(Error::Generic("Empty client response".into()));
+    }
+
+    Ok(terms.join(" "))
+}
+
+/// Checks that the "NxM" terms of a client response multiply back to the
+/// challenge value. The value is base64 with the least significant digit
+/// first, as handed to the factors worker.
+pub fn factors_multiply_to(value: &str, factors: &str) -> Result<bool, Error> {
+    let value = crate::ffi::decode_challenge_value(value)?;
+
+    let mut product = BigUint::from(1u32);
+    for term in factors.split_whitespace() {
+        let (base, count) = term
+            .split_once('x')
+            .ok_or(Error::Generic("Invalid term in client response".into()))?;
+        let base: u32 = base
+            .parse()
+            .map_err(|_| Error::Generic("Invalid base in client response".into()))?;
+        let count: u32 = count
+            .parse()
+            .map_err(|_| Error::Generic("Invalid count in client response".into()))?;
+        product *= BigUint::from(base).pow(count);
+        if product > value {
+            return Ok(false);
+        }
+    }
+
+    Ok(product == value)
+}
+
+/// Starts a blake3 hasher with the "--hash-salt" as its domain separator.
+pub fn salted_hasher(args: &Args) -> blake3::Hasher {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(args.hash_salt.as_bytes());
+    hasher
+}
+
+/// Cuts a User-Agent to at most MAX_USER_AGENT_LEN bytes, on a char boundary.
+pub fn truncate_user_agent(user_agent: &str) -> &str {
+    let mut end = user_agent.len().min(constants::MAX_USER_AGENT_LEN);
+    while !user_agent.is_char_boundary(end) {
+        end -= 1;
+    }
+    &user_agent[..end]
+}
+
+/// A hashcash nonce is the decimal counter the worker stopped at.
+pub fn validate_hashcash_nonce(nonce: &str) -> Result<(), Error> {
+    if nonce.is_empty() || nonce.len() > 20 || !nonce.bytes().all(|c| c.is_ascii_digit()) {
         return Err(Error::Generic(
-            "Invalid end state parsing client response".into(),
+            "Invalid hashcash nonce in client response".into(),
         ));
     }
 
     Ok(())
 }
 
+/// Checks the form of a client answer and returns the factors its challenge
+/// was stored under: the canonical factors, or HASHCASH_FACTORS_TAG.
+pub fn answer_factors(response: &ChallengeResponse) -> Result<String, Error> {
+    match response {
+        ChallengeResponse::Factors(factors) => canonicalize_factors(&factors.factors),
+        ChallengeResponse::Hashcash(hashcash) => {
+            validate_hashcash_nonce(&hashcash.nonce)?;
+            Ok(constants::HASHCASH_FACTORS_TAG.to_owned())
+        }
+    }
+}
+
+/// Checks that SHA-256 of the prefix in a hashcash challenge value
+/// ("<bits>:<prefix>") followed by "nonce" starts with "bits" zero bits.
+pub fn hashcash_solves(value: &str, nonce: &str) -> Result<bool, Error> {
+    let (bits, prefix) = value
+        .split_once(':')
+        .ok_or(Error::Generic("Invalid hashcash challenge value".into()))?;
+    let bits: u32 = bits.parse()?;
+
+    let digest = Sha256::new()
+        .chain_update(prefix.as_bytes())
+        .chain_update(nonce.as_bytes())
+        .finalize();
+    let mut zero_bits: u32 = 0;
+    for byte in digest.iter() {
+        zero_bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+
+    Ok(zero_bits >= bits)
+}
+
+/// Checks a client's answer against the stored challenge value.
+pub fn verify_challenge_answer(value: &str, response: &ChallengeResponse) -> Result<bool, Error> {
+    match response {
+        ChallengeResponse::Factors(factors) => factors_multiply_to(value, &factors.factors),
+        ChallengeResponse::Hashcash(hashcash) => hashcash_solves(value, &hashcash.nonce),
+    }
+}
+
+/// Normalizes a dest url so equivalent spellings forward identically: the
+/// scheme must be http or https, the host is lowercased, and trailing slashes
+/// are dropped since the request path (starting with "/") is appended to it.
+/// A query is kept, and merged with the client's by join_dest_url.
+pub fn canonicalize_dest_url(url: &str) -> Result<String, Error> {
+    let mut parsed = reqwest::Url::parse(url)
+        .map_err(|e| Error::Generic(format!("Invalid dest url \"{}\": {}", url, e)))?;
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return Err(Error::Generic(format!(
+            "Dest url \"{}\" must be http or https",
+            url
+        )));
+    } else if parsed.host_str().is_none_or(|host| host.is_empty()) {
+        return Err(Error::Generic(format!("Dest url \"{}\" has no host", url)));
+    } else if parsed.fragment().is_some() {
+        return Err(Error::Generic(format!(
+            "Dest url \"{}\" can't have a fragment",
+            url
+        )));
+    }
+
+    let query = parsed
+        .query()
+        .filter(|query| !query.is_empty())
+        .map(str::to_owned);
+    parsed.set_query(None);
+    let mut canonical = parsed.as_str().trim_end_matches('/').to_owned();
+    if let Some(query) = query {
+        canonical.push('?');
+        canonical += &query;
+    }
+
+    Ok(canonical)
+}
+
+/// Normalizes a Host header (or "--host-to-dest-url" host) for matching: any
+/// port is dropped, the name is lowercased, unicode names become their
+/// punycode "xn--" form, and a trailing dot is dropped. So "Example.COM",
+/// "example.com:8080" and "example.com." all become "example.com". Returns None
+/// if it isn't a valid host.
+pub fn normalize_host(host: &str) -> Option<String> {
+    let host = host.trim();
+    if host.is_empty() || host.contains(['/', '\\', '@', '?', '#']) {
+        return None;
+    }
+
+    let parsed = reqwest::Url::parse(&format!("http://{}/", host)).ok()?;
+    parsed
+        .host_str()
+        .map(|host| host.trim_end_matches('.').to_owned())
+        .filter(|host| !host.is_empty())
+}
+
+/// Appends the client's path and query to a dest url. The dest's own query
+/// comes first, so "https://host/base?token=x" with "/page?foo=bar" becomes
+/// "https://host/base/page?token=x&foo=bar".
+pub fn join_dest_url(dest_url: &str, path_and_query: &str) -> Result<String, Error> {
+    let mut url = reqwest::Url::parse(dest_url)
+        .map_err(|e| Error::Generic(format!("Invalid dest url \"{}\": {}", dest_url, e)))?;
+    let (path, client_query) = match path_and_query.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path_and_query, None),
+    };
+
+    let path = format!("{}{}", url.path().trim_end_matches('/'), path);
+    let query = match (
+        url.query().filter(|query| !query.is_empty()),
+        client_query.filter(|query| !query.is_empty()),
+    ) {
+        (Some(dest_query), Some(client_query)) => Some(format!("{}&{}", dest_query, client_query)),
+        (dest_query, client_query) => dest_query.or(client_query).map(str::to_owned),
+    };
+    url.set_path(&path);
+    url.set_query(query.as_deref());
+
+    Ok(url.into())
+}
+
+/// Parses a CIDR like "10.0.0.0/8" or "2001:db8::/32". A bare address is
+/// treated as a single host range.
+pub fn parse_ip_net(s: &str) -> Result<IpNet, Error> {
+    s.parse()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| Error::Generic(format!("Invalid address or CIDR \"{}\"", s)))
+}
+
+/// Returns true if "addr" is in any of "ranges".
+pub fn is_in_ranges(ranges: &[IpNet], addr: &str) -> bool {
+    match addr.parse::<IpAddr>() {
+        Ok(addr) => ranges.iter().any(|range| range.contains(&addr)),
+        Err(_) => false,
+    }
+}
+
+/// Returns true if "path" matches "glob", where each "*" matches any run of
+/// characters, "/" included.
+pub fn path_matches_glob(glob: &str, path: &str) -> bool {
+    let mut parts = glob.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = path.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No "*", so the whole path must match.
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/// Returns true if "path" matches a "--bypass-path" glob. Paths with "." or
+/// ".." segments, backslashes or percent-encoded dots and slashes never
+/// match, as the dest url could resolve those to a path outside the glob.
+pub fn is_bypass_path(globs: &[String], path: &str) -> bool {
+    if globs.is_empty()
+        || path.contains('\\')
+        || path
+            .split('/')
+            .any(|segment| segment == "." || segment == "..")
+    {
+        return false;
+    }
+    let lowercase = path.to_ascii_lowercase();
+    if ["%2e", "%2f", "%5c"]
+        .iter()
+        .any(|encoded| lowercase.contains(encoded))
+    {
+        return false;
+    }
+
+    globs.iter().any(|glob| path_matches_glob(glob, path))
+}
+
+/// Picks quads from the "--factors-distribution" pairs, each with a chance
+/// of its weight over the total weight.
+pub fn pick_factors_quads(distribution: &[(u64, u64)]) -> Result<u64, Error> {
+    let total: u64 = distribution.iter().map(|(_, weight)| weight).sum();
+    if total == 0 {
+        return Err("Empty factors distribution".into());
+    }
+    let mut buf = [0u8; 8];
+    getrandom::fill(&mut buf)?;
+    let mut roll = u64::from_ne_bytes(buf) % total;
+
+    for (quads, weight) in distribution {
+        if roll < *weight {
+            return Ok(*quads);
+        }
+        roll -= weight;
+    }
+    unreachable!("roll is less than the total weight")
+}
+
+/// Raises "quads" by BOT_SIGNAL_FACTORS_PERCENT for each of "signals", up to
+/// "max" (the "--factors-max"), which also caps quads raised otherwise.
+pub fn factors_quads_for_signals(quads: u64, signals: u64, max: u64) -> u64 {
+    let extra = quads
+        .saturating_mul(signals)
+        .saturating_mul(constants::BOT_SIGNAL_FACTORS_PERCENT)
+        / 100;
+    quads.saturating_add(extra).min(max)
+}
+
+pub fn is_prime(n: u16) -> bool {
+    let n = n as u32;
+    n >= 2
+        && (2..n)
+            .take_while(|d| d * d <= n)
+            .all(|d| !n.is_multiple_of(d))
+}
+
+/// Parses "addr", which may be a bracketed IPv6 address or have a port, and
+/// returns it in canonical form with IPv4-mapped IPv6 as plain IPv4. Client
+/// addresses are stored and compared as strings, so equivalent forms of one
+/// address must become the same string first.
+pub fn canonical_ip(addr: &str) -> Result<String, Error> {
+    let addr = addr.trim();
+    let ip: IpAddr = match addr.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => match addr.parse::<SocketAddr>() {
+            Ok(socket_addr) => socket_addr.ip(),
+            Err(_) => addr
+                .strip_prefix('[')
+                .and_then(|addr| addr.strip_suffix(']'))
+                .and_then(|addr| addr.parse::<IpAddr>().ok())
+                .ok_or(Error::Generic(format!(
+                    "Invalid client address \"{}\"",
+                    addr
+                )))?,
+        },
+    };
+
+    Ok(ip.to_canonical().to_string())
+}
+
+/// Returns the value of the first header in `names` that is present and not
+/// empty, checked in the given order. If only empty ones are present, it's an
+/// error rather than None, so the proxy's own addr isn't used for the client.
+pub fn real_ip_from_headers<'a>(
+    headers: &'a HeaderMap,
+    names: &[String],
+) -> Result<Option<&'a str>, Error> {
+    let mut saw_empty = false;
+    for name in names.iter() {
+        if let Some(value) = headers.get(name.as_str()) {
+            let value = value.to_str()?.trim();
+            if !value.is_empty() {
+                return Ok(Some(value));
+            }
+            saw_empty = true;
+        }
+    }
+
+    if saw_empty {
+        Err("Failed to get client addr (invalid header)".into())
+    } else {
+        Ok(None)
+    }
+}
+
+/// Maps a failure from forwarding to the dest url to the status code sent to
+/// the client: 504 on timeout, 502 if the dest could not be reached or sent a
+/// broken body, and 500 for anything else, such as a request that could not be
+/// built from the client's input.
+pub fn upstream_error_status(error: &Error) -> StatusCode {
+    match error {
+        Error::Reqwest(e) if e.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+        Error::Reqwest(e) if e.is_connect() || e.is_body() || e.is_decode() => {
+            StatusCode::BAD_GATEWAY
+        }
+        Error::IO(e) if e.kind() == std::io::ErrorKind::TimedOut => StatusCode::GATEWAY_TIMEOUT,
+        Error::WebSocket(_) => StatusCode::BAD_GATEWAY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// The wait before "--backend-retries" retry number "attempt", counting from
+/// 0: BACKEND_RETRY_BASE_DELAY_MS doubled each time, up to
+/// BACKEND_RETRY_MAX_DELAY_MS.
+pub fn backend_retry_delay(attempt: u32) -> std::time::Duration {
+    std::time::Duration::from_millis(
+        constants::BACKEND_RETRY_BASE_DELAY_MS
+            .saturating_mul(1u64.checked_shl(attempt).unwrap_or(u64::MAX))
+            .min(constants::BACKEND_RETRY_MAX_DELAY_MS),
+    )
+}
+
+/// Escapes "text" for use in HTML element content or a quoted attribute.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The "{NOSCRIPT}" of the challenge page: the "--no-js-message" and
+/// "--no-js-link" in a <noscript> block, or nothing if there's no message.
+pub fn noscript_block(args: &Args) -> String {
+    let Some(message) = &args.no_js_message else {
+        return String::new();
+    };
+    match &args.no_js_link {
+        Some(link) => format!(
+            r#"<noscript><p class="center">{} <a href="{}">{}</a></p></noscript>"#,
+            escape_html(message),
+            escape_html(link),
+            escape_html(link)
+        ),
+        None => format!(
+            r#"<noscript><p class="center">{}</p></noscript>"#,
+            escape_html(message)
+        ),
+    }
+}
+
+/// Returns the configured page for an upstream failure status, or
+/// HTML_UPSTREAM_ERROR if there is none.
+pub fn upstream_error_page(args: &Args, status: StatusCode) -> String {
+    let (page, message) = match status {
+        StatusCode::BAD_GATEWAY => (
+            args.bad_gateway_page.as_deref(),
+            "The site could not be reached. Please try again shortly.",
+        ),
+        StatusCode::GATEWAY_TIMEOUT => (
+            args.gateway_timeout_page.as_deref(),
+            "The site took too long to respond. Please try again shortly.",
+        ),
+        _ => (
+            args.internal_error_page.as_deref(),
+            "Something went wrong while loading the site.",
+        ),
+    };
+
+    match page {
+        Some(page) => page.to_owned(),
+        None => constants::HTML_UPSTREAM_ERROR
+            .replace("{STATUS}", &status.to_string())
+            .replacen("{MESSAGE}", message, 1),
+    }
+}
+
+/// With "--maintenance-html" or "--maintenance-status", the status and page
+/// served in place of an upstream failure "status" that means the dest url is
+/// down. None for other failures, or without either arg.
+pub fn maintenance_response(args: &Args, status: StatusCode) -> Option<(StatusCode, String)> {
+    if args.maintenance_page.is_none() && args.maintenance_status.is_none() {
+        return None;
+    }
+    if status != StatusCode::BAD_GATEWAY && status != StatusCode::GATEWAY_TIMEOUT {
+        return None;
+    }
+
+    let status = args
+        .maintenance_status
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+    let page = match &args.maintenance_page {
+        Some(page) => page.to_owned(),
+        None => constants::HTML_UPSTREAM_ERROR
+            .replace("{STATUS}", &status.to_string())
+            .replacen(
+                "{MESSAGE}",
+                "The site is down for maintenance. Please try again shortly.",
+                1,
+            ),
+    };
+    Some((status, page))
+}
+
+/// Seconds to send in "Retry-After" for an upstream failure status. Only the
+/// dest url being unreachable or slow is worth retrying.
+pub fn upstream_retry_after(status: StatusCode) -> Option<u64> {
+    match status {
+        StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT => {
+            Some(constants::UPSTREAM_RETRY_AFTER_SECS)
+        }
+        _ => None,
+    }
+}
+
+/// Response headers that may be sent more than once, so every value from the
+/// dest url is forwarded. Any other header only forwards its first value.
+const MULTI_VALUE_RESPONSE_HEADERS: [&str; 7] = [
+    "set-cookie",
+    "www-authenticate",
+    "link",
+    "vary",
+    "cache-control",
+    "via",
+    "warning",
+];
+
+/// Returns true for a random "rate" fraction of calls.
+pub fn sampled(rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    } else if rate <= 0.0 {
+        return false;
+    }
+
+    let mut buf = [0u8; 8];
+    if getrandom::fill(&mut buf).is_err() {
+        return true;
+    }
+    // The top 53 bits fit exactly in an f64 in [0, 1).
+    ((u64::from_le_bytes(buf) >> 11) as f64 / (1u64 << 53) as f64) < rate
+}
+
+/// Headers that only apply to a single connection (RFC 9110 section 7.6.1), so
+/// they're never forwarded in either direction.
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Headers the proxy sets itself on requests to the dest url, plus
+/// "x-pma-token", which is only meant for the proxy.
+const PROXY_SET_REQUEST_HEADERS: [&str; 6] = [
+    "host",
+    "content-length",
+    "x-real-ip",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-pma-token",
+];
+
+/// Returns whether "name" is a hop-by-hop header, either one of
+/// HOP_BY_HOP_HEADERS or one listed in the "connection" header of "headers".
+pub fn is_hop_by_hop_header(headers: &HeaderMap, name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    HOP_BY_HOP_HEADERS.contains(&name.as_str())
+        || headers
+            .get_all("connection")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case(&name))
+}
+
+/// Returns true if the Accept header of "headers" lists application/json.
+pub fn accepts_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("accept")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|media_type| {
+            media_type.split(';').next().is_some_and(|media_type| {
+                media_type.trim().eq_ignore_ascii_case("application/json")
+            })
+        })
+}
+
+/// Returns whether the incoming request header "name" should be copied to the
+/// request to the dest url. Hop-by-hop headers and headers the proxy sets
+/// aren't.
+pub fn is_forwarded_request_header(headers: &HeaderMap, name: &str) -> bool {
+    !PROXY_SET_REQUEST_HEADERS.contains(&name.to_ascii_lowercase().as_str())
+        && !is_hop_by_hop_header(headers, name)
+}
+
+/// Returns whether a dest url response is streamed to the client instead of
+/// buffered. Every response streams if "stream_content_types" is empty,
+/// otherwise those whose type matches one of its "type/subtype" or "type/*"
+/// entries do, as do any longer than STREAM_FALLBACK_BYTES or without a
+/// length.
+pub fn should_stream_response(
+    stream_content_types: &[String],
+    content_type: Option<&str>,
+    content_length: Option<u64>,
+) -> bool {
+    if stream_content_types.is_empty() {
+        return true;
+    }
+
+    let media_type = content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|media_type| media_type.trim().to_ascii_lowercase());
+    let is_stream_type = media_type.is_some_and(|media_type| {
+        stream_content_types
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("/*") {
+                Some(prefix) => media_type
+                    .split_once('/')
+                    .is_some_and(|(type_, _)| prefix == "*" || type_ == prefix),
+                None => *pattern == media_type,
+            })
+    });
+
+    is_stream_type
+        || content_length
+            .is_none_or(|content_length| content_length > constants::STREAM_FALLBACK_BYTES)
+}
+
+/// Copies the headers of "src" to "dst", skipping any header that would take
+/// the total name and value bytes past "max_bytes". Hop-by-hop headers are
+/// dropped, as the response's framing is salvo's own. Headers in
+/// MULTI_VALUE_RESPONSE_HEADERS are appended, others (including
+/// "content-length") are set once so a dest url repeating e.g. "content-type"
+/// can't produce an invalid response. Returns false if any header was skipped.
+pub fn copy_response_headers(
+    src: &HeaderMap,
+    dst: &mut HeaderMap,
+    max_bytes: Option<usize>,
+) -> bool {
+    let mut total: usize = 0;
+    let mut all_copied = true;
+    for k in src.keys() {
+        if is_hop_by_hop_header(src, k.as_str()) {
+            continue;
+        }
+        let is_multi_value = MULTI_VALUE_RESPONSE_HEADERS.contains(&k.as_str());
+        for v in src.get_all(k).iter() {
+            let size = k.as_str().len() + v.len();
+            if let Some(max) = max_bytes
+                && total + size > max
+            {
+                all_copied = false;
+                continue;
+            }
+            total += size;
+            if is_multi_value {
+                dst.append(k, v.clone());
+            } else {
+                dst.insert(k, v.clone());
+                break;
+            }
+        }
+    }
+
+    all_copied
+}
+
+/// Builds the "x-forwarded-for" value sent to the dest url. If "trust" is set,
+/// "client_ip" is appended to the incoming chain, otherwise it replaces it.
+pub fn forwarded_for(headers: &HeaderMap, client_ip: &str, trust: bool) -> Result<String, Error> {
+    let mut chain: Vec<&str> = Vec::new();
+    if trust {
+        for v in headers.get_all("x-forwarded-for").iter() {
+            let v = v
+                .to_str()
+                .map_err(|_| Error::Generic("Invalid x-forwarded-for header".into()))?
+                .trim();
+            if !v.is_empty() {
+                chain.push(v);
+            }
+        }
+    }
+    chain.push(client_ip);
+
+    Ok(chain.join(", "))
+}
+
+/// Lists the listeners with the dest url each one forwards to, along with the
+/// routes served by this software.
+pub fn startup_banner(args: &Args) -> String {
+    let mut banner = String::from("Startup:\n");
+    banner += &format!(
+        "  Default Dest URL: {}\n",
+        args.dest_url.as_deref().unwrap_or("none")
+    );
+
+    banner += "  Listeners:\n";
+    let mut listening_ports: Vec<u16> = Vec::new();
+    for addr_port in args.addr_port_strs.iter() {
+        let port: Option<u16> = addr_port
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok());
+        let dest = port
+            .and_then(|p| args.port_to_dest_urls.get(&p))
+            .map(|dests| dests.join(", "))
+            .or(args.dest_url.clone())
+            .unwrap_or(if args.auth_only {
+                "(auth only)".to_owned()
+            } else {
+                "(no dest url)".to_owned()
+            });
+        if let Some(p) = port {
+            listening_ports.push(p);
+        }
+        banner += &format!("    {} -> {}\n", addr_port, dest);
+    }
+
+    let mut unused: Vec<(&u16, &Vec<String>)> = args
+        .port_to_dest_urls
+        .iter()
+        .filter(|(p, _)| !listening_ports.contains(p))
+        .collect();
+    if !unused.is_empty() {
+        unused.sort();
+        banner += "  Port Mappings without a listener:\n";
+        for (port, dests) in unused {
+            banner += &format!("    {} -> {}\n", port, dests.join(", "));
+        }
+    }
+
+    if !args.host_to_dest_urls.is_empty() {
+        let mut hosts: Vec<(&String, &String)> = args.host_to_dest_urls.iter().collect();
+        hosts.sort();
+        banner += "  Host Mappings:\n";
+        for (host, dest) in hosts {
+            banner += &format!("    {} -> {}\n", host, dest);
+        }
+    }
+
+    banner += "  Routes:\n";
+    banner += &format!("    POST {} : challenge api\n", args.api_url);
+    let mut api_urls: Vec<(&u16, &String)> = args.port_to_api_urls.iter().collect();
+    api_urls.sort();
+    for (port, api_url) in api_urls {
+        banner += &format!("    POST {} : challenge api on port {}\n", api_url, port);
+    }
+    banner += &format!("    GET {} : challenge worker js\n", args.js_factors_url);
+    let mut js_factors_urls: Vec<(&u16, &String)> = args.port_to_js_factors_urls.iter().collect();
+    js_factors_urls.sort();
+    for (port, js_factors_url) in js_factors_urls {
+        banner += &format!(
+            "    GET {} : challenge worker js on port {}\n",
+            js_factors_url, port
+        );
+    }
+    if let Some(auth_request_url) = &args.auth_request_url {
+        banner += &format!("    GET {} : auth request\n", auth_request_url);
+    }
+    banner += "    GET/POST/HEAD {**} : challenge or forward to dest url\n";
+
+    banner
+}
+
 pub struct GenericCleanup<'a, T, F>
 where
     F: Fn(&'a T),
@@ -120,16 +869,587 @@ where
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_is_in_ranges() {
+        let ranges: Vec<IpNet> = ["10.0.0.0/8", "10.1.0.0/16", "192.0.2.5", "2001:db8::/32"]
+            .iter()
+            .map(|cidr| parse_ip_net(cidr).unwrap())
+            .collect();
+
+        assert!(is_in_ranges(&ranges, "10.1.2.3"));
+        assert!(is_in_ranges(&ranges, "10.200.0.1"));
+        assert!(is_in_ranges(&ranges[1..], "10.1.255.255"));
+        assert!(!is_in_ranges(&ranges[1..], "10.2.0.1"));
+        assert!(is_in_ranges(&ranges, "192.0.2.5"));
+        assert!(!is_in_ranges(&ranges, "192.0.2.6"));
+        assert!(is_in_ranges(&ranges, "2001:db8:1::1"));
+        assert!(!is_in_ranges(&ranges, "2001:db9::1"));
+        assert!(!is_in_ranges(&ranges, "not-an-ip"));
+        assert!(!is_in_ranges(&[], "10.1.2.3"));
+
+        assert!(parse_ip_net("10.0.0.0/33").is_err());
+        assert!(parse_ip_net("example.com").is_err());
+    }
+
+    #[test]
+    fn test_factors_quads_for_signals() {
+        assert_eq!(factors_quads_for_signals(100, 0, 300), 100);
+        assert_eq!(factors_quads_for_signals(100, 1, 300), 150);
+        assert_eq!(factors_quads_for_signals(100, 3, 300), 250);
+        assert_eq!(factors_quads_for_signals(100, 3, 200), 200);
+        assert_eq!(factors_quads_for_signals(400, 0, 300), 300);
+    }
+
+    #[test]
+    fn test_salted_hasher() {
+        let hash_with = |hash_salt: &str| {
+            let args = Args {
+                hash_salt: hash_salt.into(),
+                ..Default::default()
+            };
+            let mut hasher = salted_hasher(&args);
+            hasher.update(b"2x2 3x1");
+            hasher.finalize()
+        };
+
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(constants::DEFAULT_HASH_SALT.as_bytes());
+        hasher.update(b"2x2 3x1");
+        assert_eq!(hash_with(constants::DEFAULT_HASH_SALT), hasher.finalize());
+        assert_ne!(
+            hash_with(constants::DEFAULT_HASH_SALT),
+            hash_with("example.org")
+        );
+    }
+
+    #[test]
+    fn test_pick_factors_quads() {
+        let distribution = [(1800, 1), (2200, 2), (2600, 1)];
+        let mut counts: std::collections::HashMap<u64, u64> = Default::default();
+        for _ in 0..10000 {
+            *counts
+                .entry(pick_factors_quads(&distribution).unwrap())
+                .or_default() += 1;
+        }
+
+        assert_eq!(counts.len(), 3);
+        for (quads, weight) in distribution {
+            let expected = 10000 * weight / 4;
+            let observed = counts[&quads];
+            assert!(
+                observed.abs_diff(expected) < expected / 10,
+                "{} quads picked {} times, expected about {}",
+                quads,
+                observed,
+                expected
+            );
+        }
+        assert!(pick_factors_quads(&[]).is_err());
+    }
+
+    #[test]
+    fn test_hashcash_solves() {
+        let nonce = (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| hashcash_solves("8:abcd", nonce).unwrap())
+            .unwrap();
+        let digest = Sha256::digest(format!("abcd{}", nonce));
+        assert_eq!(digest[0], 0);
+
+        assert!(hashcash_solves("0:abcd", "anything").unwrap());
+        assert!(!hashcash_solves("256:abcd", &nonce).unwrap());
+        assert!(hashcash_solves("abcd", &nonce).is_err());
+    }
+
+    #[test]
+    fn test_canonicalize_dest_url() {
+        assert_eq!(
+            canonicalize_dest_url("https://x/").unwrap(),
+            canonicalize_dest_url("HTTPS://X").unwrap()
+        );
+        assert_eq!(canonicalize_dest_url("https://x/").unwrap(), "https://x");
+        assert_eq!(
+            canonicalize_dest_url("http://127.0.0.1:9999/app/").unwrap(),
+            "http://127.0.0.1:9999/app"
+        );
+        assert!(canonicalize_dest_url("ftp://x").is_err());
+        assert!(canonicalize_dest_url("127.0.0.1:9999").is_err());
+        assert_eq!(
+            canonicalize_dest_url("https://x/base/?a=b").unwrap(),
+            "https://x/base?a=b"
+        );
+        assert!(canonicalize_dest_url("https://x/#top").is_err());
+    }
+
+    #[test]
+    fn test_normalize_host() {
+        for host in [
+            "example.com",
+            "Example.COM",
+            "example.com:8080",
+            "EXAMPLE.com.:443",
+        ] {
+            assert_eq!(normalize_host(host).as_deref(), Some("example.com"));
+        }
+        for host in [
+            "xn--bcher-kva.example",
+            "XN--BCHER-KVA.example",
+            "bücher.example",
+        ] {
+            assert_eq!(
+                normalize_host(host).as_deref(),
+                Some("xn--bcher-kva.example")
+            );
+        }
+        assert_eq!(normalize_host("[::1]:8080").as_deref(), Some("[::1]"));
+        assert_eq!(normalize_host("user@example.com"), None);
+        assert_eq!(normalize_host("example.com/path"), None);
+        assert_eq!(normalize_host(""), None);
+    }
+
+    #[test]
+    fn test_join_dest_url() {
+        assert_eq!(
+            join_dest_url("https://host/base?token=x", "/page?foo=bar").unwrap(),
+            "https://host/base/page?token=x&foo=bar"
+        );
+        assert_eq!(
+            join_dest_url("https://host/base?token=x", "/page").unwrap(),
+            "https://host/base/page?token=x"
+        );
+        assert_eq!(
+            join_dest_url("https://host/base", "/page?foo=bar").unwrap(),
+            "https://host/base/page?foo=bar"
+        );
+        assert_eq!(
+            join_dest_url("http://127.0.0.1:9999", "/").unwrap(),
+            "http://127.0.0.1:9999/"
+        );
+        assert!(join_dest_url("not a url", "/").is_err());
+    }
+
+    #[test]
+    fn test_factors_multiply_to() {
+        // "M" is 12.
+        assert!(factors_multiply_to("M", "2x2 3x1").unwrap());
+        assert!(!factors_multiply_to("M", "2x1 3x1").unwrap());
+        assert!(!factors_multiply_to("M", "2x2 3x2").unwrap());
+        // "AB" is 64, the most significant digit comes last.
+        assert!(factors_multiply_to("AB", "2x6").unwrap());
+        assert!(!factors_multiply_to("BA", "2x6").unwrap());
+        assert!(factors_multiply_to("M", "2x2 3").is_err());
+        assert!(factors_multiply_to("M!", "2x2 3x1").is_err());
+    }
+
+    #[test]
+    fn test_bypass_path() {
+        let globs = vec![
+            "/.well-known/acme-challenge/*".to_owned(),
+            "/favicon.ico".to_owned(),
+            "/static/*.css".to_owned(),
+        ];
+
+        for path in [
+            "/.well-known/acme-challenge/abc123",
+            "/.well-known/acme-challenge/",
+            "/favicon.ico",
+            "/static/site.css",
+            "/static/dir/site.css",
+        ] {
+            assert!(is_bypass_path(&globs, path), "{}", path);
+        }
+        for path in [
+            "/.well-known/acme-challenge",
+            "/.well-known/other",
+            "/favicon.ico.bak",
+            "/favicon.icon",
+            "/static/site.js",
+            "/.well-known/acme-challenge/../../admin",
+            "/.well-known/acme-challenge/./x",
+            "/.well-known/acme-challenge/%2e%2e/admin",
+            "/.well-known/acme-challenge/..%2Fadmin",
+            "/.well-known/acme-challenge/..\\admin",
+        ] {
+            assert!(!is_bypass_path(&globs, path), "{}", path);
+        }
+        assert!(!is_bypass_path(&[], "/favicon.ico"));
+
+        assert!(path_matches_glob("*", "/anything"));
+        assert!(path_matches_glob("/a*b*c", "/abc"));
+        assert!(path_matches_glob("/a*b*c", "/a-b-b-c"));
+        assert!(!path_matches_glob("/a*b*c", "/a-c-b"));
+        assert!(!path_matches_glob("/ab*ba", "/aba"));
+    }
+
     #[test]
     fn test_validate() {
         let mut ret = validate_client_response("1x1 2x2 3x3");
         // println!("{:?}", ret);
         assert!(ret.is_ok());
+        // Out of order and repeated bases parse; canonicalize_factors() sorts
+        // and merges them.
         ret = validate_client_response("2x1 1x2 3x3");
         // println!("{:?}", ret);
-        assert!(!ret.is_ok());
+        assert_eq!(ret.unwrap(), vec![(2, 1), (1, 2), (3, 3)]);
         ret = validate_client_response("3x1 3x2 3x3");
         // println!("{:?}", ret);
-        assert!(!ret.is_ok());
+        assert_eq!(ret.unwrap(), vec![(3, 1), (3, 2), (3, 3)]);
+        assert_eq!(
+            validate_client_response("2x1 3x2").unwrap(),
+            vec![(2, 1), (3, 2)]
+        );
+        assert_eq!(
+            validate_client_response("2x12  61x3").unwrap(),
+            vec![(2, 12), (61, 3)]
+        );
+        assert_eq!(
+            validate_client_response(" 2x1\t3x2\n ").unwrap(),
+            vec![(2, 1), (3, 2)]
+        );
+
+        for invalid in [
+            "",
+            "  ",
+            "2x",
+            "2x 3x1",
+            "x1",
+            "2x+1",
+            "2y1",
+            "2x1x1",
+            "99999999999999999999x1",
+        ] {
+            assert!(validate_client_response(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_canonicalize_factors() {
+        assert_eq!(canonicalize_factors("2x1 3x2").unwrap(), "2x1 3x2");
+        assert_eq!(canonicalize_factors("2x12  61x3").unwrap(), "2x12 61x3");
+        assert_eq!(canonicalize_factors(" 2x1\t3x2\n ").unwrap(), "2x1 3x2");
+        assert_eq!(canonicalize_factors("3x1 2x1 2x2").unwrap(), "2x3 3x1");
+        assert_eq!(canonicalize_factors("2x1 2x1 2x1").unwrap(), "2x3");
+        assert_eq!(canonicalize_factors("3x1 3x2 3x3").unwrap(), "3x6");
+        assert_eq!(canonicalize_factors("2x0 5x1").unwrap(), "5x1");
+
+        for invalid in [
+            "", "  ", "2x0", "1x1 2x2", "0x1", "2x", "x1", "2x+1", "2y1", "2x1x1",
+        ] {
+            assert!(canonicalize_factors(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_should_stream_response() {
+        let types = vec!["video/*".to_owned(), "application/octet-stream".to_owned()];
+
+        assert!(should_stream_response(&[], Some("text/html"), Some(10)));
+        assert!(should_stream_response(&types, Some("video/mp4"), Some(10)));
+        assert!(should_stream_response(
+            &types,
+            Some("Application/Octet-Stream; foo=bar"),
+            Some(10)
+        ));
+        assert!(!should_stream_response(
+            &types,
+            Some("text/html; charset=utf-8"),
+            Some(10)
+        ));
+        assert!(!should_stream_response(
+            &types,
+            Some("videos/mp4"),
+            Some(10)
+        ));
+        assert!(!should_stream_response(&types, None, Some(10)));
+        assert!(should_stream_response(&types, Some("text/html"), None));
+        assert!(should_stream_response(
+            &types,
+            Some("text/html"),
+            Some(constants::STREAM_FALLBACK_BYTES + 1)
+        ));
+        assert!(should_stream_response(
+            &["*/*".to_owned()],
+            Some("text/html"),
+            Some(10)
+        ));
+    }
+
+    #[test]
+    fn test_canonical_ip() {
+        for addr in [
+            "::1",
+            "0:0:0:0:0:0:0:1",
+            "0000::0001",
+            "[::1]",
+            "[0:0:0:0:0:0:0:1]:8080",
+        ] {
+            assert_eq!(canonical_ip(addr).unwrap(), "::1", "{}", addr);
+        }
+        for addr in ["2001:DB8::1", "2001:0db8:0000::0001", "[2001:db8::1]:443"] {
+            assert_eq!(canonical_ip(addr).unwrap(), "2001:db8::1", "{}", addr);
+        }
+        for addr in [
+            "203.0.113.5",
+            " 203.0.113.5 ",
+            "203.0.113.5:80",
+            "::ffff:203.0.113.5",
+        ] {
+            assert_eq!(canonical_ip(addr).unwrap(), "203.0.113.5", "{}", addr);
+        }
+
+        assert!(canonical_ip("").is_err());
+        assert!(canonical_ip("example.com").is_err());
+        assert!(canonical_ip("[203.0.113.5").is_err());
+    }
+
+    #[test]
+    fn test_real_ip_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("cf-connecting-ip", "203.0.113.7".parse().unwrap());
+        headers.insert("true-client-ip", "198.51.100.3".parse().unwrap());
+        headers.insert("x-real-ip", "".parse().unwrap());
+
+        let names = vec!["cf-connecting-ip".to_owned()];
+        assert_eq!(
+            real_ip_from_headers(&headers, &names).unwrap(),
+            Some("203.0.113.7")
+        );
+
+        // Missing and empty headers fall through to the next name.
+        let names = vec![
+            "fastly-client-ip".to_owned(),
+            "x-real-ip".to_owned(),
+            "true-client-ip".to_owned(),
+            "cf-connecting-ip".to_owned(),
+        ];
+        assert_eq!(
+            real_ip_from_headers(&headers, &names).unwrap(),
+            Some("198.51.100.3")
+        );
+
+        let names = vec!["fastly-client-ip".to_owned()];
+        assert_eq!(real_ip_from_headers(&headers, &names).unwrap(), None);
+
+        // Only empty ones is an error, not a fall back to the proxy's addr.
+        let names = vec!["fastly-client-ip".to_owned(), "x-real-ip".to_owned()];
+        assert!(real_ip_from_headers(&headers, &names).is_err());
+    }
+
+    #[test]
+    fn test_startup_banner() {
+        let mut args = Args {
+            dest_url: Some("http://127.0.0.1:9999".into()),
+            addr_port_strs: vec!["127.0.0.1:8080".into(), "[::1]:9001".into()],
+            api_url: "/pma_api".into(),
+            js_factors_url: "/pma_factors.js".into(),
+            ..Default::default()
+        };
+        args.port_to_dest_urls
+            .insert(9001, vec!["https://example.com".into()]);
+        args.port_to_dest_urls.insert(
+            9002,
+            vec!["https://example.org".into(), "https://example.net".into()],
+        );
+
+        let banner = startup_banner(&args);
+        assert!(banner.contains("    127.0.0.1:8080 -> http://127.0.0.1:9999\n"));
+        assert!(banner.contains("    [::1]:9001 -> https://example.com\n"));
+        assert!(
+            banner
+                .contains("  Port Mappings without a listener:\n    9002 -> https://example.org, https://example.net\n")
+        );
+        assert!(banner.contains("    POST /pma_api : challenge api\n"));
+        assert!(banner.contains("    GET /pma_factors.js : challenge worker js\n"));
+    }
+
+    #[test]
+    fn test_maintenance_response() {
+        assert!(maintenance_response(&Args::default(), StatusCode::BAD_GATEWAY).is_none());
+
+        let args = Args {
+            maintenance_status: Some(503),
+            ..Default::default()
+        };
+        let (status, page) = maintenance_response(&args, StatusCode::GATEWAY_TIMEOUT).unwrap();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(page.contains("down for maintenance"));
+        assert!(maintenance_response(&args, StatusCode::INTERNAL_SERVER_ERROR).is_none());
+
+        let args = Args {
+            maintenance_page: Some("maintenance".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            maintenance_response(&args, StatusCode::BAD_GATEWAY),
+            Some((StatusCode::SERVICE_UNAVAILABLE, "maintenance".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_noscript_block() {
+        assert_eq!(noscript_block(&Args::default()), "");
+
+        let args = Args {
+            no_js_message: Some("JavaScript is required <here>".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            noscript_block(&args),
+            r#"<noscript><p class="center">JavaScript is required &lt;here&gt;</p></noscript>"#
+        );
+
+        let args = Args {
+            no_js_link: Some("https://example.com/contact?a=1&b=\"2\"".into()),
+            ..args
+        };
+        assert_eq!(
+            noscript_block(&args),
+            r#"<noscript><p class="center">JavaScript is required &lt;here&gt; <a href="https://example.com/contact?a=1&amp;b=&quot;2&quot;">https://example.com/contact?a=1&amp;b=&quot;2&quot;</a></p></noscript>"#
+        );
+    }
+
+    #[test]
+    fn test_backend_retry_delay() {
+        let ms = |attempt| backend_retry_delay(attempt).as_millis() as u64;
+        assert_eq!(ms(0), constants::BACKEND_RETRY_BASE_DELAY_MS);
+        assert_eq!(ms(1), constants::BACKEND_RETRY_BASE_DELAY_MS * 2);
+        assert_eq!(ms(2), constants::BACKEND_RETRY_BASE_DELAY_MS * 4);
+        assert_eq!(ms(20), constants::BACKEND_RETRY_MAX_DELAY_MS);
+        assert_eq!(ms(u32::MAX), constants::BACKEND_RETRY_MAX_DELAY_MS);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_error_status() {
+        let args = Args {
+            bad_gateway_page: Some("bad gateway".into()),
+            gateway_timeout_page: Some("gateway timeout".into()),
+            ..Default::default()
+        };
+
+        // Nothing listens on a port that was just released.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let err: Error = reqwest::get(format!("http://127.0.0.1:{}", port))
+            .await
+            .unwrap_err()
+            .into();
+        let status = upstream_error_status(&err);
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(upstream_error_page(&args, status), "bad gateway");
+        assert_eq!(
+            upstream_retry_after(status),
+            Some(constants::UPSTREAM_RETRY_AFTER_SECS)
+        );
+
+        // Accepts the connection but never responds.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _accept = tokio::spawn(async move {
+            let _conn = listener.accept().await;
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        });
+        let err: Error = reqwest::ClientBuilder::new()
+            .timeout(std::time::Duration::from_millis(200))
+            .build()
+            .unwrap()
+            .get(format!("http://127.0.0.1:{}", port))
+            .send()
+            .await
+            .unwrap_err()
+            .into();
+        let status = upstream_error_status(&err);
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(upstream_error_page(&args, status), "gateway timeout");
+        assert_eq!(
+            upstream_retry_after(status),
+            Some(constants::UPSTREAM_RETRY_AFTER_SECS)
+        );
+        assert!(
+            upstream_error_page(&Args::default(), status)
+                .contains("<h2 class=\"center\">504 Gateway Timeout</h2>")
+        );
+
+        // Sends a shorter body than its content-length and hangs up.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _accept = tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = conn.read(&mut buf).await;
+            let _ = conn
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 100\r\n\r\nshort")
+                .await;
+        });
+        let err: Error = reqwest::get(format!("http://127.0.0.1:{}", port))
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(upstream_error_status(&err), StatusCode::BAD_GATEWAY);
+
+        // A header from the client that can't be sent on is not the dest's
+        // fault.
+        let err: Error = reqwest::Client::new()
+            .get(format!("http://127.0.0.1:{}", port))
+            .header("x-client-header", "bad\nvalue")
+            .send()
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(
+            upstream_error_status(&err),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        let err = Error::Generic("Invalid HTML method FOO!".into());
+        let status = upstream_error_status(&err);
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(upstream_error_page(&args, status).contains("500 Internal Server Error"));
+        assert_eq!(upstream_retry_after(status), None);
+    }
+
+    #[test]
+    fn test_is_forwarded_request_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", "keep-alive, X-Drop-Me".parse().unwrap());
+
+        assert!(is_forwarded_request_header(&headers, "cookie"));
+        assert!(is_forwarded_request_header(&headers, "user-agent"));
+        assert!(is_forwarded_request_header(&headers, "x-custom"));
+        assert!(!is_forwarded_request_header(&headers, "Connection"));
+        assert!(!is_forwarded_request_header(&headers, "transfer-encoding"));
+        assert!(!is_forwarded_request_header(&headers, "x-drop-me"));
+        assert!(!is_forwarded_request_header(&headers, "host"));
+        assert!(!is_forwarded_request_header(&headers, "X-PMA-Token"));
+        assert!(!is_forwarded_request_header(&headers, "x-forwarded-for"));
+    }
+
+    #[test]
+    fn test_forwarded_for() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            forwarded_for(&headers, "203.0.113.12", true).unwrap(),
+            "203.0.113.12"
+        );
+
+        headers.append(
+            "x-forwarded-for",
+            "198.51.100.1, 198.51.100.2".parse().unwrap(),
+        );
+        headers.append("x-forwarded-for", "198.51.100.3".parse().unwrap());
+        assert_eq!(
+            forwarded_for(&headers, "203.0.113.12", true).unwrap(),
+            "198.51.100.1, 198.51.100.2, 198.51.100.3, 203.0.113.12"
+        );
+        assert_eq!(
+            forwarded_for(&headers, "203.0.113.12", false).unwrap(),
+            "203.0.113.12"
+        );
     }
 }
@@ -16,9 +16,202 @@
 
 use serde::{Deserialize, Serialize};
 
+/// One line of "--log-format=json" output.
+#[derive(Debug, Serialize)]
+pub struct LogLine<'a> {
+    pub event: &'a str,
+    pub level: &'a str,
+    pub ip: &'a str,
+    pub remote_port: Option<u16>,
+    pub port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    pub message: String,
+}
+
+/// A "--log-format=json" line not tied to a request.
+#[derive(Debug, Serialize)]
+pub struct StartupLogLine<'a> {
+    pub event: &'a str,
+    pub level: &'a str,
+    pub message: String,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct FactorsResponse {
     pub r#type: String,
     pub id: String,
     pub factors: String,
 }
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct HashcashResponse {
+    pub r#type: String,
+    pub id: String,
+    pub nonce: String,
+}
+
+/// Body POSTed to the api url by either challenge worker.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ChallengeResponse {
+    Factors(FactorsResponse),
+    Hashcash(HashcashResponse),
+}
+
+impl ChallengeResponse {
+    pub fn id(&self) -> &str {
+        match self {
+            ChallengeResponse::Factors(factors) => &factors.id,
+            ChallengeResponse::Hashcash(hashcash) => &hashcash.id,
+        }
+    }
+}
+
+/// Whether the api url accepted a ChallengeResponse.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiStatus {
+    Ok,
+    Error,
+}
+
+/// Body of every api url response. The status code is still 200 when the
+/// answer is accepted and 400 when it isn't.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ApiResult {
+    pub status: ApiStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl ApiResult {
+    pub fn ok() -> Self {
+        ApiResult {
+            status: ApiStatus::Ok,
+            reason: None,
+        }
+    }
+
+    pub fn error(reason: impl Into<String>) -> Self {
+        ApiResult {
+            status: ApiStatus::Error,
+            reason: Some(reason.into()),
+        }
+    }
+}
+
+/// Body POSTed to the heartbeat url by the factors worker while it works.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ChallengeHeartbeat {
+    pub id: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct SolvedEvent {
+    pub ip: String,
+    pub port: u16,
+    pub solved_at: i64,
+    pub validate_ms: u64,
+}
+
+/// How the "value" of a factors challenge is written, for native solvers.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ValueEncoding {
+    pub name: String,
+    pub radix: u32,
+    pub alphabet: String,
+    pub least_significant_first: bool,
+}
+
+/// Served in place of the factors worker with "--json-challenge" to clients
+/// that accept application/json. The answer is POSTed to "api_url" as a
+/// FactorsResponse.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct FactorsChallenge {
+    pub r#type: String,
+    pub id: String,
+    pub value: String,
+    pub encoding: ValueEncoding,
+    pub api_url: String,
+}
+
+/// What handler_fn decided to do with a client.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientDecision {
+    Allow,
+    Challenge,
+    /// Turned away by the "--policy-script".
+    Deny,
+    /// The db couldn't be queried and "--db-fail-open" isn't set.
+    Unavailable,
+}
+
+/// One line of "--record-traffic" output, read back by "--replay-traffic".
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct TrafficRecord {
+    pub ip: String,
+    pub port: u16,
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub decision: ClientDecision,
+}
+
+/// One line sent to the "--admin-unix-socket". "id" is echoed back as is.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct AdminRequest {
+    #[serde(default)]
+    pub id: serde_json::Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: AdminParams,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+pub struct AdminParams {
+    pub ip: Option<String>,
+    pub port: Option<u16>,
+    pub ttl_secs: Option<u64>,
+}
+
+/// Reply line to an AdminRequest; exactly one of "result" or "error" is set.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct AdminResponse {
+    pub id: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct AllowedEntry {
+    pub ip: String,
+    pub port: u16,
+    pub age_secs: u64,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ChallengeEntry {
+    pub ip: String,
+    pub port: u16,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct AdminStats {
+    pub allowed: usize,
+    pub cached: usize,
+    pub upstream_in_flight: usize,
+    pub db_fail_opens: u64,
+    pub monitor_only_challenges: u64,
+    pub uptime_secs: u64,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct MintedToken {
+    pub token: String,
+    pub expires: i64,
+}
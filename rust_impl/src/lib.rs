@@ -0,0 +1,57 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+//! The challenge frontend as a library. [`SharedState::new`] sets up the db
+//! and the rest of the state for some [`Args`], [`build_router`] returns the
+//! salvo Router serving with it, and [`run`] is the binary's whole startup
+//! after parsing them. To mount the handlers in a Router of your own, wrap it
+//! with [`SharedState::inject`] and push [`handler_fn`] and the others.
+
+mod admin;
+pub mod args;
+mod benchmark;
+mod constants;
+mod db;
+mod error;
+mod ffi;
+mod handlers;
+mod helpers;
+mod json_types;
+mod logging;
+#[cfg(feature = "memory")]
+mod memory_db;
+mod msql_pool;
+#[cfg(feature = "policy-script")]
+mod policy;
+#[cfg(feature = "postgres")]
+mod postgres_db;
+mod reputation;
+mod salvo_compat;
+mod server;
+mod signal;
+mod state;
+#[cfg(test)]
+mod test_util;
+mod token;
+mod traffic;
+mod webhook;
+mod websocket;
+
+pub use args::Args;
+pub use error::Error;
+pub use handlers::{api_fn, auth_request_fn, factors_js_fn, handler_fn, heartbeat_fn};
+pub use server::{build_router, run};
+pub use state::{CachedAllow, SharedState};
@@ -0,0 +1,370 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::fmt::Arguments;
+
+use salvo::http::StatusCode;
+
+use crate::{
+    args::{Args, LogFormat},
+    helpers,
+    json_types::{LogLine, StartupLogLine},
+};
+
+/// Per-request events that are logged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestEvent {
+    /// The worker js (and so a challenge) was requested.
+    ChallengeRequested,
+    /// The worker js was requested with an unknown id.
+    ChallengeLookupFailed,
+    /// Generating the challenge failed.
+    GenerationFailed,
+    ChallengeAccepted,
+    ChallengeDenied,
+    AuthDenied,
+    /// An allowed client's port has no dest url.
+    NoDestUrl,
+    /// The dest url's response headers went past "--max-response-header-bytes".
+    ResponseHeadersTruncated,
+    /// A request body went past "--max-proxy-body" and wasn't forwarded.
+    BodyTooLarge,
+    /// A request was forwarded to the dest url.
+    Proxied,
+    /// The dest url couldn't be queried.
+    UpstreamFailed,
+    /// The dest url's response body failed partway through.
+    ResponseEndedEarly,
+    /// The db couldn't be queried while checking a client.
+    DbError,
+    /// A challenge answer was turned away by "--max-concurrent-validations".
+    ValidationThrottled,
+    /// A request was turned away by "--max-concurrent" or
+    /// "--max-concurrent-challenges".
+    ConcurrencyLimited,
+    /// A client went past "--challenge-rate" and wasn't issued a challenge.
+    ChallengeRateLimited,
+    /// A client "--monitor-only" let through would have been challenged.
+    WouldChallenge,
+    /// The "--policy-script" turned a client away.
+    #[cfg(feature = "policy-script")]
+    PolicyDenied,
+    /// The "--policy-script" failed or returned an unknown decision.
+    #[cfg(feature = "policy-script")]
+    PolicyFailed,
+}
+
+impl RequestEvent {
+    /// The "event" field of json log lines.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RequestEvent::ChallengeRequested => "challenge_requested",
+            RequestEvent::ChallengeLookupFailed => "challenge_lookup_failed",
+            RequestEvent::GenerationFailed => "generation_failed",
+            RequestEvent::ChallengeAccepted => "challenge_accepted",
+            RequestEvent::ChallengeDenied => "challenge_denied",
+            RequestEvent::AuthDenied => "auth_denied",
+            RequestEvent::NoDestUrl => "no_dest_url",
+            RequestEvent::ResponseHeadersTruncated => "response_headers_truncated",
+            RequestEvent::BodyTooLarge => "body_too_large",
+            RequestEvent::Proxied => "proxied",
+            RequestEvent::UpstreamFailed => "upstream_failed",
+            RequestEvent::ResponseEndedEarly => "response_ended_early",
+            RequestEvent::DbError => "db_error",
+            RequestEvent::ValidationThrottled => "validation_throttled",
+            RequestEvent::ConcurrencyLimited => "concurrency_limited",
+            RequestEvent::ChallengeRateLimited => "challenge_rate_limited",
+            RequestEvent::WouldChallenge => "would_challenge",
+            #[cfg(feature = "policy-script")]
+            RequestEvent::PolicyDenied => "policy_denied",
+            #[cfg(feature = "policy-script")]
+            RequestEvent::PolicyFailed => "policy_failed",
+        }
+    }
+
+    fn is_warning(&self) -> bool {
+        #[cfg(feature = "policy-script")]
+        if *self == RequestEvent::PolicyFailed {
+            return true;
+        }
+
+        matches!(
+            self,
+            RequestEvent::ChallengeLookupFailed
+                | RequestEvent::GenerationFailed
+                | RequestEvent::NoDestUrl
+                | RequestEvent::ResponseHeadersTruncated
+                | RequestEvent::BodyTooLarge
+                | RequestEvent::UpstreamFailed
+                | RequestEvent::ResponseEndedEarly
+                | RequestEvent::DbError
+                | RequestEvent::ValidationThrottled
+                | RequestEvent::ConcurrencyLimited
+                | RequestEvent::ChallengeRateLimited
+        )
+    }
+}
+
+/// Messages printed once at startup, before serving.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartupEvent {
+    /// The listeners and dest urls, from helpers::startup_banner().
+    Banner,
+    /// "--enable-override-dest-url" is active.
+    OverrideDestUrl,
+    /// "--monitor-only" is active.
+    MonitorOnly,
+}
+
+impl StartupEvent {
+    /// The "event" field of json log lines.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StartupEvent::Banner => "startup",
+            StartupEvent::OverrideDestUrl => "override_dest_url_enabled",
+            StartupEvent::MonitorOnly => "monitor_only_enabled",
+        }
+    }
+
+    fn level(&self) -> &'static str {
+        match self {
+            StartupEvent::Banner => "info",
+            StartupEvent::OverrideDestUrl => "notice",
+            StartupEvent::MonitorOnly => "warning",
+        }
+    }
+}
+
+/// Prints request events in the "--log-format", leaving out the successes
+/// "--log-sample-rate" didn't pick. Copy so it can outlive the request, e.g.
+/// while a response is streamed.
+#[derive(Clone, Copy, Debug)]
+pub struct Logger {
+    format: LogFormat,
+    sample_rate: f64,
+}
+
+impl Logger {
+    pub fn new(args: &Args) -> Self {
+        Logger {
+            format: args.log_format,
+            sample_rate: args.log_sample_rate,
+        }
+    }
+
+    /// Prints "message" for "event" on "ip":"remote_port" -> "port". In json
+    /// mode, the message is one field of the line. Returns whether it was
+    /// printed.
+    pub fn log(
+        &self,
+        event: RequestEvent,
+        ip: &str,
+        remote_port: Option<u16>,
+        port: u16,
+        status: Option<StatusCode>,
+        message: Arguments,
+    ) -> bool {
+        if event == RequestEvent::Proxied && !helpers::sampled(self.sample_rate) {
+            return false;
+        }
+
+        match self.format {
+            LogFormat::Plain => eprintln!("{}", self.plain(event, message)),
+            LogFormat::Json => eprintln!(
+                "{}",
+                self.json(event, ip, remote_port, port, status, message)
+            ),
+        }
+        true
+    }
+
+    /// Prints "message" for the startup "event", in the same "--log-format" as
+    /// request events.
+    pub fn startup(&self, event: StartupEvent, message: Arguments) {
+        eprintln!("{}", self.startup_line(event, message));
+    }
+
+    fn startup_line(&self, event: StartupEvent, message: Arguments) -> String {
+        let message = message.to_string();
+        let message = message.trim_end();
+        match self.format {
+            LogFormat::Plain => match event.level() {
+                "info" => message.to_owned(),
+                level => format!("{}: {}", level.to_uppercase(), message),
+            },
+            LogFormat::Json => serde_json::to_string(&StartupLogLine {
+                event: event.name(),
+                level: event.level(),
+                message: message.to_owned(),
+            })
+            .unwrap_or_default(),
+        }
+    }
+
+    fn plain(&self, event: RequestEvent, message: Arguments) -> String {
+        if event.is_warning() {
+            format!("WARNING: {}", message)
+        } else {
+            message.to_string()
+        }
+    }
+
+    fn json(
+        &self,
+        event: RequestEvent,
+        ip: &str,
+        remote_port: Option<u16>,
+        port: u16,
+        status: Option<StatusCode>,
+        message: Arguments,
+    ) -> String {
+        serde_json::to_string(&LogLine {
+            event: event.name(),
+            level: if event.is_warning() {
+                "warning"
+            } else {
+                "info"
+            },
+            ip,
+            remote_port,
+            port,
+            status: status.map(|status| status.as_u16()),
+            message: message.to_string(),
+        })
+        .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_formats() {
+        let logger = Logger {
+            format: LogFormat::Json,
+            sample_rate: 1.0,
+        };
+        let line: serde_json::Value = serde_json::from_str(&logger.json(
+            RequestEvent::UpstreamFailed,
+            "203.0.113.1",
+            Some(40000),
+            8180,
+            Some(StatusCode::BAD_GATEWAY),
+            format_args!("Failed to query dest url"),
+        ))
+        .unwrap();
+        assert_eq!(
+            line,
+            serde_json::json!({
+                "event": "upstream_failed",
+                "level": "warning",
+                "ip": "203.0.113.1",
+                "remote_port": 40000,
+                "port": 8180,
+                "status": 502,
+                "message": "Failed to query dest url",
+            })
+        );
+
+        let line = logger.json(
+            RequestEvent::ChallengeAccepted,
+            "203.0.113.1",
+            None,
+            8180,
+            None,
+            format_args!("Challenge response accepted"),
+        );
+        assert!(!line.contains("\"status\""));
+        assert!(line.contains("\"level\":\"info\""));
+
+        assert_eq!(
+            logger.plain(RequestEvent::DbError, format_args!("Db error")),
+            "WARNING: Db error"
+        );
+        assert_eq!(
+            logger.plain(RequestEvent::ChallengeDenied, format_args!("DENIED")),
+            "DENIED"
+        );
+    }
+
+    #[test]
+    fn test_startup_log() {
+        let mut logger = Logger {
+            format: LogFormat::Json,
+            sample_rate: 1.0,
+        };
+        let line: serde_json::Value = serde_json::from_str(&logger.startup_line(
+            StartupEvent::Banner,
+            format_args!("Startup:\n  Default Dest URL: none\n"),
+        ))
+        .unwrap();
+        assert_eq!(
+            line,
+            serde_json::json!({
+                "event": "startup",
+                "level": "info",
+                "message": "Startup:\n  Default Dest URL: none",
+            })
+        );
+
+        logger.format = LogFormat::Plain;
+        assert_eq!(
+            logger.startup_line(StartupEvent::Banner, format_args!("Startup:\n")),
+            "Startup:"
+        );
+        assert_eq!(
+            logger.startup_line(StartupEvent::MonitorOnly, format_args!("Monitoring")),
+            "WARNING: Monitoring"
+        );
+    }
+
+    #[test]
+    fn test_log_sample_rate() {
+        let mut logger = Logger {
+            format: LogFormat::Plain,
+            sample_rate: 0.0,
+        };
+        for _ in 0..100 {
+            assert!(!logger.log(
+                RequestEvent::Proxied,
+                "203.0.113.1",
+                None,
+                8180,
+                Some(StatusCode::OK),
+                format_args!("Proxied (test)")
+            ));
+        }
+        assert!(logger.log(
+            RequestEvent::ChallengeDenied,
+            "203.0.113.1",
+            None,
+            8180,
+            None,
+            format_args!("DENIED (test)")
+        ));
+
+        logger.sample_rate = 1.0;
+        assert!(logger.log(
+            RequestEvent::Proxied,
+            "203.0.113.1",
+            None,
+            8180,
+            Some(StatusCode::OK),
+            format_args!("Proxied (test)")
+        ));
+    }
+}
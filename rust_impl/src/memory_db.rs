@@ -0,0 +1,486 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::db::{GETRANDOM_BUF_SIZE, PurgedRows};
+use crate::{args, error::Error, helpers, json_types, signal};
+
+/// How often "--memory-seq-file" is rewritten, besides on shutdown.
+const SEQ_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+struct ChallengeFactor {
+    ip: String,
+    port: u16,
+    factors: String,
+    value: String,
+    user_agent: Option<String>,
+    gen_time: Instant,
+    issue_time: Instant,
+}
+
+struct AllowedIp {
+    ip: String,
+    port: u16,
+    on_time: Instant,
+}
+
+#[derive(Default)]
+struct MemoryTables {
+    seq_id: Option<u64>,
+    challenge_factors: HashMap<String, ChallengeFactor>,
+    allowed_ips: Vec<AllowedIp>,
+    id_to_port: HashMap<String, (u16, Instant)>,
+}
+
+/// The "--memory-db" backend, holding the same tables as the other backends
+/// in this process. Everything but the sequence counter saved to
+/// "--memory-seq-file" is lost on exit.
+#[derive(Clone, Default)]
+pub struct MemoryDb {
+    tables: Arc<Mutex<MemoryTables>>,
+    seq_file: Option<PathBuf>,
+}
+
+impl MemoryDb {
+    /// Resumes the sequence counter from "--memory-seq-file" if it was saved
+    /// there before.
+    pub fn new(args: &args::Args) -> Result<Self, Error> {
+        let db = Self {
+            seq_file: args.memory_seq_file.clone(),
+            ..Default::default()
+        };
+
+        if let Some(seq_file) = &db.seq_file {
+            match std::fs::read_to_string(seq_file) {
+                Ok(contents) => {
+                    db.lock()?.seq_id = Some(contents.trim().parse().map_err(|e| {
+                        Error::Generic(format!("Invalid {}: {}", seq_file.display(), e))
+                    })?);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(db)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, MemoryTables>, Error> {
+        self.tables
+            .lock()
+            .map_err(|_| Error::Generic("Failed to lock MemoryDb".into()))
+    }
+
+    /// Saves the sequence counter to "--memory-seq-file", writing a temporary
+    /// file first so a crash midway keeps the previous value.
+    pub fn flush_seq(&self) -> Result<(), Error> {
+        let Some(seq_file) = &self.seq_file else {
+            return Ok(());
+        };
+        let Some(seq_id) = self.lock()?.seq_id else {
+            return Ok(());
+        };
+
+        let mut tmp_file = seq_file.clone().into_os_string();
+        tmp_file.push(".tmp");
+        std::fs::write(&tmp_file, seq_id.to_string())?;
+        std::fs::rename(&tmp_file, seq_file)?;
+
+        Ok(())
+    }
+
+    /// Saves the sequence counter every SEQ_FLUSH_INTERVAL, and a last time
+    /// once a signal is received.
+    pub fn spawn_seq_flush(&self) {
+        if self.seq_file.is_none() {
+            return;
+        }
+        let db = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(SEQ_FLUSH_INTERVAL).await;
+                let stopping = signal::SIGNAL_HANDLED.load(std::sync::atomic::Ordering::Relaxed);
+                if let Err(e) = db.flush_seq() {
+                    eprintln!("WARNING: Failed to save --memory-seq-file: {}", e);
+                }
+                if stopping {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+fn mins(mins: u64) -> Duration {
+    Duration::from_secs(mins * 60)
+}
+
+pub async fn get_next_seq_memory(db: &MemoryDb) -> Result<u64, Error> {
+    let mut tables = db.lock()?;
+
+    let seq = tables.seq_id.unwrap_or(1);
+    tables.seq_id = Some(if seq + 1 >= 0x7FFFFFFFFFFFFFFF {
+        1
+    } else {
+        seq + 1
+    });
+
+    Ok(seq)
+}
+
+pub async fn has_challenge_factor_id_memory(db: &MemoryDb, hash: &str) -> Result<bool, Error> {
+    Ok(db.lock()?.challenge_factors.contains_key(hash))
+}
+
+pub async fn set_challenge_factor_memory(
+    db: &MemoryDb,
+    ip: &str,
+    hash: &str,
+    port: u16,
+    factors_hash: &str,
+    value: &str,
+    user_agent: Option<&str>,
+) -> Result<(), Error> {
+    db.lock()?
+        .challenge_factors
+        .entry(hash.to_owned())
+        .or_insert_with(|| ChallengeFactor {
+            ip: ip.to_owned(),
+            port,
+            factors: factors_hash.to_owned(),
+            value: value.to_owned(),
+            user_agent: user_agent.map(str::to_owned),
+            gen_time: Instant::now(),
+            issue_time: Instant::now(),
+        });
+
+    Ok(())
+}
+
+pub async fn pending_challenges_memory(
+    db: &MemoryDb,
+) -> Result<Vec<(String, u16, Option<String>)>, Error> {
+    Ok(db
+        .lock()?
+        .challenge_factors
+        .values()
+        .map(|challenge| {
+            (
+                challenge.ip.clone(),
+                challenge.port,
+                challenge.user_agent.clone(),
+            )
+        })
+        .collect())
+}
+
+pub async fn extend_challenge_memory(
+    args: &args::Args,
+    db: &MemoryDb,
+    id: &str,
+    addr: &str,
+) -> Result<bool, Error> {
+    let mut tables = db.lock()?;
+
+    let timeout = mins(args.challenge_timeout_mins);
+    match tables.challenge_factors.get_mut(id) {
+        Some(challenge) if challenge.ip == addr && challenge.gen_time.elapsed() < timeout => {
+            let capped = challenge.issue_time + mins(crate::db::heartbeat_extension_mins(args));
+            challenge.gen_time = challenge.gen_time.max(Instant::now().min(capped));
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+pub async fn challenge_port_memory(
+    args: &args::Args,
+    db: &MemoryDb,
+    id: &str,
+) -> Result<u16, Error> {
+    let timeout = mins(args.challenge_timeout_mins);
+    db.lock()?
+        .id_to_port
+        .remove(id)
+        .filter(|(_, on_time)| on_time.elapsed() < timeout)
+        .map(|(port, _)| port)
+        .ok_or(Error::Generic(String::from(
+            "gen challenge, failed to get port",
+        )))
+}
+
+pub async fn validate_client_memory(
+    args: &args::Args,
+    db: &MemoryDb,
+    challenge_response: &json_types::ChallengeResponse,
+    hashed_factors: &str,
+    addr: &str,
+) -> Result<u16, Error> {
+    let mut tables = db.lock()?;
+
+    let timeout = mins(args.challenge_timeout_mins);
+    let port = match tables.challenge_factors.get(challenge_response.id()) {
+        Some(challenge)
+            if challenge.factors == hashed_factors && challenge.gen_time.elapsed() < timeout =>
+        {
+            if challenge.ip != addr || challenge.port == 0 {
+                return Err(String::from("Invalid entries from ChallengeFactor").into());
+            } else if !helpers::verify_challenge_answer(&challenge.value, challenge_response)? {
+                return Err(String::from("Incorrect").into());
+            }
+            challenge.port
+        }
+        _ => return Err(String::from("Incorrect").into()),
+    };
+
+    tables.challenge_factors.remove(challenge_response.id());
+    tables.allowed_ips.push(AllowedIp {
+        ip: addr.to_owned(),
+        port,
+        on_time: Instant::now(),
+    });
+
+    Ok(port)
+}
+
+pub async fn check_is_allowed_memory(
+    args: &args::Args,
+    db: &MemoryDb,
+    addr: &str,
+    port: u16,
+) -> Result<bool, Error> {
+    let timeout = mins(args.allowed_timeout_mins);
+    Ok(db.lock()?.allowed_ips.iter().any(|allowed| {
+        allowed.ip == addr && allowed.port == port && allowed.on_time.elapsed() < timeout
+    }))
+}
+
+pub async fn recently_allowed_memory(
+    db: &MemoryDb,
+    within: Duration,
+) -> Result<Vec<(String, u16, Duration)>, Error> {
+    Ok(db
+        .lock()?
+        .allowed_ips
+        .iter()
+        .filter(|allowed| allowed.on_time.elapsed() < within)
+        .map(|allowed| {
+            (
+                allowed.ip.clone(),
+                allowed.port,
+                // Whole seconds, as the sql backends report.
+                Duration::from_secs(allowed.on_time.elapsed().as_secs()),
+            )
+        })
+        .collect())
+}
+
+pub async fn revoke_allowed_memory(
+    db: &MemoryDb,
+    addr: Option<&str>,
+    port: Option<u16>,
+) -> Result<(), Error> {
+    db.lock()?.allowed_ips.retain(|allowed| {
+        !(addr.is_none_or(|addr| allowed.ip == addr)
+            && port.is_none_or(|port| allowed.port == port))
+    });
+
+    Ok(())
+}
+
+pub async fn purge_expired_memory(args: &args::Args, db: &MemoryDb) -> Result<PurgedRows, Error> {
+    let mut tables = db.lock()?;
+    let mut purged = PurgedRows::default();
+
+    let timeout = mins(args.challenge_timeout_mins);
+    let before = tables.challenge_factors.len();
+    tables
+        .challenge_factors
+        .retain(|_, challenge| challenge.gen_time.elapsed() < timeout);
+    purged.challenge_factors = (before - tables.challenge_factors.len()) as u64;
+
+    let before = tables.id_to_port.len();
+    tables
+        .id_to_port
+        .retain(|_, (_, on_time)| on_time.elapsed() < timeout);
+    purged.id_to_port = (before - tables.id_to_port.len()) as u64;
+
+    let timeout = mins(args.allowed_timeout_mins);
+    let before = tables.allowed_ips.len();
+    tables
+        .allowed_ips
+        .retain(|allowed| allowed.on_time.elapsed() < timeout);
+    purged.allowed_ips = (before - tables.allowed_ips.len()) as u64;
+
+    Ok(purged)
+}
+
+pub async fn add_allowed_memory(db: &MemoryDb, addr: &str, port: u16) -> Result<(), Error> {
+    db.lock()?.allowed_ips.push(AllowedIp {
+        ip: addr.to_owned(),
+        port,
+        on_time: Instant::now(),
+    });
+
+    Ok(())
+}
+
+pub async fn init_id_to_port_memory(
+    args: &args::Args,
+    db: &MemoryDb,
+    port: u16,
+) -> Result<String, Error> {
+    let mut tables = db.lock()?;
+
+    let mut buf = [0u8; GETRANDOM_BUF_SIZE];
+    loop {
+        let mut hasher = helpers::salted_hasher(args);
+        getrandom::fill(&mut buf)?;
+        hasher.update(&buf);
+        let hash = hasher.finalize().to_string();
+
+        if !tables.id_to_port.contains_key(&hash) {
+            tables
+                .id_to_port
+                .insert(hash.clone(), (port, Instant::now()));
+            return Ok(hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_db_ttl() {
+        let args = args::Args {
+            challenge_timeout_mins: 1,
+            allowed_timeout_mins: 1,
+            ..Default::default()
+        };
+        let expired_args = args::Args {
+            challenge_timeout_mins: 0,
+            allowed_timeout_mins: 0,
+            ..Default::default()
+        };
+        let db = MemoryDb::default();
+
+        assert_eq!(get_next_seq_memory(&db).await.unwrap(), 1);
+        assert_eq!(get_next_seq_memory(&db).await.unwrap(), 2);
+
+        let id = init_id_to_port_memory(&args, &db, 8180).await.unwrap();
+        assert_eq!(challenge_port_memory(&args, &db, &id).await.unwrap(), 8180);
+        assert!(challenge_port_memory(&args, &db, &id).await.is_err());
+        let id = init_id_to_port_memory(&args, &db, 8180).await.unwrap();
+        assert!(
+            challenge_port_memory(&expired_args, &db, &id)
+                .await
+                .is_err()
+        );
+        let id = init_id_to_port_memory(&args, &db, 8180).await.unwrap();
+        assert_eq!(
+            purge_expired_memory(&expired_args, &db)
+                .await
+                .unwrap()
+                .id_to_port,
+            1
+        );
+        assert!(challenge_port_memory(&args, &db, &id).await.is_err());
+
+        add_allowed_memory(&db, "203.0.113.32", 8180).await.unwrap();
+        add_allowed_memory(&db, "203.0.113.32", 8181).await.unwrap();
+        assert!(
+            check_is_allowed_memory(&args, &db, "203.0.113.32", 8180)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !check_is_allowed_memory(&args, &db, "203.0.113.33", 8180)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            recently_allowed_memory(&db, Duration::from_secs(60))
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        revoke_allowed_memory(&db, None, Some(8181)).await.unwrap();
+        assert_eq!(
+            recently_allowed_memory(&db, Duration::from_secs(60))
+                .await
+                .unwrap(),
+            vec![("203.0.113.32".to_owned(), 8180, Duration::ZERO)]
+        );
+        assert!(
+            !check_is_allowed_memory(&expired_args, &db, "203.0.113.32", 8180)
+                .await
+                .unwrap()
+        );
+        assert!(
+            check_is_allowed_memory(&args, &db, "203.0.113.32", 8180)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            purge_expired_memory(&expired_args, &db)
+                .await
+                .unwrap()
+                .allowed_ips,
+            1
+        );
+        assert!(
+            !check_is_allowed_memory(&args, &db, "203.0.113.32", 8180)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_seq_file_resumes() {
+        let seq_file = std::env::temp_dir().join(format!("pma_memory_seq_{}", std::process::id()));
+        std::fs::remove_file(&seq_file).ok();
+        let args = args::Args {
+            memory_db: true,
+            memory_seq_file: Some(seq_file.clone()),
+            ..Default::default()
+        };
+
+        let db = MemoryDb::new(&args).unwrap();
+        assert_eq!(get_next_seq_memory(&db).await.unwrap(), 1);
+        assert_eq!(get_next_seq_memory(&db).await.unwrap(), 2);
+        db.flush_seq().unwrap();
+        assert_eq!(get_next_seq_memory(&db).await.unwrap(), 3);
+        drop(db);
+
+        // Only what was flushed survives the restart.
+        let db = MemoryDb::new(&args).unwrap();
+        assert_eq!(get_next_seq_memory(&db).await.unwrap(), 3);
+        assert_eq!(get_next_seq_memory(&db).await.unwrap(), 4);
+        db.flush_seq().unwrap();
+        drop(db);
+
+        let db = MemoryDb::new(&args).unwrap();
+        std::fs::remove_file(&seq_file).ok();
+        assert_eq!(get_next_seq_memory(&db).await.unwrap(), 5);
+    }
+}
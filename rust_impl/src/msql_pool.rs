@@ -0,0 +1,172 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use msql_ffi::MSQLWrapper;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::Error;
+
+struct MSQLConfig {
+    address: String,
+    port: u16,
+    user: String,
+    password: String,
+    database: String,
+}
+
+/// Keeps up to `max_conns` MySQL connections open, handing them out one
+/// request at a time.
+#[derive(Clone)]
+pub struct MSQLPool {
+    config: Arc<MSQLConfig>,
+    idle: Arc<Mutex<Vec<MSQLWrapper>>>,
+    permits: Arc<Semaphore>,
+}
+
+/// A connection borrowed from `MSQLPool`, returned to it on drop.
+pub struct MSQLPoolConn {
+    conn: Option<MSQLWrapper>,
+    idle: Arc<Mutex<Vec<MSQLWrapper>>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl MSQLPool {
+    pub fn new(config_map: &HashMap<String, String>, max_conns: usize) -> Result<Self, Error> {
+        Ok(Self {
+            config: Arc::new(MSQLConfig {
+                address: config_map
+                    .get("address")
+                    .ok_or("Address not in msql config")?
+                    .to_owned(),
+                port: config_map
+                    .get("port")
+                    .ok_or("Port not in msql config")?
+                    .parse()?,
+                user: config_map
+                    .get("user")
+                    .ok_or("User not in msql config")?
+                    .to_owned(),
+                password: config_map
+                    .get("password")
+                    .ok_or("Password not in msql config")?
+                    .to_owned(),
+                database: config_map
+                    .get("database")
+                    .ok_or("Database not in msql config")?
+                    .to_owned(),
+            }),
+            idle: Default::default(),
+            permits: Arc::new(Semaphore::new(max_conns)),
+        })
+    }
+
+    /// Waits until fewer than `max_conns` connections are in use, then reuses
+    /// an idle connection or opens a new one.
+    pub async fn get_conn(&self) -> Result<MSQLPoolConn, Error> {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| Error::Generic("MSQLPool semaphore closed".into()))?;
+
+        let idle_conn = self
+            .idle
+            .lock()
+            .map_err(|_| Error::Generic("Failed to lock MSQLPool".into()))?
+            .pop();
+
+        let conn = match idle_conn.and_then(|mut conn| conn.ping().ok().map(|_| conn)) {
+            Some(conn) => conn,
+            None => MSQLWrapper::try_new(
+                &self.config.address,
+                self.config.port,
+                &self.config.user,
+                &self.config.password,
+                &self.config.database,
+            )
+            .map_err(|_| "Failed to create msql connection")?,
+        };
+
+        Ok(MSQLPoolConn {
+            conn: Some(conn),
+            idle: self.idle.clone(),
+            _permit: permit,
+        })
+    }
+}
+
+impl Deref for MSQLPoolConn {
+    type Target = MSQLWrapper;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for MSQLPoolConn {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for MSQLPoolConn {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take()
+            && let Ok(mut idle) = self.idle.lock()
+        {
+            idle.push(conn);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_failed_conn_releases_permit() {
+        let config_map: HashMap<String, String> = [
+            ("address", "127.0.0.1"),
+            ("port", "1"),
+            ("user", "pmauser"),
+            ("password", "pmauser"),
+            ("database", "PMA"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect();
+        let pool = MSQLPool::new(&config_map, 1).unwrap();
+
+        for _ in 0..3 {
+            let ret = tokio::time::timeout(std::time::Duration::from_secs(10), pool.get_conn())
+                .await
+                .expect("Failed connect should not hold the only permit");
+            assert!(ret.is_err());
+        }
+        assert_eq!(pool.permits.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_missing_config() {
+        let config_map: HashMap<String, String> = HashMap::new();
+        assert!(MSQLPool::new(&config_map, 1).is_err());
+    }
+}
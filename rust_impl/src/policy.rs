@@ -0,0 +1,234 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use rhai::{AST, Dynamic, Engine, Map, Scope};
+use salvo::http::HeaderMap;
+
+use crate::{error::Error, signal};
+
+/// Bounds how long one run of the script can take; rhai aborts it past this
+/// many operations.
+const MAX_OPERATIONS: u64 = 100_000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_STRING_SIZE: usize = 64 * 1024;
+const MAX_COLLECTION_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyDecision {
+    /// Skips the challenge.
+    Allow,
+    /// Turns the client away with a 403.
+    Deny,
+    /// Leaves the client to the usual checks, so it is challenged unless it
+    /// is already allowed.
+    Challenge,
+}
+
+/// The request details a policy script sees, as the "ip", "port", "method",
+/// "path", and "headers" variables.
+pub struct PolicyInput<'a> {
+    pub ip: &'a str,
+    pub port: u16,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub headers: &'a HeaderMap,
+}
+
+/// A compiled "--policy-script". The script runs without access to files or
+/// the network, and its last expression must be "allow", "deny", or
+/// "challenge".
+pub struct Policy {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Policy {
+    pub fn parse(script: &str) -> Result<Self, Error> {
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(MAX_OPERATIONS)
+            .set_max_call_levels(MAX_CALL_LEVELS)
+            .set_max_expr_depths(MAX_EXPR_DEPTH, MAX_EXPR_DEPTH)
+            .set_max_string_size(MAX_STRING_SIZE)
+            .set_max_array_size(MAX_COLLECTION_SIZE)
+            .set_max_map_size(MAX_COLLECTION_SIZE)
+            .disable_symbol("eval");
+        let ast = engine
+            .compile(script)
+            .map_err(|e| Error::Generic(format!("Failed to compile policy script: {}", e)))?;
+
+        Ok(Self { engine, ast })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn decide(&self, input: &PolicyInput) -> Result<PolicyDecision, Error> {
+        let mut headers = Map::new();
+        for name in input.headers.keys() {
+            let value = input
+                .headers
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(", ");
+            headers.insert(name.as_str().into(), value.into());
+        }
+
+        let mut scope = Scope::new();
+        scope
+            .push_constant("ip", input.ip.to_owned())
+            .push_constant("port", input.port as i64)
+            .push_constant("method", input.method.to_owned())
+            .push_constant("path", input.path.to_owned())
+            .push_constant("headers", headers);
+
+        let ret: Dynamic = self
+            .engine
+            .eval_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|e| Error::Generic(format!("Policy script failed: {}", e)))?;
+        let ret = ret.into_string().map_err(|type_name| {
+            Error::Generic(format!(
+                "Policy script returned a {}, not a string",
+                type_name
+            ))
+        })?;
+        match ret.as_str() {
+            "allow" => Ok(PolicyDecision::Allow),
+            "deny" => Ok(PolicyDecision::Deny),
+            "challenge" => Ok(PolicyDecision::Challenge),
+            _ => Err(format!("Policy script returned unknown decision \"{}\"", ret).into()),
+        }
+    }
+}
+
+/// Shared handle to the loaded "--policy-script", reloaded on SIGHUP.
+#[derive(Clone)]
+pub struct PolicyScript {
+    path: PathBuf,
+    policy: Arc<RwLock<Arc<Policy>>>,
+}
+
+impl PolicyScript {
+    pub fn load(path: PathBuf) -> Result<Self, Error> {
+        let policy = Policy::load(&path)?;
+        Ok(Self {
+            path,
+            policy: Arc::new(RwLock::new(Arc::new(policy))),
+        })
+    }
+
+    pub fn decide(&self, input: &PolicyInput) -> Result<PolicyDecision, Error> {
+        let policy = self
+            .policy
+            .read()
+            .map_err(|_| Error::Generic("Failed to lock PolicyScript".into()))?
+            .clone();
+        policy.decide(input)
+    }
+
+    pub fn reload(&self) -> Result<(), Error> {
+        let policy = Policy::load(&self.path)?;
+        *self
+            .policy
+            .write()
+            .map_err(|_| Error::Generic("Failed to lock PolicyScript".into()))? = Arc::new(policy);
+
+        Ok(())
+    }
+
+    /// Reloads the script whenever SIGHUP is received. A script that fails to
+    /// compile keeps the previous one.
+    pub fn spawn_reload_on_sighup(&self) {
+        let script = self.clone();
+        signal::spawn_reloader(format!("{:?}", self.path), move || {
+            std::future::ready(script.reload())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input<'a>(path: &'a str, headers: &'a HeaderMap) -> PolicyInput<'a> {
+        PolicyInput {
+            ip: "203.0.113.5",
+            port: 8180,
+            method: "GET",
+            path,
+            headers,
+        }
+    }
+
+    #[test]
+    fn test_policy_decide() {
+        let policy = Policy::parse(
+            r#"
+            if path.starts_with("/admin") {
+                "deny"
+            } else if headers["user-agent"] == "monitor" && ip == "203.0.113.5" {
+                "allow"
+            } else {
+                "challenge"
+            }
+            "#,
+        )
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            policy.decide(&input("/admin/users", &headers)).unwrap(),
+            PolicyDecision::Deny
+        );
+        assert_eq!(
+            policy.decide(&input("/", &headers)).unwrap(),
+            PolicyDecision::Challenge
+        );
+        headers.insert("user-agent", "monitor".parse().unwrap());
+        assert_eq!(
+            policy.decide(&input("/", &headers)).unwrap(),
+            PolicyDecision::Allow
+        );
+
+        assert!(Policy::parse("if {").is_err());
+        assert!(
+            Policy::parse("42")
+                .unwrap()
+                .decide(&input("/", &headers))
+                .is_err()
+        );
+        assert!(
+            Policy::parse(r#""maybe""#)
+                .unwrap()
+                .decide(&input("/", &headers))
+                .is_err()
+        );
+        assert!(
+            Policy::parse(r#"loop {} "allow""#)
+                .unwrap()
+                .decide(&input("/", &headers))
+                .is_err()
+        );
+        assert!(Policy::parse(r#"eval("\"allow\"")"#).is_err());
+    }
+}
@@ -0,0 +1,503 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use deadpool_postgres::{Config, GenericClient, Pool, PoolConfig, Runtime};
+use tokio_postgres::NoTls;
+
+use crate::db::{AuditEvent, GETRANDOM_BUF_SIZE, PurgedRows};
+use crate::{args, constants, error::Error, helpers, json_types};
+
+const PG_RUST_SEQ_ID_1_CREATE: &str = r"CREATE TABLE IF NOT EXISTS RUST_SEQ_ID_1 (
+        ID SERIAL PRIMARY KEY,
+        SEQ_ID BIGINT NOT NULL
+    )";
+
+const PG_RUST_CHALLENGE_FACTORS_7_CREATE: &str = r"CREATE TABLE IF NOT EXISTS RUST_CHALLENGE_FACTORS_7 (
+        ID CHAR(64) NOT NULL PRIMARY KEY,
+        IP VARCHAR(45) NOT NULL,
+        FACTORS CHAR(64) NOT NULL,
+        VALUE TEXT NOT NULL,
+        USER_AGENT VARCHAR(256),
+        PORT INTEGER NOT NULL,
+        GEN_TIME TIMESTAMP NOT NULL DEFAULT now(),
+        ISSUE_TIME TIMESTAMP NOT NULL DEFAULT now()
+    )";
+
+const PG_RUST_ALLOWED_IPS_CREATE: &str = r"CREATE TABLE IF NOT EXISTS RUST_ALLOWED_IPS (
+        ID SERIAL PRIMARY KEY,
+        IP VARCHAR(45) NOT NULL,
+        PORT INTEGER NOT NULL,
+        ON_TIME TIMESTAMP NOT NULL DEFAULT now()
+    )";
+
+const PG_RUST_ID_TO_PORT_3_CREATE: &str = r"CREATE TABLE IF NOT EXISTS RUST_ID_TO_PORT_3 (
+        ID CHAR(64) NOT NULL PRIMARY KEY,
+        PORT INTEGER NOT NULL,
+        ON_TIME TIMESTAMP NOT NULL DEFAULT now()
+    )";
+
+const PG_RUST_AUDIT_LOG_CREATE: &str = r"CREATE TABLE IF NOT EXISTS RUST_AUDIT_LOG (
+        ID SERIAL PRIMARY KEY,
+        IP VARCHAR(45) NOT NULL,
+        PORT INTEGER NOT NULL,
+        EVENT VARCHAR(16) NOT NULL,
+        ON_TIME TIMESTAMP NOT NULL DEFAULT now()
+    )";
+
+pub fn get_postgres_db_pool(config_map: &HashMap<String, String>) -> Result<Pool, Error> {
+    let mut config = Config::new();
+    config.host = Some(
+        config_map
+            .get("address")
+            .ok_or("Address not in postgres config")?
+            .to_owned(),
+    );
+    config.port = Some(
+        config_map
+            .get("port")
+            .ok_or("Port not in postgres config")?
+            .parse()?,
+    );
+    config.user = Some(
+        config_map
+            .get("user")
+            .ok_or("User not in postgres config")?
+            .to_owned(),
+    );
+    config.password = Some(
+        config_map
+            .get("password")
+            .ok_or("Password not in postgres config")?
+            .to_owned(),
+    );
+    config.dbname = Some(
+        config_map
+            .get("database")
+            .ok_or("Database not in postgres config")?
+            .to_owned(),
+    );
+    config.pool = Some(PoolConfig::new(constants::DB_POOL_MAX_CONNECTIONS));
+
+    config
+        .create_pool(Some(Runtime::Tokio1), NoTls)
+        .map_err(|e| Error::Generic(format!("Failed to create postgres pool: {}", e)))
+}
+
+pub async fn init_postgres_db(pool: &Pool) -> Result<(), Error> {
+    let conn = pool.get().await?;
+
+    conn.batch_execute(PG_RUST_SEQ_ID_1_CREATE).await?;
+
+    conn.batch_execute(r"DROP TABLE IF EXISTS RUST_CHALLENGE_FACTORS_4")
+        .await?;
+    conn.batch_execute(r"DROP TABLE IF EXISTS RUST_CHALLENGE_FACTORS_5")
+        .await?;
+    conn.batch_execute(r"DROP TABLE IF EXISTS RUST_CHALLENGE_FACTORS_6")
+        .await?;
+    conn.batch_execute(PG_RUST_CHALLENGE_FACTORS_7_CREATE)
+        .await?;
+    conn.batch_execute(
+        r"CREATE INDEX IF NOT EXISTS RUST_CHALLENGE_FACTORS_7_TIME
+        ON RUST_CHALLENGE_FACTORS_7 (GEN_TIME)",
+    )
+    .await?;
+
+    conn.batch_execute(PG_RUST_ALLOWED_IPS_CREATE).await?;
+    conn.batch_execute(
+        r"CREATE INDEX IF NOT EXISTS RUST_ALLOWED_IPS_IP_PORT ON RUST_ALLOWED_IPS (IP, PORT)",
+    )
+    .await?;
+    conn.batch_execute(
+        r"CREATE INDEX IF NOT EXISTS RUST_ALLOWED_IPS_TIME ON RUST_ALLOWED_IPS (ON_TIME)",
+    )
+    .await?;
+
+    conn.batch_execute(PG_RUST_ID_TO_PORT_3_CREATE).await?;
+    conn.batch_execute(
+        r"CREATE INDEX IF NOT EXISTS RUST_ID_TO_PORT_3_TIME ON RUST_ID_TO_PORT_3 (ON_TIME)",
+    )
+    .await?;
+
+    conn.batch_execute(PG_RUST_AUDIT_LOG_CREATE).await?;
+    conn.batch_execute(r"CREATE INDEX IF NOT EXISTS RUST_AUDIT_LOG_IP ON RUST_AUDIT_LOG (IP)")
+        .await?;
+
+    Ok(())
+}
+
+async fn insert_audit_log_postgres(
+    conn: &impl GenericClient,
+    addr: &str,
+    port: u16,
+    event: AuditEvent,
+) -> Result<(), Error> {
+    conn.execute(
+        "INSERT INTO RUST_AUDIT_LOG (IP, PORT, EVENT) VALUES ($1, $2, $3)",
+        &[&addr, &(port as i32), &event.as_str()],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_next_seq_postgres(pool: &Pool) -> Result<u64, Error> {
+    let mut conn = pool.get().await?;
+    let tx = conn.transaction().await?;
+
+    tx.batch_execute("LOCK TABLE RUST_SEQ_ID_1 IN EXCLUSIVE MODE")
+        .await?;
+
+    let seq: i64 = if let Some(row) = tx
+        .query_opt("SELECT ID, SEQ_ID FROM RUST_SEQ_ID_1 LIMIT 1", &[])
+        .await?
+    {
+        let id: i32 = row.try_get(0)?;
+        let seq: i64 = row.try_get(1)?;
+        let next: i64 = if seq >= 0x7FFFFFFFFFFFFFFE {
+            1
+        } else {
+            seq + 1
+        };
+        tx.execute(
+            "UPDATE RUST_SEQ_ID_1 SET SEQ_ID = $1 WHERE ID = $2",
+            &[&next, &id],
+        )
+        .await?;
+        seq
+    } else {
+        tx.execute("INSERT INTO RUST_SEQ_ID_1 (SEQ_ID) VALUES (1)", &[])
+            .await?;
+        1
+    };
+
+    tx.commit().await?;
+
+    Ok(seq as u64)
+}
+
+pub async fn has_challenge_factor_id_postgres(pool: &Pool, hash: &str) -> Result<bool, Error> {
+    let conn = pool.get().await?;
+
+    Ok(conn
+        .query_opt(
+            "SELECT ID FROM RUST_CHALLENGE_FACTORS_7 WHERE ID = $1",
+            &[&hash],
+        )
+        .await?
+        .is_some())
+}
+
+pub async fn set_challenge_factor_postgres(
+    pool: &Pool,
+    ip: &str,
+    hash: &str,
+    port: u16,
+    factors_hash: &str,
+    value: &str,
+    user_agent: Option<&str>,
+) -> Result<(), Error> {
+    let conn = pool.get().await?;
+
+    conn.execute(
+        "INSERT INTO RUST_CHALLENGE_FACTORS_7 (ID, IP, PORT, FACTORS, VALUE, USER_AGENT) VALUES ($1, $2, $3, $4, $5, $6)",
+        &[&hash, &ip, &(port as i32), &factors_hash, &value, &user_agent],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn pending_challenges_postgres(
+    pool: &Pool,
+) -> Result<Vec<(String, u16, Option<String>)>, Error> {
+    let conn = pool.get().await?;
+
+    let rows = conn
+        .query(
+            "SELECT IP, PORT, USER_AGENT FROM RUST_CHALLENGE_FACTORS_7",
+            &[],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            (
+                row.get::<usize, String>(0),
+                row.get::<usize, i32>(1) as u16,
+                row.get::<usize, Option<String>>(2),
+            )
+        })
+        .collect())
+}
+
+pub async fn extend_challenge_postgres(
+    args: &args::Args,
+    pool: &Pool,
+    id: &str,
+    addr: &str,
+) -> Result<bool, Error> {
+    let conn = pool.get().await?;
+
+    let extended = conn
+        .execute(
+            "UPDATE RUST_CHALLENGE_FACTORS_7 SET GEN_TIME = GREATEST(GEN_TIME, LEAST(now(), ISSUE_TIME + make_interval(mins => $1))) WHERE ID = $2 AND IP = $3 AND GEN_TIME > now() - make_interval(mins => $4)",
+            &[
+                &(crate::db::heartbeat_extension_mins(args) as i32),
+                &id,
+                &addr,
+                &(args.challenge_timeout_mins as i32),
+            ],
+        )
+        .await?;
+
+    Ok(extended != 0)
+}
+
+pub async fn challenge_port_postgres(
+    args: &args::Args,
+    pool: &Pool,
+    id: &str,
+) -> Result<u16, Error> {
+    let conn = pool.get().await?;
+
+    let row = conn
+        .query_opt(
+            "DELETE FROM RUST_ID_TO_PORT_3 WHERE ID = $1 AND ON_TIME > now() - make_interval(mins => $2) RETURNING PORT",
+            &[&id, &(args.challenge_timeout_mins as i32)],
+        )
+        .await?
+        .ok_or(Error::Generic(String::from(
+            "gen challenge, failed to get port",
+        )))?;
+
+    Ok(row.try_get::<usize, i32>(0)? as u16)
+}
+
+pub async fn validate_client_postgres(
+    args: &args::Args,
+    pool: &Pool,
+    challenge_response: &json_types::ChallengeResponse,
+    hashed_factors: &str,
+    addr: &str,
+) -> Result<u16, Error> {
+    let mut conn = pool.get().await?;
+    let tx = conn.transaction().await?;
+
+    let row = tx
+        .query_opt(
+            "SELECT IP, PORT, VALUE FROM RUST_CHALLENGE_FACTORS_7 WHERE ID = $1 AND FACTORS = $2 AND GEN_TIME > now() - make_interval(mins => $3) FOR UPDATE",
+            &[
+                &challenge_response.id(),
+                &hashed_factors,
+                &(args.challenge_timeout_mins as i32),
+            ],
+        )
+        .await?;
+
+    let (port, failure): (u16, Option<&str>) = match &row {
+        Some(row) => {
+            let client_addr: String = row.try_get(0)?;
+            let port: u16 = row.try_get::<usize, i32>(1)? as u16;
+            let value: String = row.try_get(2)?;
+
+            if client_addr != addr || port == 0 {
+                (port, Some("Invalid entries from ChallengeFactor"))
+            } else if !helpers::verify_challenge_answer(&value, challenge_response)? {
+                (port, Some("Incorrect"))
+            } else {
+                (port, None)
+            }
+        }
+        None => (0, Some("Incorrect")),
+    };
+
+    if let Some(failure) = failure {
+        // Rolled back, the audit row is written outside the transaction.
+        drop(tx);
+        if args.enable_audit_log {
+            insert_audit_log_postgres(&conn, addr, port, AuditEvent::Failed).await?;
+        }
+        return Err(String::from(failure).into());
+    }
+
+    tx.execute(
+        "DELETE FROM RUST_CHALLENGE_FACTORS_7 WHERE ID = $1",
+        &[&challenge_response.id()],
+    )
+    .await?;
+    tx.execute(
+        "INSERT INTO RUST_ALLOWED_IPS (IP, PORT) VALUES ($1, $2)",
+        &[&addr, &(port as i32)],
+    )
+    .await?;
+    if args.enable_audit_log {
+        insert_audit_log_postgres(&tx, addr, port, AuditEvent::Passed).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(port)
+}
+
+pub async fn check_is_allowed_postgres(
+    args: &args::Args,
+    pool: &Pool,
+    addr: &str,
+    port: u16,
+) -> Result<bool, Error> {
+    let conn = pool.get().await?;
+
+    Ok(conn
+        .query_opt(
+            "SELECT IP FROM RUST_ALLOWED_IPS WHERE IP = $1 AND PORT = $2 AND ON_TIME > now() - make_interval(mins => $3) LIMIT 1",
+            &[&addr, &(port as i32), &(args.allowed_timeout_mins as i32)],
+        )
+        .await?
+        .is_some())
+}
+
+pub async fn recently_allowed_postgres(
+    pool: &Pool,
+    within: Duration,
+) -> Result<Vec<(String, u16, Duration)>, Error> {
+    let conn = pool.get().await?;
+
+    let rows = conn
+        .query(
+            "SELECT IP, PORT, EXTRACT(EPOCH FROM now() - ON_TIME)::BIGINT FROM RUST_ALLOWED_IPS WHERE ON_TIME > now() - make_interval(secs => $1)",
+            &[&within.as_secs_f64()],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            (
+                row.get::<usize, String>(0),
+                row.get::<usize, i32>(1) as u16,
+                Duration::from_secs(row.get::<usize, i64>(2).max(0) as u64),
+            )
+        })
+        .collect())
+}
+
+pub async fn revoke_allowed_postgres(
+    pool: &Pool,
+    addr: Option<&str>,
+    port: Option<u16>,
+) -> Result<(), Error> {
+    let conn = pool.get().await?;
+
+    conn.execute(
+        "DELETE FROM RUST_ALLOWED_IPS WHERE ($1::VARCHAR IS NULL OR IP = $1) AND ($2::INTEGER IS NULL OR PORT = $2)",
+        &[&addr, &port.map(|port| port as i32)],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn purge_expired_postgres(args: &args::Args, pool: &Pool) -> Result<PurgedRows, Error> {
+    let conn = pool.get().await?;
+
+    Ok(PurgedRows {
+        challenge_factors: conn
+            .execute(
+                "DELETE FROM RUST_CHALLENGE_FACTORS_7 WHERE GEN_TIME <= now() - make_interval(mins => $1)",
+                &[&(args.challenge_timeout_mins as i32)],
+            )
+            .await?,
+        allowed_ips: conn
+            .execute(
+                "DELETE FROM RUST_ALLOWED_IPS WHERE ON_TIME <= now() - make_interval(mins => $1)",
+                &[&(args.allowed_timeout_mins as i32)],
+            )
+            .await?,
+        id_to_port: conn
+            .execute(
+                "DELETE FROM RUST_ID_TO_PORT_3 WHERE ON_TIME <= now() - make_interval(mins => $1)",
+                &[&(args.challenge_timeout_mins as i32)],
+            )
+            .await?,
+        audit_log: match args.audit_log_retention_mins {
+            Some(audit_log_retention_mins) => {
+                conn.execute(
+                    "DELETE FROM RUST_AUDIT_LOG WHERE ON_TIME <= now() - make_interval(mins => $1)",
+                    &[&(audit_log_retention_mins as i32)],
+                )
+                .await?
+            }
+            None => 0,
+        },
+    })
+}
+
+pub async fn add_allowed_postgres(pool: &Pool, addr: &str, port: u16) -> Result<(), Error> {
+    let conn = pool.get().await?;
+
+    conn.execute(
+        "INSERT INTO RUST_ALLOWED_IPS (IP, PORT) VALUES ($1, $2)",
+        &[&addr, &(port as i32)],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn init_id_to_port_postgres(
+    args: &args::Args,
+    pool: &Pool,
+    addr: &str,
+    port: u16,
+) -> Result<String, Error> {
+    let conn = pool.get().await?;
+
+    let mut buf = [0u8; GETRANDOM_BUF_SIZE];
+    loop {
+        let mut hasher = helpers::salted_hasher(args);
+        getrandom::fill(&mut buf)?;
+        hasher.update(&buf);
+        let hash = hasher.finalize().to_string();
+
+        let inserted = conn
+            .execute(
+                "INSERT INTO RUST_ID_TO_PORT_3 (ID, PORT) VALUES ($1, $2) ON CONFLICT (ID) DO NOTHING",
+                &[&hash, &(port as i32)],
+            )
+            .await?;
+        if inserted == 1 {
+            if args.enable_audit_log {
+                insert_audit_log_postgres(&conn, addr, port, AuditEvent::Issued).await?;
+            }
+            return Ok(hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_config() {
+        let config_map: HashMap<String, String> = HashMap::new();
+        assert!(get_postgres_db_pool(&config_map).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use ipnet::IpNet;
+
+use crate::{constants, error::Error, helpers, signal};
+
+/// Challenge difficulty multiplier for clients in the "bad" tier.
+const BAD_TIER_FACTORS_MULTIPLIER: u64 = 2;
+/// How far around a "bad" entry counts as near it for is_near_bad.
+const NEAR_BAD_IPV4_PREFIX: u8 = 24;
+const NEAR_BAD_IPV6_PREFIX: u8 = 48;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReputationTier {
+    /// Skips the challenge entirely.
+    Good,
+    /// Gets the normal challenge, same as unlisted clients.
+    Suspicious,
+    /// Gets a harder challenge.
+    Bad,
+}
+
+impl ReputationTier {
+    /// Returns how many quads the factors challenge should have for this tier,
+    /// or None if the challenge is skipped.
+    pub fn factors_quads(&self, quads: u64) -> Option<u64> {
+        match self {
+            ReputationTier::Good => None,
+            ReputationTier::Suspicious => Some(quads),
+            ReputationTier::Bad => Some(quads.saturating_mul(BAD_TIER_FACTORS_MULTIPLIER)),
+        }
+    }
+
+    /// Like factors_quads for the hashcash challenge; one more bit doubles
+    /// the expected work.
+    pub fn hashcash_bits(&self, bits: u8) -> Option<u8> {
+        match self {
+            ReputationTier::Good => None,
+            ReputationTier::Suspicious => Some(bits),
+            ReputationTier::Bad => Some((bits + 1).min(constants::MAX_HASHCASH_BITS)),
+        }
+    }
+}
+
+/// IP/CIDR to tier mappings from "--reputation-file". Each line is an address
+/// or CIDR followed by "good", "suspicious", or "bad"; "#" starts a comment.
+#[derive(Debug, Default)]
+pub struct Reputation {
+    entries: Vec<(IpNet, ReputationTier)>,
+}
+
+impl Reputation {
+    pub fn parse(contents: &str) -> Result<Self, Error> {
+        let mut entries = Vec::new();
+        for (idx, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let (Some(net), Some(tier), None) = (parts.next(), parts.next(), parts.next()) else {
+                return Err(
+                    format!("Invalid reputation entry on line {}: \"{}\"", idx + 1, line).into(),
+                );
+            };
+            let net: IpNet = helpers::parse_ip_net(net)
+                .map_err(|e| Error::Generic(format!("{} on line {}", e, idx + 1)))?;
+            let tier = match tier {
+                "good" => ReputationTier::Good,
+                "suspicious" => ReputationTier::Suspicious,
+                "bad" => ReputationTier::Bad,
+                _ => {
+                    return Err(format!("Invalid tier \"{}\" on line {}", tier, idx + 1).into());
+                }
+            };
+            entries.push((net, tier));
+        }
+
+        Ok(Self { entries })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Returns the tier of the most specific range containing "addr".
+    pub fn tier(&self, addr: &str) -> Option<ReputationTier> {
+        let addr: IpAddr = addr.parse().ok()?;
+        self.entries
+            .iter()
+            .filter(|(net, _)| net.contains(&addr))
+            .max_by_key(|(net, _)| net.prefix_len())
+            .map(|(_, tier)| *tier)
+    }
+
+    /// Returns true if "addr" isn't "bad" itself but is in the same /24
+    /// (IPv4) or /48 (IPv6) as a "bad" entry.
+    pub fn is_near_bad(&self, addr: &str) -> bool {
+        let Ok(ip) = addr.parse::<IpAddr>() else {
+            return false;
+        };
+        if self.tier(addr) == Some(ReputationTier::Bad) {
+            return false;
+        }
+
+        self.entries
+            .iter()
+            .filter(|(_, tier)| *tier == ReputationTier::Bad)
+            .any(|(net, _)| {
+                let prefix_len = match net {
+                    IpNet::V4(_) => NEAR_BAD_IPV4_PREFIX,
+                    IpNet::V6(_) => NEAR_BAD_IPV6_PREFIX,
+                };
+                IpNet::new(net.addr(), prefix_len.min(net.prefix_len()))
+                    .is_ok_and(|near| near.trunc().contains(&ip))
+            })
+    }
+}
+
+/// Shared handle to the loaded "--reputation-file", reloaded on SIGHUP.
+#[derive(Clone, Debug)]
+pub struct ReputationList {
+    path: PathBuf,
+    reputation: Arc<RwLock<Reputation>>,
+}
+
+impl ReputationList {
+    pub fn load(path: PathBuf) -> Result<Self, Error> {
+        let reputation = Reputation::load(&path)?;
+        Ok(Self {
+            path,
+            reputation: Arc::new(RwLock::new(reputation)),
+        })
+    }
+
+    pub fn tier(&self, addr: &str) -> Result<Option<ReputationTier>, Error> {
+        Ok(self
+            .reputation
+            .read()
+            .map_err(|_| Error::Generic("Failed to lock ReputationList".into()))?
+            .tier(addr))
+    }
+
+    pub fn is_near_bad(&self, addr: &str) -> Result<bool, Error> {
+        Ok(self
+            .reputation
+            .read()
+            .map_err(|_| Error::Generic("Failed to lock ReputationList".into()))?
+            .is_near_bad(addr))
+    }
+
+    pub fn reload(&self) -> Result<(), Error> {
+        let reputation = Reputation::load(&self.path)?;
+        *self
+            .reputation
+            .write()
+            .map_err(|_| Error::Generic("Failed to lock ReputationList".into()))? = reputation;
+
+        Ok(())
+    }
+
+    /// Reloads the file whenever SIGHUP is received. A file that fails to
+    /// load keeps the previous mappings.
+    pub fn spawn_reload_on_sighup(&self) {
+        let list = self.clone();
+        signal::spawn_reloader(format!("{:?}", self.path), move || {
+            std::future::ready(list.reload())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reputation_tiers() {
+        let reputation = Reputation::parse(
+            "# monitoring\n\
+             198.51.100.0/24 good\n\
+             198.51.100.66 bad # noisy host inside the good range\n\
+             203.0.113.0/24 suspicious\n\
+             2001:db8::/32 bad\n",
+        )
+        .unwrap();
+
+        assert_eq!(reputation.tier("198.51.100.7"), Some(ReputationTier::Good));
+        assert_eq!(reputation.tier("198.51.100.66"), Some(ReputationTier::Bad));
+        assert_eq!(
+            reputation.tier("203.0.113.9"),
+            Some(ReputationTier::Suspicious)
+        );
+        assert_eq!(reputation.tier("2001:db8::1"), Some(ReputationTier::Bad));
+        assert_eq!(reputation.tier("192.0.2.1"), None);
+
+        assert_eq!(ReputationTier::Good.factors_quads(100), None);
+        assert_eq!(ReputationTier::Suspicious.factors_quads(100), Some(100));
+        assert_eq!(ReputationTier::Bad.factors_quads(100), Some(200));
+        assert_eq!(ReputationTier::Bad.hashcash_bits(20), Some(21));
+
+        assert!(Reputation::parse("198.51.100.0/24 great").is_err());
+        assert!(Reputation::parse("not-an-ip good").is_err());
+        assert!(Reputation::parse("198.51.100.0/24").is_err());
+
+        assert!(reputation.is_near_bad("198.51.100.67"));
+        assert!(!reputation.is_near_bad("198.51.100.66"));
+        assert!(!reputation.is_near_bad("198.51.101.66"));
+        assert!(!reputation.is_near_bad("2001:db8::1"));
+        assert!(!reputation.is_near_bad("203.0.113.9"));
+    }
+}
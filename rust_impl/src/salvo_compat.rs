@@ -14,22 +14,37 @@
 // OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
 // PERFORMANCE OF THIS SOFTWARE.
 
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 
 use futures::{StreamExt, TryStreamExt, stream::FuturesUnordered};
 use salvo::{
     Listener,
-    conn::{Acceptor, Holding, TcpListener, tcp::TcpAcceptor},
+    conn::{
+        Accepted, Acceptor, HandshakeStream, Holding, TcpListener,
+        rustls::ServerConfig,
+        tcp::{TcpAcceptor, TcpCoupler},
+    },
     fuse::FusePolicy,
+    http::{Version, uri::Scheme},
 };
-use tokio::net::ToSocketAddrs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
 
-pub struct TcpVectorAcceptor {
-    acceptors: Vec<TcpAcceptor>,
+/// Accepts from several acceptors of the same kind, e.g. several TCP or TLS
+/// ports, as if they were one.
+pub struct VectorAcceptor<A> {
+    acceptors: Vec<A>,
     holdings: Vec<Holding>,
 }
 
-impl TcpVectorAcceptor {
+impl<A> VectorAcceptor<A>
+where
+    A: Acceptor,
+{
     fn new() -> Self {
         Self {
             acceptors: Vec::new(),
@@ -47,9 +62,12 @@ impl TcpVectorAcceptor {
     }
 }
 
-impl Acceptor for TcpVectorAcceptor {
-    type Coupler = <TcpAcceptor as Acceptor>::Coupler;
-    type Stream = <TcpAcceptor as Acceptor>::Stream;
+impl<A> Acceptor for VectorAcceptor<A>
+where
+    A: Acceptor,
+{
+    type Coupler = A::Coupler;
+    type Stream = A::Stream;
 
     fn holdings(&self) -> &[Holding] {
         &self.holdings
@@ -66,21 +84,21 @@ impl Acceptor for TcpVectorAcceptor {
             .try_ready_chunks(1)
             .next()
             .await
-            .ok_or(std::io::Error::other("accept on TcpVectorAcceptor Failed"))?
+            .ok_or(std::io::Error::other("accept on VectorAcceptor Failed"))?
             .map_err(|e| e.1)?
             .into_iter()
             .next()
-            .ok_or(std::io::Error::other("accept on TcpVectorAcceptor Failed"))
+            .ok_or(std::io::Error::other("accept on VectorAcceptor Failed"))
     }
 }
 
-pub struct TcpVectorListener<T> {
-    listeners: Vec<TcpListener<T>>,
+pub struct VectorListener<L> {
+    listeners: Vec<L>,
 }
 
-impl<T> TcpVectorListener<T>
+impl<L> VectorListener<L>
 where
-    T: ToSocketAddrs + Send,
+    L: Listener,
 {
     pub fn new() -> Self {
         Self {
@@ -88,19 +106,19 @@ where
         }
     }
 
-    pub fn push(&mut self, listener: TcpListener<T>) {
+    pub fn push(&mut self, listener: L) {
         self.listeners.push(listener);
     }
 }
 
-impl<T> Listener for TcpVectorListener<T>
+impl<L> Listener for VectorListener<L>
 where
-    T: ToSocketAddrs + Send + 'static,
+    L: Listener + 'static,
 {
-    type Acceptor = TcpVectorAcceptor;
+    type Acceptor = VectorAcceptor<L::Acceptor>;
 
     async fn try_bind(self) -> salvo::core::Result<Self::Acceptor> {
-        let mut v_acceptor = TcpVectorAcceptor::new();
+        let mut v_acceptor = VectorAcceptor::new();
 
         for listener in self.listeners.into_iter() {
             v_acceptor.acceptors.push(listener.try_bind().await?);
@@ -111,3 +129,288 @@ where
         Ok(v_acceptor)
     }
 }
+
+pub type TcpVectorListener<T> = VectorListener<TcpListener<T>>;
+
+type PeerSet = Arc<Mutex<HashSet<SocketAddr>>>;
+
+/// Remote addresses of the open TLS connections whose client presented a
+/// certificate that verified against "--tls-client-ca".
+#[derive(Clone, Default)]
+pub struct ClientCertPeers {
+    peers: PeerSet,
+}
+
+impl ClientCertPeers {
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.peers.lock().unwrap().contains(addr)
+    }
+}
+
+/// Remote addresses of the open connections that "--tls-detect" found to be
+/// plain HTTP on a TLS port.
+#[derive(Clone, Default)]
+pub struct PlainHttpPeers {
+    peers: PeerSet,
+}
+
+impl PlainHttpPeers {
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.peers.lock().unwrap().contains(addr)
+    }
+}
+
+/// Replays the byte "--tls-detect" read off a connection before the rest of
+/// it.
+pub struct Peeked<S> {
+    first: Option<u8>,
+    inner: S,
+}
+
+impl<S> AsyncRead for Peeked<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if let Some(first) = self.first.take() {
+            buf.put_slice(&[first]);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for Peeked<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+enum PeerInner<S> {
+    Tls(Box<TlsStream<Peeked<S>>>),
+    Plain(Peeked<S>),
+}
+
+/// A connection accepted on a TLS port, which was registered in
+/// "ClientCertPeers" or "PlainHttpPeers", dropping its entry once the
+/// connection closes.
+pub struct PeerStream<S> {
+    inner: PeerInner<S>,
+    registered: Option<(PeerSet, SocketAddr)>,
+}
+
+impl<S> Drop for PeerStream<S> {
+    fn drop(&mut self) {
+        if let Some((peers, addr)) = &self.registered {
+            peers.lock().unwrap().remove(addr);
+        }
+    }
+}
+
+impl<S> AsyncRead for PeerStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match &mut self.inner {
+            PeerInner::Tls(inner) => Pin::new(inner).poll_read(cx, buf),
+            PeerInner::Plain(inner) => Pin::new(inner).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for PeerStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match &mut self.inner {
+            PeerInner::Tls(inner) => Pin::new(inner).poll_write(cx, buf),
+            PeerInner::Plain(inner) => Pin::new(inner).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.inner {
+            PeerInner::Tls(inner) => Pin::new(inner).poll_flush(cx),
+            PeerInner::Plain(inner) => Pin::new(inner).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.inner {
+            PeerInner::Tls(inner) => Pin::new(inner).poll_shutdown(cx),
+            PeerInner::Plain(inner) => Pin::new(inner).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Like salvo's "RustlsListener", but records which connections presented a
+/// client certificate, which salvo doesn't expose to handlers. Given
+/// "plain_peers" it also accepts plain HTTP, telling the two apart by the
+/// first byte (0x16 starts every TLS handshake).
+pub struct TlsListener {
+    inner: TcpListener<String>,
+    config: Arc<ServerConfig>,
+    peers: ClientCertPeers,
+    plain_peers: Option<PlainHttpPeers>,
+}
+
+impl TlsListener {
+    pub fn new(
+        inner: TcpListener<String>,
+        config: Arc<ServerConfig>,
+        peers: ClientCertPeers,
+        plain_peers: Option<PlainHttpPeers>,
+    ) -> Self {
+        Self {
+            inner,
+            config,
+            peers,
+            plain_peers,
+        }
+    }
+}
+
+impl Listener for TlsListener {
+    type Acceptor = TlsListenerAcceptor;
+
+    async fn try_bind(self) -> salvo::core::Result<Self::Acceptor> {
+        let inner = self.inner.try_bind().await?;
+        let holdings = inner
+            .holdings()
+            .iter()
+            .map(|holding| Holding {
+                local_addr: holding.local_addr.clone(),
+                http_versions: vec![Version::HTTP_11, Version::HTTP_2],
+                http_scheme: Scheme::HTTPS,
+            })
+            .collect();
+
+        Ok(TlsListenerAcceptor {
+            inner,
+            holdings,
+            acceptor: TlsAcceptor::from(self.config),
+            peers: self.peers,
+            plain_peers: self.plain_peers,
+        })
+    }
+}
+
+pub struct TlsListenerAcceptor {
+    inner: TcpAcceptor,
+    holdings: Vec<Holding>,
+    acceptor: TlsAcceptor,
+    peers: ClientCertPeers,
+    plain_peers: Option<PlainHttpPeers>,
+}
+
+impl Acceptor for TlsListenerAcceptor {
+    type Coupler = TcpCoupler<Self::Stream>;
+    type Stream = HandshakeStream<PeerStream<<TcpAcceptor as Acceptor>::Stream>>;
+
+    fn holdings(&self) -> &[Holding] {
+        &self.holdings
+    }
+
+    async fn accept(
+        &mut self,
+        fuse_policy: Option<Arc<dyn FusePolicy>>,
+    ) -> std::io::Result<Accepted<Self::Coupler, Self::Stream>> {
+        let Accepted {
+            mut stream,
+            fuse_config,
+            conn_ctrl,
+            local_addr,
+            remote_addr,
+            ..
+        } = self.inner.accept(fuse_policy).await?;
+
+        let acceptor = self.acceptor.clone();
+        let peers = self.peers.clone();
+        let plain_peers = self.plain_peers.clone();
+        let peer_addr = remote_addr.clone().into_std();
+        let handshake = async move {
+            let mut first = None;
+            if let Some(plain_peers) = plain_peers {
+                let mut byte = [0u8; 1];
+                if stream.read(&mut byte).await? == 0 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                first = Some(byte[0]);
+                if byte[0] != 0x16 {
+                    let registered = peer_addr.map(|addr| {
+                        plain_peers.peers.lock().unwrap().insert(addr);
+                        (plain_peers.peers, addr)
+                    });
+                    return Ok(PeerStream {
+                        inner: PeerInner::Plain(Peeked {
+                            first,
+                            inner: stream,
+                        }),
+                        registered,
+                    });
+                }
+            }
+            let inner = acceptor
+                .accept(Peeked {
+                    first,
+                    inner: stream,
+                })
+                .await?;
+            let has_client_cert = inner
+                .get_ref()
+                .1
+                .peer_certificates()
+                .is_some_and(|certs| !certs.is_empty());
+            let registered = match peer_addr {
+                Some(addr) if has_client_cert => {
+                    peers.peers.lock().unwrap().insert(addr);
+                    Some((peers.peers, addr))
+                }
+                _ => None,
+            };
+            Ok(PeerStream {
+                inner: PeerInner::Tls(Box::new(inner)),
+                registered,
+            })
+        };
+
+        Ok(Accepted {
+            coupler: TcpCoupler::new(),
+            stream: HandshakeStream::new(handshake, fuse_config),
+            fuse_config,
+            conn_ctrl,
+            local_addr,
+            remote_addr,
+            http_scheme: Scheme::HTTPS,
+        })
+    }
+}
@@ -0,0 +1,872 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use salvo::conn::rustls::{Keycert, RustlsConfig, ServerConfig};
+use salvo::prelude::*;
+
+use crate::db::{DbPool, init_db, purge_expired};
+use crate::handlers::{
+    api_fn, auth_request_fn, factors_js_fn, handler_fn, heartbeat_fn, plain_http_redirect_fn,
+};
+use crate::logging::{Logger, StartupEvent};
+use crate::state::{LiveArgs, SharedState};
+use crate::{args, benchmark, constants, error::Error, helpers, salvo_compat, signal, traffic};
+
+pub(crate) fn local_port(req: &Request) -> Option<u16> {
+    if let Some(ipv4) = req.local_addr().as_ipv4() {
+        Some(ipv4.port())
+    } else {
+        req.local_addr().as_ipv6().map(|ipv6| ipv6.port())
+    }
+}
+
+/// A route per distinct url in "overrides" and "default", each matching only
+/// on the listening ports that use it, so on other ports the path falls
+/// through to handler_fn like any other.
+fn port_filtered_routes(overrides: &HashMap<u16, String>, default: &String) -> Vec<Router> {
+    let mut urls: Vec<&String> = overrides.values().chain([default]).collect();
+    urls.sort();
+    urls.dedup();
+
+    urls.into_iter()
+        .map(|url| {
+            let overrides = overrides.clone();
+            let default = default.clone();
+            let url = url.to_owned();
+            Router::new().path(&url).filter_fn(move |req, _| {
+                local_port(req).is_none_or(|port| overrides.get(&port).unwrap_or(&default) == &url)
+            })
+        })
+        .collect()
+}
+
+/// Routes for the api and worker js urls of every listening port, and the
+/// heartbeat url if "--challenge-heartbeat-max" is set.
+pub(crate) fn challenge_routes(args: &args::Args) -> Vec<Router> {
+    port_filtered_routes(&args.port_to_api_urls, &args.api_url)
+        .into_iter()
+        .map(|route| route.post(api_fn))
+        .chain(
+            port_filtered_routes(&args.port_to_js_factors_urls, &args.js_factors_url)
+                .into_iter()
+                .map(|route| route.get(factors_js_fn)),
+        )
+        .chain(
+            args.challenge_heartbeat_max_mins
+                .map(|_| Router::new().path(&args.heartbeat_url).post(heartbeat_fn)),
+        )
+        .collect()
+}
+
+/// The router main serves: the challenge routes, the "--auth-request-url"
+/// route and handler_fn for everything else, with "state" injected for every
+/// handler.
+pub fn build_router(state: &SharedState) -> Router {
+    let args = &state.args;
+    let mut router = state.inject(Router::new());
+    if state.plain_http_peers.is_some() {
+        router = router.hoop(plain_http_redirect_fn);
+    }
+
+    for route in challenge_routes(args) {
+        router = router.push(route);
+    }
+    if let Some(auth_request_url) = &args.auth_request_url {
+        router = router.push(Router::new().path(auth_request_url).get(auth_request_fn));
+    }
+    // Every method goes to handler_fn, which forwards it as-is.
+    router.push(Router::new().path("{**}").goal(handler_fn))
+}
+
+/// Stops "handle" once a signal is received, giving in-flight requests up to
+/// "timeout" to complete.
+fn spawn_graceful_shutdown(handle: salvo::server::ServerHandle, timeout: Duration) {
+    tokio::spawn(async move {
+        loop {
+            if signal::SIGNAL_HANDLED.load(std::sync::atomic::Ordering::Relaxed) {
+                eprintln!(
+                    "Signal received, stopping (waiting up to {:?} for in-flight requests)",
+                    timeout
+                );
+                handle.stop_graceful(Some(timeout));
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(333)).await;
+        }
+    });
+}
+
+/// Creates the server for "acceptor", with HTTP/1 keep-alive off if
+/// "--force-close" is set.
+pub(crate) fn new_server<A: salvo::conn::Acceptor + Send>(
+    acceptor: A,
+    args: &args::Args,
+) -> Server<A> {
+    let mut server = Server::new(acceptor);
+    server.http1_mut().keep_alive(!args.force_close);
+    server
+}
+
+/// Builds one TCP listener per "--addr-port=...", however many are given.
+pub(crate) fn tcp_vector_listener(args: &args::Args) -> salvo_compat::TcpVectorListener<String> {
+    let mut listener = salvo_compat::TcpVectorListener::new();
+    for addr_port_str in args.addr_port_strs.iter() {
+        listener.push(TcpListener::new(addr_port_str.to_owned()));
+    }
+
+    listener
+}
+
+/// Builds one TLS listener per "--addr-port=..." using the "--tls-cert=..." and
+/// "--tls-key=..." pair. Every port serves the same certificate. With
+/// "--tls-client-ca=..." clients may present a certificate, and those that do
+/// are recorded in "peers". With "--tls-detect" plain HTTP is accepted too,
+/// recorded in "plain_peers".
+fn tls_vector_listener(
+    args: &args::Args,
+    peers: &salvo_compat::ClientCertPeers,
+    plain_peers: Option<&salvo_compat::PlainHttpPeers>,
+) -> Result<salvo_compat::VectorListener<salvo_compat::TlsListener>, Error> {
+    let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) else {
+        return Err("--tls-cert and --tls-key are required for TLS".into());
+    };
+    let mut config = RustlsConfig::new(Keycert::new().cert_from_path(cert)?.key_from_path(key)?);
+    if let Some(client_ca) = &args.tls_client_ca {
+        config = config.client_auth_optional_path(client_ca)?;
+    }
+    let config: Arc<ServerConfig> = Arc::new(config.try_into()?);
+
+    let mut listener = salvo_compat::VectorListener::new();
+    for addr_port_str in args.addr_port_strs.iter() {
+        listener.push(salvo_compat::TlsListener::new(
+            TcpListener::new(addr_port_str.to_owned()),
+            config.clone(),
+            peers.clone(),
+            plain_peers.cloned(),
+        ));
+    }
+
+    Ok(listener)
+}
+
+/// Sets up the databases and shared state for "parsed_args", then serves
+/// until a shutdown signal.
+pub async fn run(mut parsed_args: args::Args) {
+    signal::register_signal_handlers();
+
+    if parsed_args.factors.is_none() {
+        parsed_args.factors = Some(constants::DEFAULT_FACTORS_QUADS);
+        println!(
+            "\"--factors=<digits>\" not specified, defaulting to \"{}\"",
+            constants::DEFAULT_FACTORS_QUADS
+        );
+    }
+
+    if parsed_args.purge_expired || parsed_args.benchmark_clients.is_some() {
+        let mut db_depot = Depot::new();
+        db_depot.insert_typed(LiveArgs::new(parsed_args.clone()));
+        DbPool::new(&parsed_args)
+            .await
+            .expect("Should be able to set up database pool")
+            .insert_into(&mut db_depot);
+        init_db(&db_depot)
+            .await
+            .expect("Should be able to init database");
+
+        if let Some(clients) = parsed_args.benchmark_clients {
+            match benchmark::run_benchmark(
+                Arc::new(db_depot),
+                clients,
+                parsed_args.benchmark_iterations,
+            )
+            .await
+            {
+                Ok(report) => println!("{}", report),
+                Err(e) => eprintln!("ERROR: Benchmark failed: {}", e),
+            }
+        } else {
+            match purge_expired(&db_depot).await {
+                Ok(purged) => println!("{}", purged),
+                Err(e) => eprintln!("ERROR: Purge failed: {}", e),
+            }
+        }
+        return;
+    }
+
+    let logger = Logger::new(&parsed_args);
+    logger.startup(
+        StartupEvent::Banner,
+        format_args!("{}", helpers::startup_banner(&parsed_args)),
+    );
+    if parsed_args.enable_override_dest_url {
+        logger.startup(
+            StartupEvent::OverrideDestUrl,
+            format_args!(
+                "--enable-override-dest-url is active! A well set-up firewall is highly recommended!"
+            ),
+        );
+    }
+    if parsed_args.monitor_only {
+        logger.startup(
+            StartupEvent::MonitorOnly,
+            format_args!(
+                "--monitor-only is active! No client is challenged, clients that would have been are only logged."
+            ),
+        );
+    }
+
+    let state = SharedState::new(&parsed_args)
+        .await
+        .expect("Should be able to set up the shared state");
+    if let Some(replay_traffic) = &parsed_args.replay_traffic {
+        match traffic::replay(&state.depot(), replay_traffic).await {
+            Ok(report) => println!("{}", report),
+            Err(e) => eprintln!("ERROR: Replay failed: {}", e),
+        }
+        return;
+    }
+    state
+        .spawn_tasks()
+        .expect("Should be able to start the background tasks");
+    let router = build_router(&state);
+
+    if parsed_args.tls_cert.is_some() {
+        let listener = tls_vector_listener(
+            &parsed_args,
+            &state.client_cert_peers,
+            state.plain_http_peers.as_ref(),
+        )
+        .expect("Should be able to load --tls-cert and --tls-key");
+        let server = new_server(listener.bind().await, &parsed_args);
+        spawn_graceful_shutdown(
+            server.handle(),
+            Duration::from_secs(parsed_args.shutdown_timeout_secs),
+        );
+        server.serve(router).await;
+    } else {
+        let server = new_server(tcp_vector_listener(&parsed_args).bind().await, &parsed_args);
+        spawn_graceful_shutdown(
+            server.handle(),
+            Duration::from_secs(parsed_args.shutdown_timeout_secs),
+        );
+        server.serve(router).await;
+    }
+
+    if let Err(e) = state.flush() {
+        eprintln!("WARNING: Failed to save the db on shutdown: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+
+    use super::*;
+    use crate::db::{
+        add_allowed_sqlite, check_is_allowed_sqlite, init_id_to_port_sqlite, init_sqlite_db,
+    };
+    use crate::test_util::{test_cert, test_request, test_service, test_sqlite_args};
+    use crate::{ffi, json_types};
+
+    #[tokio::test]
+    async fn test_unix_socket_listener() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut request: Vec<u8> = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buf).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 4\r\nconnection: close\r\n\r\nunix")
+                .await
+                .unwrap();
+        });
+
+        let args = args::Args {
+            dest_url: Some(format!("http://{}", upstream_addr)),
+            port_to_dest_urls: HashMap::from([(8180, vec!["http://127.0.0.1:9".into()])]),
+            bypass_paths: vec!["/*".into()],
+            ..test_sqlite_args("unix_socket_listener")
+        };
+        let service = test_service(&args).await;
+
+        let (unix_stream, _) = tokio::net::UnixStream::pair().unwrap();
+        let mut req = test_request(
+            TestClient::get("http://127.0.0.1:8180/"),
+            "203.0.113.43",
+            8180,
+        );
+        *req.local_addr_mut() = unix_stream.local_addr().unwrap().into();
+        let mut res = service.handle(req).await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        assert_eq!(res.take_string().await.unwrap(), "unix");
+    }
+
+    #[tokio::test]
+    async fn test_force_close() {
+        use salvo::conn::Acceptor;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let mut request: Vec<u8> = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..read]);
+                }
+                stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello",
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let mut closed = Vec::new();
+        for force_close in [false, true] {
+            let args = args::Args {
+                addr_port_strs: vec!["127.0.0.1:0".into()],
+                dest_url: Some(format!("http://{}", upstream_addr)),
+                force_close,
+                ..test_sqlite_args(&format!("force_close_{}", force_close))
+            };
+            init_sqlite_db(&args).await.unwrap();
+            let acceptor = tcp_vector_listener(&args).bind().await;
+            let port = acceptor.holdings()[0].local_addr.port().unwrap();
+            add_allowed_sqlite(&args, "203.0.113.36", port)
+                .await
+                .unwrap();
+            let router = build_router(&SharedState::new(&args).await.unwrap());
+            let server = new_server(acceptor, &args);
+            let handle = server.handle();
+            tokio::spawn(server.serve(router));
+
+            let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+                .await
+                .unwrap();
+            let request = b"GET / HTTP/1.1\r\nhost: 127.0.0.1\r\nx-real-ip: 203.0.113.36\r\n\r\n";
+            let mut responses: Vec<String> = Vec::new();
+            for _ in 0..2 {
+                if stream.write_all(request).await.is_err() {
+                    break;
+                }
+                let mut response: Vec<u8> = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !response.ends_with(b"hello") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => response.extend_from_slice(&buf[..read]),
+                    }
+                }
+                if response.is_empty() {
+                    break;
+                }
+                responses.push(String::from_utf8_lossy(&response).to_lowercase());
+            }
+            handle.stop_forceful();
+            std::fs::remove_file(&args.sqlite_db_file).ok();
+
+            assert!(responses[0].starts_with("http/1.1 200"));
+            closed.push((responses.len(), responses[0].contains("connection: close")));
+        }
+
+        assert_eq!(closed, vec![(2, false), (1, true)]);
+    }
+
+    #[tokio::test]
+    async fn test_port_to_api_url() {
+        let args = args::Args {
+            challenge_type: args::ChallengeType::Hashcash,
+            hashcash_bits: 8,
+            port_to_api_urls: HashMap::from([(8181, "/pma_api_8181".to_owned())]),
+            ..test_sqlite_args("port_to_api_url")
+        };
+        init_sqlite_db(&args).await.unwrap();
+        let id = init_id_to_port_sqlite(&args, "203.0.113.35", 8181)
+            .await
+            .unwrap();
+        let service = test_service(&args).await;
+
+        let js = service
+            .handle(test_request(
+                TestClient::get(format!("http://127.0.0.1:8181/pma_factors.js?id={}", id)),
+                "203.0.113.35",
+                8181,
+            ))
+            .await
+            .take_string()
+            .await
+            .unwrap();
+        assert!(js.contains("let url = \"/pma_api_8181\";"));
+        let prefix = js
+            .split_once("const prefix = \"")
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(prefix, _)| prefix.to_owned())
+            .unwrap();
+        let uuid = js
+            .split_once("\"id\": \"")
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(uuid, _)| uuid.to_owned())
+            .unwrap();
+        let value = format!("8:{}", prefix);
+        let nonce = (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| helpers::hashcash_solves(&value, nonce).unwrap())
+            .unwrap();
+        let answer = json_types::HashcashResponse {
+            r#type: "hashcash".into(),
+            id: uuid,
+            nonce,
+        };
+
+        // On port 8181 the global api url is just another path, so the
+        // unallowed client is challenged instead of answering.
+        let mut global = service
+            .handle(test_request(
+                TestClient::post("http://127.0.0.1:8181/pma_api").json(&answer),
+                "203.0.113.35",
+                8181,
+            ))
+            .await;
+        let global_body = global.take_string().await.unwrap();
+        let overridden = service
+            .handle(test_request(
+                TestClient::post("http://127.0.0.1:8181/pma_api_8181").json(&answer),
+                "203.0.113.35",
+                8181,
+            ))
+            .await;
+        let is_allowed = check_is_allowed_sqlite(&args, "203.0.113.35", 8181)
+            .await
+            .unwrap();
+        let page_body = service
+            .handle(test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.35",
+                8180,
+            ))
+            .await
+            .take_string()
+            .await
+            .unwrap();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert!(global_body.contains("/pma_factors.js?id="));
+        assert_eq!(overridden.status_code, Some(StatusCode::OK));
+        assert!(is_allowed);
+        assert!(page_body.contains("/pma_factors.js?id="));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_vector_listener_many_ports() {
+        use salvo::conn::Acceptor;
+
+        let mut p_args: Vec<String> = vec!["--dest-url=http://127.0.0.1:9999".into()];
+        p_args.extend(std::iter::repeat_n("--addr-port=127.0.0.1:0".into(), 40));
+        let args = args::parse_args_from(p_args).unwrap();
+        let acceptor = tcp_vector_listener(&args).bind().await;
+        let mut ports: Vec<u16> = acceptor
+            .holdings()
+            .iter()
+            .map(|holding| holding.local_addr.port().unwrap())
+            .collect();
+        ports.sort();
+        ports.dedup();
+        assert_eq!(ports.len(), 40);
+    }
+
+    #[tokio::test]
+    async fn test_tls_listener() {
+        use openssl::{pkey::PKey, rsa::Rsa};
+        use salvo::conn::Acceptor;
+
+        let ca_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let ca_cert = test_cert("pma test ca", 1, &ca_key, None);
+        let server_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let server_cert = test_cert("pma test server", 2, &server_key, Some((&ca_cert, &ca_key)));
+
+        let tls_cert = std::env::temp_dir().join(format!("pma_tls_cert_{}", std::process::id()));
+        let tls_key = std::env::temp_dir().join(format!("pma_tls_key_{}", std::process::id()));
+        std::fs::write(&tls_cert, server_cert.to_pem().unwrap()).unwrap();
+        std::fs::write(&tls_key, server_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let args = args::Args {
+            addr_port_strs: vec!["127.0.0.1:0".into(), "127.0.0.1:0".into()],
+            tls_cert: Some(tls_cert.clone()),
+            tls_key: Some(tls_key.clone()),
+            ..Default::default()
+        };
+        let acceptor = tls_vector_listener(&args, &salvo_compat::ClientCertPeers::default(), None)
+            .unwrap()
+            .bind()
+            .await;
+        std::fs::remove_file(&tls_cert).ok();
+        std::fs::remove_file(&tls_key).ok();
+        let ports: Vec<u16> = acceptor
+            .holdings()
+            .iter()
+            .map(|holding| holding.local_addr.port().unwrap())
+            .collect();
+        assert_eq!(ports.len(), 2);
+
+        #[handler]
+        async fn ok_fn(res: &mut Response) {
+            res.render("ok");
+        }
+        let server = Server::new(acceptor);
+        let handle = server.handle();
+        tokio::spawn(server.serve(Router::new().get(ok_fn)));
+
+        let client = reqwest::Client::builder()
+            .add_root_certificate(
+                reqwest::Certificate::from_pem(&ca_cert.to_pem().unwrap()).unwrap(),
+            )
+            .build()
+            .unwrap();
+        for port in ports {
+            let res = client
+                .get(format!("https://127.0.0.1:{}/", port))
+                .send()
+                .await
+                .unwrap();
+            assert!(res.status().is_success());
+            assert_eq!(res.text().await.unwrap(), "ok");
+        }
+
+        handle.stop_forceful();
+    }
+
+    #[tokio::test]
+    async fn test_tls_detect() {
+        use openssl::{pkey::PKey, rsa::Rsa};
+        use salvo::conn::Acceptor;
+
+        let ca_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let ca_cert = test_cert("pma test ca", 1, &ca_key, None);
+        let server_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let server_cert = test_cert("pma test server", 2, &server_key, Some((&ca_cert, &ca_key)));
+
+        let tls_cert = std::env::temp_dir().join(format!("pma_detect_cert_{}", std::process::id()));
+        let tls_key = std::env::temp_dir().join(format!("pma_detect_key_{}", std::process::id()));
+        std::fs::write(&tls_cert, server_cert.to_pem().unwrap()).unwrap();
+        std::fs::write(&tls_key, server_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let args = args::Args {
+            addr_port_strs: vec!["127.0.0.1:0".into()],
+            tls_cert: Some(tls_cert.clone()),
+            tls_key: Some(tls_key.clone()),
+            tls_detect: true,
+            ..Default::default()
+        };
+        let plain_peers = salvo_compat::PlainHttpPeers::default();
+        let acceptor = tls_vector_listener(
+            &args,
+            &salvo_compat::ClientCertPeers::default(),
+            Some(&plain_peers),
+        )
+        .unwrap()
+        .bind()
+        .await;
+        std::fs::remove_file(&tls_cert).ok();
+        std::fs::remove_file(&tls_key).ok();
+        let port = acceptor.holdings()[0].local_addr.port().unwrap();
+
+        #[handler]
+        async fn ok_fn(res: &mut Response) {
+            res.render("ok");
+        }
+        let server = Server::new(acceptor);
+        let handle = server.handle();
+        tokio::spawn(
+            server.serve(
+                Router::new()
+                    .hoop(affix_state::inject(plain_peers))
+                    .hoop(plain_http_redirect_fn)
+                    .push(Router::new().path("{**}").get(ok_fn)),
+            ),
+        );
+
+        let plain = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let res = plain
+            .get(format!("http://127.0.0.1:{}/some/path?q=1", port))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            res.headers().get("location").unwrap(),
+            &format!("https://127.0.0.1:{}/some/path?q=1", port)
+        );
+
+        let tls = reqwest::Client::builder()
+            .add_root_certificate(
+                reqwest::Certificate::from_pem(&ca_cert.to_pem().unwrap()).unwrap(),
+            )
+            .build()
+            .unwrap();
+        let res = tls
+            .get(format!("https://127.0.0.1:{}/some/path?q=1", port))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "ok");
+
+        handle.stop_forceful();
+    }
+
+    #[tokio::test]
+    async fn test_client_cert_bypass() {
+        use openssl::{pkey::PKey, rsa::Rsa};
+        use salvo::conn::Acceptor;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let ca_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let ca_cert = test_cert("pma test ca", 1, &ca_key, None);
+        let server_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let server_cert = test_cert("pma test server", 2, &server_key, Some((&ca_cert, &ca_key)));
+        let client_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let client_cert = test_cert("pma test client", 3, &client_key, Some((&ca_cert, &ca_key)));
+
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut request: Vec<u8> = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buf).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello",
+                )
+                .await
+                .unwrap();
+        });
+
+        let tls_cert = std::env::temp_dir().join(format!("pma_cc_cert_{}", std::process::id()));
+        let tls_key = std::env::temp_dir().join(format!("pma_cc_key_{}", std::process::id()));
+        let tls_client_ca = std::env::temp_dir().join(format!("pma_cc_ca_{}", std::process::id()));
+        std::fs::write(&tls_cert, server_cert.to_pem().unwrap()).unwrap();
+        std::fs::write(&tls_key, server_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        std::fs::write(&tls_client_ca, ca_cert.to_pem().unwrap()).unwrap();
+        let args = args::Args {
+            addr_port_strs: vec!["127.0.0.1:0".into()],
+            dest_url: Some(format!("http://{}", upstream_addr)),
+            tls_cert: Some(tls_cert.clone()),
+            tls_key: Some(tls_key.clone()),
+            tls_client_ca: Some(tls_client_ca.clone()),
+            client_cert_cidrs: vec![helpers::parse_ip_net("127.0.0.0/8").unwrap()],
+            ..test_sqlite_args("client_cert_bypass")
+        };
+        let state = SharedState::new(&args).await.unwrap();
+
+        let acceptor = tls_vector_listener(&args, &state.client_cert_peers, None)
+            .unwrap()
+            .bind()
+            .await;
+        std::fs::remove_file(&tls_cert).ok();
+        std::fs::remove_file(&tls_key).ok();
+        std::fs::remove_file(&tls_client_ca).ok();
+        let port = acceptor.holdings()[0].local_addr.port().unwrap();
+
+        let server = Server::new(acceptor);
+        let handle = server.handle();
+        tokio::spawn(server.serve(build_router(&state)));
+
+        let root_cert = reqwest::Certificate::from_pem(&ca_cert.to_pem().unwrap()).unwrap();
+        let with_cert = reqwest::Client::builder()
+            .add_root_certificate(root_cert.clone())
+            .identity(
+                reqwest::Identity::from_pkcs8_pem(
+                    &client_cert.to_pem().unwrap(),
+                    &client_key.private_key_to_pem_pkcs8().unwrap(),
+                )
+                .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let without_cert = reqwest::Client::builder()
+            .add_root_certificate(root_cert)
+            .build()
+            .unwrap();
+
+        let res = with_cert
+            .get(format!("https://127.0.0.1:{}/", port))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "hello");
+
+        let res = without_cert
+            .get(format!("https://127.0.0.1:{}/", port))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let body = res.text().await.unwrap();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+        assert!(body.contains("Checking Your Browser..."));
+        assert!(body.contains(&args.js_factors_url));
+
+        handle.stop_forceful();
+    }
+
+    #[tokio::test]
+    async fn test_full_challenge_flow() {
+        use salvo::conn::Acceptor;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let mut request: Vec<u8> = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..read]);
+                }
+                stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 7\r\nconnection: close\r\n\r\nbackend",
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let args = args::Args {
+            addr_port_strs: vec!["127.0.0.1:0".into()],
+            dest_url: Some(format!("http://{}", upstream_addr)),
+            factors: Some(4),
+            ..test_sqlite_args("full_challenge_flow")
+        };
+        let router = build_router(&SharedState::new(&args).await.unwrap());
+        let acceptor = tcp_vector_listener(&args).bind().await;
+        let base_url = format!(
+            "http://127.0.0.1:{}",
+            acceptor.holdings()[0].local_addr.port().unwrap()
+        );
+        let server = new_server(acceptor, &args);
+        let handle = server.handle();
+        tokio::spawn(server.serve(router));
+        let client = reqwest::Client::new();
+
+        // No x-real-ip, so the client is 127.0.0.1 throughout.
+        let page = client.get(&base_url).send().await.unwrap();
+        assert_eq!(page.status(), reqwest::StatusCode::OK);
+        let page = page.text().await.unwrap();
+        let js_url = page
+            .split_once("new Worker(\"")
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(js_url, _)| js_url.to_owned())
+            .unwrap();
+        assert!(js_url.starts_with(&format!("{}?id=", args.js_factors_url)));
+
+        let js = client
+            .get(format!("{}{}", base_url, js_url))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let between = |start: &str| {
+            js.split_once(start)
+                .and_then(|(_, rest)| rest.split_once('"'))
+                .map(|(value, _)| value.to_owned())
+                .unwrap()
+        };
+        let mut value = ffi::decode_challenge_value(&between("let ret = [ \"")).unwrap();
+        let id = between("\"id\": \"");
+
+        let mut terms = Vec::new();
+        for prime in (2u32..=constants::DEFAULT_MAX_PRIME_FACTOR as u32)
+            .filter(|n| helpers::is_prime(*n as u16))
+        {
+            let mut count = 0;
+            while (&value % prime) == num_bigint::BigUint::from(0u32) {
+                value /= prime;
+                count += 1;
+            }
+            if count > 0 {
+                terms.push(format!("{}x{}", prime, count));
+            }
+        }
+        assert_eq!(value, num_bigint::BigUint::from(1u32));
+
+        let answer = client
+            .post(format!("{}{}", base_url, args.api_url))
+            .header("content-type", "application/json")
+            .body(
+                serde_json::to_string(&json_types::FactorsResponse {
+                    r#type: "factors".into(),
+                    id,
+                    factors: terms.join(" "),
+                })
+                .unwrap(),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(answer.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            serde_json::from_str::<json_types::ApiResult>(&answer.text().await.unwrap()).unwrap(),
+            json_types::ApiResult::ok()
+        );
+
+        let proxied = client.get(&base_url).send().await.unwrap();
+        handle.stop_forceful();
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+        assert_eq!(proxied.status(), reqwest::StatusCode::OK);
+        assert_eq!(proxied.text().await.unwrap(), "backend");
+    }
+}
@@ -14,12 +14,56 @@
 // OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
 // PERFORMANCE OF THIS SOFTWARE.
 
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::time::Duration;
+
+use crate::error::Error;
 
 pub static SIGNAL_HANDLED: AtomicBool = AtomicBool::new(false);
+/// If set, SIGHUP bumps RELOAD_GENERATION instead of stopping the server.
+pub static SIGHUP_RELOADS: AtomicBool = AtomicBool::new(false);
+/// Counts SIGHUPs, so each reloader notices every one of them.
+pub static RELOAD_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Returns true if SIGHUP was received since "seen" was last updated, which
+/// starts from RELOAD_GENERATION when the reloader is spawned.
+fn reload_requested(seen: &mut u64) -> bool {
+    let generation = RELOAD_GENERATION.load(std::sync::atomic::Ordering::Relaxed);
+    let requested = generation != *seen;
+    *seen = generation;
+    requested
+}
+
+/// Runs "reload" whenever SIGHUP is received until a signal stops the server,
+/// printing whether "name" was reloaded. Once a reloader is spawned SIGHUP no
+/// longer stops the server.
+pub fn spawn_reloader<F, Fut>(name: String, mut reload: F)
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), Error>> + Send,
+{
+    SIGHUP_RELOADS.store(true, std::sync::atomic::Ordering::Relaxed);
+    tokio::spawn(async move {
+        let mut seen = RELOAD_GENERATION.load(std::sync::atomic::Ordering::Relaxed);
+        loop {
+            tokio::time::sleep(Duration::from_millis(333)).await;
+            if SIGNAL_HANDLED.load(std::sync::atomic::Ordering::Relaxed) {
+                break;
+            }
+            if reload_requested(&mut seen) {
+                match reload().await {
+                    Ok(()) => eprintln!("Reloaded {}", name),
+                    Err(e) => eprintln!("WARNING: Failed to reload {}: {}", name, e),
+                }
+            }
+        }
+    });
+}
 
 extern "C" fn handle_signal(s: std::ffi::c_int) {
-    if s == libc::SIGINT || s == libc::SIGHUP || s == libc::SIGTERM {
+    if s == libc::SIGHUP && SIGHUP_RELOADS.load(std::sync::atomic::Ordering::Relaxed) {
+        RELOAD_GENERATION.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    } else if s == libc::SIGINT || s == libc::SIGHUP || s == libc::SIGTERM {
         SIGNAL_HANDLED.store(true, std::sync::atomic::Ordering::Relaxed);
     }
 }
@@ -0,0 +1,1161 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use reqwest::Client;
+use reqwest::redirect::Policy;
+use salvo::prelude::*;
+use tokio::sync::RwLock;
+
+use crate::db::{DbPool, init_db, recently_allowed, spawn_cleanup};
+#[cfg(feature = "policy-script")]
+use crate::policy;
+use crate::{
+    admin, args, constants, error::Error, helpers, reputation, salvo_compat, signal, token,
+    traffic, webhook,
+};
+
+pub(crate) const CACHED_TIMEOUT: Duration = Duration::from_secs(120);
+const CACHED_CLEANUP_TIMEOUT: Duration = Duration::from_secs(3600);
+
+#[derive(Clone, Debug)]
+pub struct CachedAllow {
+    allowed: Arc<Mutex<RefCell<HashMap<String, Instant>>>>,
+    inst: Arc<Mutex<Cell<Instant>>>,
+}
+
+impl Default for CachedAllow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CachedAllow {
+    pub fn new() -> Self {
+        Self {
+            allowed: Default::default(),
+            inst: Arc::new(Mutex::new(Cell::new(Instant::now()))),
+        }
+    }
+
+    /// Entries are per address and port, as allowances in the db are.
+    pub fn get_allowed(&self, addr: &str, port: u16, timeout: Duration) -> Result<bool, Error> {
+        let key = Self::key(addr, port);
+        let l = self.allowed.lock();
+        let l = l.map_err(|_| Error::Generic("Failed to lock CachedAllow".into()))?;
+        let mut b = l.borrow_mut();
+        {
+            let entry = b.get(&key);
+            if let Some(v) = entry
+                && v.elapsed() < timeout
+            {
+                return Ok(true);
+            }
+        }
+        b.remove(&key);
+
+        Ok(false)
+    }
+
+    pub fn add_allowed(&self, addr: &str, port: u16) -> Result<(), Error> {
+        let l = self.allowed.lock();
+        l.map_err(|_| Error::Generic("Failed to lock CachedAllow".into()))?
+            .borrow_mut()
+            .insert(Self::key(addr, port), Instant::now());
+
+        Ok(())
+    }
+
+    pub fn evict_expired(&self, timeout: Duration) -> Result<(), Error> {
+        let l = self.allowed.lock();
+        l.map_err(|_| Error::Generic("Failed to lock CachedAllow".into()))?
+            .borrow_mut()
+            .retain(|_, v| v.elapsed() < timeout);
+
+        Ok(())
+    }
+
+    /// Evicts expired entries every "interval" so an idle server doesn't keep
+    /// them around until the next request triggers a cleanup.
+    pub fn spawn_eviction(&self, interval: Duration, timeout: Duration) {
+        let cached_allow = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if signal::SIGNAL_HANDLED.load(std::sync::atomic::Ordering::Relaxed) {
+                    break;
+                }
+                if let Err(e) = cached_allow.evict_expired(timeout) {
+                    eprintln!(
+                        "WARNING: Failed to evict expired CachedAllow entries: {}",
+                        e
+                    );
+                }
+            }
+        });
+    }
+
+    /// Loads allowances granted within "timeout" from the db every "interval",
+    /// so clients allowed by another instance sharing the db aren't
+    /// re-challenged here.
+    pub fn spawn_refresh(&self, depot: Arc<Depot>, interval: Duration, timeout: Duration) {
+        let cached_allow = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if signal::SIGNAL_HANDLED.load(std::sync::atomic::Ordering::Relaxed) {
+                    break;
+                }
+                if let Err(e) = cached_allow.refresh(&depot, timeout).await {
+                    eprintln!("WARNING: Failed to refresh CachedAllow from db: {}", e);
+                }
+            }
+        });
+    }
+
+    async fn refresh(&self, depot: &Depot, timeout: Duration) -> Result<(), Error> {
+        let allowed = recently_allowed(depot, timeout).await?;
+
+        let l = self.allowed.lock();
+        let l = l.map_err(|_| Error::Generic("Failed to lock CachedAllow".into()))?;
+        let mut b = l.borrow_mut();
+        let now = Instant::now();
+        for (addr, port, age) in allowed.into_iter() {
+            let Some(allowed_at) = now.checked_sub(age) else {
+                continue;
+            };
+            let entry = b.entry(Self::key(&addr, port)).or_insert(allowed_at);
+            if *entry < allowed_at {
+                *entry = allowed_at;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn key(addr: &str, port: u16) -> String {
+        format!("{}|{}", addr, port)
+    }
+
+    /// Drops cached entries matching "addr" and "port". None matches any.
+    pub fn revoke(&self, addr: Option<&str>, port: Option<u16>) -> Result<(), Error> {
+        let l = self.allowed.lock();
+        l.map_err(|_| Error::Generic("Failed to lock CachedAllow".into()))?
+            .borrow_mut()
+            .retain(|key, _| {
+                let Some((key_addr, key_port)) = key.rsplit_once('|') else {
+                    return false;
+                };
+                !(addr.is_none_or(|addr| addr == key_addr)
+                    && port.is_none_or(|port| port.to_string() == key_port))
+            });
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> Result<usize, Error> {
+        let l = self.allowed.lock();
+        Ok(
+            l.map_err(|_| Error::Generic("Failed to lock CachedAllow".into()))?
+                .borrow()
+                .len(),
+        )
+    }
+
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    pub fn check_cleanup(&self) -> Result<(), Error> {
+        let il = self.inst.lock();
+        let il = il.map_err(|_| Error::Generic("Failed to lock CachedAllow.inst".into()))?;
+        if il.get().elapsed() > CACHED_CLEANUP_TIMEOUT {
+            il.set(Instant::now());
+            self.evict_expired(CACHED_TIMEOUT)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Key for the stored hash of a challenge's answer. Until the first rotation
+/// there is no key, so the hash is the same across restarts and for every
+/// instance sharing the db. Rotating it invalidates every outstanding
+/// challenge, but only in this process.
+#[derive(Clone, Debug, Default)]
+pub struct FactorsSalt {
+    key: Arc<Mutex<Option<[u8; 32]>>>,
+}
+
+impl FactorsSalt {
+    pub fn rotate(&self) -> Result<(), Error> {
+        let mut key = [0u8; 32];
+        getrandom::fill(&mut key)?;
+        *self
+            .key
+            .lock()
+            .map_err(|_| Error::Generic("Failed to lock FactorsSalt".into()))? = Some(key);
+
+        Ok(())
+    }
+
+    pub fn hash(&self, args: &args::Args, factors: &str) -> Result<String, Error> {
+        let key = self
+            .key
+            .lock()
+            .map_err(|_| Error::Generic("Failed to lock FactorsSalt".into()))?;
+        Ok(salted_factors_hash(args, key.as_ref(), factors))
+    }
+}
+
+/// The "--hash-salt" hash of a challenge answer, keyed by "key" if given.
+fn salted_factors_hash(args: &args::Args, key: Option<&[u8; 32]>, factors: &str) -> String {
+    let mut hasher = match key {
+        Some(key) => {
+            let mut hasher = blake3::Hasher::new_keyed(key);
+            hasher.update(args.hash_salt.as_bytes());
+            hasher
+        }
+        None => helpers::salted_hasher(args),
+    };
+    hasher.update(factors.as_bytes());
+    hasher.finalize().to_string()
+}
+
+/// Counts requests to dest urls that haven't finished yet.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct UpstreamGauge {
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Decrements the UpstreamGauge it came from when dropped.
+pub(crate) struct UpstreamGuard {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl UpstreamGauge {
+    pub fn enter(&self) -> UpstreamGuard {
+        self.in_flight
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        UpstreamGuard {
+            in_flight: self.in_flight.clone(),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+impl Drop for UpstreamGuard {
+    fn drop(&mut self) {
+        self.in_flight
+            .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Picks among a port's "--port-to-dest-url" urls in turn.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DestRoundRobin {
+    next: Arc<Mutex<HashMap<u16, usize>>>,
+}
+
+impl DestRoundRobin {
+    pub fn pick<'a>(&self, port: u16, dest_urls: &'a [String]) -> Option<&'a String> {
+        let index = if dest_urls.len() > 1 {
+            let mut next = self.next.lock().ok()?;
+            let index = next.entry(port).or_default();
+            *index = index.wrapping_add(1);
+            index.wrapping_sub(1)
+        } else {
+            0
+        };
+        dest_urls.get(index.checked_rem(dest_urls.len())?)
+    }
+}
+
+/// The Args handlers see. With "--config", SIGHUP swaps in the reloadable
+/// fields of a fresh parse, and each request gets the args current when it
+/// arrived.
+#[derive(Clone)]
+pub struct LiveArgs {
+    args: Arc<std::sync::RwLock<Arc<args::Args>>>,
+}
+
+impl LiveArgs {
+    pub fn new(args: args::Args) -> Self {
+        Self {
+            args: Arc::new(std::sync::RwLock::new(Arc::new(args))),
+        }
+    }
+
+    pub fn load(&self) -> Result<Arc<args::Args>, Error> {
+        Ok(self
+            .args
+            .read()
+            .map_err(|_| Error::Generic("Failed to lock LiveArgs".into()))?
+            .clone())
+    }
+
+    /// Takes the reloadable fields of "fresh", setting up a client for each
+    /// dest url that is new.
+    pub async fn reload(
+        &self,
+        fresh: args::Args,
+        client_wrapper: &mut ClientWrapper,
+    ) -> Result<(), Error> {
+        let mut args = (*self.load()?).clone();
+        args.reload_from(fresh);
+        for dest_url in args
+            .dest_url
+            .iter()
+            .chain(args.port_to_dest_urls.values().flatten())
+            .chain(args.host_to_dest_urls.values())
+        {
+            if client_wrapper.get_client(dest_url).await.is_err() {
+                client_wrapper.register(dest_url.to_owned(), &args).await?;
+            }
+        }
+        *self
+            .args
+            .write()
+            .map_err(|_| Error::Generic("Failed to lock LiveArgs".into()))? = Arc::new(args);
+
+        Ok(())
+    }
+
+    /// Re-parses the args, so the "--config" file is read again, whenever
+    /// SIGHUP is received. Args that fail to parse keep the previous ones.
+    pub fn spawn_reload_on_sighup(&self, client_wrapper: ClientWrapper) {
+        let live_args = self.clone();
+        signal::spawn_reloader("args".into(), move || {
+            let live_args = live_args.clone();
+            let mut client_wrapper = client_wrapper.clone();
+            async move {
+                live_args
+                    .reload(args::parse_args()?, &mut client_wrapper)
+                    .await
+            }
+        });
+    }
+}
+
+#[handler]
+impl LiveArgs {
+    async fn handle(&self, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+        match self.load() {
+            Ok(args) => {
+                depot.insert_typed(args);
+            }
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+                res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+                ctrl.skip_rest();
+            }
+        }
+    }
+}
+
+/// Counts clients let through by "--db-fail-open" because the db couldn't be
+/// queried.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DbFailOpens {
+    count: Arc<AtomicU64>,
+}
+
+impl DbFailOpens {
+    pub fn record(&self) {
+        self.count
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+/// Counts clients "--monitor-only" let through that would otherwise have been
+/// challenged.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MonitorOnlyChallenges {
+    count: Arc<AtomicU64>,
+}
+
+impl MonitorOnlyChallenges {
+    pub fn record(&self) {
+        self.count
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+/// Limits how many challenge answers are checked against the db at once, for
+/// "--max-concurrent-validations".
+#[derive(Clone, Debug)]
+pub(crate) struct ValidationLimit {
+    permits: Arc<tokio::sync::Semaphore>,
+}
+
+impl ValidationLimit {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            permits: Arc::new(tokio::sync::Semaphore::new(max_concurrent)),
+        }
+    }
+
+    /// Returns None if the limit is already reached.
+    pub fn try_enter(&self) -> Option<tokio::sync::OwnedSemaphorePermit> {
+        self.permits.clone().try_acquire_owned().ok()
+    }
+}
+
+/// The "--max-concurrent" permits for forwarding to dest urls and the
+/// "--max-concurrent-challenges" permits for issuing challenges.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConcurrencyLimit {
+    proxy: Option<Arc<tokio::sync::Semaphore>>,
+    challenge: Option<Arc<tokio::sync::Semaphore>>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max_proxy: Option<usize>, max_challenge: Option<usize>) -> Self {
+        Self {
+            proxy: max_proxy.map(|max| Arc::new(tokio::sync::Semaphore::new(max))),
+            challenge: max_challenge.map(|max| Arc::new(tokio::sync::Semaphore::new(max))),
+        }
+    }
+
+    /// Waits up to CONCURRENCY_WAIT_MS for a permit. Returns Err if none
+    /// freed up, and Ok(None) if there's no limit.
+    async fn enter(
+        permits: &Option<Arc<tokio::sync::Semaphore>>,
+    ) -> Result<Option<tokio::sync::OwnedSemaphorePermit>, ()> {
+        let Some(permits) = permits else {
+            return Ok(None);
+        };
+        match tokio::time::timeout(
+            Duration::from_millis(constants::CONCURRENCY_WAIT_MS),
+            permits.clone().acquire_owned(),
+        )
+        .await
+        {
+            Ok(Ok(permit)) => Ok(Some(permit)),
+            _ => Err(()),
+        }
+    }
+
+    pub async fn enter_proxy(&self) -> Result<Option<tokio::sync::OwnedSemaphorePermit>, ()> {
+        Self::enter(&self.proxy).await
+    }
+
+    pub async fn enter_challenge(&self) -> Result<Option<tokio::sync::OwnedSemaphorePermit>, ()> {
+        Self::enter(&self.challenge).await
+    }
+}
+
+/// Per-ip token buckets for "--challenge-rate", each holding up to "count"
+/// challenges and refilling "count" every "per".
+#[derive(Clone, Debug)]
+pub(crate) struct ChallengeRateLimit {
+    count: u32,
+    per: Duration,
+    buckets: Arc<Mutex<HashMap<String, (f64, Instant)>>>,
+}
+
+impl ChallengeRateLimit {
+    pub fn new(count: u32, per: Duration) -> Self {
+        Self {
+            count,
+            per,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Takes a challenge from the bucket of "addr". Returns how long until
+    /// one is available if the bucket is empty.
+    pub fn try_take(&self, addr: &str) -> Result<Option<Duration>, Error> {
+        let per_token = self.per.as_secs_f64() / self.count as f64;
+        let mut buckets = self
+            .buckets
+            .lock()
+            .map_err(|_| Error::Generic("Failed to lock ChallengeRateLimit".into()))?;
+        let (tokens, last) = buckets
+            .entry(addr.to_owned())
+            .or_insert((self.count as f64, Instant::now()));
+        *tokens = (*tokens + last.elapsed().as_secs_f64() / per_token).min(self.count as f64);
+        *last = Instant::now();
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(None)
+        } else {
+            Ok(Some(Duration::from_secs_f64((1.0 - *tokens) * per_token)))
+        }
+    }
+
+    /// Drops buckets that have refilled completely, as a new one would be
+    /// the same.
+    pub fn evict_full(&self) -> Result<(), Error> {
+        let per = self.per;
+        self.buckets
+            .lock()
+            .map_err(|_| Error::Generic("Failed to lock ChallengeRateLimit".into()))?
+            .retain(|_, (_, last)| last.elapsed() < per);
+
+        Ok(())
+    }
+
+    pub fn spawn_eviction(&self) {
+        let rate_limit = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(rate_limit.per).await;
+                if signal::SIGNAL_HANDLED.load(std::sync::atomic::Ordering::Relaxed) {
+                    break;
+                }
+                if let Err(e) = rate_limit.evict_full() {
+                    eprintln!("WARNING: Failed to evict ChallengeRateLimit buckets: {}", e);
+                }
+            }
+        });
+    }
+}
+
+/// The args for "depot": those its request was given, or for the shared depot
+/// background tasks and the admin socket use, the latest of its LiveArgs.
+pub(crate) fn depot_args(depot: &Depot) -> Result<Arc<args::Args>, Error> {
+    match depot.get_typed::<Arc<args::Args>>() {
+        Ok(args) => Ok(args.clone()),
+        Err(_) => depot
+            .get_typed::<LiveArgs>()
+            .map_err(|_| Error::Generic("Args not in depot".into()))?
+            .load(),
+    }
+}
+
+/// Hashes a challenge answer with the "--hash-salt", keyed by the FactorsSalt
+/// in "depot" if there is one.
+pub(crate) fn hash_factors(depot: &Depot, factors: &str) -> Result<String, Error> {
+    let args = &depot_args(depot)?;
+    match depot.get_typed::<FactorsSalt>() {
+        Ok(salt) => salt.hash(args, factors),
+        Err(_) => Ok(salted_factors_hash(args, None, factors)),
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientWrapper {
+    clients: Arc<RwLock<HashMap<String, RwLock<Client>>>>,
+}
+
+impl Default for ClientWrapper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientWrapper {
+    pub fn new() -> Self {
+        Self {
+            clients: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub async fn register(&mut self, dest: String, args: &args::Args) -> Result<(), Error> {
+        let client = upstream_client_builder(args)?.build()?;
+
+        self.clients.write().await.insert(dest, RwLock::new(client));
+
+        Ok(())
+    }
+
+    pub async fn get_client(&self, dest: &str) -> Result<Client, Error> {
+        Ok(self
+            .clients
+            .read()
+            .await
+            .get(dest)
+            .ok_or(Into::<Error>::into(
+                "ClientWrapper::get_client failed: no matching dest!",
+            ))?
+            .read()
+            .await
+            .clone())
+    }
+}
+
+fn upstream_client_builder(args: &args::Args) -> Result<reqwest::ClientBuilder, Error> {
+    let mut builder = reqwest::ClientBuilder::new()
+        .redirect(Policy::none())
+        .no_proxy();
+    // "--backend-timeout" rejects 0, so 0 only comes from Args::default().
+    if args.backend_timeout_secs > 0 {
+        let backend_timeout = Duration::from_secs(args.backend_timeout_secs);
+        builder = builder
+            .connect_timeout(backend_timeout)
+            .timeout(backend_timeout);
+    }
+
+    if let (Some(cert), Some(key)) = (&args.upstream_client_cert, &args.upstream_client_key) {
+        Ok(builder.identity(reqwest::Identity::from_pkcs8_pem(cert, key)?))
+    } else {
+        Ok(builder)
+    }
+}
+
+/// The state handlers share: the db pool, CachedAllow, a client per dest url,
+/// the limits and so on, each built from the args it was created with.
+#[derive(Clone)]
+pub struct SharedState {
+    pub(crate) args: Arc<args::Args>,
+    live_args: LiveArgs,
+    db_pool: DbPool,
+    cached_allow: CachedAllow,
+    factors_salt: FactorsSalt,
+    upstream_gauge: UpstreamGauge,
+    dest_round_robin: DestRoundRobin,
+    db_fail_opens: DbFailOpens,
+    monitor_only_challenges: MonitorOnlyChallenges,
+    pub(crate) client_cert_peers: salvo_compat::ClientCertPeers,
+    pub(crate) plain_http_peers: Option<salvo_compat::PlainHttpPeers>,
+    client_wrapper: ClientWrapper,
+    challenge_rate_limit: Option<ChallengeRateLimit>,
+    validation_limit: Option<ValidationLimit>,
+    concurrency_limit: Option<ConcurrencyLimit>,
+    exemption_tokens: Option<token::ExemptionTokens>,
+    reputation_list: Option<reputation::ReputationList>,
+    #[cfg(feature = "policy-script")]
+    policy_script: Option<policy::PolicyScript>,
+    traffic_recorder: Option<traffic::TrafficRecorder>,
+    solved_webhook: Option<webhook::SolvedWebhook>,
+}
+
+impl SharedState {
+    /// Sets up the db (creating its tables), a client for every dest url and
+    /// whatever else "args" enables. Nothing is spawned that outlives a
+    /// request until "spawn_tasks" is called.
+    pub async fn new(args: &args::Args) -> Result<Self, Error> {
+        let mut client_wrapper = ClientWrapper::new();
+        for dest_url in args
+            .dest_url
+            .iter()
+            .chain(args.port_to_dest_urls.values().flatten())
+            .chain(args.host_to_dest_urls.values())
+        {
+            client_wrapper.register(dest_url.to_owned(), args).await?;
+        }
+
+        let state = Self {
+            args: Arc::new(args.clone()),
+            live_args: LiveArgs::new(args.clone()),
+            db_pool: DbPool::new(args).await?,
+            cached_allow: CachedAllow::new(),
+            factors_salt: FactorsSalt::default(),
+            upstream_gauge: UpstreamGauge::default(),
+            dest_round_robin: DestRoundRobin::default(),
+            db_fail_opens: DbFailOpens::default(),
+            monitor_only_challenges: MonitorOnlyChallenges::default(),
+            client_cert_peers: salvo_compat::ClientCertPeers::default(),
+            plain_http_peers: args.tls_detect.then(salvo_compat::PlainHttpPeers::default),
+            client_wrapper,
+            challenge_rate_limit: args
+                .challenge_rate
+                .map(|(count, secs)| ChallengeRateLimit::new(count, Duration::from_secs(secs))),
+            validation_limit: args.max_concurrent_validations.map(ValidationLimit::new),
+            concurrency_limit: (args.max_concurrent.is_some()
+                || args.max_concurrent_challenges.is_some())
+            .then(|| {
+                ConcurrencyLimit::new(
+                    args.max_concurrent,
+                    args.max_concurrent_challenges.or(args
+                        .max_concurrent
+                        .map(|max| max.saturating_mul(constants::CHALLENGE_CONCURRENCY_FACTOR))),
+                )
+            }),
+            exemption_tokens: args
+                .exemption_token_key
+                .as_deref()
+                .map(token::ExemptionTokens::load)
+                .transpose()?,
+            reputation_list: args
+                .reputation_file
+                .as_ref()
+                .map(|reputation_file| reputation::ReputationList::load(reputation_file.to_owned()))
+                .transpose()?,
+            #[cfg(feature = "policy-script")]
+            policy_script: args
+                .policy_script
+                .as_ref()
+                .map(|policy_script| policy::PolicyScript::load(policy_script.to_owned()))
+                .transpose()?,
+            traffic_recorder: args
+                .record_traffic
+                .as_deref()
+                .map(traffic::TrafficRecorder::new)
+                .transpose()?,
+            solved_webhook: args
+                .solved_webhook
+                .as_ref()
+                .map(|solved_webhook| webhook::SolvedWebhook::new(solved_webhook.to_owned()))
+                .transpose()?,
+        };
+        init_db(&state.depot()).await?;
+
+        Ok(state)
+    }
+
+    /// A depot holding all of the state, with the LiveArgs for args, for the
+    /// background tasks and the admin socket.
+    pub(crate) fn depot(&self) -> Depot {
+        let mut depot = Depot::new();
+        depot.insert_typed(self.live_args.clone());
+        self.db_pool.insert_into(&mut depot);
+        depot.insert_typed(self.cached_allow.clone());
+        depot.insert_typed(self.factors_salt.clone());
+        depot.insert_typed(self.upstream_gauge.clone());
+        depot.insert_typed(self.dest_round_robin.clone());
+        depot.insert_typed(self.db_fail_opens.clone());
+        depot.insert_typed(self.monitor_only_challenges.clone());
+        depot.insert_typed(self.client_cert_peers.clone());
+        depot.insert_typed(self.client_wrapper.clone());
+        if let Some(plain_http_peers) = &self.plain_http_peers {
+            depot.insert_typed(plain_http_peers.clone());
+        }
+        if let Some(challenge_rate_limit) = &self.challenge_rate_limit {
+            depot.insert_typed(challenge_rate_limit.clone());
+        }
+        if let Some(validation_limit) = &self.validation_limit {
+            depot.insert_typed(validation_limit.clone());
+        }
+        if let Some(concurrency_limit) = &self.concurrency_limit {
+            depot.insert_typed(concurrency_limit.clone());
+        }
+        if let Some(exemption_tokens) = &self.exemption_tokens {
+            depot.insert_typed(exemption_tokens.clone());
+        }
+        if let Some(reputation_list) = &self.reputation_list {
+            depot.insert_typed(reputation_list.clone());
+        }
+        #[cfg(feature = "policy-script")]
+        if let Some(policy_script) = &self.policy_script {
+            depot.insert_typed(policy_script.clone());
+        }
+        if let Some(traffic_recorder) = &self.traffic_recorder {
+            depot.insert_typed(traffic_recorder.clone());
+        }
+        if let Some(solved_webhook) = &self.solved_webhook {
+            depot.insert_typed(solved_webhook.clone());
+        }
+
+        depot
+    }
+
+    /// Starts the cache and rate limit eviction, the SIGHUP reloads, the
+    /// "--cleanup-interval" purge, the "--cache-refresh-interval" refresh, the
+    /// "--record-traffic" and "--solved-webhook" queues and the
+    /// "--admin-unix-socket" listener. The loops stop on a shutdown signal.
+    pub fn spawn_tasks(&self) -> Result<(), Error> {
+        let args = &self.args;
+        let depot = Arc::new(self.depot());
+
+        self.cached_allow.spawn_eviction(
+            Duration::from_secs(args.allow_cache_evict_interval_secs),
+            CACHED_TIMEOUT,
+        );
+        if let Some(challenge_rate_limit) = &self.challenge_rate_limit {
+            challenge_rate_limit.spawn_eviction();
+        }
+        if args.config_file.is_some() {
+            self.live_args
+                .spawn_reload_on_sighup(self.client_wrapper.clone());
+        }
+        if let Some(reputation_list) = &self.reputation_list {
+            reputation_list.spawn_reload_on_sighup();
+        }
+        #[cfg(feature = "policy-script")]
+        if let Some(policy_script) = &self.policy_script {
+            policy_script.spawn_reload_on_sighup();
+        }
+        self.db_pool.spawn_flush();
+        spawn_cleanup(
+            depot.clone(),
+            Duration::from_secs(args.cleanup_interval_secs),
+        );
+        if let Some(refresh_interval_secs) = args.cache_refresh_interval_secs {
+            self.cached_allow.spawn_refresh(
+                depot.clone(),
+                Duration::from_secs(refresh_interval_secs),
+                CACHED_TIMEOUT,
+            );
+        }
+        if let Some(traffic_recorder) = &self.traffic_recorder {
+            traffic_recorder.spawn_writer()?;
+        }
+        if let Some(solved_webhook) = &self.solved_webhook {
+            solved_webhook.spawn_sender()?;
+        }
+        if let Some(admin_unix_socket) = &args.admin_unix_socket {
+            admin::spawn(admin::bind(admin_unix_socket)?, depot);
+        }
+
+        Ok(())
+    }
+
+    /// Saves what the db backend only keeps in memory, for after the server
+    /// has stopped.
+    pub(crate) fn flush(&self) -> Result<(), Error> {
+        self.db_pool.flush()
+    }
+
+    /// Injects the state for every handler of "router". Without "--config"
+    /// nothing reloads, so the startup args are shared as they are.
+    pub fn inject(&self, router: Router) -> Router {
+        let mut router = if self.args.config_file.is_some() {
+            router.hoop(self.live_args.clone())
+        } else {
+            router.hoop(affix_state::inject(self.args.clone()))
+        };
+        router = self.db_pool.inject(router);
+        router = router
+            .hoop(affix_state::inject(self.cached_allow.clone()))
+            .hoop(affix_state::inject(self.factors_salt.clone()))
+            .hoop(affix_state::inject(self.upstream_gauge.clone()))
+            .hoop(affix_state::inject(self.dest_round_robin.clone()))
+            .hoop(affix_state::inject(self.db_fail_opens.clone()))
+            .hoop(affix_state::inject(self.monitor_only_challenges.clone()))
+            .hoop(affix_state::inject(self.client_cert_peers.clone()))
+            .hoop(affix_state::inject(self.client_wrapper.clone()));
+        router = inject_some(router, &self.plain_http_peers);
+        router = inject_some(router, &self.challenge_rate_limit);
+        router = inject_some(router, &self.validation_limit);
+        router = inject_some(router, &self.concurrency_limit);
+        router = inject_some(router, &self.exemption_tokens);
+        router = inject_some(router, &self.reputation_list);
+        #[cfg(feature = "policy-script")]
+        {
+            router = inject_some(router, &self.policy_script);
+        }
+        router = inject_some(router, &self.traffic_recorder);
+        inject_some(router, &self.solved_webhook)
+    }
+}
+
+/// Injects "value" for every handler, if there is one.
+fn inject_some<T: Clone + Send + Sync + 'static>(router: Router, value: &Option<T>) -> Router {
+    match value {
+        Some(value) => router.hoop(affix_state::inject(value.clone())),
+        None => router,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use salvo::test::TestClient;
+
+    use super::*;
+    use crate::db::init_sqlite_db;
+    use crate::server::build_router;
+    use crate::test_util::{test_cert, test_request, test_sqlite_args};
+
+    #[tokio::test]
+    async fn test_cached_allow_eviction() {
+        let cached_allow = CachedAllow::new();
+        cached_allow.add_allowed("203.0.113.10", 8180).unwrap();
+        cached_allow.spawn_eviction(Duration::from_millis(10), Duration::from_millis(50));
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(cached_allow.allowed.lock().unwrap().borrow().len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(cached_allow.allowed.lock().unwrap().borrow().is_empty());
+    }
+
+    #[test]
+    fn test_cached_allow_check_cleanup() {
+        let cached_allow = CachedAllow::new();
+        cached_allow.add_allowed("203.0.113.16", 8180).unwrap();
+        let stale = Instant::now()
+            .checked_sub(CACHED_TIMEOUT + Duration::from_secs(1))
+            .unwrap();
+        cached_allow
+            .allowed
+            .lock()
+            .unwrap()
+            .borrow_mut()
+            .insert(CachedAllow::key("203.0.113.17", 8180), stale);
+
+        // Not due yet, so nothing is swept.
+        cached_allow.check_cleanup().unwrap();
+        assert_eq!(cached_allow.len().unwrap(), 2);
+
+        cached_allow.inst.lock().unwrap().set(
+            Instant::now()
+                .checked_sub(CACHED_CLEANUP_TIMEOUT + Duration::from_secs(1))
+                .unwrap(),
+        );
+        cached_allow.check_cleanup().unwrap();
+        let allowed = cached_allow.allowed.lock().unwrap();
+        let allowed = allowed.borrow();
+        assert_eq!(allowed.len(), 1);
+        assert!(allowed.contains_key(&CachedAllow::key("203.0.113.16", 8180)));
+    }
+
+    #[test]
+    fn test_cached_allow_per_port() {
+        let cached_allow = CachedAllow::new();
+        cached_allow.add_allowed("203.0.113.12", 8080).unwrap();
+
+        assert!(
+            cached_allow
+                .get_allowed("203.0.113.12", 8080, CACHED_TIMEOUT)
+                .unwrap()
+        );
+        assert!(
+            !cached_allow
+                .get_allowed("203.0.113.12", 9090, CACHED_TIMEOUT)
+                .unwrap()
+        );
+
+        cached_allow.add_allowed("203.0.113.12", 9090).unwrap();
+        cached_allow.revoke(None, Some(8080)).unwrap();
+        assert!(
+            !cached_allow
+                .get_allowed("203.0.113.12", 8080, CACHED_TIMEOUT)
+                .unwrap()
+        );
+        assert!(
+            cached_allow
+                .get_allowed("203.0.113.12", 9090, CACHED_TIMEOUT)
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cached_allow_refresh() {
+        let args = test_sqlite_args("cached_allow_refresh");
+        init_sqlite_db(&args).await.unwrap();
+        let mut depot = Depot::new();
+        depot.insert_typed(Arc::new(args.clone()));
+
+        let cached_allow = CachedAllow::new();
+        cached_allow.spawn_refresh(Arc::new(depot), Duration::from_millis(10), CACHED_TIMEOUT);
+        assert!(
+            !cached_allow
+                .get_allowed("203.0.113.11", 8180, CACHED_TIMEOUT)
+                .unwrap()
+        );
+
+        // Inserted as if another instance sharing the db allowed the client.
+        Connection::open(&args.sqlite_db_file)
+            .unwrap()
+            .execute(
+                r"INSERT INTO ALLOWED_IP (IP, PORT) VALUES (?1, ?2)",
+                ("203.0.113.11", 8180),
+            )
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert!(
+            cached_allow
+                .get_allowed("203.0.113.11", 8180, CACHED_TIMEOUT)
+                .unwrap()
+        );
+        assert!(
+            !cached_allow
+                .get_allowed("203.0.113.11", 8181, CACHED_TIMEOUT)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_hash_factors_salt() {
+        let salt = FactorsSalt::default();
+        let mut depot = Depot::new();
+        depot.insert_typed(salt.clone());
+        assert!(hash_factors(&depot, "2x2 3x1").is_err());
+
+        let args = test_sqlite_args("hash_factors_salt");
+        let other_args = args::Args {
+            hash_salt: "other.example".into(),
+            ..args.clone()
+        };
+        depot.insert_typed(Arc::new(args.clone()));
+        assert_eq!(
+            hash_factors(&depot, "2x2 3x1").unwrap(),
+            salted_factors_hash(&args, None, "2x2 3x1")
+        );
+
+        // The --hash-salt still counts once rotate-salt has set a key.
+        salt.rotate().unwrap();
+        assert_ne!(
+            salt.hash(&args, "2x2 3x1").unwrap(),
+            salt.hash(&other_args, "2x2 3x1").unwrap()
+        );
+        assert_eq!(
+            hash_factors(&depot, "2x2 3x1").unwrap(),
+            salt.hash(&args, "2x2 3x1").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_live_args_reload() {
+        // With "--config" the handlers get the LiveArgs instead of the args.
+        let args = args::Args {
+            config_file: Some(std::env::temp_dir().join("pma_test_unused.conf")),
+            ..test_sqlite_args("live_args_reload")
+        };
+        let state = SharedState::new(&args).await.unwrap();
+        // As background tasks and the admin socket share it.
+        let shared = state.depot();
+        let live_args = shared.get_typed::<LiveArgs>().unwrap().clone();
+        let mut client_wrapper = shared.get_typed::<ClientWrapper>().unwrap().clone();
+        let service = Service::new(build_router(&state));
+        let challenge = || {
+            test_request(
+                TestClient::get("http://127.0.0.1:8180/"),
+                "203.0.113.41",
+                8180,
+            )
+        };
+
+        let before = service.handle(challenge()).await;
+        let shared_before = depot_args(&shared).unwrap().challenge_status;
+        live_args
+            .reload(
+                args::Args {
+                    challenge_status: Some(503),
+                    dest_url: Some("http://127.0.0.1:9999".into()),
+                    addr_port_strs: vec!["127.0.0.1:9000".into()],
+                    ..test_sqlite_args("live_args_reload_fresh")
+                },
+                &mut client_wrapper,
+            )
+            .await
+            .unwrap();
+        let after = service.handle(challenge()).await;
+        std::fs::remove_file(&args.sqlite_db_file).ok();
+
+        assert_eq!(before.status_code, Some(StatusCode::OK));
+        assert_eq!(after.status_code, Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(shared_before, None);
+        assert_eq!(depot_args(&shared).unwrap().challenge_status, Some(503));
+        let reloaded = live_args.load().unwrap();
+        assert_eq!(reloaded.dest_url.as_deref(), Some("http://127.0.0.1:9999"));
+        // Listeners and the db aren't reloaded.
+        assert_eq!(reloaded.addr_port_strs, args.addr_port_strs);
+        assert_eq!(reloaded.sqlite_db_file, args.sqlite_db_file);
+        assert!(
+            client_wrapper
+                .get_client("http://127.0.0.1:9999")
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_challenge_rate_limit_refills() {
+        let rate_limit = ChallengeRateLimit::new(1, Duration::from_millis(50));
+        assert_eq!(rate_limit.try_take("203.0.113.73").unwrap(), None);
+        assert!(rate_limit.try_take("203.0.113.73").unwrap().is_some());
+
+        std::thread::sleep(Duration::from_millis(60));
+        rate_limit.evict_full().unwrap();
+        assert!(rate_limit.buckets.lock().unwrap().is_empty());
+        assert_eq!(rate_limit.try_take("203.0.113.73").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_client_cert() {
+        use openssl::{
+            pkey::PKey,
+            rsa::Rsa,
+            ssl::{SslAcceptor, SslMethod, SslVerifyMode},
+        };
+        use std::io::{Read, Write};
+
+        let ca_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let ca_cert = test_cert("pma test ca", 1, &ca_key, None);
+        let server_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let server_cert = test_cert("pma test server", 2, &server_key, Some((&ca_cert, &ca_key)));
+        let client_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let client_cert = test_cert("pma test client", 3, &client_key, Some((&ca_cert, &ca_key)));
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&server_key).unwrap();
+        acceptor.set_certificate(&server_cert).unwrap();
+        acceptor.cert_store_mut().add_cert(ca_cert.clone()).unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        let acceptor = acceptor.build();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                if let Ok(mut stream) = acceptor.accept(stream.unwrap()) {
+                    let mut request: Vec<u8> = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf) {
+                            Ok(0) | Err(_) => break,
+                            Ok(read) => request.extend_from_slice(&buf[..read]),
+                        }
+                    }
+                    stream
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok",
+                        )
+                        .ok();
+                    stream.shutdown().ok();
+                }
+            }
+        });
+
+        let ca_cert = reqwest::Certificate::from_pem(&ca_cert.to_pem().unwrap()).unwrap();
+        let url = format!("https://{}/", server_addr);
+
+        let no_cert_client = upstream_client_builder(&args::Args::default())
+            .unwrap()
+            .add_root_certificate(ca_cert.clone())
+            .build()
+            .unwrap();
+        let ret = no_cert_client.get(&url).send().await;
+        assert!(ret.is_err() || !ret.unwrap().status().is_success());
+
+        let args = args::Args {
+            upstream_client_cert: Some(client_cert.to_pem().unwrap()),
+            upstream_client_key: Some(client_key.private_key_to_pem_pkcs8().unwrap()),
+            ..Default::default()
+        };
+        let cert_client = upstream_client_builder(&args)
+            .unwrap()
+            .add_root_certificate(ca_cert)
+            .build()
+            .unwrap();
+        let res = cert_client.get(&url).send().await.unwrap();
+        assert!(res.status().is_success());
+        assert_eq!(res.text().await.unwrap(), "ok");
+    }
+}
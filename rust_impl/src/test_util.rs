@@ -0,0 +1,108 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use salvo::test::RequestBuilder;
+use salvo::{Request, Service};
+
+use crate::server::build_router;
+use crate::state::SharedState;
+use crate::{args, constants};
+
+pub(crate) fn test_sqlite_args(name: &str) -> args::Args {
+    args::Args {
+        sqlite_db_file: std::env::temp_dir().join(format!(
+            "pma_test_{}_{}",
+            name,
+            std::process::id()
+        )),
+        enable_x_real_ip_header: true,
+        real_ip_headers: vec!["x-real-ip".into()],
+        api_url: "/pma_api".into(),
+        js_factors_url: "/pma_factors.js".into(),
+        challenge_timeout_mins: constants::CHALLENGE_FACTORS_TIMEOUT_MINUTES,
+        allowed_timeout_mins: constants::ALLOWED_IP_TIMEOUT_MINUTES,
+        max_proxy_body: constants::DEFAULT_MAX_PROXY_BODY,
+        max_json_size: constants::DEFAULT_JSON_MAX_SIZE,
+        mysql_has_priority: false,
+        ..Default::default()
+    }
+}
+
+/// The service "run" would serve for "args", without its background tasks.
+pub(crate) async fn test_service(args: &args::Args) -> Service {
+    Service::new(build_router(&SharedState::new(args).await.unwrap()))
+}
+
+pub(crate) fn test_request(builder: RequestBuilder, ip: &str, local_port: u16) -> Request {
+    let mut req = builder.add_header("x-real-ip", ip, true).build();
+    *req.local_addr_mut() = std::net::SocketAddr::from(([127, 0, 0, 1], local_port)).into();
+    req
+}
+
+pub(crate) fn test_cert(
+    name: &str,
+    serial: u32,
+    key: &openssl::pkey::PKey<openssl::pkey::Private>,
+    issuer: Option<(
+        &openssl::x509::X509,
+        &openssl::pkey::PKey<openssl::pkey::Private>,
+    )>,
+) -> openssl::x509::X509 {
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        hash::MessageDigest,
+        x509::{
+            X509, X509NameBuilder,
+            extension::{BasicConstraints, SubjectAlternativeName},
+        },
+    };
+
+    let mut x509_name = X509NameBuilder::new().unwrap();
+    x509_name.append_entry_by_text("CN", name).unwrap();
+    let x509_name = x509_name.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder
+        .set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap())
+        .unwrap();
+    builder.set_subject_name(&x509_name).unwrap();
+    builder.set_pubkey(key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    if let Some((issuer_cert, issuer_key)) = issuer {
+        builder.set_issuer_name(issuer_cert.subject_name()).unwrap();
+        let san = SubjectAlternativeName::new()
+            .ip("127.0.0.1")
+            .build(&builder.x509v3_context(Some(issuer_cert), None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+    } else {
+        builder.set_issuer_name(&x509_name).unwrap();
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+        builder.sign(key, MessageDigest::sha256()).unwrap();
+    }
+
+    builder.build()
+}
@@ -0,0 +1,126 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::path::Path;
+
+use crate::error::Error;
+
+/// Context string for deriving the token key from the "--exemption-token-key"
+/// file, so the file can hold any secret.
+const TOKEN_KEY_CONTEXT: &str = "PoorMansAnubis 2026 exemption token key";
+
+/// Mints and checks exemption tokens, which let trusted clients that can't run
+/// the challenge through when sent in the "x-pma-token" header.
+///
+/// A token is "<expires>_<ip>_<mac>", where "<expires>" is a unix timestamp,
+/// "<ip>" is the only client address it's valid for (or empty for any), and
+/// "<mac>" is the keyed blake3 hash of "<expires>_<ip>".
+#[derive(Clone)]
+pub struct ExemptionTokens {
+    key: [u8; 32],
+}
+
+impl ExemptionTokens {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            key: blake3::derive_key(TOKEN_KEY_CONTEXT, secret),
+        }
+    }
+
+    /// Derives the key from the contents of "path".
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let secret = std::fs::read(path)?;
+        if secret.is_empty() {
+            return Err(Error::Generic(format!(
+                "Exemption token key file {:?} is empty",
+                path
+            )));
+        }
+
+        Ok(Self::new(&secret))
+    }
+
+    fn mac(&self, claims: &str) -> blake3::Hash {
+        blake3::keyed_hash(&self.key, claims.as_bytes())
+    }
+
+    /// Returns a token valid until the unix timestamp "expires", only for
+    /// "ip" if given.
+    pub fn mint(&self, expires: i64, ip: Option<&str>) -> String {
+        let claims = format!("{}_{}", expires, ip.unwrap_or(""));
+        let mac = self.mac(&claims);
+        format!("{}_{}", claims, mac.to_hex())
+    }
+
+    /// Returns true if "token" was minted with this key, hasn't expired as of
+    /// the unix timestamp "now", and is bound to "ip" or to no address.
+    pub fn verify(&self, token: &str, ip: &str, now: i64) -> bool {
+        let Some((claims, mac)) = token.rsplit_once('_') else {
+            return false;
+        };
+        let Some((expires, bound_ip)) = claims.split_once('_') else {
+            return false;
+        };
+        let Ok(mac) = blake3::Hash::from_hex(mac) else {
+            return false;
+        };
+        // blake3::Hash compares in constant time.
+        if self.mac(claims) != mac {
+            return false;
+        }
+
+        match expires.parse::<i64>() {
+            Ok(expires) if now < expires => bound_ip.is_empty() || bound_ip == ip,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exemption_tokens() {
+        let tokens = ExemptionTokens::new(b"test secret");
+        let any_ip = tokens.mint(1000, None);
+        let bound = tokens.mint(1000, Some("203.0.113.30"));
+
+        assert!(any_ip.starts_with("1000__"));
+        assert!(tokens.verify(&any_ip, "203.0.113.31", 999));
+        assert!(tokens.verify(&bound, "203.0.113.30", 999));
+        assert!(!tokens.verify(&bound, "203.0.113.31", 999));
+
+        // Expired.
+        assert!(!tokens.verify(&any_ip, "203.0.113.31", 1000));
+        assert!(!tokens.verify(&bound, "203.0.113.30", 1001));
+
+        // Tampered with, or minted with another key.
+        assert!(!tokens.verify(&any_ip.replacen("1000", "9999", 1), "203.0.113.31", 999));
+        assert!(!tokens.verify(
+            &bound.replacen("203.0.113.30", "203.0.113.31", 1),
+            "203.0.113.31",
+            999
+        ));
+        let mut flipped = any_ip.clone();
+        let last = if flipped.ends_with('0') { "1" } else { "0" };
+        flipped.replace_range(flipped.len() - 1.., last);
+        assert!(!tokens.verify(&flipped, "203.0.113.31", 999));
+        assert!(!ExemptionTokens::new(b"other secret").verify(&any_ip, "203.0.113.31", 999));
+        assert!(!tokens.verify("garbage", "203.0.113.31", 999));
+        assert!(!tokens.verify("", "203.0.113.31", 999));
+    }
+}
@@ -0,0 +1,230 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use salvo::http::{HeaderName, HeaderValue};
+use salvo::{Depot, Request};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, Receiver, Sender, error::TrySendError};
+
+use crate::{
+    args::Args,
+    error::Error,
+    json_types::{ClientDecision, TrafficRecord},
+};
+
+const TRAFFIC_QUEUE_SIZE: usize = 1024;
+
+/// Headers that can carry credentials, left out of recorded requests.
+const SENSITIVE_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-pma-token",
+];
+
+/// The file and queue end for the writer task, until it's spawned.
+type UnspawnedWriter = Option<(std::fs::File, Receiver<TrafficRecord>)>;
+
+/// Queues records of requests for a background task that appends them to the
+/// "--record-traffic" file, one JSON object per line. Records are dropped if
+/// the queue is full.
+#[derive(Clone)]
+pub struct TrafficRecorder {
+    tx: Sender<TrafficRecord>,
+    unspawned: Arc<Mutex<UnspawnedWriter>>,
+}
+
+impl TrafficRecorder {
+    /// Opens "path". Records are queued until "spawn_writer" is called.
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let (tx, rx) = mpsc::channel(TRAFFIC_QUEUE_SIZE);
+
+        Ok(Self {
+            tx,
+            unspawned: Arc::new(Mutex::new(Some((file, rx)))),
+        })
+    }
+
+    /// Starts the task appending queued records to the file, if it isn't
+    /// already running.
+    pub fn spawn_writer(&self) -> Result<(), Error> {
+        let unspawned = self
+            .unspawned
+            .lock()
+            .map_err(|_| Error::Generic("Failed to lock TrafficRecorder".into()))?
+            .take();
+        if let Some((file, rx)) = unspawned {
+            tokio::spawn(run_recorder(tokio::fs::File::from_std(file), rx));
+        }
+
+        Ok(())
+    }
+
+    pub fn record(
+        &self,
+        args: &Args,
+        req: &Request,
+        addr: &str,
+        port: u16,
+        decision: ClientDecision,
+    ) {
+        match self.tx.try_send(record_of(args, req, addr, port, decision)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(record)) => eprintln!(
+                "WARNING: Traffic record queue full, dropping record for {}",
+                record.ip
+            ),
+            Err(TrySendError::Closed(_)) => eprintln!("WARNING: Traffic record task stopped!"),
+        }
+    }
+}
+
+/// Sanitizes "req" into a record: the query string and credential headers are
+/// left out, as are the real ip headers since "addr" is what they resolved to.
+pub fn record_of(
+    args: &Args,
+    req: &Request,
+    addr: &str,
+    port: u16,
+    decision: ClientDecision,
+) -> TrafficRecord {
+    let headers = req
+        .headers()
+        .iter()
+        .filter(|(name, _)| {
+            !SENSITIVE_HEADERS.contains(&name.as_str())
+                && !args.real_ip_headers.iter().any(|h| h == name.as_str())
+        })
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+        .collect();
+
+    TrafficRecord {
+        ip: addr.to_owned(),
+        port,
+        method: req.method().to_string(),
+        path: req.uri().path().to_owned(),
+        headers,
+        decision,
+    }
+}
+
+async fn run_recorder(mut file: tokio::fs::File, mut rx: Receiver<TrafficRecord>) {
+    while let Some(record) = rx.recv().await {
+        let mut line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("WARNING: Failed to serialize traffic record: {}", e);
+                continue;
+            }
+        };
+        line.push('\n');
+        if let Err(e) = file.write_all(line.as_bytes()).await {
+            eprintln!("WARNING: Failed to write traffic record: {}", e);
+        }
+    }
+}
+
+/// Rebuilds the request a record was made from. The client connects from the
+/// recorded ip to the recorded port on loopback.
+fn request_of(record: &TrafficRecord) -> Result<Request, Error> {
+    let mut req = Request::new();
+    *req.method_mut() = record
+        .method
+        .parse()
+        .map_err(|_| Error::Generic(format!("Invalid method \"{}\"", record.method)))?;
+    *req.uri_mut() = record
+        .path
+        .parse()
+        .map_err(|_| Error::Generic(format!("Invalid path \"{}\"", record.path)))?;
+    for (name, value) in record.headers.iter() {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| Error::Generic(format!("Invalid header name \"{}\"", name)))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| Error::Generic(format!("Invalid header value \"{}\"", value)))?;
+        req.headers_mut().append(name, value);
+    }
+    let ip: IpAddr = record
+        .ip
+        .parse()
+        .map_err(|_| Error::Generic(format!("Invalid ip \"{}\"", record.ip)))?;
+    *req.remote_addr_mut() = SocketAddr::new(ip, 0).into();
+    *req.local_addr_mut() = SocketAddr::from(([127, 0, 0, 1], record.port)).into();
+
+    Ok(req)
+}
+
+pub struct ReplayReport {
+    pub requests: u64,
+    /// Line number, recorded decision, and replayed decision of each request
+    /// that was decided differently.
+    pub mismatches: Vec<(usize, ClientDecision, ClientDecision)>,
+}
+
+impl Display for ReplayReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Replay:")?;
+        for (line, recorded, replayed) in self.mismatches.iter() {
+            writeln!(
+                f,
+                "  Line {}: recorded {:?}, replayed {:?}",
+                line, recorded, replayed
+            )?;
+        }
+        write!(
+            f,
+            "  Requests: {} ({} decided differently)",
+            self.requests,
+            self.mismatches.len()
+        )
+    }
+}
+
+/// Runs each request of a "--record-traffic" file through the same checks
+/// handler_fn makes, and compares the decisions. No challenge is issued, so
+/// the db is only read.
+pub async fn replay(depot: &Depot, path: &Path) -> Result<ReplayReport, Error> {
+    let mut report = ReplayReport {
+        requests: 0,
+        mismatches: Vec::new(),
+    };
+    for (idx, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: TrafficRecord = serde_json::from_str(line)
+            .map_err(|e| Error::Generic(format!("{} on line {}", e, idx + 1)))?;
+        let req = request_of(&record)
+            .map_err(|e| Error::Generic(format!("{} on line {}", e, idx + 1)))?;
+
+        let decision =
+            crate::handlers::client_decision(depot, &req, &record.ip, None, record.port).await;
+        report.requests += 1;
+        if decision != record.decision {
+            report.mismatches.push((idx + 1, record.decision, decision));
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,115 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use reqwest::Client;
+use tokio::sync::mpsc::{self, Receiver, Sender, error::TrySendError};
+
+use crate::{error::Error, json_types::SolvedEvent};
+
+const SOLVED_WEBHOOK_QUEUE_SIZE: usize = 256;
+const SOLVED_WEBHOOK_ATTEMPTS: u32 = 3;
+const SOLVED_WEBHOOK_BACKOFF: Duration = Duration::from_millis(500);
+const SOLVED_WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The url, client and queue end for the sender task, until it's spawned.
+type UnspawnedSender = Option<(String, Client, Receiver<SolvedEvent>)>;
+
+/// Queues solved-challenge events for a background task that POSTs them to
+/// the "--solved-webhook" url. Events are dropped if the queue is full.
+#[derive(Clone)]
+pub struct SolvedWebhook {
+    tx: Sender<SolvedEvent>,
+    unspawned: Arc<Mutex<UnspawnedSender>>,
+}
+
+impl SolvedWebhook {
+    /// Events are queued until "spawn_sender" is called.
+    pub fn new(url: String) -> Result<Self, Error> {
+        let client = reqwest::ClientBuilder::new()
+            .timeout(SOLVED_WEBHOOK_TIMEOUT)
+            .build()?;
+        let (tx, rx) = mpsc::channel(SOLVED_WEBHOOK_QUEUE_SIZE);
+
+        Ok(Self {
+            tx,
+            unspawned: Arc::new(Mutex::new(Some((url, client, rx)))),
+        })
+    }
+
+    /// Starts the task POSTing queued events, if it isn't already running.
+    pub fn spawn_sender(&self) -> Result<(), Error> {
+        let unspawned = self
+            .unspawned
+            .lock()
+            .map_err(|_| Error::Generic("Failed to lock SolvedWebhook".into()))?
+            .take();
+        if let Some((url, client, rx)) = unspawned {
+            tokio::spawn(run_webhook(url, client, rx));
+        }
+
+        Ok(())
+    }
+
+    pub fn notify(&self, event: SolvedEvent) {
+        match self.tx.try_send(event) {
+            Ok(()) => (),
+            Err(TrySendError::Full(event)) => eprintln!(
+                "WARNING: Solved webhook queue full, dropping event for {}",
+                event.ip
+            ),
+            Err(TrySendError::Closed(_)) => eprintln!("WARNING: Solved webhook task stopped!"),
+        }
+    }
+}
+
+async fn run_webhook(url: String, client: Client, mut rx: Receiver<SolvedEvent>) {
+    while let Some(event) = rx.recv().await {
+        let body = match serde_json::to_string(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!("WARNING: Failed to serialize solved webhook event: {}", e);
+                continue;
+            }
+        };
+
+        let mut backoff = SOLVED_WEBHOOK_BACKOFF;
+        for attempt in 1..=SOLVED_WEBHOOK_ATTEMPTS {
+            let ret = client
+                .post(&url)
+                .header("content-type", "application/json")
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status());
+            match ret {
+                Ok(_) => break,
+                Err(e) if attempt == SOLVED_WEBHOOK_ATTEMPTS => {
+                    eprintln!(
+                        "WARNING: Solved webhook failed for {} after {} attempts: {}",
+                        event.ip, attempt, e
+                    );
+                }
+                Err(_) => {
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,235 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use salvo::http::{HeaderMap, HeaderValue};
+use salvo::prelude::*;
+use salvo::websocket::{Message, WebSocket, WebSocketUpgrade};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message as BackendMessage};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::{error::Error, helpers};
+
+type BackendSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Headers of the client's handshake that belong to its connection with the
+/// proxy, the handshake with the dest url gets its own.
+const HANDSHAKE_HEADERS: [&str; 4] = [
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+    "sec-websocket-accept",
+];
+
+/// Returns true if "headers" ask to upgrade the connection to a WebSocket.
+pub fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    let has_upgrade_token = headers
+        .get_all("connection")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+
+    has_upgrade_token
+        && headers
+            .get("upgrade")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.trim().eq_ignore_ascii_case("websocket"))
+}
+
+/// Swaps the scheme of a joined dest url for its WebSocket counterpart.
+pub fn backend_url(url: &str) -> Result<String, Error> {
+    if let Some(rest) = url.strip_prefix("https://") {
+        Ok(format!("wss://{}", rest))
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        Ok(format!("ws://{}", rest))
+    } else {
+        Err(format!("Dest url \"{}\" is not http or https", url).into())
+    }
+}
+
+/// Opens a WebSocket to "url" (a joined dest url), then upgrades the client's
+/// connection and passes messages both ways until either side closes. The
+/// dest url is connected to first, so a failure there can still be answered
+/// with an error page. A client handshake salvo rejects is answered with its
+/// status instead. A "timeout" of None waits as long as the dest url takes.
+pub async fn proxy(
+    req: &mut Request,
+    res: &mut Response,
+    url: &str,
+    client_ip: &str,
+    trust_forwarded_for: bool,
+    timeout: Option<Duration>,
+) -> Result<(), Error> {
+    let mut backend_req = backend_url(url)?.into_client_request()?;
+    for (k, v) in req.headers().iter() {
+        if helpers::is_forwarded_request_header(req.headers(), k.as_str())
+            && !HANDSHAKE_HEADERS.contains(&k.as_str())
+        {
+            backend_req.headers_mut().append(k, v.clone());
+        }
+    }
+    let header_value = |value: &str| {
+        HeaderValue::from_str(value)
+            .map_err(|_| Error::Generic(format!("Invalid header value \"{}\"", value)))
+    };
+    backend_req
+        .headers_mut()
+        .insert("x-real-ip", header_value(client_ip)?);
+    backend_req.headers_mut().insert(
+        "x-forwarded-for",
+        header_value(&helpers::forwarded_for(
+            req.headers(),
+            client_ip,
+            trust_forwarded_for,
+        )?)?,
+    );
+
+    let connect = tokio_tungstenite::connect_async(backend_req);
+    let (backend, backend_res) = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, connect).await.map_err(|_| {
+            Error::IO(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Timed out connecting to dest url WebSocket",
+            ))
+        })??,
+        None => connect.await?,
+    };
+
+    // Only the subprotocol the dest url picked may be agreed to with the
+    // client.
+    let protocol: Option<String> = backend_res
+        .headers()
+        .get("sec-websocket-protocol")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    let mut upgrade = WebSocketUpgrade::new();
+    if let Some(protocol) = &protocol {
+        upgrade = upgrade.protocols(&[protocol]);
+    }
+
+    if let Err(status_error) = upgrade
+        .upgrade(req, res, move |client| pass_messages(client, backend))
+        .await
+    {
+        res.render(status_error);
+    }
+
+    Ok(())
+}
+
+async fn pass_messages(client: WebSocket, backend: BackendSocket) {
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut backend_tx, mut backend_rx) = backend.split();
+
+    let to_backend = async {
+        while let Some(Ok(message)) = client_rx.next().await {
+            let Some(message) = to_backend_message(message) else {
+                continue;
+            };
+            let is_close = message.is_close();
+            if backend_tx.send(message).await.is_err() || is_close {
+                break;
+            }
+        }
+        backend_tx.close().await.ok();
+    };
+    let to_client = async {
+        while let Some(Ok(message)) = backend_rx.next().await {
+            let Some(message) = to_client_message(message) else {
+                continue;
+            };
+            let is_close = message.is_close();
+            if client_tx.send(message).await.is_err() || is_close {
+                break;
+            }
+        }
+        client_tx.close().await.ok();
+    };
+
+    futures::join!(to_backend, to_client);
+}
+
+/// Pings and pongs are answered by each side's own connection, so only data
+/// and close messages are passed on.
+fn to_backend_message(message: Message) -> Option<BackendMessage> {
+    if message.is_text() {
+        message
+            .as_str()
+            .ok()
+            .map(|text| BackendMessage::text(text.to_owned()))
+    } else if message.is_binary() {
+        Some(BackendMessage::binary(message.as_bytes().to_vec()))
+    } else if message.is_close() {
+        Some(BackendMessage::Close(message.close_frame().map(
+            |(code, reason)| CloseFrame {
+                code: code.into(),
+                reason: reason.to_owned().into(),
+            },
+        )))
+    } else {
+        None
+    }
+}
+
+fn to_client_message(message: BackendMessage) -> Option<Message> {
+    match message {
+        BackendMessage::Text(text) => Some(Message::text(text)),
+        BackendMessage::Binary(data) => Some(Message::binary(data)),
+        BackendMessage::Close(Some(frame)) => {
+            Some(Message::close_with(u16::from(frame.code), frame.reason))
+        }
+        BackendMessage::Close(None) => Some(Message::close()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_upgrade_request() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_upgrade_request(&headers));
+
+        headers.insert("upgrade", "WebSocket".parse().unwrap());
+        assert!(!is_upgrade_request(&headers));
+
+        headers.insert("connection", "keep-alive, Upgrade".parse().unwrap());
+        assert!(is_upgrade_request(&headers));
+
+        headers.insert("upgrade", "h2c".parse().unwrap());
+        assert!(!is_upgrade_request(&headers));
+    }
+
+    #[test]
+    fn test_backend_url() {
+        assert_eq!(
+            backend_url("http://127.0.0.1:9999/chat?room=1").unwrap(),
+            "ws://127.0.0.1:9999/chat?room=1"
+        );
+        assert_eq!(
+            backend_url("https://example.com/chat").unwrap(),
+            "wss://example.com/chat"
+        );
+        assert!(backend_url("ftp://example.com/").is_err());
+    }
+}
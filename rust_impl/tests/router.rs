@@ -0,0 +1,114 @@
+// ISC License
+//
+// Copyright (c) 2025-2026 Stephen Seo
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH
+// REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY
+// AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT,
+// INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM
+// LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR
+// OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR
+// PERFORMANCE OF THIS SOFTWARE.
+
+use std::net::SocketAddr;
+
+use poor_mans_anubis_rust_frontend::{Args, SharedState, build_router, factors_js_fn, handler_fn};
+use salvo::prelude::*;
+use salvo::test::{ResponseExt, TestClient};
+
+fn client_request(url: &str) -> Request {
+    let mut req = TestClient::get(url).build();
+    *req.remote_addr_mut() = SocketAddr::from(([203, 0, 113, 60], 40000)).into();
+    *req.local_addr_mut() = SocketAddr::from(([127, 0, 0, 1], 8180)).into();
+    req
+}
+
+#[tokio::test]
+async fn test_challenge_through_router() {
+    let args = Args {
+        sqlite_db_file: std::env::temp_dir()
+            .join(format!("pma_test_router_{}", std::process::id())),
+        api_url: "/pma_api".into(),
+        js_factors_url: "/pma_factors.js".into(),
+        challenge_timeout_mins: 5,
+        allowed_timeout_mins: 60,
+        ..Default::default()
+    };
+    let state = SharedState::new(&args).await.unwrap();
+    let service = Service::new(build_router(&state));
+
+    let mut page = service
+        .handle(client_request("http://127.0.0.1:8180/some/page"))
+        .await;
+    let page_status = page.status_code;
+    let page = page.take_string().await.unwrap();
+    let js_url = page
+        .split_once("new Worker(\"")
+        .and_then(|(_, rest)| rest.split_once('"'))
+        .map(|(js_url, _)| js_url.to_owned())
+        .unwrap();
+
+    let mut js = service
+        .handle(client_request(&format!("http://127.0.0.1:8180{}", js_url)))
+        .await;
+    let js_status = js.status_code;
+    let js = js.take_string().await.unwrap();
+    std::fs::remove_file(&args.sqlite_db_file).ok();
+
+    assert_eq!(page_status, Some(StatusCode::OK));
+    assert!(js_url.starts_with("/pma_factors.js?id="));
+    assert_eq!(js_status, Some(StatusCode::OK));
+    assert!(js.contains("\"id\": \""));
+}
+
+#[tokio::test]
+async fn test_handler_fn_in_caller_router() {
+    let args = Args {
+        sqlite_db_file: std::env::temp_dir()
+            .join(format!("pma_test_caller_router_{}", std::process::id())),
+        api_url: "/pma_api".into(),
+        js_factors_url: "/pma_factors.js".into(),
+        challenge_timeout_mins: 5,
+        allowed_timeout_mins: 60,
+        ..Default::default()
+    };
+    let state = SharedState::new(&args).await.unwrap();
+
+    #[handler]
+    async fn health_fn(res: &mut Response) {
+        res.render("healthy");
+    }
+    let router = state
+        .inject(Router::new())
+        .push(Router::new().path("health").get(health_fn))
+        .push(Router::new().path("pma_factors.js").get(factors_js_fn))
+        .push(Router::new().path("{**}").goal(handler_fn));
+    let service = Service::new(router);
+
+    let mut health = service
+        .handle(client_request("http://127.0.0.1:8180/health"))
+        .await;
+    let health = health.take_string().await.unwrap();
+    let mut page = service
+        .handle(client_request("http://127.0.0.1:8180/some/page"))
+        .await;
+    let page_status = page.status_code;
+    let page = page.take_string().await.unwrap();
+    let js_url = page
+        .split_once("new Worker(\"")
+        .and_then(|(_, rest)| rest.split_once('"'))
+        .map(|(js_url, _)| js_url.to_owned())
+        .unwrap();
+    let js = service
+        .handle(client_request(&format!("http://127.0.0.1:8180{}", js_url)))
+        .await;
+    std::fs::remove_file(&args.sqlite_db_file).ok();
+
+    assert_eq!(health, "healthy");
+    assert_eq!(page_status, Some(StatusCode::OK));
+    assert_eq!(js.status_code, Some(StatusCode::OK));
+}